@@ -1,7 +1,7 @@
 /*
-    This will be used to apply a standard yellow/red colorization to the output images.
+    Applies a standard yellow/red colorization to the output images.
 */
-use cr2_to_tiff_halpha::{constants, print, vprintln, path, imagebuffer};
+use cr2_to_tiff_halpha::{constants, print, vprintln, path, imagebuffer, colorize};
 
 #[macro_use]
 extern crate clap;
@@ -32,6 +32,46 @@ fn main() {
                         .help("Output")
                         .required(true)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_PALETTE)
+                        .short(constants::param::PARAM_PALETTE_SHORT)
+                        .long(constants::param::PARAM_PALETTE)
+                        .value_name("PALETTE")
+                        .help("Standard palette")
+                        .required(false)
+                        .possible_values(&[constants::colorize::PALETTE_RED, 
+                                           constants::colorize::PALETTE_ORANGE, 
+                                           constants::colorize::PALETTE_YELLOW])
+                        .conflicts_with_all(&[constants::param::PARAM_GRADIENT, constants::param::PARAM_GAMMA])
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_GRADIENT)
+                        .short(constants::param::PARAM_GRADIENT_SHORT)
+                        .long(constants::param::PARAM_GRADIENT)
+                        .value_name("GRADIENT")
+                        .help("Gradient stops as POSITION:RRGGBB,... (e.g. 0:000000,0.5:c03000,1:ffffff)")
+                        .required(false)
+                        .conflicts_with(constants::param::PARAM_GAMMA)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_GAMMA)
+                        .short(constants::param::PARAM_GAMMA_SHORT)
+                        .long(constants::param::PARAM_GAMMA)
+                        .value_name("GAMMA")
+                        .help("Per-channel gamma as RED,GREEN,BLUE (e.g. 1.4,0.8,0.4)")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_INVERT)
+                        .short(constants::param::PARAM_INVERT_SHORT)
+                        .long(constants::param::PARAM_INVERT)
+                        .help("Invert intensities before coloring"))
+                    .arg(Arg::with_name(constants::param::PARAM_FORMAT)
+                        .short(constants::param::PARAM_FORMAT_SHORT)
+                        .long(constants::param::PARAM_FORMAT)
+                        .value_name("FORMAT")
+                        .help("Output sample format")
+                        .required(false)
+                        .possible_values(&[constants::format::RGB16, 
+                                           constants::format::FLOAT32])
+                        .default_value(constants::format::RGB16)
+                        .takes_value(true))
                     .get_matches();
 
     if matches.is_present(constants::param::PARAM_VERBOSE) {
@@ -40,4 +80,42 @@ fn main() {
 
     let input = matches.value_of(constants::param::PARAM_INPUTS).unwrap();
     let output = matches.value_of(constants::param::PARAM_OUTPUT).unwrap();
-}
\ No newline at end of file
+
+    let method = if let Some(gradient) = matches.value_of(constants::param::PARAM_GRADIENT) {
+        colorize::Palette::from_gradient_str(gradient).map(colorize::ColorizeMethod::Gradient)
+    } else if let Some(gamma) = matches.value_of(constants::param::PARAM_GAMMA) {
+        colorize::ColorizeMethod::from_gamma_str(gamma)
+    } else {
+        let palette = matches.value_of(constants::param::PARAM_PALETTE).unwrap_or(constants::colorize::PALETTE_RED);
+        colorize::Palette::from_name(palette).map(colorize::ColorizeMethod::Gradient)
+    };
+
+    let method = match method {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let options = colorize::ColorizeOptions{
+        method,
+        invert:matches.is_present(constants::param::PARAM_INVERT)
+    };
+    let format = imagebuffer::OutputFormat::from_name(matches.value_of(constants::param::PARAM_FORMAT).unwrap()).unwrap();
+
+    if !path::file_exists(input) {
+        eprintln!("File not found: {}", input);
+        std::process::exit(1);
+    }
+
+    vprintln!("Processing File: {}", input);
+    let result = imagebuffer::ImageBuffer::from_file(input)
+                    .and_then(|image| colorize::colorize(&image, &options))
+                    .and_then(|colored| colored.save_as(output, format));
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
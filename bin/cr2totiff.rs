@@ -1,11 +1,16 @@
 
-use cr2_to_tiff_halpha::{constants, print, raw_to_tiff};
+use cr2_to_tiff_halpha::{cfa, calibration, flatfield, parallel, demosaic, constants, error, print, raw_to_tiff, imagebuffer};
 
 #[macro_use]
 extern crate clap;
 
 use clap::{Arg, App};
 
+fn exit_with_error(e:error::Error) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(1);
+}
+
 fn main() { 
     let matches = App::new(crate_name!())
                     .version(crate_version!())
@@ -24,6 +29,49 @@ fn main() {
                         .help("Master flat file")
                         .required(false)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_DARK_SCALE)
+                        .short(constants::param::PARAM_DARK_SCALE_SHORT)
+                        .long(constants::param::PARAM_DARK_SCALE)
+                        .value_name("DARKSCALE")
+                        .help("Scale the master dark by the exposure time ratio or an optimized factor")
+                        .required(false)
+                        .possible_values(&[constants::darkscale::NONE, 
+                                           constants::darkscale::EXPOSURE, 
+                                           constants::darkscale::OPTIMIZE])
+                        .default_value(constants::darkscale::NONE)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_BIAS)
+                        .short(constants::param::PARAM_BIAS_SHORT)
+                        .long(constants::param::PARAM_BIAS)
+                        .value_name("BIAS")
+                        .help("Master bias file")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FLAT_DARK)
+                        .short(constants::param::PARAM_FLAT_DARK_SHORT)
+                        .long(constants::param::PARAM_FLAT_DARK)
+                        .value_name("FLATDARK")
+                        .help("Master dark file for the flats")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FLAT_NORM)
+                        .short(constants::param::PARAM_FLAT_NORM_SHORT)
+                        .long(constants::param::PARAM_FLAT_NORM)
+                        .value_name("FLATNORM")
+                        .help("Level of the master flat normalized to unity")
+                        .required(false)
+                        .possible_values(&[constants::flatnorm::MEAN, 
+                                           constants::flatnorm::MEDIAN, 
+                                           constants::flatnorm::CENTER])
+                        .default_value(constants::flatnorm::MEAN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FLAT_OUTPUT)
+                        .short(constants::param::PARAM_FLAT_OUTPUT_SHORT)
+                        .long(constants::param::PARAM_FLAT_OUTPUT)
+                        .value_name("FLATOUT")
+                        .help("Save the normalized flat to this file")
+                        .required(false)
+                        .takes_value(true))
                     .arg(Arg::with_name(constants::param::PARAM_INPUTS)
                         .short(constants::param::PARAM_INPUTS_SHORT)
                         .long(constants::param::PARAM_INPUTS)
@@ -32,9 +80,82 @@ fn main() {
                         .required(true)
                         .multiple(true)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FORMAT)
+                        .short(constants::param::PARAM_FORMAT_SHORT)
+                        .long(constants::param::PARAM_FORMAT)
+                        .value_name("FORMAT")
+                        .help("Output sample format")
+                        .required(false)
+                        .possible_values(&[constants::format::RGB16, 
+                                           constants::format::GRAY16, 
+                                           constants::format::FLOAT32])
+                        .default_value(constants::format::RGB16)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_CHANNEL)
+                        .short(constants::param::PARAM_CHANNEL_SHORT)
+                        .long(constants::param::PARAM_CHANNEL)
+                        .value_name("CHANNEL")
+                        .help("Bayer plane to extract from the raws")
+                        .required(false)
+                        .possible_values(&[constants::cfa::RED, 
+                                           constants::cfa::GREEN1, 
+                                           constants::cfa::GREEN2, 
+                                           constants::cfa::BLUE, 
+                                           constants::cfa::GREEN])
+                        .default_value(constants::cfa::RED)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_DEMOSAIC)
+                        .short(constants::param::PARAM_DEMOSAIC_SHORT)
+                        .long(constants::param::PARAM_DEMOSAIC)
+                        .value_name("DEMOSAIC")
+                        .help("Convert to full resolution color using the given demosaic method")
+                        .required(false)
+                        .possible_values(&[constants::demosaic::BILINEAR, 
+                                           constants::demosaic::EDGE_AWARE])
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_THREADS)
+                        .short(constants::param::PARAM_THREADS_SHORT)
+                        .long(constants::param::PARAM_THREADS)
+                        .value_name("THREADS")
+                        .help("Number of worker threads (requires the parallel feature)")
+                        .required(false)
+                        .takes_value(true))
                     .arg(Arg::with_name(constants::param::PARAM_VERBOSE)
                         .short(constants::param::PARAM_VERBOSE)
                         .help("Show verbose output"))
+                    .arg(Arg::with_name(constants::param::PARAM_CENTER)
+                        .short(constants::param::PARAM_CENTER_SHORT)
+                        .long(constants::param::PARAM_CENTER)
+                        .value_name("CENTER")
+                        .help("Method used to locate the solar disk for centering")
+                        .required(false)
+                        .possible_values(&[constants::centering::CENTER_OF_MASS, 
+                                           constants::centering::LIMB])
+                        .default_value(constants::centering::CENTER_OF_MASS)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_CROP)
+                        .short(constants::param::PARAM_CROP_SHORT)
+                        .long(constants::param::PARAM_CROP)
+                        .value_name("WIDTHxHEIGHT")
+                        .help("Centered crop size (default 1400x1400)")
+                        .required(false)
+                        .conflicts_with_all(&[constants::param::PARAM_ROI, constants::param::PARAM_MARGIN])
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_ROI)
+                        .short(constants::param::PARAM_ROI_SHORT)
+                        .long(constants::param::PARAM_ROI)
+                        .value_name("LEFT,TOP,WIDTH,HEIGHT")
+                        .help("Crop region within the centered frame")
+                        .required(false)
+                        .conflicts_with(constants::param::PARAM_MARGIN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_MARGIN)
+                        .short(constants::param::PARAM_MARGIN_SHORT)
+                        .long(constants::param::PARAM_MARGIN)
+                        .value_name("MARGIN")
+                        .help("Crop to the disk plus a margin, as a fraction of the detected solar radius")
+                        .required(false)
+                        .takes_value(true))
                     .get_matches();
 
 
@@ -44,7 +165,48 @@ fn main() {
         print::set_verbose(true);
     }
 
-    let dark = if matches.value_of(constants::param::PARAM_DARK) == None { constants::status::EMPTY } else { matches.value_of(constants::param::PARAM_DARK).unwrap() };
-    let flat = if matches.value_of(constants::param::PARAM_FLAT) == None { constants::status::EMPTY } else { matches.value_of(constants::param::PARAM_FLAT).unwrap() };
-    raw_to_tiff::run_convert(vals, dark, flat);
+    parallel::set_threads(value_t!(matches, constants::param::PARAM_THREADS, usize).unwrap_or(0));
+
+    let dark = matches.value_of(constants::param::PARAM_DARK).unwrap_or(constants::status::EMPTY);
+    let flat = matches.value_of(constants::param::PARAM_FLAT).unwrap_or(constants::status::EMPTY);
+    let crop = if let Some(size) = matches.value_of(constants::param::PARAM_CROP) {
+        raw_to_tiff::CropMode::from_size_str(size).unwrap_or_else(|e| exit_with_error(e))
+    } else if let Some(region) = matches.value_of(constants::param::PARAM_ROI) {
+        raw_to_tiff::CropMode::from_region_str(region).unwrap_or_else(|e| exit_with_error(e))
+    } else if let Some(margin) = matches.value_of(constants::param::PARAM_MARGIN) {
+        raw_to_tiff::CropMode::from_margin_str(margin).unwrap_or_else(|e| exit_with_error(e))
+    } else {
+        raw_to_tiff::CalibrationOptions::default().crop
+    };
+
+    let channel = cfa::CfaChannel::from_name(matches.value_of(constants::param::PARAM_CHANNEL).unwrap()).unwrap();
+
+    let options = raw_to_tiff::CalibrationOptions{
+        centering:raw_to_tiff::Centering::from_name(matches.value_of(constants::param::PARAM_CENTER).unwrap()).unwrap(),
+        crop,
+        channel,
+        demosaic:matches.value_of(constants::param::PARAM_DEMOSAIC).map(|m| demosaic::DemosaicMethod::from_name(m).unwrap()),
+        dark_scaling:calibration::DarkScaling::from_name(matches.value_of(constants::param::PARAM_DARK_SCALE).unwrap()).unwrap()
+    };
+    let format = imagebuffer::OutputFormat::from_name(matches.value_of(constants::param::PARAM_FORMAT).unwrap()).unwrap();
+    let bias = matches.value_of(constants::param::PARAM_BIAS).unwrap_or(constants::status::EMPTY);
+    let flat_dark = matches.value_of(constants::param::PARAM_FLAT_DARK).unwrap_or(constants::status::EMPTY);
+    let flat_normalization = flatfield::FlatNormalization::from_name(matches.value_of(constants::param::PARAM_FLAT_NORM).unwrap()).unwrap();
+    let frames = match calibration::CalibrationFrames::from_files(bias, dark, flat_dark, flat, flat_normalization) {
+        Ok(frames) => frames,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(flat_output) = matches.value_of(constants::param::PARAM_FLAT_OUTPUT) {
+        if let Err(e) = frames.save_flat_field(flat_output) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = raw_to_tiff::run_convert(vals, &frames, &options, format) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 
-use cr2_to_tiff_halpha::{mean, constants, print};
+use cr2_to_tiff_halpha::{cfa, parallel, mean, constants, print, stacking, imagebuffer};
 
 
 #[macro_use]
@@ -27,6 +27,64 @@ fn main() {
                         .required(true)
                         .multiple(true)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_METHOD)
+                        .short(constants::param::PARAM_METHOD_SHORT)
+                        .long(constants::param::PARAM_METHOD)
+                        .value_name("METHOD")
+                        .help("Stacking method")
+                        .required(false)
+                        .possible_values(&[constants::stacking::METHOD_MEAN, 
+                                           constants::stacking::METHOD_MEDIAN, 
+                                           constants::stacking::METHOD_SIGMA_CLIP, 
+                                           constants::stacking::METHOD_WINSORIZED])
+                        .default_value(constants::stacking::METHOD_MEAN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_KAPPA)
+                        .short(constants::param::PARAM_KAPPA_SHORT)
+                        .long(constants::param::PARAM_KAPPA)
+                        .value_name("KAPPA")
+                        .help("Rejection threshold in standard deviations for sigma clipping/winsorizing")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FORMAT)
+                        .short(constants::param::PARAM_FORMAT_SHORT)
+                        .long(constants::param::PARAM_FORMAT)
+                        .value_name("FORMAT")
+                        .help("Output sample format")
+                        .required(false)
+                        .possible_values(&[constants::format::RGB16, 
+                                           constants::format::GRAY16, 
+                                           constants::format::FLOAT32])
+                        .default_value(constants::format::RGB16)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_CHANNEL)
+                        .short(constants::param::PARAM_CHANNEL_SHORT)
+                        .long(constants::param::PARAM_CHANNEL)
+                        .value_name("CHANNEL")
+                        .help("Bayer plane to extract from the raws, or the whole mosaic")
+                        .required(false)
+                        .possible_values(&[constants::cfa::RED, 
+                                           constants::cfa::GREEN1, 
+                                           constants::cfa::GREEN2, 
+                                           constants::cfa::BLUE, 
+                                           constants::cfa::GREEN, 
+                                           constants::cfa::MOSAIC])
+                        .default_value(constants::cfa::RED)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_THREADS)
+                        .short(constants::param::PARAM_THREADS_SHORT)
+                        .long(constants::param::PARAM_THREADS)
+                        .value_name("THREADS")
+                        .help("Number of worker threads (requires the parallel feature)")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_WEIGHTS)
+                        .short(constants::param::PARAM_WEIGHTS_SHORT)
+                        .long(constants::param::PARAM_WEIGHTS)
+                        .value_name("WEIGHTS")
+                        .help("File of FILE,WEIGHT lines giving each input's weight in the stack")
+                        .required(false)
+                        .takes_value(true))
                     .arg(Arg::with_name(constants::param::PARAM_VERBOSE)
                         .short(constants::param::PARAM_VERBOSE)
                         .help("Show verbose output"))
@@ -39,11 +97,36 @@ fn main() {
         print::set_verbose(true);
     }
 
+    parallel::set_threads(value_t!(matches, constants::param::PARAM_THREADS, usize).unwrap_or(0));
+
+    let kappa = value_t!(matches, constants::param::PARAM_KAPPA, f32).unwrap_or(constants::DEFAULT_CLIP_KAPPA);
+    let method = stacking::StackMethod::from_name(matches.value_of(constants::param::PARAM_METHOD).unwrap(), 
+                                                  kappa, 
+                                                  constants::DEFAULT_CLIP_ITERATIONS).unwrap();
+    let channel = match matches.value_of(constants::param::PARAM_CHANNEL).unwrap() {
+        constants::cfa::MOSAIC => None,
+        name => Some(cfa::CfaChannel::from_name(name).unwrap())
+    };
+    let format = imagebuffer::OutputFormat::from_name(matches.value_of(constants::param::PARAM_FORMAT).unwrap()).unwrap();
+    let weights = match matches.value_of(constants::param::PARAM_WEIGHTS) {
+        Some(weights_file) => match stacking::FrameWeights::from_file(weights_file) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None
+    };
+
     if matches.value_of(constants::param::PARAM_OUTPUT) == None {
         eprintln!("Error: Output path parameter required for stack output");
     } else {
         let output = matches.value_of(constants::param::PARAM_OUTPUT).unwrap();
-        mean::run_mean_stack(vals, output); 
+        if let Err(e) = mean::run_stack(vals, output, &method, channel, weights.as_ref(), format) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     }
 
 }
\ No newline at end of file
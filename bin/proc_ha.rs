@@ -1,5 +1,5 @@
 
-use cr2_to_tiff_halpha::{constants, print, vprintln, path, imagebuffer, raw_to_tiff, mean};
+use cr2_to_tiff_halpha::{cfa, calibration, flatfield, demosaic, parallel, constants, error, print, imagebuffer, raw_to_tiff, mean, batch, quality, stacking, alignment, drizzle, datetime, fieldrotation, solar, interpolation, fits, limb, vprintln};
 
 #[macro_use]
 extern crate clap;
@@ -8,6 +8,10 @@ use clap::{Arg, App};
 
 
 
+fn exit_with_error(e:error::Error) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(1);
+}
 
 fn main() {
     
@@ -30,6 +34,40 @@ fn main() {
                         .required(false)
                         .multiple(true)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_BIAS)
+                        .short(constants::param::PARAM_BIAS_SHORT)
+                        .long(constants::param::PARAM_BIAS)
+                        .value_name("BIAS")
+                        .help("Master bias file(s)")
+                        .required(false)
+                        .multiple(true)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FLAT_DARK)
+                        .short(constants::param::PARAM_FLAT_DARK_SHORT)
+                        .long(constants::param::PARAM_FLAT_DARK)
+                        .value_name("FLATDARK")
+                        .help("Master dark file(s) for the flats")
+                        .required(false)
+                        .multiple(true)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FLAT_NORM)
+                        .short(constants::param::PARAM_FLAT_NORM_SHORT)
+                        .long(constants::param::PARAM_FLAT_NORM)
+                        .value_name("FLATNORM")
+                        .help("Level of the master flat normalized to unity")
+                        .required(false)
+                        .possible_values(&[constants::flatnorm::MEAN, 
+                                           constants::flatnorm::MEDIAN, 
+                                           constants::flatnorm::CENTER])
+                        .default_value(constants::flatnorm::MEAN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FLAT_OUTPUT)
+                        .short(constants::param::PARAM_FLAT_OUTPUT_SHORT)
+                        .long(constants::param::PARAM_FLAT_OUTPUT)
+                        .value_name("FLATOUT")
+                        .help("Save the normalized flat to this file")
+                        .required(false)
+                        .takes_value(true))
                     .arg(Arg::with_name(constants::param::PARAM_INPUTS)
                         .short(constants::param::PARAM_INPUTS_SHORT)
                         .long(constants::param::PARAM_INPUTS)
@@ -38,6 +76,153 @@ fn main() {
                         .required(true)
                         .multiple(true)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_FORMAT)
+                        .short(constants::param::PARAM_FORMAT_SHORT)
+                        .long(constants::param::PARAM_FORMAT)
+                        .value_name("FORMAT")
+                        .help("Output sample format")
+                        .required(false)
+                        .possible_values(&[constants::format::RGB16, 
+                                           constants::format::GRAY16, 
+                                           constants::format::FLOAT32])
+                        .default_value(constants::format::RGB16)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_CHANNEL)
+                        .short(constants::param::PARAM_CHANNEL_SHORT)
+                        .long(constants::param::PARAM_CHANNEL)
+                        .value_name("CHANNEL")
+                        .help("Bayer plane to extract from the raws")
+                        .required(false)
+                        .possible_values(&[constants::cfa::RED, 
+                                           constants::cfa::GREEN1, 
+                                           constants::cfa::GREEN2, 
+                                           constants::cfa::BLUE, 
+                                           constants::cfa::GREEN])
+                        .default_value(constants::cfa::RED)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_DEMOSAIC)
+                        .short(constants::param::PARAM_DEMOSAIC_SHORT)
+                        .long(constants::param::PARAM_DEMOSAIC)
+                        .value_name("DEMOSAIC")
+                        .help("Stack in full resolution color using the given demosaic method")
+                        .required(false)
+                        .possible_values(&[constants::demosaic::BILINEAR, 
+                                           constants::demosaic::EDGE_AWARE])
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_THREADS)
+                        .short(constants::param::PARAM_THREADS_SHORT)
+                        .long(constants::param::PARAM_THREADS)
+                        .value_name("THREADS")
+                        .help("Number of worker threads (requires the parallel feature)")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_BATCH)
+                        .short(constants::param::PARAM_BATCH_SHORT)
+                        .long(constants::param::PARAM_BATCH)
+                        .value_name("BATCH")
+                        .help("Number of lights decoded at once (default: two per worker thread)")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_QUALITY)
+                        .short(constants::param::PARAM_QUALITY_SHORT)
+                        .long(constants::param::PARAM_QUALITY)
+                        .value_name("METRIC")
+                        .help("Sharpness metric used to rank lights")
+                        .required(false)
+                        .possible_values(&[constants::quality::LAPLACIAN, 
+                                           constants::quality::GRADIENT, 
+                                           constants::quality::LIMB])
+                        .default_value(constants::quality::LAPLACIAN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_SELECT)
+                        .short(constants::param::PARAM_SELECT_SHORT)
+                        .long(constants::param::PARAM_SELECT)
+                        .value_name("N|X%")
+                        .help("Stack only the sharpest N lights, or the sharpest X percent. Scoring, also done for -Q and -w, decodes each light an extra time.")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_REPORT)
+                        .short(constants::param::PARAM_REPORT_SHORT)
+                        .long(constants::param::PARAM_REPORT)
+                        .value_name("CSV")
+                        .help("Write the ranked light quality scores to a CSV file")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_WEIGHT)
+                        .short(constants::param::PARAM_WEIGHT_SHORT)
+                        .long(constants::param::PARAM_WEIGHT)
+                        .help("Weight each light by its quality score relative to the sharpest")
+                        .conflicts_with(constants::param::PARAM_WEIGHTS))
+                    .arg(Arg::with_name(constants::param::PARAM_WEIGHTS)
+                        .short(constants::param::PARAM_WEIGHTS_SHORT)
+                        .long(constants::param::PARAM_WEIGHTS)
+                        .value_name("WEIGHTS")
+                        .help("File of FILE,WEIGHT lines giving each light's weight in the stack")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_AP)
+                        .short(constants::param::PARAM_AP_SHORT)
+                        .long(constants::param::PARAM_AP)
+                        .value_name("BOXSIZE")
+                        .help("Stack with multi-point local alignment using alignment boxes of this size")
+                        .required(false)
+                        .conflicts_with(constants::param::PARAM_DRIZZLE)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_DRIZZLE)
+                        .short(constants::param::PARAM_DRIZZLE_SHORT)
+                        .long(constants::param::PARAM_DRIZZLE)
+                        .value_name("SCALE")
+                        .help("Drizzle lights onto a grid this many times finer (default 2), saving a weight map alongside the output")
+                        .required(false)
+                        .min_values(0)
+                        .max_values(1)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_PIXFRAC)
+                        .short(constants::param::PARAM_PIXFRAC_SHORT)
+                        .long(constants::param::PARAM_PIXFRAC)
+                        .value_name("PIXFRAC")
+                        .help("Drizzle drop size as a fraction of an input pixel (default 0.5)")
+                        .required(false)
+                        .requires(constants::param::PARAM_DRIZZLE)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_OBSERVER)
+                        .short(constants::param::PARAM_OBSERVER_SHORT)
+                        .long(constants::param::PARAM_OBSERVER)
+                        .value_name("LAT,LON")
+                        .help("Observer latitude and longitude in degrees, east positive. Derotates lights shot on an alt-az mount.")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_TARGET)
+                        .short(constants::param::PARAM_TARGET_SHORT)
+                        .long(constants::param::PARAM_TARGET)
+                        .value_name("RA,DEC")
+                        .help("Target right ascension and declination in degrees (default: the Sun)")
+                        .required(false)
+                        .requires(constants::param::PARAM_OBSERVER)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_UTC_OFFSET)
+                        .short(constants::param::PARAM_UTC_OFFSET_SHORT)
+                        .long(constants::param::PARAM_UTC_OFFSET)
+                        .value_name("HOURS")
+                        .help("Hours the camera clock is ahead of UTC (default 0)")
+                        .required(false)
+                        .allow_hyphen_values(true)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_DARK_SCALE)
+                        .short(constants::param::PARAM_DARK_SCALE_SHORT)
+                        .long(constants::param::PARAM_DARK_SCALE)
+                        .value_name("DARKSCALE")
+                        .help("Scale the master dark by the exposure time ratio or an optimized factor")
+                        .required(false)
+                        .possible_values(&[constants::darkscale::NONE, 
+                                           constants::darkscale::EXPOSURE, 
+                                           constants::darkscale::OPTIMIZE])
+                        .default_value(constants::darkscale::NONE)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_NORTH_UP)
+                        .short(constants::param::PARAM_NORTH_UP_SHORT)
+                        .long(constants::param::PARAM_NORTH_UP)
+                        .help("Rotate the stack so solar north is up"))
                     .arg(Arg::with_name(constants::param::PARAM_VERBOSE)
                         .short(constants::param::PARAM_VERBOSE)
                         .help("Show verbose output"))
@@ -48,51 +233,285 @@ fn main() {
                         .help("Output")
                         .required(true)
                         .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_METHOD)
+                        .short(constants::param::PARAM_METHOD_SHORT)
+                        .long(constants::param::PARAM_METHOD)
+                        .value_name("METHOD")
+                        .help("Stacking method for darks, flats and lights")
+                        .required(false)
+                        .possible_values(&[constants::stacking::METHOD_MEAN, 
+                                           constants::stacking::METHOD_MEDIAN, 
+                                           constants::stacking::METHOD_SIGMA_CLIP, 
+                                           constants::stacking::METHOD_WINSORIZED])
+                        .default_value(constants::stacking::METHOD_MEAN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_KAPPA)
+                        .short(constants::param::PARAM_KAPPA_SHORT)
+                        .long(constants::param::PARAM_KAPPA)
+                        .value_name("KAPPA")
+                        .help("Rejection threshold in standard deviations for sigma clipping/winsorizing")
+                        .required(false)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_REGISTER)
+                        .short(constants::param::PARAM_REGISTER_SHORT)
+                        .long(constants::param::PARAM_REGISTER)
+                        .help("Register lights against the first frame using sub-pixel phase correlation"))
+                    .arg(Arg::with_name(constants::param::PARAM_INTERPOLATION)
+                        .short(constants::param::PARAM_INTERPOLATION_SHORT)
+                        .long(constants::param::PARAM_INTERPOLATION)
+                        .value_name("INTERP")
+                        .help("Interpolation used for sub-pixel registration")
+                        .required(false)
+                        .possible_values(&[constants::interpolation::BILINEAR, 
+                                           constants::interpolation::BICUBIC, 
+                                           constants::interpolation::LANCZOS])
+                        .default_value(constants::interpolation::BICUBIC)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_CENTER)
+                        .short(constants::param::PARAM_CENTER_SHORT)
+                        .long(constants::param::PARAM_CENTER)
+                        .value_name("CENTER")
+                        .help("Method used to locate the solar disk for centering")
+                        .required(false)
+                        .possible_values(&[constants::centering::CENTER_OF_MASS, 
+                                           constants::centering::LIMB])
+                        .default_value(constants::centering::CENTER_OF_MASS)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_CROP)
+                        .short(constants::param::PARAM_CROP_SHORT)
+                        .long(constants::param::PARAM_CROP)
+                        .value_name("WIDTHxHEIGHT")
+                        .help("Centered crop size (default 1400x1400)")
+                        .required(false)
+                        .conflicts_with_all(&[constants::param::PARAM_ROI, constants::param::PARAM_MARGIN])
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_ROI)
+                        .short(constants::param::PARAM_ROI_SHORT)
+                        .long(constants::param::PARAM_ROI)
+                        .value_name("LEFT,TOP,WIDTH,HEIGHT")
+                        .help("Crop region within the centered frame")
+                        .required(false)
+                        .conflicts_with(constants::param::PARAM_MARGIN)
+                        .takes_value(true))
+                    .arg(Arg::with_name(constants::param::PARAM_MARGIN)
+                        .short(constants::param::PARAM_MARGIN_SHORT)
+                        .long(constants::param::PARAM_MARGIN)
+                        .value_name("MARGIN")
+                        .help("Crop to the disk plus a margin, as a fraction of the detected solar radius")
+                        .required(false)
+                        .takes_value(true))
                     .get_matches();
 
 
     let lights: Vec<&str> = matches.values_of(constants::param::PARAM_INPUTS).unwrap().collect();
-    let darks: Vec<&str> = matches.values_of(constants::param::PARAM_DARK).unwrap().collect();
-    let flats: Vec<&str> = matches.values_of(constants::param::PARAM_FLAT).unwrap().collect();
 
     if matches.is_present(constants::param::PARAM_VERBOSE) {
         print::set_verbose(true);
     }
 
-    
-    // Change to use median
-    let darks_stack = mean::process_mean(darks).unwrap();
-    let flats_stack = mean::process_mean(flats).unwrap();
-
-    let mut stack = imagebuffer::ImageBuffer::new(1, 1).unwrap();
-    let mut cnt = 0;
-
-    for in_file in lights.iter() {
-        if path::file_exists(in_file) {
-            vprintln!("Processing File: {}", in_file);
-            
-            let calibrated = raw_to_tiff::calibrate_raw(in_file, &flats_stack, &darks_stack).unwrap();
-
-            if cnt == 0 {
-                stack = calibrated;
-            } else {
-                stack = stack.add(&calibrated).unwrap();
+    parallel::set_threads(value_t!(matches, constants::param::PARAM_THREADS, usize).unwrap_or(0));
+
+    let kappa = value_t!(matches, constants::param::PARAM_KAPPA, f32).unwrap_or(constants::DEFAULT_CLIP_KAPPA);
+    let method = stacking::StackMethod::from_name(matches.value_of(constants::param::PARAM_METHOD).unwrap(), 
+                                                  kappa, 
+                                                  constants::DEFAULT_CLIP_ITERATIONS).unwrap();
+    let register = matches.is_present(constants::param::PARAM_REGISTER);
+    let interp = interpolation::Interpolation::from_name(matches.value_of(constants::param::PARAM_INTERPOLATION).unwrap()).unwrap();
+    let channel = cfa::CfaChannel::from_name(matches.value_of(constants::param::PARAM_CHANNEL).unwrap()).unwrap();
+    let format = imagebuffer::OutputFormat::from_name(matches.value_of(constants::param::PARAM_FORMAT).unwrap()).unwrap();
+    let metric = quality::QualityMetric::from_name(matches.value_of(constants::param::PARAM_QUALITY).unwrap()).unwrap();
+    let selection = match matches.value_of(constants::param::PARAM_SELECT) {
+        Some(s) => quality::Selection::from_spec(s).unwrap_or_else(|e| exit_with_error(e)),
+        None => quality::Selection::All
+    };
+    let report = matches.value_of(constants::param::PARAM_REPORT);
+    let weight_by_quality = matches.is_present(constants::param::PARAM_WEIGHT);
+    let mut weights = matches.value_of(constants::param::PARAM_WEIGHTS)
+                             .map(|f| stacking::FrameWeights::from_file(f).unwrap_or_else(|e| exit_with_error(e)));
+    let local_alignment = if matches.is_present(constants::param::PARAM_AP) {
+        let box_size = value_t!(matches, constants::param::PARAM_AP, usize).unwrap_or_else(|e| e.exit());
+        let options = alignment::AlignmentOptions::with_box_size(box_size).unwrap_or_else(|e| exit_with_error(e));
+        if method != stacking::StackMethod::Mean {
+            eprintln!("Warning: Local alignment always combines frames with the mean, ignoring {:?}", method);
+        }
+        Some(alignment::AlignmentOptions{interpolation:interp, ..options})
+    } else {
+        None
+    };
+    let drizzle_options = if matches.is_present(constants::param::PARAM_DRIZZLE) {
+        let scale = match matches.value_of(constants::param::PARAM_DRIZZLE) {
+            Some(_) => value_t!(matches, constants::param::PARAM_DRIZZLE, f32).unwrap_or_else(|e| e.exit()),
+            None => constants::DEFAULT_DRIZZLE_SCALE
+        };
+        let pixfrac = value_t!(matches, constants::param::PARAM_PIXFRAC, f32).unwrap_or(constants::DEFAULT_DRIZZLE_PIXFRAC);
+        if method != stacking::StackMethod::Mean {
+            eprintln!("Warning: Drizzle always combines frames with the mean, ignoring {:?}", method);
+        }
+        Some(drizzle::DrizzleOptions::new(scale, pixfrac).unwrap_or_else(|e| exit_with_error(e)))
+    } else {
+        None
+    };
+    let crop = if let Some(size) = matches.value_of(constants::param::PARAM_CROP) {
+        raw_to_tiff::CropMode::from_size_str(size).unwrap_or_else(|e| exit_with_error(e))
+    } else if let Some(region) = matches.value_of(constants::param::PARAM_ROI) {
+        raw_to_tiff::CropMode::from_region_str(region).unwrap_or_else(|e| exit_with_error(e))
+    } else if let Some(margin) = matches.value_of(constants::param::PARAM_MARGIN) {
+        raw_to_tiff::CropMode::from_margin_str(margin).unwrap_or_else(|e| exit_with_error(e))
+    } else {
+        raw_to_tiff::CalibrationOptions::default().crop
+    };
+
+    let options = raw_to_tiff::CalibrationOptions{
+        centering:raw_to_tiff::Centering::from_name(matches.value_of(constants::param::PARAM_CENTER).unwrap()).unwrap(),
+        crop,
+        channel,
+        demosaic:matches.value_of(constants::param::PARAM_DEMOSAIC).map(|m| demosaic::DemosaicMethod::from_name(m).unwrap_or_else(|e| exit_with_error(e))),
+        dark_scaling:calibration::DarkScaling::from_name(matches.value_of(constants::param::PARAM_DARK_SCALE).unwrap()).unwrap_or_else(|e| exit_with_error(e))
+    };
+    // Color frames are stacked and registered whole; local alignment and drizzle work on a single plane
+    if options.demosaic.is_some() {
+        for param in [constants::param::PARAM_AP, constants::param::PARAM_DRIZZLE].iter() {
+            if matches.is_present(param) {
+                exit_with_error(error::Error::invalid_parameter(constants::status::UNSUPPORTED_WITH_COLOR, param));
             }
+        }
+    }
 
-            cnt = cnt + 1;
-        } else {
-            eprintln!("File not found: {}", in_file);
+    let observer = matches.value_of(constants::param::PARAM_OBSERVER)
+                          .map(|s| fieldrotation::Observer::from_spec(s).unwrap_or_else(|e| exit_with_error(e)));
+    let target = matches.value_of(constants::param::PARAM_TARGET)
+                        .map(|s| fieldrotation::Equatorial::from_spec(s).unwrap_or_else(|e| exit_with_error(e)));
+    let north_up = matches.is_present(constants::param::PARAM_NORTH_UP);
+    let utc_offset = if matches.is_present(constants::param::PARAM_UTC_OFFSET) {
+        value_t!(matches, constants::param::PARAM_UTC_OFFSET, f64).unwrap_or_else(|e| e.exit())
+    } else {
+        0.0
+    };
+
+    let mut batch_options = batch::BatchOptions{
+        calibration:options,
+        registration:if register { Some(interp) } else { None },
+        batch_size:value_t!(matches, constants::param::PARAM_BATCH, usize).unwrap_or(0),
+        field_rotation:None
+    };
+
+    // Color lights are calibrated before demosaicing, so their masters are whole mosaics
+    let master_channel = if options.demosaic.is_some() { None } else { Some(channel) };
+    let stack_masters = |param:&str| matches.values_of(param).map(|files| {
+        mean::process_stack(files.collect(), &method, master_channel).unwrap_or_else(|e| exit_with_error(e))
+    });
+    let bias_stack = stack_masters(constants::param::PARAM_BIAS);
+    let darks_stack = stack_masters(constants::param::PARAM_DARK);
+    let flat_darks_stack = stack_masters(constants::param::PARAM_FLAT_DARK);
+    let flats_stack = stack_masters(constants::param::PARAM_FLAT);
+    if let (Some(dark), Some(first)) = (&darks_stack, lights.first()) {
+        raw_to_tiff::check_dark(dark, first, options.dark_scaling);
+    }
+    let flat_normalization = flatfield::FlatNormalization::from_name(matches.value_of(constants::param::PARAM_FLAT_NORM).unwrap())
+                                                    .unwrap_or_else(|e| exit_with_error(e));
+    let frames = calibration::CalibrationFrames::normalized(bias_stack, darks_stack, flat_darks_stack, flats_stack, flat_normalization)
+                                                    .unwrap_or_else(|e| exit_with_error(e));
+    if let Some(flat_output) = matches.value_of(constants::param::PARAM_FLAT_OUTPUT) {
+        frames.save_flat_field(flat_output).unwrap_or_else(|e| exit_with_error(e));
+    }
+    vprintln!("Calibration: {}", frames.corrections(options.dark_scaling).join(", "));
+
+    // The session starts with the first light given that records a capture time. Field
+    // rotation is corrected to the orientation at that time and the solar ephemeris is
+    // taken then too, so P and celestial north agree however the lights are ranked below.
+    let session_start = if observer.is_some() || north_up {
+        let start = lights.iter().find_map(|file| match datetime::capture_time(file) {
+            Ok(time) => Some(time),
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                None
+            }
+        });
+        if start.is_none() {
+            exit_with_error(error::Error::decode(lights.first().unwrap_or(&""), constants::status::NO_CAPTURE_TIME));
+        }
+        start
+    } else {
+        None
+    };
+    let start_jd = session_start.map(|t| t.julian_date() - utc_offset / 24.0);
+    if let (Some(observer), Some(start), Some(jd)) = (observer, session_start, start_jd) {
+        let target = target.unwrap_or_else(|| solar::position(jd));
+        batch_options.field_rotation = Some(fieldrotation::FieldRotation::new(observer, target, utc_offset, &start, interp));
+    }
+
+    // Lucky imaging: score every light first, then stack only the sharpest, best first
+    // so the sharpest frame is the registration reference. Only the scores are kept, so
+    // each selected light is decoded and calibrated a second time when it is stacked.
+    let lights:Vec<&str> = if selection != quality::Selection::All || report.is_some() || weight_by_quality {
+        let scores = batch::score_lights(&lights, &frames, metric, &batch_options);
+        if let Some(report) = report {
+            quality::write_report(&scores, selection, report).unwrap_or_else(|e| exit_with_error(e));
         }
+        let selected = quality::select(&scores, selection);
+        if weight_by_quality {
+            weights = Some(quality::weights_from_scores(&selected));
+        }
+        selected.iter().map(|s| lights[s.index]).collect()
+    } else {
+        lights
+    };
+
+    let ephemeris = start_jd.map(solar::SolarEphemeris::at_julian_date);
+    if let Some(e) = ephemeris {
+        vprintln!("Solar P: {:.3}, B0: {:.3}, L0: {:.3}, radius: {:.1}\"", e.p, e.b0, e.l0, e.semi_diameter);
     }
 
     let output = matches.value_of(constants::param::PARAM_OUTPUT).unwrap();
-    if cnt > 0 {
-        stack = stack.scale(1.0 / cnt as f32).unwrap();
-        let stackmm = stack.get_min_max(-1.0).unwrap();
-        vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, cnt);
-        stack.save(output).expect(constants::status::OK);
+    let celestial_north = batch_options.field_rotation.map(|r| r.reference_north()).unwrap_or(0.0);
+    let header_for = |summary:&stacking::StackSummary, luminance:&imagebuffer::ImageBuffer| {
+        let mut header = mean::stack_header(summary);
+        header.disk = limb::find_disk(luminance).ok();
+        if let Some(jd) = start_jd {
+            header.date_obs = Some(datetime::DateTime::from_julian_date(jd).to_iso());
+        }
+        header.solar = ephemeris;
+        header
+    };
+
+    if let Some(demosaic) = options.demosaic {
+        let (stack, summary) = batch::stack_color_lights(&lights, &frames, &method, demosaic, weights.as_ref(), &batch_options)
+                                    .unwrap_or_else(|e| exit_with_error(e));
+        let stack = match ephemeris {
+            Some(e) if north_up => stack.map(|band| solar::north_up(band, &e, celestial_north, interp)).unwrap_or_else(|e| exit_with_error(e)),
+            _ => stack
+        };
+        if fits::is_fits_path(output) {
+            let header = header_for(&summary, &stack.luminance().unwrap_or_else(|e| exit_with_error(e)));
+            fits::save_bands(stack.bands(), output, format.fits_depth(), &header).unwrap_or_else(|e| exit_with_error(e));
+        } else {
+            stack.save_as(output, format).unwrap_or_else(|e| exit_with_error(e));
+        }
+        return;
+    }
+
+    let (stack, summary) = match (local_alignment, drizzle_options) {
+        (Some(alignment_options), _) => batch::stack_local_lights(&lights, &frames, weights.as_ref(), &alignment_options, &batch_options),
+        (None, Some(drizzle_options)) => {
+            batch::drizzle_lights(&lights, &frames, weights.as_ref(), &drizzle_options, &batch_options).and_then(|(stack, weight_map, summary)| {
+                let weight_map_file = drizzle::weight_map_path(output);
+                vprintln!("Saving drizzle weight map to {}", weight_map_file);
+                drizzle::save_weight_map(&weight_map, &weight_map_file, format)?;
+                Ok((stack, summary))
+            })
+        },
+        (None, None) => batch::stack_lights(&lights, &frames, &method, weights.as_ref(), &batch_options)
+    }.unwrap_or_else(|e| exit_with_error(e));
+
+    let stack = match ephemeris {
+        Some(e) if north_up => solar::north_up(&stack, &e, celestial_north, interp).unwrap_or_else(|e| exit_with_error(e)),
+        _ => stack
+    };
+
+    if fits::is_fits_path(output) {
+        fits::save(&stack, output, format.fits_depth(), &header_for(&summary, &stack)).unwrap_or_else(|e| exit_with_error(e));
     } else {
-        eprintln!("No files used");
+        stack.save_as(output, format).unwrap_or_else(|e| exit_with_error(e));
     }
     
 }
\ No newline at end of file
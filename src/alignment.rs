@@ -0,0 +1,334 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::interpolation::Interpolation;
+use crate::registration;
+use crate::parallel;
+use crate::limb;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// Options for multi-point local alignment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlignmentOptions {
+    // Width and height of each alignment box. Boxes are placed every half box so they overlap.
+    pub box_size: usize,
+    // Local shifts larger than this, in pixels, are treated as failed measurements
+    pub max_shift: f32,
+    // Boxes whose reference patch has a standard deviation below this fraction of
+    // the reference's range carry too little detail to correlate and are dropped
+    pub min_contrast: f32,
+    pub interpolation: Interpolation,
+}
+
+impl Default for AlignmentOptions {
+    fn default() -> Self {
+        AlignmentOptions{
+            box_size:constants::DEFAULT_AP_BOX_SIZE,
+            max_shift:constants::DEFAULT_AP_BOX_SIZE as f32 * constants::DEFAULT_AP_MAX_SHIFT_FRACTION,
+            min_contrast:constants::DEFAULT_AP_MIN_CONTRAST,
+            interpolation:Interpolation::Bicubic
+        }
+    }
+}
+
+impl AlignmentOptions {
+    // Options for the given box size, with the maximum shift scaled to match
+    pub fn with_box_size(box_size:usize) -> error::Result<AlignmentOptions> {
+        if box_size < constants::MIN_AP_BOX_SIZE {
+            return Err(Error::invalid_parameter(constants::status::INVALID_AP_BOX_SIZE, &box_size.to_string()));
+        }
+        Ok(AlignmentOptions{
+            box_size,
+            max_shift:box_size as f32 * constants::DEFAULT_AP_MAX_SHIFT_FRACTION,
+            ..Default::default()
+        })
+    }
+}
+
+// Residual shift of an alignment box after global registration, along with the mean
+// squared difference to the reference at the best match
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalOffset {
+    pub h: f32,
+    pub v: f32,
+    pub residual: f32,
+}
+
+// Center of an alignment box, in reference image coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlignmentPoint {
+    pub x: usize,
+    pub y: usize,
+}
+
+impl AlignmentPoint {
+    // The box around the point, extended by margin pixels on each side
+    fn patch(&self, image:&ImageBuffer, box_size:usize, margin:usize) -> error::Result<ImageBuffer> {
+        let half = (box_size / 2 + margin) as i64;
+        image.crop_region(self.x as i64 - half, self.y as i64 - half, box_size + 2 * margin, box_size + 2 * margin)
+    }
+}
+
+fn values_of(image:&ImageBuffer) -> error::Result<Vec<f32>> {
+    let mut v:Vec<f32> = Vec::with_capacity(image.width * image.height);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            v.push(image.get(x, y)?);
+        }
+    }
+    Ok(v)
+}
+
+// Finds the integer displacement of a box within a search area by minimizing the mean
+// squared difference over shifts of up to margin pixels in each direction. The search
+// area is the box extended by margin on every side. Returns the displacement and its
+// mean squared difference, or None if the best match is at the edge of the search.
+fn search(reference:&[f32], area:&[f32], box_size:usize, margin:usize) -> Option<(i64, i64, f64)> {
+    let area_size = box_size + 2 * margin;
+    let span = 2 * margin + 1;
+    let mut best = (0, 0, f64::MAX);
+    for sy in 0..span {
+        for sx in 0..span {
+            let mut total = 0.0_f64;
+            for y in 0..box_size {
+                let r = &reference[y * box_size..(y + 1) * box_size];
+                let a = &area[(y + sy) * area_size + sx..(y + sy) * area_size + sx + box_size];
+                for (rv, av) in r.iter().zip(a.iter()) {
+                    let d = (*rv - *av) as f64;
+                    total += d * d;
+                }
+            }
+            if total < best.2 {
+                best = (sx, sy, total);
+            }
+        }
+    }
+
+    let (bx, by, total) = best;
+    if bx == 0 || by == 0 || bx == span - 1 || by == span - 1 {
+        return None;
+    }
+    Some((bx as i64 - margin as i64, by as i64 - margin as i64, total / (box_size * box_size) as f64))
+}
+
+// Refines a displacement to sub-pixel accuracy with Lucas-Kanade iterations, solving
+// the linearized least squares problem for the remaining shift using the gradient of
+// the resampled search area. Stops early once the update is negligible, and keeps the
+// current estimate if the box has too little structure in one direction to solve.
+fn refine(reference:&[f32], area:&ImageBuffer, box_size:usize, margin:usize, start:(f32, f32), method:Interpolation) -> (f32, f32) {
+    let (mut dx, mut dy) = start;
+    let origin = margin as f32;
+    for _ in 0..constants::AP_REFINE_ITERATIONS {
+        let (mut gxx, mut gxy, mut gyy, mut bx, mut by) = (0.0_f64, 0.0_f64, 0.0_f64, 0.0_f64, 0.0_f64);
+        for y in 0..box_size {
+            for x in 0..box_size {
+                let sx = origin + x as f32 + dx;
+                let sy = origin + y as f32 + dy;
+                let f = area.get_interpolated(sx, sy, method);
+                let gx = (area.get_interpolated(sx + 0.5, sy, method) - area.get_interpolated(sx - 0.5, sy, method)) as f64;
+                let gy = (area.get_interpolated(sx, sy + 0.5, method) - area.get_interpolated(sx, sy - 0.5, method)) as f64;
+                let e = (reference[y * box_size + x] - f) as f64;
+                gxx += gx * gx;
+                gxy += gx * gy;
+                gyy += gy * gy;
+                bx += gx * e;
+                by += gy * e;
+            }
+        }
+
+        let det = gxx * gyy - gxy * gxy;
+        if det <= 1e-6 * gxx * gyy {
+            break;
+        }
+        let ux = ((gyy * bx - gxy * by) / det) as f32;
+        let uy = ((gxx * by - gxy * bx) / det) as f32;
+        dx += ux.clamp(-1.0, 1.0);
+        dy += uy.clamp(-1.0, 1.0);
+        if ux.abs() < 0.01 && uy.abs() < 0.01 {
+            break;
+        }
+    }
+    (dx, dy)
+}
+
+fn stddev_of(image:&ImageBuffer) -> error::Result<f32> {
+    let n = (image.width * image.height) as f64;
+    let mut sum = 0.0_f64;
+    let mut sum_sq = 0.0_f64;
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let v = image.get(x, y)? as f64;
+            sum += v;
+            sum_sq += v * v;
+        }
+    }
+    let mean = sum / n;
+    Ok((sum_sq / n - mean * mean).max(0.0).sqrt() as f32)
+}
+
+// Lays a grid of overlapping boxes over the reference, keeping those centered on
+// the solar disk with enough detail to register. If the disk can't be found the
+// whole frame is covered.
+pub fn place_points(reference:&ImageBuffer, options:&AlignmentOptions) -> error::Result<Vec<AlignmentPoint>> {
+    if reference.is_empty() {
+        return Err(Error::EmptyImage);
+    }
+    let step = (options.box_size / 2).max(1);
+    let half = options.box_size / 2;
+    let disk = limb::find_disk(reference).ok();
+    let mm = reference.get_min_max(-1.0)?;
+    let min_stddev = (mm.max - mm.min) * options.min_contrast;
+
+    let mut candidates:Vec<AlignmentPoint> = Vec::new();
+    let mut y = half;
+    while y + half <= reference.height {
+        let mut x = half;
+        while x + half <= reference.width {
+            let on_disk = match disk {
+                Some(d) => ((x as f32 - d.x).powi(2) + (y as f32 - d.y).powi(2)).sqrt() <= d.radius,
+                None => true
+            };
+            if on_disk {
+                candidates.push(AlignmentPoint{x, y});
+            }
+            x += step;
+        }
+        y += step;
+    }
+
+    let contrast = parallel::map_items(&candidates, |p| p.patch(reference, options.box_size, 0).and_then(|patch| stddev_of(&patch)));
+    let mut points:Vec<AlignmentPoint> = Vec::with_capacity(candidates.len());
+    for (p, c) in candidates.iter().zip(contrast) {
+        if c? > min_stddev {
+            points.push(*p);
+        }
+    }
+
+    vprintln!("    Placed {} alignment points of {}x{} pixels ({} candidates)", points.len(), options.box_size, options.box_size, candidates.len());
+    Ok(points)
+}
+
+// Tent weight falling from 1 at the box center to 0 at its edges. With boxes every
+// half box the weights of overlapping boxes blend smoothly.
+fn blend_weight(dx:f32, dy:f32, half:f32) -> f32 {
+    ((1.0 - dx.abs() / half).max(0.0)) * ((1.0 - dy.abs() / half).max(0.0))
+}
+
+// Stacks frames by aligning each alignment box separately, correcting the local
+// warping that a single global shift can't. Frames are first registered as a whole
+// against the reference, then each box is shifted by its own sub-pixel offset and
+// blended into the stack. A small weight is given to the globally aligned frame
+// everywhere so areas outside the boxes are still stacked.
+pub struct LocalStacker {
+    reference: ImageBuffer,
+    points: Vec<AlignmentPoint>,
+    patches: Vec<Vec<f32>>,
+    options: AlignmentOptions,
+    sum: Vec<f32>,
+    weight: Vec<f32>,
+    count: usize,
+    rejected: usize,
+    measured: usize,
+}
+
+impl LocalStacker {
+    pub fn new(reference:ImageBuffer, options:&AlignmentOptions) -> error::Result<LocalStacker> {
+        let points = place_points(&reference, options)?;
+        let patches = points.iter().map(|p| p.patch(&reference, options.box_size, 0).and_then(|patch| values_of(&patch))).collect::<error::Result<Vec<Vec<f32>>>>()?;
+        let len = reference.width * reference.height;
+        Ok(LocalStacker{reference, points, patches, options:*options, sum:vec![0.0; len], weight:vec![0.0; len], count:0, rejected:0, measured:0})
+    }
+
+    pub fn points(&self) -> &[AlignmentPoint] {
+        &self.points
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    // Measures the residual offset of each box in an already registered frame, as
+    // the shift that aligns the box with the reference. Boxes that moved further
+    // than the maximum shift come back as None.
+    pub fn measure(&self, frame:&ImageBuffer) -> Vec<Option<LocalOffset>> {
+        let margin = self.options.max_shift.ceil() as usize + 1;
+        let indices:Vec<usize> = (0..self.points.len()).collect();
+        parallel::map_items(&indices, |i| {
+            let area = self.points[*i].patch(frame, self.options.box_size, margin).ok()?;
+            let (ix, iy, residual) = search(&self.patches[*i], &values_of(&area).ok()?, self.options.box_size, margin)?;
+            let (dx, dy) = refine(&self.patches[*i], &area, self.options.box_size, margin, (ix as f32, iy as f32), self.options.interpolation);
+            if dx.abs() > self.options.max_shift || dy.abs() > self.options.max_shift {
+                None
+            } else {
+                Some(LocalOffset{h:-dx, v:-dy, residual:residual as f32})
+            }
+        })
+    }
+
+    pub fn add(&mut self, frame:&ImageBuffer) -> error::Result<()> {
+        self.add_weighted(frame, 1.0)
+    }
+
+    pub fn add_weighted(&mut self, frame:&ImageBuffer, frame_weight:f32) -> error::Result<()> {
+        if frame.width != self.reference.width || frame.height != self.reference.height {
+            return Err(Error::DimensionMismatch{expected:(self.reference.width, self.reference.height), found:(frame.width, frame.height)});
+        }
+        if !frame_weight.is_finite() || frame_weight < 0.0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, &frame_weight.to_string()));
+        }
+
+        let aligned = registration::register(&self.reference, frame, self.options.interpolation)?;
+        let offsets = self.measure(&aligned);
+
+        let width = aligned.width;
+        let background = frame_weight * constants::AP_BACKGROUND_WEIGHT;
+        for y in 0..aligned.height {
+            for x in 0..width {
+                let i = y * width + x;
+                self.sum[i] += background * aligned.get(x, y)?;
+                self.weight[i] += background;
+            }
+        }
+
+        let half = (self.options.box_size / 2) as f32;
+        for (point, offset) in self.points.iter().zip(offsets.iter()) {
+            self.measured += 1;
+            let offset = match offset {
+                Some(o) => o,
+                None => {
+                    self.rejected += 1;
+                    continue;
+                }
+            };
+
+            let x0 = point.x.saturating_sub(self.options.box_size / 2);
+            let y0 = point.y.saturating_sub(self.options.box_size / 2);
+            let x1 = (point.x + self.options.box_size / 2).min(aligned.width);
+            let y1 = (point.y + self.options.box_size / 2).min(aligned.height);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let w = frame_weight * blend_weight(x as f32 - point.x as f32, y as f32 - point.y as f32, half);
+                    if w <= 0.0 {
+                        continue;
+                    }
+                    let v = aligned.get_interpolated(x as f32 - offset.h, y as f32 - offset.v, self.options.interpolation);
+                    let i = y * width + x;
+                    self.sum[i] += w * v;
+                    self.weight[i] += w;
+                }
+            }
+        }
+
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> error::Result<ImageBuffer> {
+        if self.count == 0 {
+            return Err(Error::NoFilesUsed);
+        }
+        vprintln!("    Local alignment rejected {} of {} box measurements", self.rejected, self.measured);
+        let v = self.sum.iter().zip(self.weight.iter()).map(|(s, w)| if *w > 0.0 { s / w } else { 0.0 }).collect();
+        ImageBuffer::from_vec(v, self.reference.width, self.reference.height)
+    }
+}
@@ -0,0 +1,453 @@
+use crate::imagebuffer::{ImageBuffer, SubpixelOffset};
+use crate::raw_to_tiff::{self, CalibrationOptions, Framing};
+use crate::calibration::CalibrationFrames;
+use crate::interpolation::Interpolation;
+use crate::stacking::{FrameWeights, StackAccumulator, StackMethod, StackSummary};
+use crate::registration;
+use crate::multiband::MultiBandImage;
+use crate::demosaic::DemosaicMethod;
+use crate::alignment::{AlignmentOptions, LocalStacker};
+use crate::drizzle::{Drizzle, DrizzleOptions, FrameTransform};
+use crate::fieldrotation::FieldRotation;
+use crate::datetime::{self, DateTime};
+use crate::quality::{self, FrameScore, QualityMetric};
+use crate::parallel;
+use crate::path;
+use crate::constants;
+use crate::error;
+use crate::vprintln;
+
+// Options for stacking a batch of light frames
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchOptions {
+    pub calibration: CalibrationOptions,
+    // When set, frames are registered against the first good frame using this interpolation
+    pub registration: Option<Interpolation>,
+    // Number of frames decoded concurrently, and so the most held in memory at once.
+    // Zero picks a size from the number of worker threads.
+    pub batch_size: usize,
+    // When set, each light is derotated to the orientation of the reference frame
+    pub field_rotation: Option<FieldRotation>,
+}
+
+impl BatchOptions {
+    fn effective_batch_size(&self) -> usize {
+        if self.batch_size > 0 {
+            self.batch_size
+        } else {
+            parallel::num_threads() * constants::DEFAULT_FRAMES_PER_THREAD
+        }
+    }
+}
+
+// Loads the items a batch at a time, running the loader concurrently across each
+// batch and passing the frames that loaded to the sink, with their item, in input
+// order. Only one batch of frames is in memory at a time.
+pub fn for_each_batch<T, F, L, S>(items:&[T], batch_size:usize, load:L, mut sink:S) -> error::Result<()>
+    where T: Sync, F: Send, L: Fn(&T) -> Option<F> + Sync + Send, S: FnMut(&T, F) -> error::Result<()> {
+    for batch in items.chunks(batch_size.max(1)) {
+        for (item, frame) in batch.iter().zip(parallel::map_items(batch, &load)) {
+            if let Some(frame) = frame {
+                sink(item, frame)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Stacks frames produced by the loader, returning the stack along with a summary of
+// the frames used. Items that fail to load are reported and skipped. When registering,
+// the first item that loads becomes the reference for the rest. With weights, each
+// frame contributes in proportion to the weight listed for its item.
+pub fn stack_with<T, L>(items:&[T], load:L, method:&StackMethod, weights:Option<&FrameWeights>, options:&BatchOptions) -> error::Result<(ImageBuffer, StackSummary)>
+    where T: Sync + std::fmt::Display, L: Fn(&T) -> error::Result<ImageBuffer> + Sync + Send {
+    let load_item = |item:&T| match load(item) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", item, e);
+            None
+        }
+    };
+
+    let batch_size = options.effective_batch_size();
+    let mut accumulator = StackAccumulator::new(method);
+    let mut summary = StackSummary::default();
+    let mut add = |item:&T, frame:ImageBuffer| -> error::Result<()> {
+        let name = item.to_string();
+        let weight = weights.map(|w| w.weight_or_default(&name)).unwrap_or(1.0);
+        let metadata = frame.metadata().cloned();
+        accumulator.add_weighted(frame, weight)?;
+        summary.add(&name, weight, metadata.as_ref());
+        Ok(())
+    };
+    vprintln!("Stacking {} frames in batches of {} using {:?}", items.len(), batch_size, method);
+    if *method != StackMethod::Mean && items.len() > constants::STACK_FRAMES_IN_MEMORY {
+        vprintln!("    {:?} needs every frame at once, so frames beyond the first {} are kept in a scratch file", method, constants::STACK_FRAMES_IN_MEMORY);
+    }
+
+    match options.registration {
+        Some(interp) => {
+            if let Some((first, reference, remaining)) = split_first_loaded(items, load_item) {
+                add(first, reference.clone())?;
+                for_each_batch(remaining, batch_size, |item| {
+                    let frame = load_item(item)?;
+                    match registration::register(&reference, &frame, interp) {
+                        Ok(r) => Some(r),
+                        Err(e) => {
+                            eprintln!("Skipping {}: {}", item, e);
+                            None
+                        }
+                    }
+                }, &mut add)?;
+            }
+        },
+        None => for_each_batch(items, batch_size, load_item, &mut add)?
+    }
+
+    let mut stack = accumulator.finish()?;
+    stack.set_metadata(summary.metadata.clone());
+    let stackmm = stack.get_min_max(-1.0)?;
+    vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, summary.count());
+    summary.print_weights();
+    Ok((stack, summary))
+}
+
+// Stacks color frames produced by the loader a band at a time, as stack_with does for a
+// single plane. When registering, each frame's offset is measured on its luminance
+// against the first item that loads and every band is shifted by it.
+pub fn stack_bands_with<T, L>(items:&[T], load:L, method:&StackMethod, weights:Option<&FrameWeights>, options:&BatchOptions) -> error::Result<(MultiBandImage, StackSummary)>
+    where T: Sync + std::fmt::Display, L: Fn(&T) -> error::Result<MultiBandImage> + Sync + Send {
+    let load_item = |item:&T| match load(item) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", item, e);
+            None
+        }
+    };
+
+    let batch_size = options.effective_batch_size();
+    let mut accumulators:Vec<StackAccumulator> = Vec::new();
+    let mut summary = StackSummary::default();
+    let mut add = |item:&T, frame:MultiBandImage| -> error::Result<()> {
+        let name = item.to_string();
+        let weight = weights.map(|w| w.weight_or_default(&name)).unwrap_or(1.0);
+        let metadata = frame.band(0)?.metadata().cloned();
+        if accumulators.is_empty() {
+            accumulators = (0..frame.num_bands()).map(|_| StackAccumulator::new(method)).collect();
+        }
+        for (accumulator, band) in accumulators.iter_mut().zip(frame.split()) {
+            accumulator.add_weighted(band, weight)?;
+        }
+        summary.add(&name, weight, metadata.as_ref());
+        Ok(())
+    };
+    vprintln!("Stacking {} color frames in batches of {} using {:?}", items.len(), batch_size, method);
+
+    match options.registration {
+        Some(interp) => {
+            if let Some((first, reference, remaining)) = split_first_loaded(items, load_item) {
+                let reference_luminance = reference.luminance()?;
+                add(first, reference)?;
+                for_each_batch(remaining, batch_size, |item| {
+                    let frame = load_item(item)?;
+                    match frame.luminance().and_then(|l| registration::phase_correlate(&reference_luminance, &l))
+                                           .and_then(|offset| frame.shift_subpixel(offset.h, offset.v, interp)) {
+                        Ok(r) => Some(r),
+                        Err(e) => {
+                            eprintln!("Skipping {}: {}", item, e);
+                            None
+                        }
+                    }
+                }, &mut add)?;
+            }
+        },
+        None => for_each_batch(items, batch_size, load_item, &mut add)?
+    }
+
+    if accumulators.is_empty() {
+        return Err(error::Error::NoFilesUsed);
+    }
+    let bands = accumulators.into_iter().map(|accumulator| {
+        let mut band = accumulator.finish()?;
+        band.set_metadata(summary.metadata.clone());
+        Ok(band)
+    }).collect::<error::Result<Vec<ImageBuffer>>>()?;
+    let stack = MultiBandImage::merge(bands)?;
+    let stackmm = stack.get_min_max()?;
+    vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, summary.count());
+    summary.print_weights();
+    Ok((stack, summary))
+}
+
+// Loads items in order until one succeeds, returning it with the items after it
+fn split_first_loaded<T, F, L>(items:&[T], load_item:L) -> Option<(&T, F, &[T])>
+    where L: Fn(&T) -> Option<F> {
+    let mut remaining = items;
+    while let Some((item, rest)) = remaining.split_first() {
+        remaining = rest;
+        if let Some(frame) = load_item(item) {
+            return Some((item, frame, remaining));
+        }
+    }
+    None
+}
+
+// Stacks frames produced by the loader using multi-point local alignment against the
+// first item that loads. Frames are combined as a (weighted) mean as they arrive.
+pub fn stack_local_with<T, L>(items:&[T], load:L, weights:Option<&FrameWeights>, alignment:&AlignmentOptions, options:&BatchOptions) -> error::Result<(ImageBuffer, StackSummary)>
+    where T: Sync + std::fmt::Display, L: Fn(&T) -> error::Result<ImageBuffer> + Sync + Send {
+    let load_item = |item:&T| match load(item) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", item, e);
+            None
+        }
+    };
+    let weight_of = |name:&str| weights.map(|w| w.weight_or_default(name)).unwrap_or(1.0);
+
+    let batch_size = options.effective_batch_size();
+    vprintln!("Stacking {} frames in batches of {} with local alignment", items.len(), batch_size);
+
+    let (first, reference, remaining) = split_first_loaded(items, load_item).ok_or(error::Error::NoFilesUsed)?;
+    let mut summary = StackSummary::default();
+    let mut stacker = LocalStacker::new(reference.clone(), alignment)?;
+    let name = first.to_string();
+    let weight = weight_of(&name);
+    stacker.add_weighted(&reference, weight)?;
+    summary.add(&name, weight, reference.metadata());
+
+    for_each_batch(remaining, batch_size, load_item, |item, frame| {
+        let name = item.to_string();
+        let weight = weight_of(&name);
+        match stacker.add_weighted(&frame, weight) {
+            Ok(_) => summary.add(&name, weight, frame.metadata()),
+            Err(e) => eprintln!("Skipping {}: {}", item, e)
+        }
+        Ok(())
+    })?;
+
+    let mut stack = stacker.finish()?;
+    stack.set_metadata(summary.metadata.clone());
+    let stackmm = stack.get_min_max(-1.0)?;
+    vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, summary.count());
+    summary.print_weights();
+    Ok((stack, summary))
+}
+
+// Drizzles frames produced by the loader onto a finer grid, measuring each frame's
+// sub-pixel offset against the first item that loads. Returns the drizzled image, its
+// weight map and a summary of the frames used.
+pub fn drizzle_with<T, L>(items:&[T], load:L, weights:Option<&FrameWeights>, drizzle:&DrizzleOptions, options:&BatchOptions) -> error::Result<(ImageBuffer, ImageBuffer, StackSummary)>
+    where T: Sync + std::fmt::Display, L: Fn(&T) -> error::Result<ImageBuffer> + Sync + Send {
+    let load_item = |item:&T| match load(item) {
+        Ok(frame) => Some(frame),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", item, e);
+            None
+        }
+    };
+    let weight_of = |name:&str| weights.map(|w| w.weight_or_default(name)).unwrap_or(1.0);
+
+    let batch_size = options.effective_batch_size();
+    vprintln!("Drizzling {} frames in batches of {} at {}x with pixfrac {}", items.len(), batch_size, drizzle.scale, drizzle.pixfrac);
+
+    let (first, reference, remaining) = split_first_loaded(items, load_item).ok_or(error::Error::NoFilesUsed)?;
+    let mut summary = StackSummary::default();
+    let mut drizzler = Drizzle::new(reference.clone(), drizzle)?;
+    let name = first.to_string();
+    let weight = weight_of(&name);
+    drizzler.add_with_offset(&reference, &SubpixelOffset{h:0.0, v:0.0, peak:1.0}, weight)?;
+    summary.add(&name, weight, reference.metadata());
+
+    for_each_batch(remaining, batch_size, load_item, |item, frame| {
+        let name = item.to_string();
+        let weight = weight_of(&name);
+        match drizzler.add_weighted(&frame, weight) {
+            Ok(_) => summary.add(&name, weight, frame.metadata()),
+            Err(e) => eprintln!("Skipping {}: {}", item, e)
+        }
+        Ok(())
+    })?;
+
+    let (mut stack, weight_map) = drizzler.finish()?;
+    stack.set_metadata(summary.metadata.clone());
+    let stackmm = stack.get_min_max(-1.0)?;
+    vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, summary.count());
+    summary.print_weights();
+    Ok((stack, weight_map, summary))
+}
+
+// Scores each frame produced by the loader, a batch at a time. Frames are dropped
+// once scored, so only the scores are kept. Items that fail to load or score are skipped.
+pub fn score_with<T, L>(items:&[T], load:L, metric:QualityMetric, batch_size:usize) -> Vec<FrameScore>
+    where T: Sync + std::fmt::Display, L: Fn(&T) -> error::Result<ImageBuffer> + Sync + Send {
+    let indexed:Vec<(usize, &T)> = items.iter().enumerate().collect();
+    let mut scores:Vec<FrameScore> = Vec::with_capacity(items.len());
+    for batch in indexed.chunks(batch_size.max(1)) {
+        let scored = parallel::map_items(batch, |(index, item)| {
+            match load(item).and_then(|frame| quality::score(&frame, metric)) {
+                Ok(score) => {
+                    vprintln!("    {:?} score of {}: {}", metric, item, score);
+                    Some(FrameScore{index:*index, name:item.to_string(), score})
+                },
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", item, e);
+                    None
+                }
+            }
+        });
+        scores.extend(scored.into_iter().flatten());
+    }
+    scores
+}
+
+fn load_light(in_file:&str, frames:&CalibrationFrames, options:&CalibrationOptions) -> error::Result<ImageBuffer> {
+    if !path::file_exists(in_file) {
+        return Err(error::Error::FileNotFound(String::from(in_file)));
+    }
+    vprintln!("Processing File: {}", in_file);
+    raw_to_tiff::calibrate_raw(in_file, frames, options)
+}
+
+// Capture time of a decoded light from the metadata read with it, going back to the
+// raw file only when the metadata lacks one
+fn light_time(in_file:&str, light:&ImageBuffer) -> error::Result<DateTime> {
+    match light.metadata().and_then(|m| m.timestamp) {
+        Some(time) => Ok(time),
+        None => datetime::capture_time(in_file)
+    }
+}
+
+// Loads a light as for scoring, then corrects its field rotation if requested
+fn load_derotated_light(in_file:&str, frames:&CalibrationFrames, options:&BatchOptions) -> error::Result<ImageBuffer> {
+    let light = load_light(in_file, frames, &options.calibration)?;
+    match options.field_rotation {
+        Some(rotation) => rotation.derotate(&light, &light_time(in_file, &light)?),
+        None => Ok(light)
+    }
+}
+
+fn load_color_light(in_file:&str, frames:&CalibrationFrames, method:DemosaicMethod, options:&CalibrationOptions) -> error::Result<MultiBandImage> {
+    if !path::file_exists(in_file) {
+        return Err(error::Error::FileNotFound(String::from(in_file)));
+    }
+    vprintln!("Processing File: {}", in_file);
+    raw_to_tiff::calibrate_raw_rgb(in_file, frames, options, method)
+}
+
+// Loads a light as full resolution color, then corrects its field rotation if requested
+fn load_derotated_color_light(in_file:&str, frames:&CalibrationFrames, method:DemosaicMethod, options:&BatchOptions) -> error::Result<MultiBandImage> {
+    let light = load_color_light(in_file, frames, method, &options.calibration)?;
+    match options.field_rotation {
+        Some(rotation) => {
+            let time = light_time(in_file, light.band(0)?)?;
+            light.map(|band| rotation.derotate(band, &time))
+        },
+        None => Ok(light)
+    }
+}
+
+// Decodes and calibrates each raw and scores its sharpness. Color lights are scored
+// on their luminance.
+pub fn score_lights(file_list:&[&str], frames:&CalibrationFrames, metric:QualityMetric, options:&BatchOptions) -> Vec<FrameScore> {
+    match options.calibration.demosaic {
+        Some(method) => score_with(file_list, |in_file| load_color_light(in_file, frames, method, &options.calibration)?.luminance(),
+                                   metric, options.effective_batch_size()),
+        None => score_with(file_list, |in_file| load_light(in_file, frames, &options.calibration), metric, options.effective_batch_size())
+    }
+}
+
+// Decodes, demosaics, calibrates and optionally derotates and registers each raw,
+// feeding them into a full color stack
+pub fn stack_color_lights(file_list:&[&str], frames:&CalibrationFrames, method:&StackMethod, demosaic:DemosaicMethod, weights:Option<&FrameWeights>, options:&BatchOptions) -> error::Result<(MultiBandImage, StackSummary)> {
+    stack_bands_with(file_list, |in_file| load_derotated_color_light(in_file, frames, demosaic, options), method, weights, options)
+}
+
+// Decodes, calibrates and optionally derotates and registers each raw, feeding them into the stack
+pub fn stack_lights(file_list:&[&str], frames:&CalibrationFrames, method:&StackMethod, weights:Option<&FrameWeights>, options:&BatchOptions) -> error::Result<(ImageBuffer, StackSummary)> {
+    stack_with(file_list, |in_file| load_derotated_light(in_file, frames, options), method, weights, options)
+}
+
+// A light prepared for drizzling: its calibrated plane, neither rescaled nor resampled,
+// the transform that centers, crops and derotates the plane into the reference framing,
+// and a preview resampled by that transform to measure what offset is left
+struct DrizzleLight {
+    plane: ImageBuffer,
+    framing: Framing,
+    transform: FrameTransform,
+    preview: ImageBuffer,
+}
+
+// Loads a light for drizzling. The crop is the reference's when given, so every frame
+// lands on the same grid, otherwise the light's own.
+fn load_drizzle_light(in_file:&str, frames:&CalibrationFrames, reference:Option<&Framing>, options:&BatchOptions) -> error::Result<DrizzleLight> {
+    if !path::file_exists(in_file) {
+        return Err(error::Error::FileNotFound(String::from(in_file)));
+    }
+    vprintln!("Processing File: {}", in_file);
+    let plane = raw_to_tiff::calibrate_raw_plane(in_file, frames, &options.calibration)?;
+    let scaled = plane.normalize(0.0, constants::_16_BIT_MAX)?;
+    let own = raw_to_tiff::find_framing(&scaled, &options.calibration)?;
+    let framing = match reference {
+        Some(r) => Framing{h:own.h, v:own.v, ..*r},
+        None => own
+    };
+
+    let cropped = scaled.shift(framing.h, framing.v)?.crop_region(framing.left, framing.top, framing.width, framing.height)?;
+    let centered = FrameTransform::shift((framing.h as i64 - framing.left) as f32, (framing.v as i64 - framing.top) as f32);
+    let (transform, preview) = match options.field_rotation {
+        Some(rotation) => {
+            let degrees = rotation.angle_at(&light_time(in_file, &plane)?) as f32;
+            let center = ((framing.width as f32 - 1.0) / 2.0, (framing.height as f32 - 1.0) / 2.0);
+            (centered.then(&FrameTransform::rotation(center.0, center.1, degrees)), cropped.rotate(degrees, rotation.interpolation)?)
+        },
+        None => (centered, cropped)
+    };
+    Ok(DrizzleLight{plane, framing, transform, preview})
+}
+
+// Decodes and calibrates each raw, drizzling them onto a finer grid. The calibrated
+// pixels are dropped as they are: centering, cropping, derotation and registration
+// only decide where each drop lands, and the result is rescaled once at the end.
+pub fn drizzle_lights(file_list:&[&str], frames:&CalibrationFrames, weights:Option<&FrameWeights>, drizzle:&DrizzleOptions, options:&BatchOptions) -> error::Result<(ImageBuffer, ImageBuffer, StackSummary)> {
+    let load_item = |in_file:&&str, reference:Option<&Framing>| match load_drizzle_light(in_file, frames, reference, options) {
+        Ok(light) => Some(light),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", in_file, e);
+            None
+        }
+    };
+    let weight_of = |name:&str| weights.map(|w| w.weight_or_default(name)).unwrap_or(1.0);
+
+    let batch_size = options.effective_batch_size();
+    vprintln!("Drizzling {} frames in batches of {} at {}x with pixfrac {}", file_list.len(), batch_size, drizzle.scale, drizzle.pixfrac);
+
+    let (first, reference, remaining) = split_first_loaded(file_list, |in_file| load_item(in_file, None)).ok_or(error::Error::NoFilesUsed)?;
+    let mut summary = StackSummary::default();
+    let mut drizzler = Drizzle::new(reference.preview.clone(), drizzle)?;
+    let weight = weight_of(first);
+    drizzler.add_transformed(&reference.plane, &reference.transform, weight)?;
+    summary.add(first, weight, reference.plane.metadata());
+
+    for_each_batch(remaining, batch_size, |in_file| load_item(in_file, Some(&reference.framing)), |in_file, light| {
+        let weight = weight_of(in_file);
+        match drizzler.add_registered(&light.plane, &light.preview, &light.transform, weight) {
+            Ok(_) => summary.add(in_file, weight, light.plane.metadata()),
+            Err(e) => eprintln!("Skipping {}: {}", in_file, e)
+        }
+        Ok(())
+    })?;
+
+    let (drizzled, weight_map) = drizzler.finish()?;
+    let mut stack = drizzled.normalize(0.0, constants::_16_BIT_MAX)?;
+    stack.set_metadata(summary.metadata.clone());
+    let stackmm = stack.get_min_max(-1.0)?;
+    vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, summary.count());
+    summary.print_weights();
+    Ok((stack, weight_map, summary))
+}
+
+// Decodes and calibrates each raw, stacking them with multi-point local alignment
+pub fn stack_local_lights(file_list:&[&str], frames:&CalibrationFrames, weights:Option<&FrameWeights>, alignment:&AlignmentOptions, options:&BatchOptions) -> error::Result<(ImageBuffer, StackSummary)> {
+    stack_local_with(file_list, |in_file| load_derotated_light(in_file, frames, options), weights, alignment, options)
+}
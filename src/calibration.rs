@@ -0,0 +1,244 @@
+use crate::imagebuffer::{ImageBuffer, OutputFormat};
+use crate::flatfield::{self, FlatNormalization};
+use crate::metadata::RawMetadata;
+use crate::parallel;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// How a master dark is scaled before it is subtracted from a light
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DarkScaling {
+    // Subtracted as is
+    #[default]
+    None,
+    // By the ratio of the exposure times, adjusted for any difference in sensor temperature
+    Exposure,
+    // By the factor leaving the least fixed pattern noise in the calibrated light
+    Optimize,
+}
+
+impl DarkScaling {
+    pub fn from_name(name:&str) -> error::Result<DarkScaling> {
+        match name.to_lowercase().as_str() {
+            constants::darkscale::NONE => Ok(DarkScaling::None),
+            constants::darkscale::EXPOSURE => Ok(DarkScaling::Exposure),
+            constants::darkscale::OPTIMIZE => Ok(DarkScaling::Optimize),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_DARK_SCALING, name))
+        }
+    }
+}
+
+// Subtracts the black level of each photosite of a frame, using its own when recorded,
+// otherwise the fallback's
+fn subtract_black(frame:&ImageBuffer, fallback:Option<&RawMetadata>) -> error::Result<ImageBuffer> {
+    match frame.metadata().filter(|m| m.has_black_level()).or_else(|| fallback.filter(|m| m.has_black_level())) {
+        Some(m) => frame.subtract_black_level_of(m),
+        None => Ok(frame.clone())
+    }
+}
+
+// Scale for a dark taken at a different exposure time or temperature than the light.
+// Dark current roughly doubles for every few degrees of sensor temperature. None
+// when either exposure time is unknown.
+pub fn exposure_scale(light:&RawMetadata, dark:&RawMetadata) -> Option<f32> {
+    let ratio = light.exposure? / dark.exposure?;
+    let thermal = match (light.temperature, dark.temperature) {
+        (Some(light_temp), Some(dark_temp)) => 2.0_f32.powf((light_temp - dark_temp) / constants::DARK_CURRENT_DOUBLING_TEMPERATURE),
+        _ => 1.0
+    };
+    Some(ratio * thermal)
+}
+
+// Each interior pixel less the mean of its four neighbors, which keeps the hot pixels
+// and fixed pattern a dark corrects while dropping the smooth detail of the light
+fn high_pass(image:&ImageBuffer) -> Vec<f32> {
+    let w = image.width - 2;
+    let px = |x:usize, y:usize| image.get(x, y).unwrap_or(0.0);
+    parallel::generate(w * (image.height - 2), |i| {
+        let (x, y) = (i % w + 1, i / w + 1);
+        px(x, y) - (px(x - 1, y) + px(x + 1, y) + px(x, y - 1) + px(x, y + 1)) / 4.0
+    })
+}
+
+// Finds the factor k minimizing the high frequency noise of light - k * thermal, where
+// thermal is a bias-subtracted dark: the least squares fit of the dark's fine structure
+// to the light's. Falls back to 1 for a dark with no structure.
+pub fn optimize_scale(light:&ImageBuffer, thermal:&ImageBuffer) -> error::Result<f32> {
+    if light.width != thermal.width || light.height != thermal.height {
+        return Err(Error::DimensionMismatch{expected:(light.width, light.height), found:(thermal.width, thermal.height)});
+    }
+    if light.is_empty() || light.width < 3 || light.height < 3 {
+        return Err(Error::EmptyImage);
+    }
+
+    let l = high_pass(light);
+    let d = high_pass(thermal);
+    let (cross, _) = parallel::sum_where(&parallel::zip_map(&l, &d, |a, b| a * b), |_| true);
+    let (energy, _) = parallel::sum_where(&parallel::map(&d, |v| v * v), |_| true);
+    if energy <= 0.0 {
+        return Ok(1.0);
+    }
+    Ok(((cross / energy) as f32).clamp(0.0, constants::MAX_DARK_SCALE))
+}
+
+// Subtracts a master dark from a light. When scaling, the bias is removed from both
+// first so only the dark's thermal signal is scaled. The bias is the master bias when
+// there is one, otherwise the black level of each photosite; a dark without a recorded
+// black level is assumed to share the light's.
+pub fn subtract_dark(light:&ImageBuffer, dark:&ImageBuffer, bias:Option<&ImageBuffer>, scaling:DarkScaling) -> error::Result<ImageBuffer> {
+    if scaling == DarkScaling::None {
+        return light.subtract(dark);
+    }
+
+    let (light_bias, thermal) = match bias {
+        Some(bias) => (light.subtract(bias)?, dark.subtract(bias)?),
+        None => (subtract_black(light, dark.metadata())?, subtract_black(dark, light.metadata())?)
+    };
+
+    let scale = match scaling {
+        DarkScaling::Exposure => match (light.metadata(), dark.metadata()) {
+            (Some(l), Some(d)) => exposure_scale(l, d),
+            _ => None
+        }.unwrap_or_else(|| {
+            vprintln!("    Exposure times unknown, subtracting the dark unscaled");
+            1.0
+        }),
+        DarkScaling::Optimize => optimize_scale(&light_bias, &thermal)?,
+        DarkScaling::None => 1.0
+    };
+    vprintln!("    Dark scale factor: {}", scale);
+
+    light_bias.subtract(&thermal.scale(scale)?)
+}
+
+// The master frames used to calibrate lights, each optional:
+//   bias      - the sensor's offset, read out with no exposure
+//   dark      - thermal signal plus bias, at the lights' exposure (or scaled to it)
+//   flat_dark - thermal signal plus bias, at the flats' exposure
+//   flat      - the optical system's response to an even field
+// A light has its dark removed, or failing that its bias, or failing that the black
+// level recorded in its metadata. It is then divided by the flat once the flat has had
+// its own offset removed the same way, using the flat-dark in place of the dark.
+#[derive(Debug, Clone, Default)]
+pub struct CalibrationFrames {
+    bias: Option<ImageBuffer>,
+    dark: Option<ImageBuffer>,
+    flat_dark: Option<ImageBuffer>,
+    flat: Option<ImageBuffer>,
+    // The flat with its offset removed and normalized to unity
+    flat_field: Option<ImageBuffer>,
+}
+
+impl CalibrationFrames {
+    pub fn new(bias:Option<ImageBuffer>, dark:Option<ImageBuffer>, flat_dark:Option<ImageBuffer>, flat:Option<ImageBuffer>) -> error::Result<CalibrationFrames> {
+        CalibrationFrames::normalized(bias, dark, flat_dark, flat, FlatNormalization::default())
+    }
+
+    // As new, normalizing the flat to unity at the given level
+    pub fn normalized(bias:Option<ImageBuffer>, dark:Option<ImageBuffer>, flat_dark:Option<ImageBuffer>, flat:Option<ImageBuffer>, normalization:FlatNormalization) -> error::Result<CalibrationFrames> {
+        let flat_field = match &flat {
+            Some(flat) => {
+                let corrected = match (&flat_dark, &bias) {
+                    (Some(flat_dark), _) => flat.subtract(flat_dark)?,
+                    (None, Some(bias)) => flat.subtract(bias)?,
+                    (None, None) => flat.subtract_black_level()?
+                };
+                Some(flatfield::normalize(&corrected, normalization)?)
+            },
+            None => None
+        };
+        Ok(CalibrationFrames{bias, dark, flat_dark, flat, flat_field})
+    }
+
+    // Loads whichever master frames are given as files, skipping empty paths
+    pub fn from_files(bias_file:&str, dark_file:&str, flat_dark_file:&str, flat_file:&str, normalization:FlatNormalization) -> error::Result<CalibrationFrames> {
+        let load = |kind:&str, file:&str| -> error::Result<Option<ImageBuffer>> {
+            if file.is_empty() {
+                return Ok(None);
+            }
+            vprintln!("{} File: {}", kind, file);
+            ImageBuffer::from_file(file).map(Some)
+        };
+        CalibrationFrames::normalized(load("Bias", bias_file)?, load("Dark", dark_file)?, load("Flat-dark", flat_dark_file)?, load("Flat", flat_file)?, normalization)
+    }
+
+    pub fn bias(&self) -> Option<&ImageBuffer> {
+        self.bias.as_ref()
+    }
+
+    pub fn dark(&self) -> Option<&ImageBuffer> {
+        self.dark.as_ref()
+    }
+
+    pub fn flat_dark(&self) -> Option<&ImageBuffer> {
+        self.flat_dark.as_ref()
+    }
+
+    pub fn flat(&self) -> Option<&ImageBuffer> {
+        self.flat.as_ref()
+    }
+
+    // The flat with its offset removed and normalized, which lights are divided by
+    pub fn flat_field(&self) -> Option<&ImageBuffer> {
+        self.flat_field.as_ref()
+    }
+
+    // Saves the normalized flat as 32 bit float for inspection
+    pub fn save_flat_field(&self, to_file:&str) -> error::Result<()> {
+        match &self.flat_field {
+            Some(flat_field) => flat_field.save_as(to_file, OutputFormat::Float32),
+            None => Err(Error::invalid_parameter(constants::status::NO_MASTER_FLAT, to_file))
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_none() && self.dark.is_none() && self.flat_dark.is_none() && self.flat.is_none()
+    }
+
+    // The corrections apply will make to each light, in order
+    pub fn corrections(&self, scaling:DarkScaling) -> Vec<String> {
+        let offset = match (&self.dark, &self.bias, scaling) {
+            (Some(_), _, DarkScaling::None) => "subtract master dark",
+            (Some(_), _, DarkScaling::Exposure) => "subtract master dark scaled by exposure",
+            (Some(_), _, DarkScaling::Optimize) => "subtract master dark with optimized scale",
+            (None, Some(_), _) => "subtract master bias",
+            (None, None, _) => "subtract black level"
+        };
+        let mut corrections = vec![offset.to_string()];
+        if self.flat.is_some() {
+            let flat_offset = match (&self.flat_dark, &self.bias) {
+                (Some(_), _) => "master flat-dark",
+                (None, Some(_)) => "master bias",
+                (None, None) => "black level"
+            };
+            corrections.push(format!("divide by master flat less its {}", flat_offset));
+        }
+        corrections
+    }
+
+    // Removes the offset from a light, then divides out the flat
+    pub fn apply(&self, light:&ImageBuffer, scaling:DarkScaling) -> error::Result<ImageBuffer> {
+        let corrected = match (&self.dark, &self.bias) {
+            (Some(dark), bias) => {
+                vprintln!("    Subtracting master dark");
+                subtract_dark(light, dark, bias.as_ref(), scaling)?
+            },
+            (None, Some(bias)) => {
+                vprintln!("    Subtracting master bias");
+                light.subtract(bias)?
+            },
+            (None, None) => {
+                vprintln!("    Subtracting black level {}", light.metadata().map(|m| m.black_at(0, 0)).unwrap_or(0.0));
+                light.subtract_black_level()?
+            }
+        };
+        match &self.flat_field {
+            Some(flat_field) => {
+                vprintln!("    Dividing by master flat");
+                corrected.divide(flat_field)
+            },
+            None => Ok(corrected)
+        }
+    }
+}
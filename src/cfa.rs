@@ -0,0 +1,188 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::constants;
+use crate::error::{self, Error};
+
+use libraw_sys as sys;
+
+// Color of a single photosite in the color filter array
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CfaColor {
+    Red,
+    Green,
+    Blue,
+}
+
+// A plane that can be pulled out of a Bayer mosaic. Green1 is the green sharing
+// a row with red, Green2 the green sharing a row with blue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CfaChannel {
+    Red,
+    Green1,
+    Green2,
+    Blue,
+    // Mean of the two greens
+    Green,
+}
+
+impl CfaChannel {
+    pub fn from_name(name:&str) -> error::Result<CfaChannel> {
+        match name.to_lowercase().as_str() {
+            constants::cfa::RED => Ok(CfaChannel::Red),
+            constants::cfa::GREEN1 => Ok(CfaChannel::Green1),
+            constants::cfa::GREEN2 => Ok(CfaChannel::Green2),
+            constants::cfa::BLUE => Ok(CfaChannel::Blue),
+            constants::cfa::GREEN => Ok(CfaChannel::Green),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_CFA_CHANNEL, name))
+        }
+    }
+}
+
+// Layout of the repeating 2x2 Bayer cell, indexed as [row][column], starting
+// at the first visible pixel of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CfaPattern {
+    cells: [[CfaColor; 2]; 2],
+}
+
+impl CfaPattern {
+    // Builds a pattern, checking that it is a Bayer layout: the two greens on one
+    // diagonal of the cell and red and blue on the other
+    pub fn new(cells:[[CfaColor; 2]; 2]) -> Option<CfaPattern> {
+        let bayer = |greens:[CfaColor; 2], others:[CfaColor; 2]| {
+            greens == [CfaColor::Green, CfaColor::Green]
+                && (others == [CfaColor::Red, CfaColor::Blue] || others == [CfaColor::Blue, CfaColor::Red])
+        };
+        let (main, anti) = ([cells[0][0], cells[1][1]], [cells[0][1], cells[1][0]]);
+        if bayer(main, anti) || bayer(anti, main) {
+            Some(CfaPattern{cells})
+        } else {
+            None
+        }
+    }
+
+    // The layout of the 5D Mk III and most other Canon bodies. Buffers with no
+    // known pattern are treated as RGGB.
+    pub fn rggb() -> CfaPattern {
+        CfaPattern{cells:[[CfaColor::Red, CfaColor::Green], [CfaColor::Green, CfaColor::Blue]]}
+    }
+
+    // Parses the conventional four letter name (RGGB, BGGR, GRBG, GBRG)
+    pub fn from_name(name:&str) -> error::Result<CfaPattern> {
+        let colors:Vec<CfaColor> = name.to_uppercase().chars().filter_map(|c| match c {
+            'R' => Some(CfaColor::Red),
+            'G' => Some(CfaColor::Green),
+            'B' => Some(CfaColor::Blue),
+            _ => None
+        }).collect();
+
+        if colors.len() != 4 || name.len() != 4 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_CFA_PATTERN, name));
+        }
+
+        CfaPattern::new([[colors[0], colors[1]], [colors[2], colors[3]]])
+            .ok_or_else(|| Error::invalid_parameter(constants::status::INVALID_CFA_PATTERN, name))
+    }
+
+    pub fn name(&self) -> String {
+        self.cells.iter().flatten().map(|c| match c {
+            CfaColor::Red => 'R',
+            CfaColor::Green => 'G',
+            CfaColor::Blue => 'B',
+        }).collect()
+    }
+
+    pub fn color_at(&self, x:usize, y:usize) -> CfaColor {
+        self.cells[y % 2][x % 2]
+    }
+
+    // Position within the 2x2 cell of the photosite feeding a single color plane.
+    // Not meaningful for the averaged green channel, for which Green1 is returned.
+    pub fn offset_of(&self, channel:CfaChannel) -> (usize, usize) {
+        let red_row = if self.cells[0].contains(&CfaColor::Red) { 0 } else { 1 };
+        let find = |row:usize, color:CfaColor| -> (usize, usize) {
+            let col = if self.cells[row][0] == color { 0 } else { 1 };
+            (col, row)
+        };
+
+        match channel {
+            CfaChannel::Red => find(red_row, CfaColor::Red),
+            CfaChannel::Green1 | CfaChannel::Green => find(red_row, CfaColor::Green),
+            CfaChannel::Green2 => find(1 - red_row, CfaColor::Green),
+            CfaChannel::Blue => find(1 - red_row, CfaColor::Blue),
+        }
+    }
+}
+
+// Reads the Bayer layout of a raw file from its metadata. Returns None for
+// sensors without a 2x2 Bayer filter (monochrome, X-Trans, CMYG).
+pub fn read_pattern(raw_data:&[u8]) -> Option<CfaPattern> {
+    unsafe {
+        let data = sys::libraw_init(0);
+        if data.is_null() {
+            return None;
+        }
+
+        let mut pattern = None;
+        if sys::libraw_open_buffer(data, raw_data.as_ptr() as *const _, raw_data.len()) == 0 {
+            pattern = pattern_of(data);
+        }
+
+        sys::libraw_close(data);
+        pattern
+    }
+}
+
+// Bayer layout of a raw already opened by libraw
+pub(crate) unsafe fn pattern_of(data:*mut sys::libraw_data_t) -> Option<CfaPattern> {
+    if (*data).idata.filters < 1000 {
+        return None;
+    }
+
+    let cdesc = (*data).idata.cdesc;
+    let color = |row:i32, col:i32| -> Option<CfaColor> {
+        let idx = sys::libraw_COLOR(data, row, col);
+        if !(0..4).contains(&idx) {
+            return None;
+        }
+        match cdesc[idx as usize] as u8 {
+            b'R' => Some(CfaColor::Red),
+            b'G' => Some(CfaColor::Green),
+            b'B' => Some(CfaColor::Blue),
+            _ => None
+        }
+    };
+
+    match (color(0, 0), color(0, 1), color(1, 0), color(1, 1)) {
+        (Some(c00), Some(c01), Some(c10), Some(c11)) => CfaPattern::new([[c00, c01], [c10, c11]]),
+        _ => None
+    }
+}
+
+fn extract_offset(image:&ImageBuffer, ox:usize, oy:usize) -> error::Result<ImageBuffer> {
+    let dest_width = image.width / 2;
+    let dest_height = image.height / 2;
+
+    let mut dest = ImageBuffer::new(dest_width, dest_height)?;
+
+    for y in 0..dest_height {
+        for x in 0..dest_width {
+            dest.put(x, y, image.get(x * 2 + ox, y * 2 + oy)?)?;
+        }
+    }
+    Ok(dest)
+}
+
+// Extracts a half resolution plane of a single color from a Bayer mosaic
+pub fn extract_channel(image:&ImageBuffer, pattern:&CfaPattern, channel:CfaChannel) -> error::Result<ImageBuffer> {
+    match channel {
+        CfaChannel::Green => {
+            let (x1, y1) = pattern.offset_of(CfaChannel::Green1);
+            let (x2, y2) = pattern.offset_of(CfaChannel::Green2);
+            extract_offset(image, x1, y1)?.add(&extract_offset(image, x2, y2)?)?.scale(0.5)
+        },
+        _ => {
+            let (ox, oy) = pattern.offset_of(channel);
+            extract_offset(image, ox, oy)
+        }
+    }
+}
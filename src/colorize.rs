@@ -0,0 +1,183 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::multiband::MultiBandImage;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// A color at a position (0 to 1) along a gradient. Color components are 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GradientStop {
+    pub position: f32,
+    pub color: [f32; 3],
+}
+
+// Piecewise linear mapping from intensity to color
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    stops: Vec<GradientStop>,
+}
+
+fn stop(position:f32, r:f32, g:f32, b:f32) -> GradientStop {
+    GradientStop{position, color:[r, g, b]}
+}
+
+impl Palette {
+
+    // Builds a palette from at least two stops. Stops are sorted by position.
+    pub fn from_stops(stops:Vec<GradientStop>) -> error::Result<Palette> {
+        let mut stops = stops;
+        if stops.len() < 2 || stops.iter().any(|s| !(0.0..=1.0).contains(&s.position)) {
+            return Err(Error::invalid_parameter(constants::status::INVALID_GRADIENT, &format!("{:?}", stops)));
+        }
+        stops.sort_by(|a, b| a.position.partial_cmp(&b.position).unwrap());
+        Ok(Palette{stops})
+    }
+
+    // The standard H-alpha renderings, running from black through the palette's
+    // hue to near white at the brightest features.
+    pub fn from_name(name:&str) -> error::Result<Palette> {
+        let stops = match name.to_lowercase().as_str() {
+            constants::colorize::PALETTE_RED => vec![
+                stop(0.0, 0.0, 0.0, 0.0),
+                stop(0.45, 0.6, 0.04, 0.0),
+                stop(0.8, 1.0, 0.35, 0.1),
+                stop(1.0, 1.0, 0.85, 0.7),
+            ],
+            constants::colorize::PALETTE_ORANGE => vec![
+                stop(0.0, 0.0, 0.0, 0.0),
+                stop(0.4, 0.6, 0.18, 0.0),
+                stop(0.75, 1.0, 0.55, 0.1),
+                stop(1.0, 1.0, 0.95, 0.8),
+            ],
+            constants::colorize::PALETTE_YELLOW => vec![
+                stop(0.0, 0.0, 0.0, 0.0),
+                stop(0.35, 0.5, 0.3, 0.0),
+                stop(0.7, 1.0, 0.8, 0.2),
+                stop(1.0, 1.0, 1.0, 0.9),
+            ],
+            _ => return Err(Error::invalid_parameter(constants::status::UNKNOWN_PALETTE, name))
+        };
+        Palette::from_stops(stops)
+    }
+
+    // Parses stops given as POSITION:RRGGBB pairs separated by commas,
+    // e.g. 0:000000,0.5:c03000,1:ffffff
+    pub fn from_gradient_str(s:&str) -> error::Result<Palette> {
+        let bad = || Error::invalid_parameter(constants::status::INVALID_GRADIENT, s);
+
+        let mut stops:Vec<GradientStop> = Vec::new();
+        for item in s.split(',') {
+            let mut parts = item.trim().splitn(2, ':');
+            let position:f32 = parts.next().ok_or_else(bad)?.trim().parse().map_err(|_| bad())?;
+            let hex = parts.next().ok_or_else(bad)?.trim().trim_start_matches('#');
+            if hex.len() != 6 {
+                return Err(bad());
+            }
+            let channel = |i:usize| -> error::Result<f32> {
+                let v = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| bad())?;
+                Ok(v as f32 / 255.0)
+            };
+            stops.push(GradientStop{position, color:[channel(0)?, channel(1)?, channel(2)?]});
+        }
+        Palette::from_stops(stops).map_err(|_| bad())
+    }
+
+    pub fn stops(&self) -> &[GradientStop] {
+        &self.stops
+    }
+
+    // Color at an intensity of 0 to 1
+    pub fn color_at(&self, v:f32) -> [f32; 3] {
+        let first = self.stops[0];
+        let last = self.stops[self.stops.len() - 1];
+        if v <= first.position {
+            return first.color;
+        }
+        if v >= last.position {
+            return last.color;
+        }
+
+        for pair in self.stops.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if v <= b.position {
+                let span = b.position - a.position;
+                let t = if span > 0.0 { (v - a.position) / span } else { 1.0 };
+                return [a.color[0] + (b.color[0] - a.color[0]) * t,
+                        a.color[1] + (b.color[1] - a.color[1]) * t,
+                        a.color[2] + (b.color[2] - a.color[2]) * t];
+            }
+        }
+        last.color
+    }
+}
+
+// How intensities are turned into color
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorizeMethod {
+    Gradient(Palette),
+    // Each channel is the intensity raised to 1/gamma. With red given the highest
+    // gamma and blue the lowest, mid tones come out orange and highlights near white.
+    Gamma{red:f32, green:f32, blue:f32},
+}
+
+impl ColorizeMethod {
+    // Parses per-channel gammas given as RED,GREEN,BLUE
+    pub fn from_gamma_str(s:&str) -> error::Result<ColorizeMethod> {
+        let bad = || Error::invalid_parameter(constants::status::INVALID_GAMMA, s);
+        let values = s.split(',').map(|v| v.trim().parse::<f32>()).collect::<Result<Vec<f32>, _>>().map_err(|_| bad())?;
+        if values.len() != 3 || values.iter().any(|g| *g <= 0.0) {
+            return Err(bad());
+        }
+        Ok(ColorizeMethod::Gamma{red:values[0], green:values[1], blue:values[2]})
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorizeOptions {
+    pub method: ColorizeMethod,
+    // Inverts intensities before coloring, for the dark-disk look
+    pub invert: bool,
+}
+
+impl Default for ColorizeOptions {
+    fn default() -> Self {
+        ColorizeOptions{
+            method:ColorizeMethod::Gradient(Palette::from_name(constants::colorize::PALETTE_RED).unwrap()),
+            invert:false
+        }
+    }
+}
+
+// Maps a grayscale image to 16 bit range RGB. Intensities are first stretched
+// to 0 to 1 using the image's minimum and maximum.
+pub fn colorize(image:&ImageBuffer, options:&ColorizeOptions) -> error::Result<MultiBandImage> {
+    if image.is_empty() {
+        return Err(Error::EmptyImage);
+    }
+
+    let mm = image.get_min_max(-1.0)?;
+    let range = mm.max - mm.min;
+    vprintln!("    Colorizing with input range {} to {}, inverted: {}", mm.min, mm.max, options.invert);
+
+    let mut dest = MultiBandImage::new(image.width, image.height, 3)?;
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let mut v = if range > 0.0 { (image.get(x, y)? - mm.min) / range } else { 0.0 };
+            if options.invert {
+                v = 1.0 - v;
+            }
+
+            let rgb = match &options.method {
+                ColorizeMethod::Gradient(palette) => palette.color_at(v),
+                ColorizeMethod::Gamma{red, green, blue} => [v.powf(1.0 / red), v.powf(1.0 / green), v.powf(1.0 / blue)],
+            };
+
+            dest.put_pixel(x, y, &[rgb[0] * constants::_16_BIT_MAX,
+                                   rgb[1] * constants::_16_BIT_MAX,
+                                   rgb[2] * constants::_16_BIT_MAX])?;
+        }
+    }
+
+    Ok(dest)
+}
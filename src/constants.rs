@@ -2,16 +2,143 @@
 pub const _14_BIT_MAX : f32 = 16383.0;
 pub const _16_BIT_MAX : f32 = std::u16::MAX as f32;
 
-// http://lclevy.free.fr/cr2/
-// 14bit value
-pub const SENSOR_DARK_LEVEL : f32 = 1023.0;
-
 // Canon EOS 50D with IR block filter removed. 
 // Tamron 150-600mm lens @ 250mm zoom, masked to f/10
 // Exposure 1/400s, ISO 160
 pub const DEFAULT_CENTER_OF_MASS_THRESHOLD : f32 = 20000.0;
 
+// Output framing defaults
+pub const DEFAULT_CROP_WIDTH : usize = 1400;
+pub const DEFAULT_CROP_HEIGHT : usize = 1400;
+
+// Limb detection defaults
+pub const DEFAULT_LIMB_RAYS : usize = 360;
+pub const DEFAULT_RANSAC_ITERATIONS : usize = 500;
+pub const DEFAULT_RANSAC_TOLERANCE : f32 = 2.0;
+
+// Stacking defaults
+pub const DEFAULT_CLIP_KAPPA : f32 = 3.0;
+pub const DEFAULT_CLIP_ITERATIONS : usize = 5;
+// Smallest deviation, in ADU, a value is clipped or winsorized against
+pub const MIN_CLIP_SIGMA : f32 = 1.0;
+
+// Multi-point local alignment defaults
+pub const DEFAULT_AP_BOX_SIZE : usize = 64;
+pub const MIN_AP_BOX_SIZE : usize = 8;
+pub const DEFAULT_AP_MAX_SHIFT_FRACTION : f32 = 0.125;
+pub const DEFAULT_AP_MIN_CONTRAST : f32 = 0.01;
+pub const AP_BACKGROUND_WEIGHT : f32 = 0.01;
+pub const AP_REFINE_ITERATIONS : usize = 5;
+
+// Drizzle defaults. A red photosite spans half the width of its Bayer cell, so a
+// pixfrac of 0.5 at twice the plane's resolution drops each one onto its true footprint.
+pub const DEFAULT_DRIZZLE_SCALE : f32 = 2.0;
+pub const DEFAULT_DRIZZLE_PIXFRAC : f32 = 0.5;
+pub const MAX_DRIZZLE_SCALE : f32 = 8.0;
+pub const DRIZZLE_WEIGHT_MAP_SUFFIX : &str = "_weights";
+
+// Julian date of the J2000.0 epoch
+pub const J2000 : f64 = 2_451_545.0;
+
+// Apparent radius of the Sun in arcseconds at a distance of 1 AU
+pub const SOLAR_SEMI_DIAMETER_AT_1AU : f64 = 959.63;
+
+// Largest relative difference in exposure time for a dark to match a light
+pub const EXPOSURE_MATCH_TOLERANCE : f32 = 0.01;
+
+// Dark scaling. Dark current roughly doubles with every rise of this many degrees C,
+// and an unscaled dark more than this many degrees from the lights is reported.
+pub const DARK_CURRENT_DOUBLING_TEMPERATURE : f32 = 6.0;
+pub const DARK_TEMPERATURE_TOLERANCE : f32 = 3.0;
+pub const MAX_DARK_SCALE : f32 = 100.0;
+
+// Flat fielding. The center region spans this fraction of the flat's width and height,
+// and normalized responses are floored here so dim corners are boosted at most 20x.
+pub const FLAT_CENTER_FRACTION : f32 = 0.5;
+pub const MIN_FLAT_RESPONSE : f32 = 0.05;
+
+// Frames decoded at once per worker thread by the batch pipeline
+pub const DEFAULT_FRAMES_PER_THREAD : usize = 2;
+
+// Frames the median, sigma clip and winsorized stacks hold in memory before moving them
+// to a scratch file, and the memory used to read them back a band of rows at a time
+pub const STACK_FRAMES_IN_MEMORY : usize = 32;
+pub const STACK_READ_BUDGET_BYTES : usize = 256 * 1024 * 1024;
+
+pub mod interpolation {
+    pub const NEAREST : &str = "nearest";
+    pub const BILINEAR : &str = "bilinear";
+    pub const BICUBIC : &str = "bicubic";
+    pub const LANCZOS : &str = "lanczos";
+}
+
+pub mod centering {
+    pub const CENTER_OF_MASS : &str = "com";
+    pub const LIMB : &str = "limb";
+}
+
+pub mod stacking {
+    pub const METHOD_MEAN : &str = "mean";
+    pub const METHOD_MEDIAN : &str = "median";
+    pub const METHOD_SIGMA_CLIP : &str = "sigmaclip";
+    pub const METHOD_WINSORIZED : &str = "winsorized";
+}
+
 // Strings
+pub mod cfa {
+    pub const RED : &str = "red";
+    pub const GREEN1 : &str = "green1";
+    pub const GREEN2 : &str = "green2";
+    pub const BLUE : &str = "blue";
+    pub const GREEN : &str = "green";
+    // The whole mosaic, for masters calibrating color output
+    pub const MOSAIC : &str = "mosaic";
+}
+
+pub mod demosaic {
+    pub const BILINEAR : &str = "bilinear";
+    pub const EDGE_AWARE : &str = "edge";
+}
+
+pub mod colorize {
+    pub const PALETTE_RED : &str = "red";
+    pub const PALETTE_ORANGE : &str = "orange";
+    pub const PALETTE_YELLOW : &str = "yellow";
+}
+
+pub mod quality {
+    pub const LAPLACIAN : &str = "laplacian";
+    pub const GRADIENT : &str = "gradient";
+    pub const LIMB : &str = "limb";
+}
+
+// EXIF tags holding the capture time, and how much of the start of a raw is read to
+// find them before falling back to the whole file
+pub mod exif {
+    pub const HEADER_BYTES : usize = 64 * 1024;
+    pub const DATE_TIME : u32 = 0x0132;
+    pub const EXIF_IFD : u32 = 0x8769;
+    pub const DATE_TIME_ORIGINAL : u32 = 0x9003;
+}
+
+pub mod darkscale {
+    pub const NONE : &str = "none";
+    pub const EXPOSURE : &str = "exposure";
+    pub const OPTIMIZE : &str = "optimize";
+}
+
+pub mod flatnorm {
+    pub const MEAN : &str = "mean";
+    pub const MEDIAN : &str = "median";
+    pub const CENTER : &str = "center";
+}
+
+pub mod format {
+    pub const RGB16 : &str = "rgb16";
+    pub const GRAY16 : &str = "gray16";
+    pub const FLOAT32 : &str = "float32";
+}
+
 pub mod status {
     pub const EMPTY : &str = "";
     pub const OK : &str = "ok";
@@ -21,6 +148,41 @@ pub mod status {
     pub const ARRAY_SIZE_MISMATCH : &str = "Array size mismatch";
     pub const NOT_IMPLEMENTED : &str = "Not yet implemented";
     pub const DIMENSIONS_DO_NOT_MATCH_VECTOR_LENGTH : &str = "Image dimensions do not match supplied vector length";    
+    pub const NO_FILES_USED : &str = "No files used";
+    pub const UNKNOWN_STACK_METHOD : &str = "Unknown stacking method";
+    pub const UNKNOWN_INTERPOLATION : &str = "Unknown interpolation method";
+    pub const EMPTY_IMAGE : &str = "Image is empty";
+    pub const DISK_NOT_FOUND : &str = "Unable to locate solar disk";
+    pub const INSUFFICIENT_LIMB_POINTS : &str = "Insufficient limb points for circle fit";
+    pub const DEGENERATE_CIRCLE_FIT : &str = "Degenerate circle fit";
+    pub const UNKNOWN_CENTERING_METHOD : &str = "Unknown centering method";
+    pub const INVALID_CROP_SPECIFICATION : &str = "Invalid crop specification";
+    pub const UNKNOWN_OUTPUT_FORMAT : &str = "Unknown output format";
+    pub const UNKNOWN_CFA_CHANNEL : &str = "Unknown CFA channel";
+    pub const INVALID_CFA_PATTERN : &str = "Invalid CFA pattern";
+    pub const UNKNOWN_DEMOSAIC_METHOD : &str = "Unknown demosaic method";
+    pub const INVALID_BAND : &str = "Band index out of range";
+    pub const UNSUPPORTED_BAND_COUNT : &str = "Only 1 or 3 band images can be saved as TIFF or PNG";
+    pub const UNKNOWN_PALETTE : &str = "Unknown palette";
+    pub const INVALID_GRADIENT : &str = "Invalid gradient specification";
+    pub const UNKNOWN_QUALITY_METRIC : &str = "Unknown quality metric";
+    pub const INVALID_SELECTION : &str = "Invalid frame selection";
+    pub const INVALID_WEIGHT : &str = "Invalid frame weight";
+    pub const INVALID_AP_BOX_SIZE : &str = "Alignment box size is too small";
+    pub const INVALID_DRIZZLE_SCALE : &str = "Invalid drizzle scale";
+    pub const INVALID_PIXFRAC : &str = "Invalid drizzle pixfrac";
+    pub const INVALID_DATETIME : &str = "Invalid date and time";
+    pub const NO_CAPTURE_TIME : &str = "No capture time in raw metadata";
+    pub const NO_RAW_METADATA : &str = "Unable to read raw metadata";
+    pub const UNKNOWN_DARK_SCALING : &str = "Unknown dark scaling method";
+    pub const UNKNOWN_FLAT_NORMALIZATION : &str = "Unknown flat normalization method";
+    pub const NO_MASTER_FLAT : &str = "No master flat to save";
+    pub const UNSUPPORTED_WITH_COLOR : &str = "Option is not supported with color output";
+    pub const INVALID_OBSERVER : &str = "Invalid observer location";
+    pub const INVALID_TARGET : &str = "Invalid target position";
+    pub const WEIGHT_COUNT_MISMATCH : &str = "Number of weights does not match number of frames";
+    pub const INVALID_GAMMA : &str = "Invalid gamma specification";
+    pub const FLOAT_OUTPUT_REQUIRES_TIFF_OR_FITS : &str = "32 bit float output requires a TIFF or FITS file";
 }
 
 pub mod param {
@@ -34,5 +196,73 @@ pub mod param {
     pub const PARAM_FLAT_SHORT : &str = "f";
     pub const PARAM_INPUTS : &str = "inputs";
     pub const PARAM_INPUTS_SHORT : &str = "i";
+    pub const PARAM_METHOD : &str = "method";
+    pub const PARAM_METHOD_SHORT : &str = "m";
+    pub const PARAM_KAPPA : &str = "kappa";
+    pub const PARAM_KAPPA_SHORT : &str = "k";
+    pub const PARAM_REGISTER : &str = "register";
+    pub const PARAM_REGISTER_SHORT : &str = "r";
+    pub const PARAM_INTERPOLATION : &str = "interp";
+    pub const PARAM_INTERPOLATION_SHORT : &str = "I";
+    pub const PARAM_CENTER : &str = "center";
+    pub const PARAM_CENTER_SHORT : &str = "c";
+    pub const PARAM_CROP : &str = "crop";
+    pub const PARAM_CROP_SHORT : &str = "C";
+    pub const PARAM_ROI : &str = "roi";
+    pub const PARAM_ROI_SHORT : &str = "R";
+    pub const PARAM_MARGIN : &str = "margin";
+    pub const PARAM_MARGIN_SHORT : &str = "M";
+    pub const PARAM_FORMAT : &str = "format";
+    pub const PARAM_FORMAT_SHORT : &str = "F";
+    pub const PARAM_CHANNEL : &str = "channel";
+    pub const PARAM_CHANNEL_SHORT : &str = "b";
+    pub const PARAM_DEMOSAIC : &str = "demosaic";
+    pub const PARAM_DEMOSAIC_SHORT : &str = "D";
+    pub const PARAM_PALETTE : &str = "palette";
+    pub const PARAM_PALETTE_SHORT : &str = "p";
+    pub const PARAM_GRADIENT : &str = "gradient";
+    pub const PARAM_GRADIENT_SHORT : &str = "g";
+    pub const PARAM_GAMMA : &str = "gamma";
+    pub const PARAM_GAMMA_SHORT : &str = "G";
+    pub const PARAM_INVERT : &str = "invert";
+    pub const PARAM_INVERT_SHORT : &str = "n";
+    pub const PARAM_THREADS : &str = "threads";
+    pub const PARAM_THREADS_SHORT : &str = "t";
+    pub const PARAM_BATCH : &str = "batch";
+    pub const PARAM_BATCH_SHORT : &str = "B";
+    pub const PARAM_QUALITY : &str = "quality";
+    pub const PARAM_QUALITY_SHORT : &str = "q";
+    pub const PARAM_SELECT : &str = "select";
+    pub const PARAM_SELECT_SHORT : &str = "s";
+    pub const PARAM_REPORT : &str = "report";
+    pub const PARAM_REPORT_SHORT : &str = "Q";
+    pub const PARAM_WEIGHT : &str = "weight";
+    pub const PARAM_WEIGHT_SHORT : &str = "w";
+    pub const PARAM_WEIGHTS : &str = "weights";
+    pub const PARAM_WEIGHTS_SHORT : &str = "W";
+    pub const PARAM_AP : &str = "ap";
+    pub const PARAM_AP_SHORT : &str = "A";
+    pub const PARAM_DRIZZLE : &str = "drizzle";
+    pub const PARAM_DRIZZLE_SHORT : &str = "z";
+    pub const PARAM_PIXFRAC : &str = "pixfrac";
+    pub const PARAM_PIXFRAC_SHORT : &str = "P";
+    pub const PARAM_OBSERVER : &str = "observer";
+    pub const PARAM_OBSERVER_SHORT : &str = "L";
+    pub const PARAM_TARGET : &str = "target";
+    pub const PARAM_TARGET_SHORT : &str = "T";
+    pub const PARAM_UTC_OFFSET : &str = "utcoffset";
+    pub const PARAM_UTC_OFFSET_SHORT : &str = "U";
+    pub const PARAM_NORTH_UP : &str = "northup";
+    pub const PARAM_NORTH_UP_SHORT : &str = "N";
+    pub const PARAM_DARK_SCALE : &str = "darkscale";
+    pub const PARAM_DARK_SCALE_SHORT : &str = "S";
+    pub const PARAM_BIAS : &str = "bias";
+    pub const PARAM_BIAS_SHORT : &str = "a";
+    pub const PARAM_FLAT_DARK : &str = "flatdark";
+    pub const PARAM_FLAT_DARK_SHORT : &str = "j";
+    pub const PARAM_FLAT_NORM : &str = "flatnorm";
+    pub const PARAM_FLAT_NORM_SHORT : &str = "e";
+    pub const PARAM_FLAT_OUTPUT : &str = "flatout";
+    pub const PARAM_FLAT_OUTPUT_SHORT : &str = "E";
 }
 
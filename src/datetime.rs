@@ -0,0 +1,167 @@
+use crate::constants;
+use crate::error::{self, Error};
+
+use std::fs;
+use std::io::Read;
+
+// Calendar date and time of day, as recorded by the camera's clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: f64,
+}
+
+impl DateTime {
+    pub fn new(year:i32, month:u32, day:u32, hour:u32, minute:u32, second:f64) -> error::Result<DateTime> {
+        let dt = DateTime{year, month, day, hour, minute, second};
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || !(0.0..61.0).contains(&second) {
+            return Err(Error::invalid_parameter(constants::status::INVALID_DATETIME, &dt.to_string()));
+        }
+        Ok(dt)
+    }
+
+    // Parses an EXIF date, e.g. 2021:03:16 17:45:02. Dashes are accepted between the
+    // date fields and a T between date and time, so ISO 8601 times parse as well.
+    pub fn from_exif(s:&str) -> error::Result<DateTime> {
+        let bad = || Error::invalid_parameter(constants::status::INVALID_DATETIME, s);
+        let s = s.trim().trim_end_matches('\0').trim_end_matches('Z');
+        let (date, time) = s.split_at(s.find([' ', 'T']).ok_or_else(bad)?);
+        let date:Vec<&str> = date.split([':', '-']).collect();
+        let time:Vec<&str> = time[1..].split(':').collect();
+        if date.len() != 3 || time.len() != 3 {
+            return Err(bad());
+        }
+        DateTime::new(date[0].parse().map_err(|_| bad())?,
+                      date[1].parse().map_err(|_| bad())?,
+                      date[2].parse().map_err(|_| bad())?,
+                      time[0].parse().map_err(|_| bad())?,
+                      time[1].parse().map_err(|_| bad())?,
+                      time[2].parse().map_err(|_| bad())?)
+    }
+
+    // Julian date of the time, treating it as UT (Meeus, Astronomical Algorithms, 7.1)
+    pub fn julian_date(&self) -> f64 {
+        let (mut y, mut m) = (self.year as f64, self.month as f64);
+        if self.month <= 2 {
+            y -= 1.0;
+            m += 12.0;
+        }
+        let a = (y / 100.0).floor();
+        let b = 2.0 - a + (a / 4.0).floor();
+        let day = self.day as f64 + (self.hour as f64 + (self.minute as f64 + self.second / 60.0) / 60.0) / 24.0;
+        (365.25 * (y + 4716.0)).floor() + (30.6001 * (m + 1.0)).floor() + day + b - 1524.5
+    }
+
+    // Calendar date and time of a Julian date (Meeus, chapter 7), to the nearest millisecond
+    pub fn from_julian_date(jd:f64) -> DateTime {
+        let shifted = jd + 0.5;
+        let mut z = shifted.floor();
+        let mut millis = ((shifted - z) * 86_400_000.0).round();
+        // Rounding to the millisecond can carry into the next day
+        if millis >= 86_400_000.0 {
+            z += 1.0;
+            millis = 0.0;
+        }
+
+        let a = if z < 2_299_161.0 {
+            z
+        } else {
+            let alpha = ((z - 1_867_216.25) / 36_524.25).floor();
+            z + 1.0 + alpha - (alpha / 4.0).floor()
+        };
+        let b = a + 1524.0;
+        let c = ((b - 122.1) / 365.25).floor();
+        let d = (365.25 * c).floor();
+        let e = ((b - d) / 30.6001).floor();
+        let day = b - d - (30.6001 * e).floor();
+        let month = if e < 14.0 { e - 1.0 } else { e - 13.0 };
+        let year = if month > 2.0 { c - 4716.0 } else { c - 4715.0 };
+
+        let seconds = millis / 1000.0;
+        DateTime{
+            year:year as i32,
+            month:month as u32,
+            day:day as u32,
+            hour:(seconds / 3600.0).floor() as u32,
+            minute:((seconds % 3600.0) / 60.0).floor() as u32,
+            second:seconds % 60.0
+        }
+    }
+
+    // ISO 8601 form, as used for DATE-OBS
+    pub fn to_iso(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:06.3}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+impl std::fmt::Display for DateTime {
+    fn fmt(&self, f:&mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:06.3}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// Julian centuries since J2000.0
+pub fn centuries_since_j2000(jd:f64) -> f64 {
+    (jd - constants::J2000) / 36525.0
+}
+
+// Reads an unsigned integer of the given size at offset from TIFF data of either byte order
+fn read_uint(data:&[u8], offset:usize, size:usize, little_endian:bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + size)?;
+    let mut value = 0_u32;
+    for i in 0..size {
+        let b = if little_endian { bytes[size - 1 - i] } else { bytes[i] };
+        value = (value << 8) | b as u32;
+    }
+    Some(value)
+}
+
+// Finds a tag in the TIFF directory at offset, returning the value field's offset
+fn find_tag(data:&[u8], ifd:usize, tag:u32, little_endian:bool) -> Option<usize> {
+    let count = read_uint(data, ifd, 2, little_endian)? as usize;
+    (0..count).map(|i| ifd + 2 + i * 12)
+              .find(|entry| read_uint(data, *entry, 2, little_endian) == Some(tag))
+              .map(|entry| entry + 8)
+}
+
+fn read_ascii_tag(data:&[u8], ifd:usize, tag:u32, little_endian:bool) -> Option<String> {
+    let entry = find_tag(data, ifd, tag, little_endian)?;
+    let count = read_uint(data, entry - 4, 4, little_endian)? as usize;
+    let start = if count <= 4 { entry } else { read_uint(data, entry, 4, little_endian)? as usize };
+    let bytes = data.get(start..start + count)?;
+    Some(String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string())
+}
+
+// Reads the capture time from the EXIF metadata of a TIFF based raw such as a CR2,
+// preferring the original capture time over the file's modification time.
+pub fn read_capture_time(raw_data:&[u8]) -> Option<DateTime> {
+    let little_endian = match raw_data.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None
+    };
+    let ifd0 = read_uint(raw_data, 4, 4, little_endian)? as usize;
+
+    let original = find_tag(raw_data, ifd0, constants::exif::EXIF_IFD, little_endian)
+        .and_then(|entry| read_uint(raw_data, entry, 4, little_endian))
+        .and_then(|exif_ifd| read_ascii_tag(raw_data, exif_ifd as usize, constants::exif::DATE_TIME_ORIGINAL, little_endian));
+    let text = original.or_else(|| read_ascii_tag(raw_data, ifd0, constants::exif::DATE_TIME, little_endian))?;
+    DateTime::from_exif(&text).ok()
+}
+
+// Reads the capture time of a raw file. A CR2 keeps its EXIF data near the start, so
+// only the header is read unless the time isn't found there.
+pub fn capture_time(raw_file:&str) -> error::Result<DateTime> {
+    let mut head = Vec::with_capacity(constants::exif::HEADER_BYTES);
+    fs::File::open(raw_file).and_then(|f| f.take(constants::exif::HEADER_BYTES as u64).read_to_end(&mut head))
+                            .map_err(|e| Error::io(raw_file, e))?;
+    if let Some(time) = read_capture_time(&head) {
+        return Ok(time);
+    }
+    let buf = fs::read(raw_file).map_err(|e| Error::io(raw_file, e))?;
+    read_capture_time(&buf).ok_or_else(|| Error::decode(raw_file, constants::status::NO_CAPTURE_TIME))
+}
@@ -0,0 +1,203 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::multiband::MultiBandImage;
+use crate::cfa::{CfaColor, CfaPattern};
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// Algorithm used to reconstruct full resolution color from a Bayer mosaic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DemosaicMethod {
+    // Average of the nearest photosites of each color
+    Bilinear,
+    // Hamilton-Adams style: green is interpolated along the direction of least
+    // gradient with a Laplacian correction from the co-sited color, then red and
+    // blue are filled in as color differences against the full green plane.
+    EdgeAware,
+}
+
+impl DemosaicMethod {
+    pub fn from_name(name:&str) -> error::Result<DemosaicMethod> {
+        match name.to_lowercase().as_str() {
+            constants::demosaic::BILINEAR => Ok(DemosaicMethod::Bilinear),
+            constants::demosaic::EDGE_AWARE => Ok(DemosaicMethod::EdgeAware),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_DEMOSAIC_METHOD, name))
+        }
+    }
+}
+
+// Read access to the mosaic with coordinates mirrored at the borders. Mirroring
+// keeps the parity of the coordinate, and with it the CFA color.
+struct Mosaic<'a> {
+    buffer: &'a [f32],
+    width: i64,
+    height: i64,
+    pattern: &'a CfaPattern,
+}
+
+impl<'a> Mosaic<'a> {
+    fn reflect(v:i64, size:i64) -> i64 {
+        let mut v = v;
+        if v < 0 {
+            v = -v;
+        }
+        if v >= size {
+            v = 2 * size - 2 - v;
+        }
+        v.clamp(0, size - 1)
+    }
+
+    fn at(&self, x:i64, y:i64) -> f32 {
+        let x = Mosaic::reflect(x, self.width);
+        let y = Mosaic::reflect(y, self.height);
+        self.buffer[(y * self.width + x) as usize]
+    }
+
+    fn color(&self, x:i64, y:i64) -> CfaColor {
+        self.pattern.color_at(x as usize, y as usize)
+    }
+}
+
+fn check_size(image:&ImageBuffer) -> error::Result<()> {
+    if image.width < 2 || image.height < 2 {
+        return Err(Error::EmptyImage);
+    }
+    Ok(())
+}
+
+fn mosaic_buffer(image:&ImageBuffer) -> error::Result<Vec<f32>> {
+    let mut v:Vec<f32> = Vec::with_capacity(image.width * image.height);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            v.push(image.get(x, y)?);
+        }
+    }
+    Ok(v)
+}
+
+fn demosaic_bilinear(m:&Mosaic) -> error::Result<MultiBandImage> {
+    let mut dest = MultiBandImage::new(m.width as usize, m.height as usize, 3)?;
+
+    for y in 0..m.height {
+        for x in 0..m.width {
+            let mut sums = [0.0_f32; 3];
+            let mut counts = [0_u32; 3];
+
+            let own = m.color(x, y);
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let nx = x + dx;
+                    let ny = y + dy;
+                    if nx < 0 || ny < 0 || nx >= m.width || ny >= m.height {
+                        continue;
+                    }
+                    let c = m.color(nx, ny);
+                    // A photosite's own color comes only from itself
+                    if c == own && (dx != 0 || dy != 0) {
+                        continue;
+                    }
+                    let i = color_index(c);
+                    sums[i] += m.at(nx, ny);
+                    counts[i] += 1;
+                }
+            }
+
+            let mut rgb = [0.0_f32; 3];
+            for i in 0..3 {
+                rgb[i] = if counts[i] > 0 { sums[i] / counts[i] as f32 } else { 0.0 };
+            }
+            dest.put_pixel(x as usize, y as usize, &rgb)?;
+        }
+    }
+    Ok(dest)
+}
+
+fn color_index(c:CfaColor) -> usize {
+    match c {
+        CfaColor::Red => 0,
+        CfaColor::Green => 1,
+        CfaColor::Blue => 2,
+    }
+}
+
+fn demosaic_edge_aware(m:&Mosaic) -> error::Result<MultiBandImage> {
+    let w = m.width;
+    let h = m.height;
+
+    // Full resolution green first
+    let mut green:Vec<f32> = vec![0.0; (w * h) as usize];
+    for y in 0..h {
+        for x in 0..w {
+            let c = m.at(x, y);
+            green[(y * w + x) as usize] = if m.color(x, y) == CfaColor::Green {
+                c
+            } else {
+                let lap_h = 2.0 * c - m.at(x - 2, y) - m.at(x + 2, y);
+                let lap_v = 2.0 * c - m.at(x, y - 2) - m.at(x, y + 2);
+                let dh = (m.at(x - 1, y) - m.at(x + 1, y)).abs() + lap_h.abs();
+                let dv = (m.at(x, y - 1) - m.at(x, y + 1)).abs() + lap_v.abs();
+                let gh = (m.at(x - 1, y) + m.at(x + 1, y)) / 2.0 + lap_h / 4.0;
+                let gv = (m.at(x, y - 1) + m.at(x, y + 1)) / 2.0 + lap_v / 4.0;
+                let g = if dh < dv {
+                    gh
+                } else if dv < dh {
+                    gv
+                } else {
+                    (gh + gv) / 2.0
+                };
+                g.max(0.0)
+            };
+        }
+    }
+
+    let g = |x:i64, y:i64| green[(Mosaic::reflect(y, h) * w + Mosaic::reflect(x, w)) as usize];
+
+    // Red and blue are interpolated as differences from green, which is much
+    // smoother than the colors themselves across an edge.
+    let mut dest = MultiBandImage::new(w as usize, h as usize, 3)?;
+    for y in 0..h {
+        for x in 0..w {
+            let own = m.color(x, y);
+            let mut rgb = [0.0_f32; 3];
+            rgb[1] = g(x, y);
+
+            for target in [CfaColor::Red, CfaColor::Blue].iter() {
+                let i = color_index(*target);
+                rgb[i] = if own == *target {
+                    m.at(x, y)
+                } else {
+                    let mut diff = 0.0;
+                    let mut n = 0.0;
+                    for dy in -1..=1 {
+                        for dx in -1..=1 {
+                            let (nx, ny) = (x + dx, y + dy);
+                            if (dx != 0 || dy != 0) && nx >= 0 && ny >= 0 && nx < w && ny < h
+                                && m.color(nx, ny) == *target {
+                                diff += m.at(nx, ny) - g(nx, ny);
+                                n += 1.0;
+                            }
+                        }
+                    }
+                    let d = if n > 0.0 { diff / n } else { 0.0 };
+                    (rgb[1] + d).max(0.0)
+                };
+            }
+            dest.put_pixel(x as usize, y as usize, &rgb)?;
+        }
+    }
+    Ok(dest)
+}
+
+// Reconstructs a full resolution, three band color image from a raw Bayer mosaic
+pub fn demosaic(image:&ImageBuffer, pattern:&CfaPattern, method:DemosaicMethod) -> error::Result<MultiBandImage> {
+    check_size(image)?;
+    vprintln!("    Demosaicing {}x{} {} mosaic using {:?}", image.width, image.height, pattern.name(), method);
+
+    let buffer = mosaic_buffer(image)?;
+    let m = Mosaic{buffer:&buffer, width:image.width as i64, height:image.height as i64, pattern};
+
+    match method {
+        DemosaicMethod::Bilinear => demosaic_bilinear(&m),
+        DemosaicMethod::EdgeAware => demosaic_edge_aware(&m),
+    }
+}
@@ -0,0 +1,223 @@
+use crate::imagebuffer::{ImageBuffer, OutputFormat, SubpixelOffset};
+use crate::registration;
+use crate::fits;
+use crate::path;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// Options for drizzle integration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DrizzleOptions {
+    // Size of the output grid relative to the input frames. 2 restores the sensor
+    // resolution when drizzling a single Bayer plane.
+    pub scale: f32,
+    // Width of each input pixel's drop as a fraction of the input pixel. Smaller drops
+    // keep more resolution but need more frames, with more dithering, to fill the grid.
+    pub pixfrac: f32,
+}
+
+impl Default for DrizzleOptions {
+    fn default() -> Self {
+        DrizzleOptions{
+            scale:constants::DEFAULT_DRIZZLE_SCALE,
+            pixfrac:constants::DEFAULT_DRIZZLE_PIXFRAC
+        }
+    }
+}
+
+impl DrizzleOptions {
+    pub fn new(scale:f32, pixfrac:f32) -> error::Result<DrizzleOptions> {
+        if !scale.is_finite() || scale <= 0.0 || scale > constants::MAX_DRIZZLE_SCALE {
+            return Err(Error::invalid_parameter(constants::status::INVALID_DRIZZLE_SCALE, &scale.to_string()));
+        }
+        if !pixfrac.is_finite() || pixfrac <= 0.0 || pixfrac > 1.0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_PIXFRAC, &pixfrac.to_string()));
+        }
+        Ok(DrizzleOptions{scale, pixfrac})
+    }
+
+    // Output dimensions for input frames of the given size
+    pub fn output_size(&self, width:usize, height:usize) -> (usize, usize) {
+        ((width as f32 * self.scale).round() as usize, (height as f32 * self.scale).round() as usize)
+    }
+}
+
+// Where a frame's pixel centers land on the reference grid, as an affine map. Built up
+// from shifts and rotations so a frame can be dropped without first being resampled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameTransform {
+    m: [[f32; 3]; 2],
+}
+
+impl Default for FrameTransform {
+    fn default() -> Self {
+        FrameTransform::shift(0.0, 0.0)
+    }
+}
+
+impl FrameTransform {
+    pub fn shift(h:f32, v:f32) -> FrameTransform {
+        FrameTransform{m:[[1.0, 0.0, h], [0.0, 1.0, v]]}
+    }
+
+    // Rotation about a point by an angle in degrees, counterclockwise as displayed, the
+    // same way ImageBuffer::rotate turns an image
+    pub fn rotation(cx:f32, cy:f32, degrees:f32) -> FrameTransform {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        FrameTransform{m:[[cos, sin, cx - cos * cx - sin * cy], [-sin, cos, cy + sin * cx - cos * cy]]}
+    }
+
+    // This transform followed by next
+    pub fn then(&self, next:&FrameTransform) -> FrameTransform {
+        let (a, b) = (&next.m, &self.m);
+        let mut m = [[0.0; 3]; 2];
+        for (r, row) in m.iter_mut().enumerate() {
+            row[0] = a[r][0] * b[0][0] + a[r][1] * b[1][0];
+            row[1] = a[r][0] * b[0][1] + a[r][1] * b[1][1];
+            row[2] = a[r][0] * b[0][2] + a[r][1] * b[1][2] + a[r][2];
+        }
+        FrameTransform{m}
+    }
+
+    pub fn apply(&self, x:f32, y:f32) -> (f32, f32) {
+        (self.m[0][0] * x + self.m[0][1] * y + self.m[0][2], self.m[1][0] * x + self.m[1][1] * y + self.m[1][2])
+    }
+}
+
+// Length of the overlap between the intervals [a0, a1) and [b0, b1)
+fn overlap(a0:f32, a1:f32, b0:f32, b1:f32) -> f32 {
+    (a1.min(b1) - a0.max(b0)).max(0.0)
+}
+
+// Integrates frames onto a finer output grid. Each input pixel is shrunk to a drop of
+// pixfrac times its size, placed on the reference by the frame's transform and
+// added to the output pixels it overlaps in proportion to the area covered. The
+// accumulated coverage is kept as a weight map, which shows how well the dithering
+// between frames filled the grid.
+pub struct Drizzle {
+    reference: ImageBuffer,
+    options: DrizzleOptions,
+    width: usize,
+    height: usize,
+    sum: Vec<f32>,
+    weight: Vec<f32>,
+    count: usize,
+}
+
+impl Drizzle {
+    pub fn new(reference:ImageBuffer, options:&DrizzleOptions) -> error::Result<Drizzle> {
+        if reference.is_empty() {
+            return Err(Error::EmptyImage);
+        }
+        let (width, height) = options.output_size(reference.width, reference.height);
+        vprintln!("    Drizzling onto a {}x{} grid with pixfrac {}", width, height, options.pixfrac);
+        Ok(Drizzle{reference, options:*options, width, height, sum:vec![0.0; width * height], weight:vec![0.0; width * height], count:0})
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn add(&mut self, frame:&ImageBuffer) -> error::Result<()> {
+        self.add_weighted(frame, 1.0)
+    }
+
+    // Measures the frame's offset from the reference by phase correlation and drops it
+    pub fn add_weighted(&mut self, frame:&ImageBuffer, frame_weight:f32) -> error::Result<()> {
+        let offset = registration::phase_correlate(&self.reference, frame)?;
+        self.add_with_offset(frame, &offset, frame_weight)
+    }
+
+    // Drops a frame whose offset is already known, as the shift that aligns it with the reference
+    pub fn add_with_offset(&mut self, frame:&ImageBuffer, offset:&SubpixelOffset, frame_weight:f32) -> error::Result<()> {
+        if frame.width != self.reference.width || frame.height != self.reference.height {
+            return Err(Error::DimensionMismatch{expected:(self.reference.width, self.reference.height), found:(frame.width, frame.height)});
+        }
+        self.add_transformed(frame, &FrameTransform::shift(offset.h, offset.v), frame_weight)
+    }
+
+    // Drops a frame of any size whose pixels reach the reference through the transform,
+    // after measuring the offset still left between the reference and a preview of the
+    // frame already resampled by that transform
+    pub fn add_registered(&mut self, frame:&ImageBuffer, preview:&ImageBuffer, transform:&FrameTransform, frame_weight:f32) -> error::Result<()> {
+        let offset = registration::phase_correlate(&self.reference, preview)?;
+        self.add_transformed(frame, &transform.then(&FrameTransform::shift(offset.h, offset.v)), frame_weight)
+    }
+
+    // Drops a frame whose pixels reach the reference through the transform. Drops stay
+    // square on the output grid however the frame is turned.
+    pub fn add_transformed(&mut self, frame:&ImageBuffer, transform:&FrameTransform, frame_weight:f32) -> error::Result<()> {
+        if !frame_weight.is_finite() || frame_weight < 0.0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, &frame_weight.to_string()));
+        }
+
+        // Input pixel centers are at integer coordinates, so pixel x covers x - 0.5 to
+        // x + 0.5. On the output grid that is (x + 0.5) * scale, give or take half a drop.
+        let scale = self.options.scale;
+        let half_drop = self.options.pixfrac * scale / 2.0;
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let (tx, ty) = transform.apply(x as f32, y as f32);
+                let (cx, cy) = ((tx + 0.5) * scale, (ty + 0.5) * scale);
+                let (left, right, top, bottom) = (cx - half_drop, cx + half_drop, cy - half_drop, cy + half_drop);
+                if right <= 0.0 || left >= self.width as f32 || bottom <= 0.0 || top >= self.height as f32 {
+                    continue;
+                }
+
+                let value = frame.get(x, y)?;
+                for oy in (top.floor().max(0.0) as usize)..(bottom.ceil() as usize).min(self.height) {
+                    let dy = overlap(top, bottom, oy as f32, oy as f32 + 1.0);
+                    for ox in (left.floor().max(0.0) as usize)..(right.ceil() as usize).min(self.width) {
+                        let w = frame_weight * dy * overlap(left, right, ox as f32, ox as f32 + 1.0);
+                        if w > 0.0 {
+                            let i = oy * self.width + ox;
+                            self.sum[i] += w * value;
+                            self.weight[i] += w;
+                        }
+                    }
+                }
+            }
+        }
+
+        let (h, v) = transform.apply(0.0, 0.0);
+        vprintln!("    Drizzled frame with its origin at {:.3}, {:.3}", h, v);
+        self.count += 1;
+        Ok(())
+    }
+
+    // Returns the drizzled image and its weight map. Output pixels no drop reached are zero.
+    pub fn finish(self) -> error::Result<(ImageBuffer, ImageBuffer)> {
+        if self.count == 0 {
+            return Err(Error::NoFilesUsed);
+        }
+        let empty = self.weight.iter().filter(|w| **w <= 0.0).count();
+        if empty > 0 {
+            vprintln!("    {} of {} drizzled pixels received no data", empty, self.weight.len());
+        }
+        let v = self.sum.iter().zip(self.weight.iter()).map(|(s, w)| if *w > 0.0 { s / w } else { 0.0 }).collect();
+        let image = ImageBuffer::from_vec(v, self.width, self.height)?;
+        let weights = ImageBuffer::from_vec(self.weight, self.width, self.height)?;
+        Ok((image, weights))
+    }
+}
+
+// Saves a weight map. Float output keeps the accumulated coverage as is, 16 bit
+// output is scaled so the best covered pixel is white.
+pub fn save_weight_map(weights:&ImageBuffer, to_file:&str, format:OutputFormat) -> error::Result<()> {
+    let float_output = match format {
+        OutputFormat::Float32 => true,
+        _ => fits::is_fits_path(to_file) && format.fits_depth() == fits::BitDepth::Float32
+    };
+    let mm = weights.get_min_max(-1.0)?;
+    if float_output || mm.max <= 0.0 {
+        weights.save_as(to_file, format)
+    } else {
+        weights.scale(constants::_16_BIT_MAX / mm.max)?.save_as(to_file, format)
+    }
+}
+
+// Path the weight map of a drizzled output is saved to, alongside it
+pub fn weight_map_path(output:&str) -> String {
+    path::append_to_stem(output, constants::DRIZZLE_WEIGHT_MAP_SUFFIX)
+}
@@ -0,0 +1,79 @@
+use crate::constants;
+
+use std::fmt;
+
+// Errors raised by the library. Variants carry enough context (file paths,
+// image sizes, coordinates) to be reported without the caller needing to know
+// what was being attempted.
+#[derive(Debug)]
+pub enum Error {
+    Io { path:String, source:std::io::Error },
+    FileNotFound(String),
+    ParentNotWritable(String),
+    Decode { path:String, message:String },
+    Encode { path:String, message:String },
+    DimensionMismatch { expected:(usize, usize), found:(usize, usize) },
+    VectorLengthMismatch { width:usize, height:usize, length:usize },
+    InvalidCoordinates { x:usize, y:usize, width:usize, height:usize },
+    InvalidParameter { message:&'static str, value:String },
+    EmptyImage,
+    NoFilesUsed,
+    DiskNotFound,
+    InsufficientLimbPoints(usize),
+    DegenerateCircleFit,
+    InvalidBand { band:usize, bands:usize },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path:&str, source:std::io::Error) -> Error {
+        Error::Io{path:String::from(path), source}
+    }
+
+    pub fn decode(path:&str, message:&str) -> Error {
+        Error::Decode{path:String::from(path), message:String::from(message)}
+    }
+
+    pub fn encode(path:&str, message:&str) -> Error {
+        Error::Encode{path:String::from(path), message:String::from(message)}
+    }
+
+    pub fn invalid_parameter(message:&'static str, value:&str) -> Error {
+        Error::InvalidParameter{message, value:String::from(value)}
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f:&mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io{path, source} => write!(f, "I/O error on {}: {}", path, source),
+            Error::FileNotFound(path) => write!(f, "{}: {}", constants::status::FILE_NOT_FOUND, path),
+            Error::ParentNotWritable(path) => write!(f, "{}: {}", constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE, path),
+            Error::Decode{path, message} => write!(f, "Failed to decode {}: {}", path, message),
+            Error::Encode{path, message} => write!(f, "Failed to write {}: {}", path, message),
+            Error::DimensionMismatch{expected, found} => write!(f, "{}: expected {}x{}, found {}x{}",
+                                                                constants::status::ARRAY_SIZE_MISMATCH, expected.0, expected.1, found.0, found.1),
+            Error::VectorLengthMismatch{width, height, length} => write!(f, "{}: {}x{} requires {} values, got {}",
+                                                                constants::status::DIMENSIONS_DO_NOT_MATCH_VECTOR_LENGTH, width, height, width * height, length),
+            Error::InvalidCoordinates{x, y, width, height} => write!(f, "{}: ({}, {}) in {}x{} image",
+                                                                constants::status::INVALID_PIXEL_COORDINATES, x, y, width, height),
+            Error::InvalidParameter{message, value} => write!(f, "{}: '{}'", message, value),
+            Error::EmptyImage => write!(f, "{}", constants::status::EMPTY_IMAGE),
+            Error::NoFilesUsed => write!(f, "{}", constants::status::NO_FILES_USED),
+            Error::DiskNotFound => write!(f, "{}", constants::status::DISK_NOT_FOUND),
+            Error::InsufficientLimbPoints(count) => write!(f, "{} ({} found)", constants::status::INSUFFICIENT_LIMB_POINTS, count),
+            Error::DegenerateCircleFit => write!(f, "{}", constants::status::DEGENERATE_CIRCLE_FIT),
+            Error::InvalidBand{band, bands} => write!(f, "{}: band {} of {}", constants::status::INVALID_BAND, band, bands),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io{source, ..} => Some(source),
+            _ => None
+        }
+    }
+}
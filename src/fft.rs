@@ -0,0 +1,108 @@
+use std::f64::consts::PI;
+
+// Minimal complex number type for the transforms below.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re:f64, im:f64) -> Complex {
+        Complex{re, im}
+    }
+
+    pub fn zero() -> Complex {
+        Complex{re:0.0, im:0.0}
+    }
+
+    pub fn conj(&self) -> Complex {
+        Complex{re:self.re, im:-self.im}
+    }
+
+    pub fn multiply(&self, other:&Complex) -> Complex {
+        Complex{
+            re:self.re * other.re - self.im * other.im,
+            im:self.re * other.im + self.im * other.re
+        }
+    }
+
+    pub fn norm(&self) -> f64 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+}
+
+// Smallest power of two that is greater than or equal to n
+pub fn next_pow2(n:usize) -> usize {
+    let mut p = 1;
+    while p < n {
+        p <<= 1;
+    }
+    p
+}
+
+// In-place iterative radix-2 FFT. The length of the data must be a power of two.
+pub fn fft(data:&mut [Complex], inverse:bool) {
+    let n = data.len();
+    if n <= 1 {
+        return;
+    }
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let ang = sign * 2.0 * PI / len as f64;
+        let wlen = Complex::new(ang.cos(), ang.sin());
+        for start in (0..n).step_by(len) {
+            let mut w = Complex::new(1.0, 0.0);
+            for k in 0..len / 2 {
+                let u = data[start + k];
+                let v = data[start + k + len / 2].multiply(&w);
+                data[start + k] = Complex::new(u.re + v.re, u.im + v.im);
+                data[start + k + len / 2] = Complex::new(u.re - v.re, u.im - v.im);
+                w = w.multiply(&wlen);
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        let scale = 1.0 / n as f64;
+        for c in data.iter_mut() {
+            c.re *= scale;
+            c.im *= scale;
+        }
+    }
+}
+
+// In-place 2D FFT over a row-major buffer. Both dimensions must be powers of two.
+pub fn fft2d(data:&mut [Complex], width:usize, height:usize, inverse:bool) {
+    for row in data.chunks_mut(width) {
+        fft(row, inverse);
+    }
+
+    let mut column:Vec<Complex> = vec![Complex::zero(); height];
+    for x in 0..width {
+        for (y, c) in column.iter_mut().enumerate() {
+            *c = data[y * width + x];
+        }
+        fft(&mut column, inverse);
+        for (y, c) in column.iter().enumerate() {
+            data[y * width + x] = *c;
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::interpolation::Interpolation;
+use crate::datetime::{self, DateTime};
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// Parses a pair of comma separated numbers
+fn parse_pair(s:&str, message:&'static str) -> error::Result<(f64, f64)> {
+    let bad = || Error::invalid_parameter(message, s);
+    let values:Vec<f64> = s.split(',').map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>().map_err(|_| bad())?;
+    if values.len() != 2 || !values.iter().all(|v| v.is_finite()) {
+        return Err(bad());
+    }
+    Ok((values[0], values[1]))
+}
+
+// Location of the observer in degrees. Longitudes are positive east of Greenwich.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Observer {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Observer {
+    // Parses LATITUDE,LONGITUDE in degrees, e.g. 34.2,-118.2
+    pub fn from_spec(s:&str) -> error::Result<Observer> {
+        let (latitude, longitude) = parse_pair(s, constants::status::INVALID_OBSERVER)?;
+        if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_OBSERVER, s));
+        }
+        Ok(Observer{latitude, longitude})
+    }
+}
+
+// Position of the target on the sky, right ascension and declination in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Equatorial {
+    pub ra: f64,
+    pub dec: f64,
+}
+
+impl Equatorial {
+    // Parses RA,DEC in degrees, e.g. 355.1,-2.3
+    pub fn from_spec(s:&str) -> error::Result<Equatorial> {
+        let (ra, dec) = parse_pair(s, constants::status::INVALID_TARGET)?;
+        if !(0.0..360.0).contains(&ra) || dec.abs() > 90.0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_TARGET, s));
+        }
+        Ok(Equatorial{ra, dec})
+    }
+}
+
+// Wraps an angle in degrees to the range -180 to 180
+pub fn wrap_degrees(degrees:f64) -> f64 {
+    let d = degrees.rem_euclid(360.0);
+    if d > 180.0 { d - 360.0 } else { d }
+}
+
+// Greenwich mean sidereal time in degrees for a Julian date in UT (Meeus, 12.4)
+pub fn greenwich_sidereal_time(jd:f64) -> f64 {
+    let t = datetime::centuries_since_j2000(jd);
+    let theta = 280.460_618_37 + 360.985_647_366_29 * (jd - constants::J2000) + 0.000_387_933 * t * t - t * t * t / 38_710_000.0;
+    theta.rem_euclid(360.0)
+}
+
+pub fn local_sidereal_time(jd:f64, observer:&Observer) -> f64 {
+    (greenwich_sidereal_time(jd) + observer.longitude).rem_euclid(360.0)
+}
+
+// Angle in degrees between the directions to the zenith and to the celestial pole,
+// seen at the target (Meeus, 14.1). Negative before the target crosses the meridian.
+// With the zenith up in an unmirrored image, north lies this far clockwise from up.
+pub fn parallactic_angle(jd:f64, observer:&Observer, target:&Equatorial) -> f64 {
+    let h = (local_sidereal_time(jd, observer) - target.ra).to_radians();
+    let lat = observer.latitude.to_radians();
+    let dec = target.dec.to_radians();
+    h.sin().atan2(lat.tan() * dec.cos() - dec.sin() * h.cos()).to_degrees()
+}
+
+// Corrects the field rotation of an alt-az mount. The camera stays level with the
+// horizon, so the sky turns in the frame as the parallactic angle changes. Each
+// frame is rotated back by the change since the reference frame was captured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldRotation {
+    pub observer: Observer,
+    pub target: Equatorial,
+    // Hours the camera clock is ahead of UT
+    pub utc_offset: f64,
+    pub interpolation: Interpolation,
+    // Julian date, in UT, of the frame others are rotated to match
+    reference: f64,
+}
+
+impl FieldRotation {
+    pub fn new(observer:Observer, target:Equatorial, utc_offset:f64, reference:&DateTime, interpolation:Interpolation) -> FieldRotation {
+        let mut rotation = FieldRotation{observer, target, utc_offset, interpolation, reference:0.0};
+        rotation.reference = rotation.julian_date(reference);
+        rotation
+    }
+
+    // Julian date in UT of a time read from the camera clock
+    pub fn julian_date(&self, time:&DateTime) -> f64 {
+        time.julian_date() - self.utc_offset / 24.0
+    }
+
+    // Julian date, in UT, of the reference frame
+    pub fn reference_julian_date(&self) -> f64 {
+        self.reference
+    }
+
+    // Direction of celestial north in the reference frame, in degrees clockwise from up
+    pub fn reference_north(&self) -> f64 {
+        parallactic_angle(self.reference, &self.observer, &self.target)
+    }
+
+    // Rotation of the field, in degrees clockwise, since the reference frame
+    pub fn angle_at(&self, time:&DateTime) -> f64 {
+        let now = parallactic_angle(self.julian_date(time), &self.observer, &self.target);
+        let then = parallactic_angle(self.reference, &self.observer, &self.target);
+        wrap_degrees(now - then)
+    }
+
+    // Rotates a frame captured at the given time to the orientation of the reference
+    pub fn derotate(&self, image:&ImageBuffer, time:&DateTime) -> error::Result<ImageBuffer> {
+        let angle = self.angle_at(time);
+        vprintln!("    Field rotation at {}: {:.3} degrees", time, angle);
+        image.rotate(angle as f32, self.interpolation)
+    }
+}
@@ -0,0 +1,420 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::limb::Disk;
+use crate::solar::SolarEphemeris;
+use crate::metadata::RawMetadata;
+use crate::datetime::DateTime;
+use crate::path;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+use std::fs;
+
+const BLOCK_SIZE : usize = 2880;
+const CARD_SIZE : usize = 80;
+
+// Sample format of the primary data array
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitDepth {
+    // BITPIX 16, stored as unsigned via BZERO = 32768
+    Int16,
+    // BITPIX -32, IEEE single precision
+    Float32,
+}
+
+impl BitDepth {
+    pub fn bitpix(&self) -> i32 {
+        match self {
+            BitDepth::Int16 => 16,
+            BitDepth::Float32 => -32,
+        }
+    }
+}
+
+// Header values we know how to write and read back. Anything else found
+// when reading is kept in cards as raw keyword/value pairs, strings still
+// quoted, so they are written back unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct FitsHeader {
+    pub exposure: Option<f32>,
+    pub iso: Option<f32>,
+    pub date_obs: Option<String>,
+    // Camera make and model
+    pub instrument: Option<String>,
+    // Sensor temperature in degrees Celsius
+    pub temperature: Option<f32>,
+    // Mean black level of the raw data
+    pub black_level: Option<f32>,
+    pub stack_count: Option<usize>,
+    // Kish effective number of frames, when the frames were weighted
+    pub effective_count: Option<f32>,
+    // Name and weight of each frame combined, written as HISTORY cards
+    pub frame_weights: Vec<(String, f32)>,
+    pub disk: Option<Disk>,
+    // Solar orientation at DATE-OBS
+    pub solar: Option<SolarEphemeris>,
+    pub cards: Vec<(String, String)>,
+}
+
+impl FitsHeader {
+    // Records the shooting metadata of a raw frame. An existing DATE-OBS is kept, since
+    // the camera's clock may not be set to UTC.
+    pub fn record_metadata(&mut self, metadata:&RawMetadata) {
+        self.exposure = metadata.exposure;
+        self.iso = metadata.iso;
+        if self.date_obs.is_none() {
+            self.date_obs = metadata.timestamp.map(|t| t.to_iso());
+        }
+        let camera = metadata.camera();
+        if !camera.is_empty() {
+            self.instrument = Some(camera);
+        }
+        self.temperature = metadata.temperature;
+        if metadata.has_black_level() {
+            let cells:f32 = metadata.cell_black.iter().flatten().sum();
+            self.black_level = Some(metadata.black_level + cells / 4.0);
+        }
+    }
+
+    // Shooting metadata recorded in the header, if any
+    pub fn metadata(&self) -> Option<RawMetadata> {
+        if self.exposure.is_none() && self.iso.is_none() && self.instrument.is_none() && self.temperature.is_none() && self.black_level.is_none() {
+            return None;
+        }
+        Some(RawMetadata{
+            model:self.instrument.clone().unwrap_or_default(),
+            exposure:self.exposure,
+            iso:self.iso,
+            timestamp:self.date_obs.as_deref().and_then(|d| DateTime::from_exif(d).ok()),
+            temperature:self.temperature,
+            black_level:self.black_level.unwrap_or(0.0),
+            ..Default::default()
+        })
+    }
+}
+
+// Whether the path has one of the usual FITS extensions
+pub fn is_fits_path(file_path:&str) -> bool {
+    let lower = file_path.to_lowercase();
+    lower.ends_with(".fits") || lower.ends_with(".fit") || lower.ends_with(".fts")
+}
+
+// Formats a fixed-format header card. Strings start in column 11, other
+// values are right justified to column 30.
+fn card(keyword:&str, value:&str, comment:&str) -> String {
+    let value = if value.starts_with('\'') { format!("{:<20}", value) } else { format!("{:>20}", value) };
+    let mut c = if comment.is_empty() {
+        format!("{:<8}= {}", keyword, value)
+    } else {
+        format!("{:<8}= {} / {}", keyword, value, comment)
+    };
+    c.truncate(CARD_SIZE);
+    format!("{:<80}", c)
+}
+
+// Formats a HISTORY commentary card, truncated to the card width
+fn history(text:&str) -> String {
+    let mut c = format!("HISTORY {}", text);
+    c.truncate(CARD_SIZE);
+    format!("{:<80}", c)
+}
+
+fn string_value(s:&str) -> String {
+    format!("'{:<8}'", s.replace('\'', "''"))
+}
+
+fn pad_to_block(data:&mut Vec<u8>, fill:u8) {
+    let rem = data.len() % BLOCK_SIZE;
+    if rem != 0 {
+        data.resize(data.len() + BLOCK_SIZE - rem, fill);
+    }
+}
+
+fn build_header(width:usize, height:usize, bands:usize, depth:BitDepth, header:&FitsHeader) -> Vec<String> {
+    let mut cards:Vec<String> = vec![
+        card("SIMPLE", "T", "conforms to FITS standard"),
+        card("BITPIX", &depth.bitpix().to_string(), "array data type"),
+        card("NAXIS", if bands > 1 { "3" } else { "2" }, "number of array dimensions"),
+        card("NAXIS1", &width.to_string(), ""),
+        card("NAXIS2", &height.to_string(), ""),
+    ];
+    if bands > 1 {
+        cards.push(card("NAXIS3", &bands.to_string(), "number of bands"));
+    }
+
+    if depth == BitDepth::Int16 {
+        cards.push(card("BZERO", "32768", "offset for unsigned 16 bit data"));
+        cards.push(card("BSCALE", "1", ""));
+    }
+
+    if let Some(exposure) = header.exposure {
+        cards.push(card("EXPTIME", &exposure.to_string(), "[s] exposure time"));
+    }
+    if let Some(iso) = header.iso {
+        cards.push(card("ISOSPEED", &iso.to_string(), "ISO sensitivity"));
+    }
+    if let Some(date_obs) = &header.date_obs {
+        cards.push(card("DATE-OBS", &string_value(date_obs), "UTC start of observation"));
+    }
+    if let Some(instrument) = &header.instrument {
+        cards.push(card("INSTRUME", &string_value(instrument), "camera"));
+    }
+    if let Some(temperature) = header.temperature {
+        cards.push(card("CCD-TEMP", &temperature.to_string(), "[C] sensor temperature"));
+    }
+    if let Some(black_level) = header.black_level {
+        cards.push(card("BLACKLVL", &black_level.to_string(), "[ADU] black level of the raw data"));
+    }
+    if let Some(stack_count) = header.stack_count {
+        cards.push(card("NCOMBINE", &stack_count.to_string(), "number of frames combined"));
+    }
+    if let Some(effective_count) = header.effective_count {
+        cards.push(card("NEFFECT", &effective_count.to_string(), "effective number of weighted frames"));
+    }
+    for (name, weight) in header.frame_weights.iter() {
+        cards.push(history(&format!("Weight {} {}", weight, name)));
+    }
+    if let Some(disk) = header.disk {
+        cards.push(card("CENTER_X", &disk.x.to_string(), "[px] solar disk center, x"));
+        cards.push(card("CENTER_Y", &disk.y.to_string(), "[px] solar disk center, y"));
+        cards.push(card("SOLAR_R", &disk.radius.to_string(), "[px] solar disk radius"));
+    }
+    if let Some(solar) = header.solar {
+        cards.push(card("SOLAR_P", &solar.p.to_string(), "[deg] position angle of solar north"));
+        cards.push(card("SOLAR_B0", &solar.b0.to_string(), "[deg] heliographic latitude of disk center"));
+        cards.push(card("SOLAR_L0", &solar.l0.to_string(), "[deg] Carrington longitude of disk center"));
+        cards.push(card("RSUN_OBS", &solar.semi_diameter.to_string(), "[arcsec] apparent solar radius"));
+        cards.push(card("DSUN_AU", &solar.distance.to_string(), "[AU] distance to the Sun"));
+    }
+    for (keyword, value) in header.cards.iter() {
+        cards.push(card(keyword, value, ""));
+    }
+
+    cards.push(format!("{:<80}", "END"));
+    cards
+}
+
+// Writes the image as the primary HDU of a FITS file. Rows are written bottom
+// up so the image displays upright in FITS viewers.
+pub fn save(image:&ImageBuffer, to_file:&str, depth:BitDepth, header:&FitsHeader) -> error::Result<()> {
+    write_planes(&[image], to_file, depth, header)
+}
+
+// Writes a multi-band image as a cube, one plane per band along NAXIS3
+pub fn save_bands(bands:&[ImageBuffer], to_file:&str, depth:BitDepth, header:&FitsHeader) -> error::Result<()> {
+    let planes:Vec<&ImageBuffer> = bands.iter().collect();
+    write_planes(&planes, to_file, depth, header)
+}
+
+fn write_planes(planes:&[&ImageBuffer], to_file:&str, depth:BitDepth, header:&FitsHeader) -> error::Result<()> {
+    if planes.is_empty() {
+        return Err(Error::EmptyImage);
+    }
+    if !path::parent_exists_and_writable(to_file) {
+        return Err(Error::ParentNotWritable(path::get_parent(to_file)));
+    }
+
+    let width = planes[0].width;
+    let height = planes[0].height;
+    for plane in planes.iter() {
+        if plane.width != width || plane.height != height {
+            return Err(Error::DimensionMismatch{expected:(width, height), found:(plane.width, plane.height)});
+        }
+    }
+
+    vprintln!("    Writing FITS (BITPIX {}, {} band(s)) to file at {}", depth.bitpix(), planes.len(), to_file);
+
+    let mut data:Vec<u8> = Vec::new();
+    for c in build_header(width, height, planes.len(), depth, header).iter() {
+        data.extend_from_slice(c.as_bytes());
+    }
+    pad_to_block(&mut data, b' ');
+
+    for image in planes.iter() {
+        for y in (0..height).rev() {
+            for x in 0..width {
+                let value = image.get(x, y)?;
+                match depth {
+                    BitDepth::Int16 => {
+                        let v = value.round().clamp(0.0, 65535.0) as i32 - 32768;
+                        data.extend_from_slice(&(v as i16).to_be_bytes());
+                    },
+                    BitDepth::Float32 => {
+                        data.extend_from_slice(&value.to_be_bytes());
+                    }
+                }
+            }
+        }
+    }
+    pad_to_block(&mut data, 0);
+
+    fs::write(to_file, &data).map_err(|e| Error::io(to_file, e))?;
+    vprintln!("    File saved.");
+    Ok(())
+}
+
+// The text of a header value as written, dropping any trailing comment. Strings keep
+// their quotes, and a '/' inside one doesn't start a comment.
+fn value_text(raw:&str) -> String {
+    let raw = raw.trim();
+    if raw.starts_with('\'') {
+        let bytes = raw.as_bytes();
+        let mut i = 1;
+        while i < bytes.len() {
+            if bytes[i] == b'\'' {
+                if bytes.get(i + 1) == Some(&b'\'') {
+                    i += 2;
+                    continue;
+                }
+                return raw[..=i].to_string();
+            }
+            i += 1;
+        }
+        raw.to_string()
+    } else {
+        match raw.find('/') {
+            Some(idx) => raw[..idx].trim().to_string(),
+            None => raw.to_string()
+        }
+    }
+}
+
+// Parses a header value, dropping any trailing comment and string quoting
+fn parse_value(raw:&str) -> String {
+    let text = value_text(raw);
+    match text.strip_prefix('\'') {
+        Some(stripped) => {
+            let stripped = stripped.strip_suffix('\'').unwrap_or(stripped);
+            stripped.replace("''", "'").trim_end().to_string()
+        },
+        None => text
+    }
+}
+
+// Reads the primary HDU of a FITS file along with the header values we recognize.
+// For a cube, only the first plane is returned.
+pub fn load(file_path:&str) -> error::Result<(ImageBuffer, FitsHeader)> {
+    let (mut bands, header) = load_bands(file_path)?;
+    Ok((bands.swap_remove(0), header))
+}
+
+// Reads every plane of the primary HDU
+pub fn load_bands(file_path:&str) -> error::Result<(Vec<ImageBuffer>, FitsHeader)> {
+    if !path::file_exists(file_path) {
+        return Err(Error::FileNotFound(String::from(file_path)));
+    }
+
+    let bytes = fs::read(file_path).map_err(|e| Error::io(file_path, e))?;
+
+    let mut bitpix:i32 = 0;
+    let mut naxis:usize = 0;
+    let mut width:usize = 0;
+    let mut height:usize = 0;
+    let mut bands:usize = 1;
+    let mut bzero:f64 = 0.0;
+    let mut bscale:f64 = 1.0;
+    let mut header = FitsHeader::default();
+    let mut disk_x:Option<f32> = None;
+    let mut disk_y:Option<f32> = None;
+    let mut disk_r:Option<f32> = None;
+    let mut solar:[Option<f64>; 5] = [None; 5];
+
+    let mut offset = 0;
+    let mut found_end = false;
+    while offset + CARD_SIZE <= bytes.len() {
+        let c = &bytes[offset..offset + CARD_SIZE];
+        offset += CARD_SIZE;
+
+        let keyword = String::from_utf8_lossy(&c[..8]).trim().to_string();
+        if keyword == "END" {
+            found_end = true;
+            break;
+        }
+        if &c[8..10] != b"= " {
+            continue;
+        }
+        let raw = String::from_utf8_lossy(&c[10..]);
+        let value = parse_value(&raw);
+
+        let bad_value = || Error::decode(file_path, &format!("Invalid value for {}: {}", keyword, value));
+        match keyword.as_str() {
+            "SIMPLE" | "EXTEND" => {},
+            "BITPIX" => bitpix = value.parse().map_err(|_| bad_value())?,
+            "NAXIS" => naxis = value.parse().map_err(|_| bad_value())?,
+            "NAXIS1" => width = value.parse().map_err(|_| bad_value())?,
+            "NAXIS2" => height = value.parse().map_err(|_| bad_value())?,
+            "NAXIS3" => bands = value.parse().map_err(|_| bad_value())?,
+            "BZERO" => bzero = value.parse().map_err(|_| bad_value())?,
+            "BSCALE" => bscale = value.parse().map_err(|_| bad_value())?,
+            "EXPTIME" | "EXPOSURE" => header.exposure = value.parse().ok(),
+            "ISOSPEED" => header.iso = value.parse().ok(),
+            "DATE-OBS" => header.date_obs = Some(value),
+            "INSTRUME" => header.instrument = Some(value),
+            "CCD-TEMP" => header.temperature = value.parse().ok(),
+            "BLACKLVL" => header.black_level = value.parse().ok(),
+            "NCOMBINE" => header.stack_count = value.parse().ok(),
+            "NEFFECT" => header.effective_count = value.parse().ok(),
+            "CENTER_X" => disk_x = value.parse().ok(),
+            "CENTER_Y" => disk_y = value.parse().ok(),
+            "SOLAR_R" => disk_r = value.parse().ok(),
+            "SOLAR_P" => solar[0] = value.parse().ok(),
+            "SOLAR_B0" => solar[1] = value.parse().ok(),
+            "SOLAR_L0" => solar[2] = value.parse().ok(),
+            "RSUN_OBS" => solar[3] = value.parse().ok(),
+            "DSUN_AU" => solar[4] = value.parse().ok(),
+            _ => header.cards.push((keyword, value_text(&raw)))
+        }
+    }
+
+    if !found_end {
+        return Err(Error::decode(file_path, "Missing END card"));
+    }
+    if naxis < 2 {
+        return Err(Error::decode(file_path, "Primary HDU is not an image"));
+    }
+
+    if let (Some(x), Some(y), Some(radius)) = (disk_x, disk_y, disk_r) {
+        header.disk = Some(Disk{x, y, radius});
+    }
+    if let [Some(p), Some(b0), Some(l0), Some(semi_diameter), Some(distance)] = solar {
+        header.solar = Some(SolarEphemeris{p, b0, l0, semi_diameter, distance});
+    }
+
+    // Data begins on the block boundary following the header
+    let data_start = offset.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    let bytes_per_sample = (bitpix.unsigned_abs() / 8) as usize;
+    let bands = if naxis > 2 { bands.max(1) } else { 1 };
+    let need = width * height * bands * bytes_per_sample;
+    if bytes_per_sample == 0 || data_start + need > bytes.len() {
+        return Err(Error::decode(file_path, "Data array is truncated"));
+    }
+
+    vprintln!("    Input FITS dimensions: {}x{}x{}, BITPIX {}", width, height, bands, bitpix);
+
+    let mut planes:Vec<ImageBuffer> = Vec::with_capacity(bands);
+    for band in 0..bands {
+        let plane_size = width * height * bytes_per_sample;
+        let data = &bytes[data_start + band * plane_size..data_start + (band + 1) * plane_size];
+        let mut v:Vec<f32> = vec![0.0; width * height];
+
+        for row in 0..height {
+            // Rows are stored bottom up
+            let y = height - 1 - row;
+            for x in 0..width {
+                let i = (row * width + x) * bytes_per_sample;
+                let s = &data[i..i + bytes_per_sample];
+                let raw:f64 = match bitpix {
+                    8 => s[0] as f64,
+                    16 => i16::from_be_bytes([s[0], s[1]]) as f64,
+                    32 => i32::from_be_bytes([s[0], s[1], s[2], s[3]]) as f64,
+                    -32 => f32::from_be_bytes([s[0], s[1], s[2], s[3]]) as f64,
+                    -64 => f64::from_be_bytes([s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]]),
+                    _ => return Err(Error::decode(file_path, &format!("Unsupported BITPIX {}", bitpix)))
+                };
+                v[y * width + x] = (bzero + bscale * raw) as f32;
+            }
+        }
+        planes.push(ImageBuffer::from_vec(v, width, height)?);
+    }
+
+    Ok((planes, header))
+}
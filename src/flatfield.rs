@@ -0,0 +1,60 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+// The level of a master flat that is scaled to a response of one
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlatNormalization {
+    // Mean of every pixel
+    #[default]
+    Mean,
+    // Median of every pixel, unaffected by dust shadows and hot pixels
+    Median,
+    // Mean of the central region, where the optics are least vignetted
+    Center,
+}
+
+impl FlatNormalization {
+    pub fn from_name(name:&str) -> error::Result<FlatNormalization> {
+        match name.to_lowercase().as_str() {
+            constants::flatnorm::MEAN => Ok(FlatNormalization::Mean),
+            constants::flatnorm::MEDIAN => Ok(FlatNormalization::Median),
+            constants::flatnorm::CENTER => Ok(FlatNormalization::Center),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_FLAT_NORMALIZATION, name))
+        }
+    }
+}
+
+// The level of an offset-corrected flat that normalization divides by. Zeros count
+// towards it, unlike ImageBuffer::mean, so a flat's dark corners aren't skipped.
+pub fn reference_level(flat:&ImageBuffer, method:FlatNormalization) -> error::Result<f32> {
+    if flat.is_empty() {
+        return Err(Error::EmptyImage);
+    }
+    let level = match method {
+        FlatNormalization::Mean => flat.mean_all(),
+        FlatNormalization::Median => flat.median(),
+        FlatNormalization::Center => {
+            let width = ((flat.width as f32 * constants::FLAT_CENTER_FRACTION) as usize).max(1);
+            let height = ((flat.height as f32 * constants::FLAT_CENTER_FRACTION) as usize).max(1);
+            flat.crop_region(((flat.width - width) / 2) as i64, ((flat.height - height) / 2) as i64, width, height)?.mean_all()
+        }
+    };
+    if level.is_finite() && level > 0.0 { Ok(level) } else { Err(Error::EmptyImage) }
+}
+
+// Scales an offset-corrected flat to a response of one at its reference level. Responses
+// below the floor, such as deeply vignetted corners or dead pixels, are raised to it so
+// dividing a light by the flat can't blow up.
+pub fn normalize(flat:&ImageBuffer, method:FlatNormalization) -> error::Result<ImageBuffer> {
+    let level = reference_level(flat, method)?;
+    vprintln!("    Flat {:?} level: {}", method, level);
+
+    let normalized = flat.scale(1.0 / level)?;
+    let clamped = normalized.count_below(constants::MIN_FLAT_RESPONSE);
+    if clamped > 0 {
+        vprintln!("    Raised {} flat pixels to the minimum response of {}", clamped, constants::MIN_FLAT_RESPONSE);
+    }
+    normalized.raise_to(constants::MIN_FLAT_RESPONSE)
+}
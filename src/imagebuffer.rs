@@ -1,11 +1,24 @@
 
 use crate::path;
 use crate::constants;
+use crate::error::{self, Error};
+use crate::interpolation;
+use crate::fits;
+use crate::parallel;
+use crate::cfa::{self, CfaChannel, CfaPattern};
+use crate::metadata::{self, RawMetadata};
+use crate::demosaic::{self, DemosaicMethod};
+use crate::multiband::MultiBandImage;
 use crate::vprintln;
 
 extern crate image;
-use image::{open, DynamicImage, Rgb};
+use image::{open, DynamicImage, Rgb, Luma};
 use std::fs;
+use std::io::BufWriter;
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::ColorType;
 
 // A simple image raster buffer.
 #[derive(Debug, Clone)]
@@ -14,6 +27,8 @@ pub struct ImageBuffer {
     pub width: usize,
     pub height: usize,
     empty: bool,
+    // Shooting metadata of the raw the image came from, kept through pixel operations
+    metadata: Option<RawMetadata>,
 }
 
 pub struct Offset {
@@ -21,16 +36,60 @@ pub struct Offset {
     pub v: i32,
 }
 
+// Fractional offset, as determined by registration, along with
+// the strength of the correlation peak it came from.
+#[derive(Debug, Clone, Copy)]
+pub struct SubpixelOffset {
+    pub h: f32,
+    pub v: f32,
+    pub peak: f32,
+}
+
 pub struct MinMax {
     pub min: f32,
     pub max: f32,
 }
 
+// Sample layout used when saving to a non-FITS file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    // 16 bit RGB with the value repeated in each channel
+    Rgb16,
+    // Single channel 16 bit
+    Gray16,
+    // Single channel 32 bit IEEE float, unquantized. TIFF and FITS only.
+    Float32,
+}
+
+impl OutputFormat {
+    pub fn from_name(name:&str) -> error::Result<OutputFormat> {
+        match name.to_lowercase().as_str() {
+            constants::format::RGB16 => Ok(OutputFormat::Rgb16),
+            constants::format::GRAY16 => Ok(OutputFormat::Gray16),
+            constants::format::FLOAT32 => Ok(OutputFormat::Float32),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_OUTPUT_FORMAT, name))
+        }
+    }
+
+    // Equivalent FITS sample format. RGB output has no FITS equivalent so is kept as float.
+    pub fn fits_depth(&self) -> fits::BitDepth {
+        match self {
+            OutputFormat::Gray16 => fits::BitDepth::Int16,
+            OutputFormat::Rgb16 | OutputFormat::Float32 => fits::BitDepth::Float32,
+        }
+    }
+}
+
+pub fn is_tiff_path(file_path:&str) -> bool {
+    let lower = file_path.to_lowercase();
+    lower.ends_with(".tif") || lower.ends_with(".tiff")
+}
+
 #[allow(dead_code)]
 impl ImageBuffer {
 
     // Creates a new image buffer of the requested width and height
-    pub fn new(width:usize, height:usize) -> Result<ImageBuffer, &'static str> {
+    pub fn new(width:usize, height:usize) -> error::Result<ImageBuffer> {
 
         let mut v:Vec<f32> = Vec::with_capacity(width * height);
         v.resize(width * height, 0.0);
@@ -38,39 +97,52 @@ impl ImageBuffer {
         Ok(ImageBuffer{buffer:v,
             width:width,
             height:height,
-            empty:false
+            empty:false,
+            metadata:None
         })
     }
 
-    pub fn new_empty() -> Result<ImageBuffer, &'static str> {
+    pub fn new_empty() -> error::Result<ImageBuffer> {
         Ok(ImageBuffer{buffer:Vec::new(),
             width:0,
             height:0,
-            empty:true
+            empty:true,
+            metadata:None
         })
     }
 
     // Creates a new image buffer at the requested width, height and data
-    pub fn from_vec(v:Vec<f32>, width:usize, height:usize) -> Result<ImageBuffer, &'static str> {
+    pub fn from_vec(v:Vec<f32>, width:usize, height:usize) -> error::Result<ImageBuffer> {
 
         if v.len() != (width * height) {
-            return Err(constants::status::DIMENSIONS_DO_NOT_MATCH_VECTOR_LENGTH);
+            return Err(Error::VectorLengthMismatch{width, height, length:v.len()});
         }
 
         Ok(ImageBuffer{buffer:v,
                     width:width,
                     height:height,
-                    empty:false
+                    empty:false,
+                    metadata:None
         })
     }
 
-    pub fn from_file(file_path:&str) -> Result<ImageBuffer, &'static str> {
+    pub fn from_file(file_path:&str) -> error::Result<ImageBuffer> {
 
         if !path::file_exists(file_path) {
-            return Err(constants::status::FILE_NOT_FOUND);
+            return Err(Error::FileNotFound(String::from(file_path)));
+        }
+
+        if fits::is_fits_path(file_path) {
+            return ImageBuffer::from_fits(file_path);
         }
 
-        let image_data = open(file_path).unwrap().into_luma16();
+        if is_tiff_path(file_path) {
+            if let Some(image) = ImageBuffer::from_gray_tiff(file_path)? {
+                return Ok(image);
+            }
+        }
+
+        let image_data = open(file_path).map_err(|e| Error::decode(file_path, &e.to_string()))?.into_luma16();
         let dims = image_data.dimensions();
 
         let width = dims.0 as usize;
@@ -93,7 +165,40 @@ impl ImageBuffer {
         ImageBuffer::from_vec(v, width, height)
     }
 
-    pub fn from_libraw(raw_image:&libraw::RawImage) -> Result<ImageBuffer, &str> {
+    // Reads single channel 8, 16 and 32 bit float TIFFs directly so float data
+    // isn't quantized. Returns None for layouts better left to the image crate.
+    fn from_gray_tiff(file_path:&str) -> error::Result<Option<ImageBuffer>> {
+        let file = fs::File::open(file_path).map_err(|e| Error::io(file_path, e))?;
+        let mut decoder = match Decoder::new(file) {
+            Ok(decoder) => decoder,
+            Err(_) => return Ok(None)
+        };
+
+        match decoder.colortype() {
+            Ok(ColorType::Gray(_)) => {},
+            _ => return Ok(None)
+        }
+
+        let (width, height) = decoder.dimensions().map_err(|e| Error::decode(file_path, &e.to_string()))?;
+        let v:Vec<f32> = match decoder.read_image().map_err(|e| Error::decode(file_path, &e.to_string()))? {
+            DecodingResult::U8(data) => data.iter().map(|p| *p as f32).collect(),
+            DecodingResult::U16(data) => data.iter().map(|p| *p as f32).collect(),
+            DecodingResult::F32(data) => data,
+            _ => return Ok(None)
+        };
+
+        vprintln!("    Input TIFF dimensions: {}x{}", width, height);
+        Ok(Some(ImageBuffer::from_vec(v, width as usize, height as usize)?))
+    }
+
+    // Loads the primary image of a FITS file at full precision
+    pub fn from_fits(file_path:&str) -> error::Result<ImageBuffer> {
+        let (mut image, header) = fits::load(file_path)?;
+        image.metadata = header.metadata();
+        Ok(image)
+    }
+
+    pub fn from_libraw(raw_image:&libraw::RawImage) -> error::Result<ImageBuffer> {
         let top_margin:u32  = raw_image.sizes().top_margin as u32;
         let left_margin:u32 = raw_image.sizes().left_margin as u32;
         let h:u32 = raw_image.sizes().raw_height as u32;
@@ -125,282 +230,356 @@ impl ImageBuffer {
         ImageBuffer::from_vec(v, (w-left_margin) as usize, (h-top_margin) as usize)
     }  
 
-    pub fn from_cr2(raw_file:&str) -> Result<ImageBuffer, &str> {
+    pub fn from_cr2(raw_file:&str) -> error::Result<ImageBuffer> {
         vprintln!("    Reading raw image file {}", raw_file);
 
         if !path::file_exists(raw_file) {
-            return Err("File does not exist");
+            return Err(Error::FileNotFound(String::from(raw_file)));
         }
-        let buf = fs::read(raw_file).expect("read in");
+        let buf = fs::read(raw_file).map_err(|e| Error::io(raw_file, e))?;
     
         vprintln!("    Decoding for raw pixel values");
         let processor = libraw::Processor::new();
-        let raw_image = processor.decode(&buf).unwrap();
+        let raw_image = processor.decode(&buf).map_err(|e| Error::decode(raw_file, &e.to_string()))?;
     
-        Ok(ImageBuffer::from_libraw(&raw_image).unwrap())
+        let mut image = ImageBuffer::from_libraw(&raw_image)?;
+        image.metadata = metadata::read_metadata(&buf);
+        if let Some(m) = image.metadata.as_mut().filter(|m| !m.has_black_level()) {
+            let sizes = raw_image.sizes();
+            if let Some(black) = metadata::measure_masked_black(&raw_image, sizes.raw_width as usize, sizes.raw_height as usize,
+                                                                 sizes.left_margin as usize, sizes.top_margin as usize) {
+                vprintln!("    No black level in metadata, measured {:?} from the masked border", black);
+                m.cell_black = black;
+            }
+        }
+        if let Some(m) = &image.metadata {
+            vprintln!("    Camera: {}, exposure {:?}s, ISO {:?}, taken {:?}", m.camera(), m.exposure, m.iso, m.timestamp.map(|t| t.to_string()));
+            vprintln!("    Black level: {} {:?}, white level: {}", m.black_level, m.cell_black, m.white_level);
+        }
+        match image.cfa_pattern() {
+            Some(pattern) => vprintln!("    CFA pattern: {}", pattern.name()),
+            None => vprintln!("    No Bayer pattern found, assuming {}", CfaPattern::rggb().name())
+        }
+        Ok(image)
+    }
+
+    // Subtracts the black level of each photosite of a raw mosaic, clamping at zero.
+    // Images without metadata are returned unchanged.
+    pub fn subtract_black_level(&self) -> error::Result<ImageBuffer> {
+        match &self.metadata {
+            Some(m) => self.subtract_black_level_of(m),
+            None => Ok(self.clone())
+        }
+    }
+
+    // Subtracts the black level of each photosite recorded in other metadata, such as
+    // that of a frame shot alongside this one, clamping at zero
+    pub fn subtract_black_level_of(&self, metadata:&RawMetadata) -> error::Result<ImageBuffer> {
+        let v = parallel::generate(self.buffer.len(), |i| {
+            (self.buffer[i] - metadata.black_at(i % self.width, i / self.width)).max(0.0)
+        });
+        self.with_buffer(v)
+    }
+
+    // Metadata of the raw the image came from, when known
+    pub fn metadata(&self) -> Option<&RawMetadata> {
+        self.metadata.as_ref()
+    }
+
+    pub fn set_metadata(&mut self, metadata:Option<RawMetadata>) {
+        self.metadata = metadata;
     }
 
-    pub fn get(&self, x:usize, y:usize) -> Result<f32, &str> {
+    // Bayer layout of a raw mosaic, if it was read from a raw file
+    pub fn cfa_pattern(&self) -> Option<CfaPattern> {
+        self.metadata.as_ref().and_then(|m| m.cfa)
+    }
+
+    pub fn set_cfa_pattern(&mut self, pattern:Option<CfaPattern>) {
+        match self.metadata.as_mut() {
+            Some(m) => m.cfa = pattern,
+            None if pattern.is_some() => self.metadata = Some(RawMetadata{cfa:pattern, ..Default::default()}),
+            None => {}
+        }
+    }
+
+    pub fn get(&self, x:usize, y:usize) -> error::Result<f32> {
         if x < self.width && y < self.height {
             let index = y * self.width + x;
-            return Ok(self.buffer[index]);
+            Ok(self.buffer[index])
         } else {
-            return Err(constants::status::INVALID_PIXEL_COORDINATES); // TODO: learn to throw exceptions
+            Err(Error::InvalidCoordinates{x, y, width:self.width, height:self.height})
         }
     }
 
+    fn check_same_size(&self, other:&ImageBuffer) -> error::Result<()> {
+        if self.width != other.width || self.height != other.height {
+            return Err(Error::DimensionMismatch{expected:(self.width, self.height), found:(other.width, other.height)});
+        }
+        Ok(())
+    }
+
     pub fn is_empty(&self) -> bool {
         self.empty
     }
 
-    pub fn put_u16(&mut self, x:usize, y:usize, val:u16) -> Result<&str, &str> {
+    pub fn put_u16(&mut self, x:usize, y:usize, val:u16) -> error::Result<()> {
         self.put(x, y, val as f32)
     }
 
-    pub fn put(&mut self, x:usize, y:usize, val:f32) -> Result<&str, &str>{
+    pub fn put(&mut self, x:usize, y:usize, val:f32) -> error::Result<()> {
         if x < self.width && y < self.height {
             let index = y * self.width + x;
             self.buffer[index] = val;
-            return Ok(constants::status::OK);
+            Ok(())
         } else {
-            return Err(constants::status::INVALID_PIXEL_COORDINATES);
+            Err(Error::InvalidCoordinates{x, y, width:self.width, height:self.height})
         }
     }
 
-    fn put_to_index_u16(&mut self, index:usize, val:u16) -> Result<&str, &str> {
+    fn put_to_index_u16(&mut self, index:usize, val:u16) -> error::Result<()> {
         self.put_to_index(index, val as f32)
     }
 
-    fn put_to_index(&mut self, index:usize, val:f32) -> Result<&str, &str> {
+    fn put_to_index(&mut self, index:usize, val:f32) -> error::Result<()> {
         if index >= (self.width * self.height) {
-            return Err(constants::status::INVALID_PIXEL_COORDINATES);
+            let w = self.width.max(1);
+            return Err(Error::InvalidCoordinates{x:index % w, y:index / w, width:self.width, height:self.height});
         }
 
         self.buffer[index] = val;
 
-        Ok(constants::status::OK)
+        Ok(())
     }
 
-    // Computes the mean of all pixel values
-    pub fn mean(&self) -> f32 {
-
-        let mut total:f32 = 0.0;
-        let mut count:f32 = 0.0;
-
-        // It is *soooo* inefficient to keep doing this...
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let pixel_value = self.get(x, y).unwrap();
-                if pixel_value > 0.0 {
-                    total = total + pixel_value;
-                    count = count + 1.0;
-                }
-            }
-        }
-
-        return total / count;
+    // Wraps a buffer computed from this one in an image of the same size
+    fn with_buffer(&self, v:Vec<f32>) -> error::Result<ImageBuffer> {
+        let mut image = ImageBuffer::from_vec(v, self.width, self.height)?;
+        image.metadata = self.metadata.clone();
+        Ok(image)
     }
 
-    pub fn divide(&self, other:&ImageBuffer) -> Result<ImageBuffer, &str> {
-
-        if self.width != other.width || self.height != other.height {
-            return Err(constants::status::ARRAY_SIZE_MISMATCH);
-        }
-
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
-
-        let need_len = self.width * self.height;
-
-        for i in 0..need_len {
-            let quotient = if other.buffer[i] != 0.0 { self.buffer[i] / other.buffer[i] } else { 0.0 };
-            dest.put_to_index(i, quotient).unwrap();
-        }
-
-        Ok(dest)
+    // Pixel values in row-major order
+    pub fn pixels(&self) -> &[f32] {
+        &self.buffer
     }
 
-    pub fn divide_into(&self, divisor:f32) -> Result<ImageBuffer, &str> {
-        let need_len = self.width * self.height;
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
-
-        for i in 0..need_len {
-            let quotient = if self.buffer[i] != 0.0 { divisor / self.buffer[i] } else { 0.0 };
-            dest.put_to_index(i, quotient).unwrap();
-        }
-
-        Ok(dest)
+    // Computes the mean of the nonzero pixel values
+    pub fn mean(&self) -> f32 {
+        let (total, count) = parallel::sum_where(&self.buffer, |v| v > 0.0);
+        (total / count as f64) as f32
     }
 
-    pub fn scale(&self, scalar:f32) -> Result<ImageBuffer, &str> {
-        let need_len = self.width * self.height;
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
-
-        for i in 0..need_len {
-            let product = self.buffer[i] * scalar;
-            dest.put_to_index(i, product).unwrap();
-            //v[i] = product;
-        }
-
-        Ok(dest)
+    // Computes the mean of all finite pixel values, zeros included
+    pub fn mean_all(&self) -> f32 {
+        let (total, count) = parallel::sum_where(&self.buffer, |v| v.is_finite());
+        if count == 0 { 0.0 } else { (total / count as f64) as f32 }
     }
 
-    pub fn multiply(&self, other:&ImageBuffer) -> Result<ImageBuffer, &str> {
-
-        if self.width != other.width || self.height != other.height {
-            return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    // Computes the median of all finite pixel values
+    pub fn median(&self) -> f32 {
+        let mut values:Vec<f32> = self.buffer.iter().copied().filter(|v| v.is_finite()).collect();
+        if values.is_empty() {
+            return 0.0;
         }
-
-        let need_len = self.width * self.height;
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
-
-        for i in 0..need_len {
-            let product = self.buffer[i] * other.buffer[i];
-            dest.put_to_index(i, product).unwrap();
-            //v[i] = product;
-        }
-
-        Ok(dest)
+        let mid = values.len() / 2;
+        *values.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap()).1
     }
 
-    pub fn add(&self, other:&ImageBuffer) -> Result<ImageBuffer, &str> {
-
-        if self.width != other.width || self.height != other.height {
-            return Err(constants::status::ARRAY_SIZE_MISMATCH);
-        }
-
-        let need_len = self.width * self.height;
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
-
-        for i in 0..need_len {
-            let result = self.buffer[i] + other.buffer[i];
-            dest.put_to_index(i, result).unwrap();
-            //v[i] = result;
-        }
+    // Number of pixels below the threshold, or not finite
+    pub fn count_below(&self, threshold:f32) -> usize {
+        let (_, above) = parallel::sum_where(&self.buffer, |v| v >= threshold);
+        self.buffer.len() - above
+    }
 
-        Ok(dest)
+    // Raises every pixel below the floor, including any that aren't finite, to it
+    pub fn raise_to(&self, floor:f32) -> error::Result<ImageBuffer> {
+        self.with_buffer(parallel::map(&self.buffer, |v| if v >= floor { v } else { floor }))
     }
 
-    pub fn subtract(&self, other:&ImageBuffer) -> Result<ImageBuffer, &str> {
+    pub fn divide(&self, other:&ImageBuffer) -> error::Result<ImageBuffer> {
+        self.check_same_size(other)?;
+        self.with_buffer(parallel::zip_map(&self.buffer, &other.buffer, |a, b| if b != 0.0 { a / b } else { 0.0 }))
+    }
 
-        if self.width != other.width || self.height != other.height {
-            return Err(constants::status::ARRAY_SIZE_MISMATCH);
-        }
+    pub fn divide_into(&self, divisor:f32) -> error::Result<ImageBuffer> {
+        self.with_buffer(parallel::map(&self.buffer, |v| if v != 0.0 { divisor / v } else { 0.0 }))
+    }
 
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
-        let need_len = self.width * self.height;
+    pub fn scale(&self, scalar:f32) -> error::Result<ImageBuffer> {
+        self.with_buffer(parallel::map(&self.buffer, |v| v * scalar))
+    }
 
-        for i in 0..need_len {
-            let mut difference = self.buffer[i] - other.buffer[i];
-            if difference < 0.0 {
-                difference = 0.0;
-            }
-            dest.put_to_index(i, difference).unwrap();
-        }
+    pub fn multiply(&self, other:&ImageBuffer) -> error::Result<ImageBuffer> {
+        self.check_same_size(other)?;
+        self.with_buffer(parallel::zip_map(&self.buffer, &other.buffer, |a, b| a * b))
+    }
 
-        Ok(dest)
+    pub fn add(&self, other:&ImageBuffer) -> error::Result<ImageBuffer> {
+        self.check_same_size(other)?;
+        self.with_buffer(parallel::zip_map(&self.buffer, &other.buffer, |a, b| a + b))
     }
 
+    // Subtracts other, clamping the result at zero
+    pub fn subtract(&self, other:&ImageBuffer) -> error::Result<ImageBuffer> {
+        self.check_same_size(other)?;
+        self.with_buffer(parallel::zip_map(&self.buffer, &other.buffer, |a, b| (a - b).max(0.0)))
+    }
 
-    pub fn shift_to_min_zero(&self) -> Result<ImageBuffer, &str> {
+    // Subtracts a constant, clamping at zero
+    pub fn subtract_scalar(&self, value:f32) -> error::Result<ImageBuffer> {
+        self.with_buffer(parallel::map(&self.buffer, |v| (v - value).max(0.0)))
+    }
 
-        let minmax = self.get_min_max(-1.0).unwrap();
+    pub fn shift_to_min_zero(&self) -> error::Result<ImageBuffer> {
+        let minmax = self.get_min_max(-1.0)?;
+        let offset = if minmax.min < 0.0 { minmax.min } else { -minmax.min };
+        self.with_buffer(parallel::map(&self.buffer, |v| v + offset))
+    }
 
-        let need_len = self.width * self.height;
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
+    pub fn normalize(&self, min:f32, max:f32) -> error::Result<ImageBuffer> {
+        let shifted = self.shift_to_min_zero()?;
+        let minmax = shifted.get_min_max(-1.0)?;
+        self.with_buffer(parallel::map(&shifted.buffer, |v| ((v - minmax.min) / (minmax.max - minmax.min)) * (max - min) + min))
+    }
 
-        for i in 0..need_len {
-            let value = self.buffer[i];
-            if minmax.min < 0.0 {
-                dest.put_to_index(i, value + minmax.min).unwrap();
-            } else {
-                dest.put_to_index(i, value - minmax.min).unwrap();
-            }
-        }
+    // Maps the range from_min..from_max linearly onto min..max, e.g. to rescale several
+    // images by the same factor
+    pub fn normalize_from(&self, from_min:f32, from_max:f32, min:f32, max:f32) -> error::Result<ImageBuffer> {
+        let scale = (max - min) / (from_max - from_min);
+        self.with_buffer(parallel::map(&self.buffer, |v| (v - from_min) * scale + min))
+    }
 
-        Ok(dest)
+    // Extracts a single plane from a raw mosaic using its Bayer pattern
+    pub fn channel(&self, channel:CfaChannel) -> error::Result<ImageBuffer> {
+        let pattern = self.cfa_pattern().unwrap_or_else(CfaPattern::rggb);
+        let mut plane = cfa::extract_channel(self, &pattern, channel)?;
+        // A single plane is no longer a mosaic, and has the black level of its photosites throughout
+        plane.metadata = self.metadata.clone().map(|m| {
+            let cells:Vec<(usize, usize)> = match channel {
+                CfaChannel::Green => vec![pattern.offset_of(CfaChannel::Green1), pattern.offset_of(CfaChannel::Green2)],
+                _ => vec![pattern.offset_of(channel)]
+            };
+            let black = cells.iter().map(|(x, y)| m.cell_black[*y][*x]).sum::<f32>() / cells.len() as f32;
+            RawMetadata{cfa:None, cell_black:[[black; 2]; 2], ..m}
+        });
+        Ok(plane)
     }
 
-    pub fn normalize(&self, min:f32, max:f32) -> Result<ImageBuffer, &str> {
+    pub fn red(&self) -> error::Result<ImageBuffer> {
+        self.channel(CfaChannel::Red)
+    }
 
-        let shifted = self.shift_to_min_zero().unwrap();
+    pub fn green1(&self) -> error::Result<ImageBuffer> {
+        self.channel(CfaChannel::Green1)
+    }
 
-        let need_len = self.width * self.height;
-        let mut dest = ImageBuffer::new(self.width, self.height).unwrap();
+    pub fn green2(&self) -> error::Result<ImageBuffer> {
+        self.channel(CfaChannel::Green2)
+    }
 
-        let minmax = shifted.get_min_max(-1.0).unwrap();
-        
-        for i in 0..need_len {
-            let value = ((shifted.buffer[i] - minmax.min) / (minmax.max - minmax.min)) * (max - min) + min;
-            dest.put_to_index(i, value).unwrap();
-        }
-        Ok(dest)
+    pub fn blue(&self) -> error::Result<ImageBuffer> {
+        self.channel(CfaChannel::Blue)
     }
 
-    pub fn red(&self) -> Result<ImageBuffer, &str> {
-        let dest_height = self.height / 2;
-        let dest_width = self.width / 2;
+    // Mean of the two green planes
+    pub fn green(&self) -> error::Result<ImageBuffer> {
+        self.channel(CfaChannel::Green)
+    }
 
-        let mut dest = ImageBuffer::new(dest_width, dest_height).unwrap();
+    // Reconstructs a full resolution color image from a raw mosaic
+    pub fn demosaic(&self, method:DemosaicMethod) -> error::Result<MultiBandImage> {
+        let pattern = self.cfa_pattern().unwrap_or_else(CfaPattern::rggb);
+        demosaic::demosaic(self, &pattern, method)
+    }
 
-        for y in (0..self.height).step_by(2) {
-            for x in (0..self.width).step_by(2) {
-                let put_x = x / 2;
-                let put_y = y / 2;
-                let put_idx = (put_y * dest_width) + put_x;
 
-                let val_f32 :f32 = self.get(x, y).expect(constants::status::OK) as f32;
-                dest.put_to_index(put_idx, val_f32).unwrap();
-            }
-        }
-        Ok(dest)
+    // Crops a centered region of the requested size. If the image is smaller
+    // than the requested size, the outside area is padded with zeros.
+    pub fn crop(&self, height:usize, width:usize) -> error::Result<ImageBuffer> {
+        let left = (self.width as i64 - width as i64) / 2;
+        let top = (self.height as i64 - height as i64) / 2;
+        self.crop_region(left, top, width, height)
     }
 
+    // Crops an arbitrary rectangle with its top left corner at (left, top). Any part
+    // of the rectangle falling outside of the image is padded with zeros.
+    pub fn crop_region(&self, left:i64, top:i64, width:usize, height:usize) -> error::Result<ImageBuffer> {
 
-    pub fn crop(&self, height:usize, width:usize) -> Result<ImageBuffer, &str> {
-
-        let mut cropped_buffer = ImageBuffer::new(width, height).unwrap();
+        let mut cropped_buffer = ImageBuffer::new(width, height)?;
 
         for y in 0..height {
             for x in 0..width {
+                let src_x = left + x as i64;
+                let src_y = top + y as i64;
 
-                let src_x = ((self.width - width) / 2) + x;
-                let src_y = ((self.height - height) / 2) + y;
-
-                cropped_buffer.put(x, y, self.get(src_x, src_y).unwrap()).unwrap();
+                if src_x >= 0 && src_y >= 0 && (src_x as usize) < self.width && (src_y as usize) < self.height {
+                    cropped_buffer.put(x, y, self.get(src_x as usize, src_y as usize)?)?;
+                }
             }
         }
 
-        return Ok(cropped_buffer)
+        cropped_buffer.metadata = self.metadata.clone();
+        Ok(cropped_buffer)
     }
 
-    pub fn shift(&self, horiz:i32, vert:i32) -> Result<ImageBuffer, &str> {
+    pub fn shift(&self, horiz:i32, vert:i32) -> error::Result<ImageBuffer> {
+        let w = self.width as i64;
+        let h = self.height as i64;
+        let v = parallel::generate(self.buffer.len(), |i| {
+            let src_x = (i % self.width) as i64 - horiz as i64;
+            let src_y = (i / self.width) as i64 - vert as i64;
+            if src_x >= 0 && src_y >= 0 && src_x < w && src_y < h {
+                self.buffer[(src_y * w + src_x) as usize]
+            } else {
+                0.0
+            }
+        });
+        self.with_buffer(v)
+    }
 
-        let mut shifted_buffer = ImageBuffer::new(self.width, self.height).unwrap();
+    // Reads the value at fractional pixel coordinates using the requested interpolation
+    pub fn get_interpolated(&self, x:f32, y:f32, method:interpolation::Interpolation) -> f32 {
+        interpolation::sample(&self.buffer, self.width, self.height, x, y, method)
+    }
 
-        let h = self.height as i32;
-        let w = self.width as i32;
+    // Shifts the image by a fractional number of pixels, resampling with the 
+    // requested interpolation. Areas shifted in from outside the image are zero.
+    pub fn shift_subpixel(&self, horiz:f32, vert:f32, method:interpolation::Interpolation) -> error::Result<ImageBuffer> {
+        let v = parallel::generate(self.buffer.len(), |i| {
+            let src_x = (i % self.width) as f32 - horiz;
+            let src_y = (i / self.width) as f32 - vert;
+            self.get_interpolated(src_x, src_y, method)
+        });
+        self.with_buffer(v)
+    }
 
-        for y in 0..h {
-            for x in 0..w {
-                let shift_x = x as i32 + horiz;
-                let shift_y = y as i32 + vert;
-            
-                if shift_x >= 0 && shift_y >= 0 && shift_x < w  && shift_y < h {
-                    shifted_buffer.put(shift_x as usize, shift_y as usize, self.get(x as usize, y as usize).unwrap()).unwrap();
-                }
-            }
-        }
-        return Ok(shifted_buffer)
+    // Rotates the image about its center by an angle in degrees, counterclockwise as
+    // displayed. Areas rotated in from outside the image are zero.
+    pub fn rotate(&self, degrees:f32, method:interpolation::Interpolation) -> error::Result<ImageBuffer> {
+        self.rotate_about((self.width as f32 - 1.0) / 2.0, (self.height as f32 - 1.0) / 2.0, degrees, method)
+    }
+
+    // Rotates the image about a point by an angle in degrees, counterclockwise as displayed
+    pub fn rotate_about(&self, cx:f32, cy:f32, degrees:f32, method:interpolation::Interpolation) -> error::Result<ImageBuffer> {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let v = parallel::generate(self.buffer.len(), |i| {
+            let dx = (i % self.width) as f32 - cx;
+            let dy = (i / self.width) as f32 - cy;
+            // Samples the source where the pixel came from, rotating back clockwise
+            self.get_interpolated(cx + dx * cos - dy * sin, cy + dx * sin + dy * cos, method)
+        });
+        self.with_buffer(v)
     }
 
-    pub fn calc_center_of_mass_offset(&self, threshold:f32) -> Result<Offset, &str> {
+    pub fn calc_center_of_mass_offset(&self, threshold:f32) -> error::Result<Offset> {
         let mut ox: f32 = 0.0;
         let mut oy: f32 = 0.0;
         let mut count: u32 = 0;
     
         for y in 0..self.height {
             for x in 0..self.width {
-                let val = self.get(x, y).unwrap();
+                let val = self.get(x, y)?;
                 if val >= threshold {
                     ox = ox + (x as f32);
                     oy = oy + (y as f32);
@@ -419,18 +598,9 @@ impl ImageBuffer {
 
     // Determined the minimum and maximum values within the 
     // red pixel channel.
-    pub fn get_min_max(&self, override_dark:f32) -> Result<MinMax, &str> {
+    pub fn get_min_max(&self, override_dark:f32) -> error::Result<MinMax> {
         
-        let mut mx:f32 = std::f32::MIN;
-        let mut mn:f32 = std::f32::MAX;
-
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let val = self.get(x, y).unwrap() as f32;
-                mx = if val > mx { val } else { mx };
-                mn = if val < mn { val } else { mn };
-            }
-        }
+        let (mut mn, mx) = parallel::min_max(&self.buffer);
         if override_dark >= 0.0 {
             mn = override_dark;
         }
@@ -438,25 +608,67 @@ impl ImageBuffer {
         Ok(MinMax{min:mn, max:mx})
     }
 
-    pub fn save(&self, to_file:&str) -> Result<&str, &str> {
+    // Saves the buffer as 16 bit RGB, or as 32 bit float FITS for files with a FITS extension.
+    pub fn save(&self, to_file:&str) -> error::Result<()> {
+        self.save_as(to_file, OutputFormat::Rgb16)
+    }
+
+    // Saves the buffer in the requested sample format. Files with a FITS extension
+    // are written as FITS, anything else in the format implied by the extension.
+    pub fn save_as(&self, to_file:&str, format:OutputFormat) -> error::Result<()> {
+        if fits::is_fits_path(to_file) {
+            return fits::save(self, to_file, format.fits_depth(), &fits::FitsHeader::default());
+        }
+
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(Error::ParentNotWritable(path::get_parent(to_file)));
+        }
+
+        vprintln!("    Writing image buffer to file at {}", to_file);
+        match format {
+            OutputFormat::Rgb16 => self.save_rgb16(to_file)?,
+            OutputFormat::Gray16 => self.save_gray16(to_file)?,
+            OutputFormat::Float32 => self.save_float_tiff(to_file)?,
+        }
+        vprintln!("    File saved.");
+        Ok(())
+    }
+
+    fn save_rgb16(&self, to_file:&str) -> error::Result<()> {
         let mut out_img = DynamicImage::new_rgb16(self.width as u32, self.height as u32).into_rgb16();
-        
+
         for y in 0..self.height {
             for x in 0..self.width {
-                let val = self.get(x, y).unwrap().round() as u16;
+                let val = self.get(x, y)?.round() as u16;
                 out_img.put_pixel(x as u32, y as u32, Rgb([val, val, val]));
             }
         }
 
-        vprintln!("    Writing image buffer to file at {}", to_file);
-        if path::parent_exists_and_writable(&to_file) {
-            out_img.save(to_file).unwrap();
-            vprintln!("    File saved.");
-            return Ok(constants::status::OK);
-        } else {
-            eprintln!("Parent does not exist or cannot be written: {}", path::get_parent(to_file));
-            return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+        out_img.save(to_file).map_err(|e| Error::encode(to_file, &e.to_string()))
+    }
+
+    fn save_gray16(&self, to_file:&str) -> error::Result<()> {
+        let mut out_img = DynamicImage::new_luma16(self.width as u32, self.height as u32).into_luma16();
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let val = self.get(x, y)?.round() as u16;
+                out_img.put_pixel(x as u32, y as u32, Luma([val]));
+            }
         }
+
+        out_img.save(to_file).map_err(|e| Error::encode(to_file, &e.to_string()))
+    }
+
+    fn save_float_tiff(&self, to_file:&str) -> error::Result<()> {
+        if !is_tiff_path(to_file) {
+            return Err(Error::encode(to_file, constants::status::FLOAT_OUTPUT_REQUIRES_TIFF_OR_FITS));
+        }
+
+        let file = fs::File::create(to_file).map_err(|e| Error::io(to_file, e))?;
+        let mut encoder = TiffEncoder::new(BufWriter::new(file)).map_err(|e| Error::encode(to_file, &e.to_string()))?;
+        encoder.write_image::<colortype::Gray32Float>(self.width as u32, self.height as u32, &self.buffer)
+            .map_err(|e| Error::encode(to_file, &e.to_string()))
     }
 }
 
@@ -0,0 +1,104 @@
+use crate::constants;
+use crate::error::{self, Error};
+
+// Resampling kernel used when reading an image at non-integer coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos3,
+}
+
+impl Interpolation {
+    pub fn from_name(name:&str) -> error::Result<Interpolation> {
+        match name.to_lowercase().as_str() {
+            constants::interpolation::NEAREST => Ok(Interpolation::Nearest),
+            constants::interpolation::BILINEAR => Ok(Interpolation::Bilinear),
+            constants::interpolation::BICUBIC => Ok(Interpolation::Bicubic),
+            constants::interpolation::LANCZOS => Ok(Interpolation::Lanczos3),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_INTERPOLATION, name))
+        }
+    }
+}
+
+// Catmull-Rom style cubic convolution kernel (a = -0.5)
+fn cubic_weight(t:f32) -> f32 {
+    let a = -0.5;
+    let t = t.abs();
+    if t <= 1.0 {
+        (a + 2.0) * t * t * t - (a + 3.0) * t * t + 1.0
+    } else if t < 2.0 {
+        a * t * t * t - 5.0 * a * t * t + 8.0 * a * t - 4.0 * a
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x:f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+fn lanczos_weight(t:f32, a:f32) -> f32 {
+    if t.abs() < a {
+        sinc(t) * sinc(t / a)
+    } else {
+        0.0
+    }
+}
+
+// Samples a row-major buffer at fractional coordinates using the supplied kernel.
+// Samples that fall entirely outside of the buffer return zero.
+pub fn sample(buffer:&[f32], width:usize, height:usize, x:f32, y:f32, method:Interpolation) -> f32 {
+    if x < -1.0 || y < -1.0 || x > width as f32 || y > height as f32 {
+        return 0.0;
+    }
+
+    let get = |xi:i64, yi:i64| -> f32 {
+        if xi < 0 || yi < 0 || xi >= width as i64 || yi >= height as i64 {
+            0.0
+        } else {
+            buffer[yi as usize * width + xi as usize]
+        }
+    };
+
+    match method {
+        Interpolation::Nearest => {
+            get(x.round() as i64, y.round() as i64)
+        },
+        Interpolation::Bilinear => {
+            let x0 = x.floor();
+            let y0 = y.floor();
+            let fx = x - x0;
+            let fy = y - y0;
+            let (x0, y0) = (x0 as i64, y0 as i64);
+
+            let top = get(x0, y0) * (1.0 - fx) + get(x0 + 1, y0) * fx;
+            let bottom = get(x0, y0 + 1) * (1.0 - fx) + get(x0 + 1, y0 + 1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        },
+        Interpolation::Bicubic | Interpolation::Lanczos3 => {
+            let radius:i64 = if method == Interpolation::Bicubic { 2 } else { 3 };
+            let x0 = x.floor() as i64;
+            let y0 = y.floor() as i64;
+
+            let mut total = 0.0;
+            let mut weight_total = 0.0;
+            for yi in (y0 - radius + 1)..=(y0 + radius) {
+                let wy = if method == Interpolation::Bicubic { cubic_weight(y - yi as f32) } else { lanczos_weight(y - yi as f32, 3.0) };
+                for xi in (x0 - radius + 1)..=(x0 + radius) {
+                    let wx = if method == Interpolation::Bicubic { cubic_weight(x - xi as f32) } else { lanczos_weight(x - xi as f32, 3.0) };
+                    total += get(xi, yi) * wx * wy;
+                    weight_total += wx * wy;
+                }
+            }
+
+            if weight_total != 0.0 { total / weight_total } else { 0.0 }
+        }
+    }
+}
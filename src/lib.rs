@@ -5,9 +5,31 @@ extern crate clap;
 pub mod print;
 
 pub mod constants;
+pub mod error;
 
 pub mod imagebuffer;
+pub mod cfa;
+pub mod metadata;
+pub mod multiband;
+pub mod demosaic;
+pub mod interpolation;
 pub mod path;
+pub mod parallel;
 
 pub mod raw_to_tiff;
-pub mod mean;
\ No newline at end of file
+pub mod calibration;
+pub mod flatfield;
+pub mod mean;
+pub mod batch;
+pub mod quality;
+pub mod stacking;
+pub mod fft;
+pub mod registration;
+pub mod alignment;
+pub mod drizzle;
+pub mod datetime;
+pub mod fieldrotation;
+pub mod solar;
+pub mod limb;
+pub mod fits;
+pub mod colorize;
\ No newline at end of file
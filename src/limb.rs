@@ -0,0 +1,224 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::interpolation::Interpolation;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+use std::f32::consts::PI;
+
+// Center and radius of the solar disk, in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disk {
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+}
+
+// A point on the detected limb
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimbPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+// Small xorshift generator so RANSAC is repeatable without pulling in a dependency
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed:u64) -> XorShift {
+        XorShift{state:if seed == 0 { 0x2545F4914F6CDD1D } else { seed }}
+    }
+
+    fn next_index(&mut self, n:usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % n as u64) as usize
+    }
+}
+
+// Makes a rough estimate of the disk by thresholding halfway between the
+// minimum and maximum and treating the bright area as a filled circle.
+pub fn estimate_disk(image:&ImageBuffer) -> error::Result<Disk> {
+    let minmax = image.get_min_max(-1.0)?;
+    let threshold = minmax.min + (minmax.max - minmax.min) / 2.0;
+
+    let mut sx = 0.0_f64;
+    let mut sy = 0.0_f64;
+    let mut count = 0_usize;
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            if image.get(x, y)? >= threshold {
+                sx += x as f64;
+                sy += y as f64;
+                count += 1;
+            }
+        }
+    }
+
+    if count == 0 || minmax.max <= minmax.min {
+        return Err(Error::DiskNotFound);
+    }
+
+    Ok(Disk{
+        x:(sx / count as f64) as f32,
+        y:(sy / count as f64) as f32,
+        radius:(count as f32 / PI).sqrt()
+    })
+}
+
+// Casts rays outward from the estimated center and, along each, locates the
+// position of steepest brightness drop-off between 0.5 and 1.5 times the estimated radius.
+pub fn find_limb_points(image:&ImageBuffer, estimate:&Disk, num_rays:usize) -> Vec<LimbPoint> {
+    if image.width == 0 || image.height == 0 {
+        return Vec::new();
+    }
+    let mut points:Vec<LimbPoint> = Vec::with_capacity(num_rays);
+
+    let r_min = estimate.radius * 0.5;
+    let r_max = estimate.radius * 1.5;
+    let step = 0.5;
+
+    for i in 0..num_rays {
+        let theta = 2.0 * PI * i as f32 / num_rays as f32;
+        let (sin, cos) = theta.sin_cos();
+
+        let mut best_r = -1.0;
+        let mut best_grad = 0.0;
+
+        let mut r = r_min;
+        while r <= r_max {
+            let inner_x = estimate.x + (r - 1.0) * cos;
+            let inner_y = estimate.y + (r - 1.0) * sin;
+            let outer_x = estimate.x + (r + 1.0) * cos;
+            let outer_y = estimate.y + (r + 1.0) * sin;
+
+            // Don't trust gradients measured against the image border
+            if outer_x < 0.0 || outer_y < 0.0 || outer_x >= (image.width - 1) as f32 || outer_y >= (image.height - 1) as f32 {
+                break;
+            }
+
+            let grad = image.get_interpolated(inner_x, inner_y, Interpolation::Bilinear)
+                     - image.get_interpolated(outer_x, outer_y, Interpolation::Bilinear);
+            if grad > best_grad {
+                best_grad = grad;
+                best_r = r;
+            }
+            r += step;
+        }
+
+        if best_r > 0.0 {
+            points.push(LimbPoint{x:estimate.x + best_r * cos, y:estimate.y + best_r * sin});
+        }
+    }
+
+    points
+}
+
+// Algebraic (Kasa) least squares circle fit
+pub fn fit_circle_least_squares(points:&[LimbPoint]) -> error::Result<Disk> {
+    if points.len() < 3 {
+        return Err(Error::InsufficientLimbPoints(points.len()));
+    }
+
+    // Center the points to keep the normal equations well conditioned
+    let n = points.len() as f64;
+    let mx = points.iter().map(|p| p.x as f64).sum::<f64>() / n;
+    let my = points.iter().map(|p| p.y as f64).sum::<f64>() / n;
+
+    let mut suu = 0.0;
+    let mut svv = 0.0;
+    let mut suv = 0.0;
+    let mut suuu = 0.0;
+    let mut svvv = 0.0;
+    let mut suvv = 0.0;
+    let mut svuu = 0.0;
+
+    for p in points.iter() {
+        let u = p.x as f64 - mx;
+        let v = p.y as f64 - my;
+        suu += u * u;
+        svv += v * v;
+        suv += u * v;
+        suuu += u * u * u;
+        svvv += v * v * v;
+        suvv += u * v * v;
+        svuu += v * u * u;
+    }
+
+    let det = suu * svv - suv * suv;
+    if det.abs() < f64::EPSILON {
+        return Err(Error::DegenerateCircleFit);
+    }
+
+    let b1 = 0.5 * (suuu + suvv);
+    let b2 = 0.5 * (svvv + svuu);
+    let uc = (b1 * svv - b2 * suv) / det;
+    let vc = (suu * b2 - suv * b1) / det;
+
+    let radius = (uc * uc + vc * vc + (suu + svv) / n).sqrt();
+
+    Ok(Disk{x:(uc + mx) as f32, y:(vc + my) as f32, radius:radius as f32})
+}
+
+// Circle through three points, if they aren't colinear
+fn circle_from_three(a:&LimbPoint, b:&LimbPoint, c:&LimbPoint) -> Option<Disk> {
+    let d = 2.0 * (a.x * (b.y - c.y) + b.x * (c.y - a.y) + c.x * (a.y - b.y));
+    if d.abs() < 1e-6 {
+        return None;
+    }
+    let a2 = a.x * a.x + a.y * a.y;
+    let b2 = b.x * b.x + b.y * b.y;
+    let c2 = c.x * c.x + c.y * c.y;
+    let x = (a2 * (b.y - c.y) + b2 * (c.y - a.y) + c2 * (a.y - b.y)) / d;
+    let y = (a2 * (c.x - b.x) + b2 * (a.x - c.x) + c2 * (b.x - a.x)) / d;
+    let radius = ((a.x - x) * (a.x - x) + (a.y - y) * (a.y - y)).sqrt();
+    Some(Disk{x, y, radius})
+}
+
+fn residual(disk:&Disk, p:&LimbPoint) -> f32 {
+    (((p.x - disk.x) * (p.x - disk.x) + (p.y - disk.y) * (p.y - disk.y)).sqrt() - disk.radius).abs()
+}
+
+// Robust circle fit. Random three point circles are scored by how many limb points fall
+// within the tolerance, then the best consensus set is refined with a least squares fit.
+// Points thrown off by prominences or clouds end up as outliers.
+pub fn fit_circle_ransac(points:&[LimbPoint], iterations:usize, tolerance:f32) -> error::Result<Disk> {
+    if points.len() < 3 {
+        return Err(Error::InsufficientLimbPoints(points.len()));
+    }
+
+    let mut rng = XorShift::new(points.len() as u64);
+    let mut best_inliers:Vec<LimbPoint> = Vec::new();
+
+    for _ in 0..iterations {
+        let a = &points[rng.next_index(points.len())];
+        let b = &points[rng.next_index(points.len())];
+        let c = &points[rng.next_index(points.len())];
+
+        if let Some(candidate) = circle_from_three(a, b, c) {
+            let inliers:Vec<LimbPoint> = points.iter().filter(|p| residual(&candidate, p) <= tolerance).cloned().collect();
+            if inliers.len() > best_inliers.len() {
+                best_inliers = inliers;
+            }
+        }
+    }
+
+    vprintln!("    RANSAC circle fit: {} of {} limb points are inliers", best_inliers.len(), points.len());
+    fit_circle_least_squares(&best_inliers)
+}
+
+// Locates the solar disk, returning its center and radius.
+pub fn find_disk(image:&ImageBuffer) -> error::Result<Disk> {
+    let estimate = estimate_disk(image)?;
+    vprintln!("    Estimated disk center: {}, {}, radius: {}", estimate.x, estimate.y, estimate.radius);
+
+    let points = find_limb_points(image, &estimate, constants::DEFAULT_LIMB_RAYS);
+    let disk = fit_circle_ransac(&points, constants::DEFAULT_RANSAC_ITERATIONS, constants::DEFAULT_RANSAC_TOLERANCE)?;
+    vprintln!("    Fitted disk center: {}, {}, radius: {}", disk.x, disk.y, disk.radius);
+
+    Ok(disk)
+}
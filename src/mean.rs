@@ -1,49 +1,160 @@
 
-use crate::imagebuffer::ImageBuffer;
+use crate::imagebuffer::{ImageBuffer, OutputFormat};
+use crate::cfa::CfaChannel;
 use crate::path;
-use crate::constants;
+use crate::error::{self, Error};
+use crate::stacking;
+use crate::fits;
+use crate::parallel;
 use crate::vprintln;
 
-pub fn process_mean(file_list:Vec<&str>) -> Result<ImageBuffer, &'static str> {
-    // This feels hacky....
-    let mut stack = ImageBuffer::new(1, 1).unwrap();
-    let mut cnt = 0;
+// Loads a single Bayer plane of a raw frame, or the whole mosaic when no channel is given
+fn load_frame(in_file:&str, channel:Option<CfaChannel>) -> error::Result<ImageBuffer> {
+    let raw = ImageBuffer::from_cr2(in_file)?;
+    let image = match channel {
+        Some(channel) => raw.channel(channel)?,
+        None => raw
+    };
+    let imagemm = image.get_min_max(-1.0)?;
+    vprintln!("    Image Min/Max : {}, {}", imagemm.min, imagemm.max);
+    Ok(image)
+}
+
+// Looks up the weight of a frame, defaulting to 1 when unweighted
+fn weight_for(in_file:&str, weights:Option<&stacking::FrameWeights>) -> f32 {
+    weights.map(|w| w.weight_or_default(in_file)).unwrap_or(1.0)
+}
+
+// Running (weighted) mean of the raws, returned with a summary of the frames that went into it
+fn mean_files(file_list:Vec<&str>, channel:Option<CfaChannel>, weights:Option<&stacking::FrameWeights>) -> error::Result<(ImageBuffer, stacking::StackSummary)> {
+    let mut accumulator = stacking::StackAccumulator::new(&stacking::StackMethod::Mean);
+    let mut summary = stacking::StackSummary::default();
 
     for in_file in file_list.iter() {
         if path::file_exists(in_file) {
             vprintln!("Processing File: {}", in_file);
-            
-            let image = ImageBuffer::from_cr2(&in_file).unwrap().red().unwrap();
-            let imagemm = image.get_min_max(-1.0).unwrap();
-            vprintln!("    Image Min/Max : {}, {}", imagemm.min, imagemm.max);
-
-            if cnt == 0 {
-                stack = image;
-            } else {
-                stack = stack.add(&image).unwrap();
-            }
 
-            cnt = cnt + 1;
+            let image = match load_frame(in_file, channel) {
+                Ok(image) => image,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", in_file, e);
+                    continue;
+                }
+            };
+
+            let weight = weight_for(in_file, weights);
+            let metadata = image.metadata().cloned();
+            match accumulator.add_weighted(image, weight) {
+                Ok(_) => summary.add(in_file, weight, metadata.as_ref()),
+                Err(e) => eprintln!("Skipping {}: {}", in_file, e)
+            }
         } else {
             eprintln!("File not found: {}", in_file);
-            return Err("File not found");
+            return Err(Error::FileNotFound(String::from(*in_file)));
         }
     }
 
-    if cnt > 0 {
-        stack = stack.scale(1.0 / cnt as f32).unwrap();
-        let stackmm = stack.get_min_max(-1.0).unwrap();
-        vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, cnt);
-        return Ok(stack);
+    if summary.count() > 0 {
+        let mut stack = accumulator.finish()?;
+        stack.set_metadata(summary.metadata.clone());
+        let stackmm = stack.get_min_max(-1.0)?;
+        vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, summary.count());
+        summary.print_weights();
+        Ok((stack, summary))
     } else {
         eprintln!("No files used");
-        return Err("No files used");
+        Err(Error::NoFilesUsed)
     }
 
 }
 
+pub fn process_mean(file_list:Vec<&str>) -> error::Result<ImageBuffer> {
+    let (stack, _) = mean_files(file_list, Some(CfaChannel::Red), None)?;
+    Ok(stack)
+}
+
+// Loads each of the raws and combines them using the requested method. The
+// plain mean is accumulated as a running sum so it doesn't need to hold every frame.
+fn combine_files(file_list:Vec<&str>, method:&stacking::StackMethod, channel:Option<CfaChannel>, weights:Option<&stacking::FrameWeights>) -> error::Result<(ImageBuffer, stacking::StackSummary)> {
+    if *method == stacking::StackMethod::Mean {
+        return mean_files(file_list, channel, weights);
+    }
+
+    if let Some(missing) = file_list.iter().find(|f| !path::file_exists(f)) {
+        eprintln!("File not found: {}", missing);
+        return Err(Error::FileNotFound(String::from(*missing)));
+    }
+
+    let loaded:Vec<Option<ImageBuffer>> = parallel::map_items(&file_list, |in_file| {
+        vprintln!("Processing File: {}", in_file);
+        match load_frame(in_file, channel) {
+            Ok(image) => Some(image),
+            Err(e) => {
+                eprintln!("Skipping {}: {}", in_file, e);
+                None
+            }
+        }
+    });
+
+    let mut summary = stacking::StackSummary::default();
+    let mut frames:Vec<ImageBuffer> = Vec::with_capacity(file_list.len());
+    for (in_file, image) in file_list.iter().zip(loaded) {
+        if let Some(image) = image {
+            summary.add(in_file, weight_for(in_file, weights), image.metadata());
+            frames.push(image);
+        }
+    }
 
-pub fn run_mean_stack(file_list:Vec<&str>, output:&str) {
-    let mean_stack = process_mean(file_list).unwrap();
-    mean_stack.save(output).expect(constants::status::OK);
-}
\ No newline at end of file
+    if frames.is_empty() {
+        eprintln!("No files used");
+        return Err(Error::NoFilesUsed);
+    }
+
+    let frame_weights:Vec<f32> = summary.frames.iter().map(|(_, w)| *w).collect();
+    let mut stack = stacking::combine_weighted(&frames, &frame_weights, method)?;
+    stack.set_metadata(summary.metadata.clone());
+    let stackmm = stack.get_min_max(-1.0)?;
+    vprintln!("    Stack Min/Max : {}, {} ({} images)", stackmm.min, stackmm.max, frames.len());
+    summary.print_weights();
+    Ok((stack, summary))
+}
+
+pub fn process_stack(file_list:Vec<&str>, method:&stacking::StackMethod, channel:Option<CfaChannel>) -> error::Result<ImageBuffer> {
+    let (stack, _) = combine_files(file_list, method, channel, None)?;
+    Ok(stack)
+}
+
+// FITS header describing a stack, including the shooting metadata of its first frame.
+// Weights are only recorded when they aren't all equal.
+pub fn stack_header(summary:&stacking::StackSummary) -> fits::FitsHeader {
+    let weighted = summary.is_weighted();
+    let mut header = fits::FitsHeader{
+        stack_count:Some(summary.count()),
+        effective_count:if weighted { Some(summary.effective_count()) } else { None },
+        frame_weights:if weighted { summary.frames.clone() } else { Vec::new() },
+        ..Default::default()
+    };
+    if let Some(metadata) = &summary.metadata {
+        header.record_metadata(metadata);
+    }
+    header
+}
+
+// Saves a master frame. FITS output records the frames combined and their weights.
+fn save_master(stack:&ImageBuffer, output:&str, summary:&stacking::StackSummary, format:OutputFormat) -> error::Result<()> {
+    if fits::is_fits_path(output) {
+        fits::save(stack, output, format.fits_depth(), &stack_header(summary))
+    } else {
+        stack.save_as(output, format)
+    }
+}
+
+pub fn run_mean_stack(file_list:Vec<&str>, output:&str) -> error::Result<()> {
+    let (mean_stack, summary) = mean_files(file_list, Some(CfaChannel::Red), None)?;
+    save_master(&mean_stack, output, &summary, OutputFormat::Rgb16)
+}
+
+pub fn run_stack(file_list:Vec<&str>, output:&str, method:&stacking::StackMethod, channel:Option<CfaChannel>, weights:Option<&stacking::FrameWeights>, format:OutputFormat) -> error::Result<()> {
+    let (stack, summary) = combine_files(file_list, method, channel, weights)?;
+    save_master(&stack, output, &summary, format)
+}
@@ -0,0 +1,174 @@
+use crate::cfa::{self, CfaPattern};
+use crate::datetime::{self, DateTime};
+use crate::constants;
+use crate::error::{self, Error};
+
+use libraw_sys as sys;
+
+use std::fs;
+use std::os::raw::c_char;
+
+// Shooting and sensor metadata of a raw frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RawMetadata {
+    pub make: String,
+    pub model: String,
+    // Exposure time in seconds
+    pub exposure: Option<f32>,
+    pub iso: Option<f32>,
+    // Capture time from the camera's clock
+    pub timestamp: Option<DateTime>,
+    // Black level common to every photosite
+    pub black_level: f32,
+    // Additional black level of each photosite in the 2x2 cell, indexed as [row][column]
+    pub cell_black: [[f32; 2]; 2],
+    // Saturation level of the raw data
+    pub white_level: f32,
+    pub cfa: Option<CfaPattern>,
+    // Sensor (or, failing that, camera body) temperature in degrees Celsius
+    pub temperature: Option<f32>,
+}
+
+impl RawMetadata {
+    // Make and model, e.g. Canon EOS 50D
+    pub fn camera(&self) -> String {
+        format!("{} {}", self.make, self.model).trim().to_string()
+    }
+
+    // Whether the raw's headers gave a black level
+    pub fn has_black_level(&self) -> bool {
+        self.black_level > 0.0 || self.cell_black.iter().flatten().any(|b| *b > 0.0)
+    }
+
+    // Total black level of the photosite at x, y of the mosaic
+    pub fn black_at(&self, x:usize, y:usize) -> f32 {
+        self.black_level + self.cell_black[y % 2][x % 2]
+    }
+
+    // Differences in the camera or ISO, either of which changes the sensor's response.
+    // Values missing from either frame aren't compared.
+    pub fn settings_mismatches(&self, other:&RawMetadata) -> Vec<String> {
+        let mut found:Vec<String> = Vec::new();
+        let (camera, other_camera) = (self.camera(), other.camera());
+        if !camera.is_empty() && !other_camera.is_empty() && camera != other_camera {
+            found.push(format!("camera {} vs {}", camera, other_camera));
+        }
+        if let (Some(iso), Some(other_iso)) = (self.iso, other.iso) {
+            if iso != other_iso {
+                found.push(format!("ISO {} vs {}", iso, other_iso));
+            }
+        }
+        found
+    }
+
+    // Differences that make a dark unsuitable for calibrating this frame as is: the
+    // camera, ISO or exposure time. Values missing from either frame aren't compared.
+    pub fn mismatches(&self, other:&RawMetadata) -> Vec<String> {
+        let mut found = self.settings_mismatches(other);
+        if let (Some(exposure), Some(other_exposure)) = (self.exposure, other.exposure) {
+            if (exposure - other_exposure).abs() > constants::EXPOSURE_MATCH_TOLERANCE * exposure.max(other_exposure) {
+                found.push(format!("exposure {}s vs {}s", exposure, other_exposure));
+            }
+        }
+        found
+    }
+}
+
+// Converts a fixed size, nul terminated C string
+fn c_string(chars:&[c_char]) -> String {
+    let bytes:Vec<u8> = chars.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+// Reads the metadata of a raw file. The levels are those libraw reports from the
+// file's headers, so the black level may be zero for cameras that only record it in
+// the masked border. The capture time is read from the EXIF tags, as libraw
+// converts it through the local time zone.
+pub fn read_metadata(raw_data:&[u8]) -> Option<RawMetadata> {
+    unsafe {
+        let data = sys::libraw_init(0);
+        if data.is_null() {
+            return None;
+        }
+
+        let mut metadata = None;
+        if sys::libraw_open_buffer(data, raw_data.as_ptr() as *const _, raw_data.len()) == 0 {
+            let color = &(*data).color;
+            let other = &(*data).other;
+            let common = &(*data).makernotes.common;
+
+            // Per-channel offsets come first, then an optional repeating pattern
+            let (pattern_rows, pattern_cols) = (color.cblack[4] as usize, color.cblack[5] as usize);
+            let mut cell_black = [[0.0_f32; 2]; 2];
+            for (row, cells) in cell_black.iter_mut().enumerate() {
+                for (col, cell) in cells.iter_mut().enumerate() {
+                    let idx = sys::libraw_COLOR(data, row as i32, col as i32);
+                    let mut black = if (0..4).contains(&idx) { color.cblack[idx as usize] } else { 0 };
+                    if pattern_rows > 0 && pattern_cols > 0 {
+                        black += color.cblack[6 + (row % pattern_rows) * pattern_cols + col % pattern_cols];
+                    }
+                    *cell = black as f32;
+                }
+            }
+
+            metadata = Some(RawMetadata{
+                make:c_string(&(*data).idata.make),
+                model:c_string(&(*data).idata.model),
+                exposure:if other.shutter > 0.0 { Some(other.shutter) } else { None },
+                iso:if other.iso_speed > 0.0 { Some(other.iso_speed) } else { None },
+                timestamp:datetime::read_capture_time(raw_data),
+                black_level:color.black as f32,
+                cell_black,
+                white_level:color.maximum as f32,
+                cfa:cfa::pattern_of(data),
+                // libraw marks temperatures it didn't find with -1000
+                temperature:[common.SensorTemperature, common.CameraTemperature].iter().copied().find(|t| *t > -273.0)
+            });
+        }
+
+        sys::libraw_close(data);
+        metadata
+    }
+}
+
+// Measures the black level of each photosite in the 2x2 cell as the median of the
+// masked optical black columns to the left of the visible area, or the rows above it
+// when there are none. data is the full sensor readout, raw_width pixels per row, and
+// cells are counted from the first visible pixel.
+pub fn measure_masked_black(data:&[u16], raw_width:usize, raw_height:usize, left_margin:usize, top_margin:usize) -> Option<[[f32; 2]; 2]> {
+    if data.len() < raw_width * raw_height {
+        return None;
+    }
+    let (columns, rows) = if left_margin >= 2 {
+        (0..left_margin, top_margin..raw_height)
+    } else if top_margin >= 2 {
+        (left_margin..raw_width, 0..top_margin)
+    } else {
+        return None;
+    };
+
+    let mut cells:Vec<Vec<u16>> = vec![Vec::new(); 4];
+    for y in rows {
+        for x in columns.clone() {
+            let row = (y as i64 - top_margin as i64).rem_euclid(2) as usize;
+            let col = (x as i64 - left_margin as i64).rem_euclid(2) as usize;
+            cells[row * 2 + col].push(data[y * raw_width + x]);
+        }
+    }
+
+    let mut black = [[0.0_f32; 2]; 2];
+    for (i, values) in cells.iter_mut().enumerate() {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        black[i / 2][i % 2] = values[values.len() / 2] as f32;
+    }
+    Some(black)
+}
+
+// Reads the metadata of a raw file on disk
+pub fn read_file(raw_file:&str) -> error::Result<RawMetadata> {
+    let buf = fs::read(raw_file).map_err(|e| Error::io(raw_file, e))?;
+    read_metadata(&buf).ok_or_else(|| Error::decode(raw_file, constants::status::NO_RAW_METADATA))
+}
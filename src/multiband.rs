@@ -0,0 +1,297 @@
+use crate::imagebuffer::{self, ImageBuffer, MinMax, OutputFormat};
+use crate::path;
+use crate::constants;
+use crate::fits;
+use crate::error::{self, Error};
+use crate::interpolation::Interpolation;
+use crate::vprintln;
+
+use image::{DynamicImage, Rgb};
+use std::fs;
+use std::io::BufWriter;
+
+use tiff::encoder::{colortype, TiffEncoder};
+
+// An image made up of one or more bands of equal size, each held as an ImageBuffer.
+// Color images are three bands in red, green, blue order.
+#[derive(Debug, Clone)]
+pub struct MultiBandImage {
+    bands: Vec<ImageBuffer>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl MultiBandImage {
+
+    // Creates a new image of the requested size with zeroed bands
+    pub fn new(width:usize, height:usize, num_bands:usize) -> error::Result<MultiBandImage> {
+        let mut bands:Vec<ImageBuffer> = Vec::with_capacity(num_bands);
+        for _ in 0..num_bands {
+            bands.push(ImageBuffer::new(width, height)?);
+        }
+        MultiBandImage::merge(bands)
+    }
+
+    // Builds an image from separate bands, which must all be the same size
+    pub fn merge(bands:Vec<ImageBuffer>) -> error::Result<MultiBandImage> {
+        if bands.is_empty() {
+            return Err(Error::EmptyImage);
+        }
+        let width = bands[0].width;
+        let height = bands[0].height;
+        for b in bands.iter() {
+            if b.width != width || b.height != height {
+                return Err(Error::DimensionMismatch{expected:(width, height), found:(b.width, b.height)});
+            }
+        }
+        Ok(MultiBandImage{bands, width, height})
+    }
+
+    pub fn from_rgb(red:ImageBuffer, green:ImageBuffer, blue:ImageBuffer) -> error::Result<MultiBandImage> {
+        MultiBandImage::merge(vec![red, green, blue])
+    }
+
+    // Loads every band of a FITS cube
+    pub fn from_fits(file_path:&str) -> error::Result<MultiBandImage> {
+        let (bands, _) = fits::load_bands(file_path)?;
+        MultiBandImage::merge(bands)
+    }
+
+    // Separates the image into its bands
+    pub fn split(self) -> Vec<ImageBuffer> {
+        self.bands
+    }
+
+    pub fn bands(&self) -> &[ImageBuffer] {
+        &self.bands
+    }
+
+    pub fn num_bands(&self) -> usize {
+        self.bands.len()
+    }
+
+    pub fn band(&self, band:usize) -> error::Result<&ImageBuffer> {
+        self.bands.get(band).ok_or(Error::InvalidBand{band, bands:self.bands.len()})
+    }
+
+    pub fn set_band(&mut self, band:usize, image:ImageBuffer) -> error::Result<()> {
+        if band >= self.bands.len() {
+            return Err(Error::InvalidBand{band, bands:self.bands.len()});
+        }
+        if image.width != self.width || image.height != self.height {
+            return Err(Error::DimensionMismatch{expected:(self.width, self.height), found:(image.width, image.height)});
+        }
+        self.bands[band] = image;
+        Ok(())
+    }
+
+    pub fn red(&self) -> error::Result<&ImageBuffer> {
+        self.band(0)
+    }
+
+    pub fn green(&self) -> error::Result<&ImageBuffer> {
+        self.band(1)
+    }
+
+    pub fn blue(&self) -> error::Result<&ImageBuffer> {
+        self.band(2)
+    }
+
+    pub fn get(&self, x:usize, y:usize, band:usize) -> error::Result<f32> {
+        self.band(band)?.get(x, y)
+    }
+
+    pub fn put(&mut self, x:usize, y:usize, band:usize, val:f32) -> error::Result<()> {
+        if band >= self.bands.len() {
+            return Err(Error::InvalidBand{band, bands:self.bands.len()});
+        }
+        self.bands[band].put(x, y, val)
+    }
+
+    // Values of every band at a pixel
+    pub fn get_pixel(&self, x:usize, y:usize) -> error::Result<Vec<f32>> {
+        self.bands.iter().map(|b| b.get(x, y)).collect()
+    }
+
+    pub fn put_pixel(&mut self, x:usize, y:usize, values:&[f32]) -> error::Result<()> {
+        if values.len() != self.bands.len() {
+            return Err(Error::InvalidBand{band:values.len(), bands:self.bands.len()});
+        }
+        for (b, v) in self.bands.iter_mut().zip(values.iter()) {
+            b.put(x, y, *v)?;
+        }
+        Ok(())
+    }
+
+    // Applies a single band operation to each band
+    pub fn map<F>(&self, f:F) -> error::Result<MultiBandImage>
+        where F: Fn(&ImageBuffer) -> error::Result<ImageBuffer> {
+        MultiBandImage::merge(self.bands.iter().map(f).collect::<error::Result<Vec<ImageBuffer>>>()?)
+    }
+
+    // Applies a band-by-band operation between two images with the same number of bands
+    pub fn zip<F>(&self, other:&MultiBandImage, f:F) -> error::Result<MultiBandImage>
+        where F: Fn(&ImageBuffer, &ImageBuffer) -> error::Result<ImageBuffer> {
+        if other.bands.len() != self.bands.len() {
+            return Err(Error::InvalidBand{band:other.bands.len(), bands:self.bands.len()});
+        }
+        MultiBandImage::merge(self.bands.iter().zip(other.bands.iter()).map(|(a, b)| f(a, b)).collect::<error::Result<Vec<ImageBuffer>>>()?)
+    }
+
+    pub fn add(&self, other:&MultiBandImage) -> error::Result<MultiBandImage> {
+        self.zip(other, |a, b| a.add(b))
+    }
+
+    pub fn subtract(&self, other:&MultiBandImage) -> error::Result<MultiBandImage> {
+        self.zip(other, |a, b| a.subtract(b))
+    }
+
+    pub fn multiply(&self, other:&MultiBandImage) -> error::Result<MultiBandImage> {
+        self.zip(other, |a, b| a.multiply(b))
+    }
+
+    pub fn divide(&self, other:&MultiBandImage) -> error::Result<MultiBandImage> {
+        self.zip(other, |a, b| a.divide(b))
+    }
+
+    // Applies a single band image (such as a mono flat) to every band
+    pub fn divide_each(&self, other:&ImageBuffer) -> error::Result<MultiBandImage> {
+        self.map(|b| b.divide(other))
+    }
+
+    pub fn subtract_each(&self, other:&ImageBuffer) -> error::Result<MultiBandImage> {
+        self.map(|b| b.subtract(other))
+    }
+
+    pub fn scale(&self, scalar:f32) -> error::Result<MultiBandImage> {
+        self.map(|b| b.scale(scalar))
+    }
+
+    // Scales each band by its own factor, e.g. for white balance
+    pub fn scale_bands(&self, scalars:&[f32]) -> error::Result<MultiBandImage> {
+        if scalars.len() != self.bands.len() {
+            return Err(Error::InvalidBand{band:scalars.len(), bands:self.bands.len()});
+        }
+        MultiBandImage::merge(self.bands.iter().zip(scalars.iter()).map(|(b, s)| b.scale(*s)).collect::<error::Result<Vec<ImageBuffer>>>()?)
+    }
+
+    pub fn shift(&self, horiz:i32, vert:i32) -> error::Result<MultiBandImage> {
+        self.map(|b| b.shift(horiz, vert))
+    }
+
+    pub fn shift_subpixel(&self, horiz:f32, vert:f32, method:Interpolation) -> error::Result<MultiBandImage> {
+        self.map(|b| b.shift_subpixel(horiz, vert, method))
+    }
+
+    pub fn crop(&self, height:usize, width:usize) -> error::Result<MultiBandImage> {
+        self.map(|b| b.crop(height, width))
+    }
+
+    pub fn crop_region(&self, left:i64, top:i64, width:usize, height:usize) -> error::Result<MultiBandImage> {
+        self.map(|b| b.crop_region(left, top, width, height))
+    }
+
+    // Minimum and maximum across all bands
+    pub fn get_min_max(&self) -> error::Result<MinMax> {
+        let mut mm = MinMax{min:f32::MAX, max:f32::MIN};
+        for b in self.bands.iter() {
+            let bmm = b.get_min_max(-1.0)?;
+            mm.min = mm.min.min(bmm.min);
+            mm.max = mm.max.max(bmm.max);
+        }
+        Ok(mm)
+    }
+
+    // Rescales all bands by the same factor so the balance between them is kept
+    pub fn normalize(&self, min:f32, max:f32) -> error::Result<MultiBandImage> {
+        let mm = self.get_min_max()?;
+        if mm.max <= mm.min {
+            return Ok(self.clone());
+        }
+        self.map(|b| b.normalize_from(mm.min, mm.max, min, max))
+    }
+
+    // Rescales each band to the range independently
+    pub fn normalize_bands(&self, min:f32, max:f32) -> error::Result<MultiBandImage> {
+        self.map(|b| b.normalize(min, max))
+    }
+
+    // Rec. 709 weighted luminance for three band images, otherwise the mean of the bands
+    pub fn luminance(&self) -> error::Result<ImageBuffer> {
+        if self.bands.len() == 3 {
+            self.bands[0].scale(0.2126)?.add(&self.bands[1].scale(0.7152)?)?.add(&self.bands[2].scale(0.0722)?)
+        } else {
+            let mut sum = self.bands[0].clone();
+            for b in self.bands.iter().skip(1) {
+                sum = sum.add(b)?;
+            }
+            sum.scale(1.0 / self.bands.len() as f32)
+        }
+    }
+
+    // Saves as 16 bit RGB, or as a float FITS cube for files with a FITS extension
+    pub fn save(&self, to_file:&str) -> error::Result<()> {
+        self.save_as(to_file, OutputFormat::Rgb16)
+    }
+
+    // Saves in the requested sample format. FITS files are written as a cube with one
+    // plane per band. TIFF and PNG need one band (gray) or three (RGB).
+    pub fn save_as(&self, to_file:&str, format:OutputFormat) -> error::Result<()> {
+        if fits::is_fits_path(to_file) {
+            return fits::save_bands(&self.bands, to_file, format.fits_depth(), &fits::FitsHeader::default());
+        }
+
+        match self.bands.len() {
+            1 => return self.bands[0].save_as(to_file, format),
+            3 => {},
+            _ => return Err(Error::encode(to_file, constants::status::UNSUPPORTED_BAND_COUNT))
+        }
+
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(Error::ParentNotWritable(path::get_parent(to_file)));
+        }
+
+        vprintln!("    Writing RGB image to file at {}", to_file);
+        match format {
+            OutputFormat::Rgb16 | OutputFormat::Gray16 => self.save_rgb16(to_file)?,
+            OutputFormat::Float32 => self.save_float_tiff(to_file)?,
+        }
+        vprintln!("    File saved.");
+        Ok(())
+    }
+
+    fn save_rgb16(&self, to_file:&str) -> error::Result<()> {
+        let mut out_img = DynamicImage::new_rgb16(self.width as u32, self.height as u32).into_rgb16();
+        let px = |c:f32| c.round().clamp(0.0, u16::MAX as f32) as u16;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                out_img.put_pixel(x as u32, y as u32, Rgb([px(self.bands[0].get(x, y)?),
+                                                           px(self.bands[1].get(x, y)?),
+                                                           px(self.bands[2].get(x, y)?)]));
+            }
+        }
+
+        out_img.save(to_file).map_err(|e| Error::encode(to_file, &e.to_string()))
+    }
+
+    fn save_float_tiff(&self, to_file:&str) -> error::Result<()> {
+        if !imagebuffer::is_tiff_path(to_file) {
+            return Err(Error::encode(to_file, constants::status::FLOAT_OUTPUT_REQUIRES_TIFF_OR_FITS));
+        }
+
+        let mut data:Vec<f32> = Vec::with_capacity(self.width * self.height * 3);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for b in self.bands.iter() {
+                    data.push(b.get(x, y)?);
+                }
+            }
+        }
+
+        let file = fs::File::create(to_file).map_err(|e| Error::io(to_file, e))?;
+        let mut encoder = TiffEncoder::new(BufWriter::new(file)).map_err(|e| Error::encode(to_file, &e.to_string()))?;
+        encoder.write_image::<colortype::RGB32Float>(self.width as u32, self.height as u32, &data)
+            .map_err(|e| Error::encode(to_file, &e.to_string()))
+    }
+}
@@ -0,0 +1,105 @@
+// Data-parallel helpers used by the pixel operations and the per-file loops. With
+// the `parallel` feature these run on the rayon thread pool, otherwise they fall
+// back to plain iterators with identical results.
+
+use crate::vprintln;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+// Sizes the global thread pool. Must be called before any parallel work is done.
+// Zero leaves the pool sized to the number of logical CPUs.
+#[cfg(feature = "parallel")]
+pub fn set_threads(threads:usize) {
+    if threads == 0 {
+        return;
+    }
+    match rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+        Ok(_) => vprintln!("Using {} worker threads", threads),
+        Err(e) => eprintln!("Unable to set worker thread count: {}", e)
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn set_threads(threads:usize) {
+    if threads > 1 {
+        vprintln!("Built without the parallel feature, ignoring thread count of {}", threads);
+    }
+}
+
+pub fn is_enabled() -> bool {
+    cfg!(feature = "parallel")
+}
+
+// Number of threads work is spread across
+pub fn num_threads() -> usize {
+    #[cfg(feature = "parallel")]
+    { rayon::current_num_threads() }
+
+    #[cfg(not(feature = "parallel"))]
+    { 1 }
+}
+
+// Applies f to each value
+pub fn map<F>(src:&[f32], f:F) -> Vec<f32>
+    where F: Fn(f32) -> f32 + Sync + Send {
+    #[cfg(feature = "parallel")]
+    { src.par_iter().map(|v| f(*v)).collect() }
+
+    #[cfg(not(feature = "parallel"))]
+    { src.iter().map(|v| f(*v)).collect() }
+}
+
+// Applies f to each pair of values. The slices must be the same length.
+pub fn zip_map<F>(a:&[f32], b:&[f32], f:F) -> Vec<f32>
+    where F: Fn(f32, f32) -> f32 + Sync + Send {
+    #[cfg(feature = "parallel")]
+    { a.par_iter().zip(b.par_iter()).map(|(x, y)| f(*x, *y)).collect() }
+
+    #[cfg(not(feature = "parallel"))]
+    { a.iter().zip(b.iter()).map(|(x, y)| f(*x, *y)).collect() }
+}
+
+// Builds a buffer of the given length from a function of the index
+pub fn generate<F>(len:usize, f:F) -> Vec<f32>
+    where F: Fn(usize) -> f32 + Sync + Send {
+    #[cfg(feature = "parallel")]
+    { (0..len).into_par_iter().map(f).collect() }
+
+    #[cfg(not(feature = "parallel"))]
+    { (0..len).map(f).collect() }
+}
+
+// Minimum and maximum of the values
+pub fn min_max(src:&[f32]) -> (f32, f32) {
+    let init = (f32::MAX, f32::MIN);
+    let fold = |acc:(f32, f32), v:&f32| (acc.0.min(*v), acc.1.max(*v));
+
+    #[cfg(feature = "parallel")]
+    { src.par_iter().fold(|| init, fold).reduce(|| init, |a, b| (a.0.min(b.0), a.1.max(b.1))) }
+
+    #[cfg(not(feature = "parallel"))]
+    { src.iter().fold(init, fold) }
+}
+
+// Sum and count of the values matching the predicate
+pub fn sum_where<P>(src:&[f32], predicate:P) -> (f64, usize)
+    where P: Fn(f32) -> bool + Sync + Send {
+    let fold = |acc:(f64, usize), v:&f32| if predicate(*v) { (acc.0 + *v as f64, acc.1 + 1) } else { acc };
+
+    #[cfg(feature = "parallel")]
+    { src.par_iter().fold(|| (0.0, 0), fold).reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1)) }
+
+    #[cfg(not(feature = "parallel"))]
+    { src.iter().fold((0.0, 0), fold) }
+}
+
+// Runs f on each item, returning the results in the original order
+pub fn map_items<T, R, F>(items:&[T], f:F) -> Vec<R>
+    where T: Sync, R: Send, F: Fn(&T) -> R + Sync + Send {
+    #[cfg(feature = "parallel")]
+    { items.par_iter().map(f).collect() }
+
+    #[cfg(not(feature = "parallel"))]
+    { items.iter().map(f).collect() }
+}
@@ -29,4 +29,14 @@ pub fn parent_writable(chk_path:&str) -> bool {
 
 pub fn parent_exists_and_writable(chk_path:&str) -> bool {
     parent_exists(chk_path) && parent_writable(chk_path)
-}
\ No newline at end of file
+}
+// Inserts the suffix between the file name and its extension
+pub fn append_to_stem(chk_path:&str, suffix:&str) -> String {
+    let path = Path::new(&chk_path);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext),
+        None => format!("{}{}", stem, suffix)
+    };
+    String::from(path.with_file_name(name).to_str().unwrap())
+}
@@ -0,0 +1,214 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::limb;
+use crate::interpolation::Interpolation;
+use crate::parallel;
+use crate::stacking::FrameWeights;
+use crate::constants;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+use std::fs;
+
+// Measure of how sharp a frame is. Higher is sharper for all metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityMetric {
+    // Variance of the Laplacian, which responds to fine detail
+    Laplacian,
+    // Mean squared intensity gradient
+    Gradient,
+    // Steepness of the brightness drop-off across the fitted limb
+    Limb,
+}
+
+impl QualityMetric {
+    pub fn from_name(name:&str) -> error::Result<QualityMetric> {
+        match name.to_lowercase().as_str() {
+            constants::quality::LAPLACIAN => Ok(QualityMetric::Laplacian),
+            constants::quality::GRADIENT => Ok(QualityMetric::Gradient),
+            constants::quality::LIMB => Ok(QualityMetric::Limb),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_QUALITY_METRIC, name))
+        }
+    }
+}
+
+// Which of the ranked frames are kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    All,
+    Best(usize),
+    BestPercent(f32),
+}
+
+impl Selection {
+    // Parses a frame count, e.g. 200, or a percentage of the frames, e.g. 10%
+    pub fn from_spec(s:&str) -> error::Result<Selection> {
+        let bad = || Error::invalid_parameter(constants::status::INVALID_SELECTION, s);
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent:f32 = percent.trim().parse().map_err(|_| bad())?;
+            if percent <= 0.0 || percent > 100.0 {
+                return Err(bad());
+            }
+            Ok(Selection::BestPercent(percent))
+        } else {
+            let count:usize = s.parse().map_err(|_| bad())?;
+            if count == 0 {
+                return Err(bad());
+            }
+            Ok(Selection::Best(count))
+        }
+    }
+
+    // Number of frames kept out of the total. Percentages round up so at least one is kept.
+    pub fn count_of(&self, total:usize) -> usize {
+        match *self {
+            Selection::All => total,
+            Selection::Best(n) => n.min(total),
+            Selection::BestPercent(_) if total == 0 => 0,
+            Selection::BestPercent(p) => ((total as f32 * p / 100.0).ceil() as usize).clamp(1, total),
+        }
+    }
+}
+
+// Score of a single frame. The index is the frame's position in the input list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameScore {
+    pub index: usize,
+    pub name: String,
+    pub score: f64,
+}
+
+// Applies f to each pixel not on the image border
+fn interior_values<F>(image:&ImageBuffer, f:F) -> Vec<f32>
+    where F: Fn(usize, usize) -> f32 + Sync + Send {
+    let w = image.width - 2;
+    let h = image.height - 2;
+    parallel::generate(w * h, |i| f(i % w + 1, i / w + 1))
+}
+
+// Mean and variance of the values
+fn mean_variance(values:&[f32]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let (sum, _) = parallel::sum_where(values, |_| true);
+    let mean = sum / n;
+    let deviations = parallel::map(values, |v| (v as f64 - mean).powi(2) as f32);
+    let (var, _) = parallel::sum_where(&deviations, |_| true);
+    (mean, var / n)
+}
+
+// Scales a score by the square of the mean brightness so frames dimmed by
+// haze or thin cloud aren't ranked on their contrast alone.
+fn brightness_normalized(image:&ImageBuffer, value:f64) -> f64 {
+    let (sum, count) = parallel::sum_where(&interior_values(image, |x, y| image.get(x, y).unwrap_or(0.0)), |_| true);
+    let mean = if count > 0 { sum / count as f64 } else { 0.0 };
+    if mean > 0.0 { value / (mean * mean) } else { value }
+}
+
+pub fn laplacian_variance(image:&ImageBuffer) -> error::Result<f64> {
+    check_size(image)?;
+    // Coordinates are always within the image, so get can't fail
+    let px = |x:usize, y:usize| image.get(x, y).unwrap_or(0.0);
+    let laplacian = interior_values(image, |x, y| {
+        4.0 * px(x, y) - px(x - 1, y) - px(x + 1, y) - px(x, y - 1) - px(x, y + 1)
+    });
+    let (_, variance) = mean_variance(&laplacian);
+    Ok(brightness_normalized(image, variance))
+}
+
+pub fn gradient_energy(image:&ImageBuffer) -> error::Result<f64> {
+    check_size(image)?;
+    let px = |x:usize, y:usize| image.get(x, y).unwrap_or(0.0);
+    let energy = interior_values(image, |x, y| {
+        let gx = (px(x + 1, y) - px(x - 1, y)) / 2.0;
+        let gy = (px(x, y + 1) - px(x, y - 1)) / 2.0;
+        gx * gx + gy * gy
+    });
+    let (mean, _) = mean_variance(&energy);
+    Ok(brightness_normalized(image, mean))
+}
+
+// Mean brightness drop across the limb over two pixels, relative to the image's
+// range. A steep drop means the seeing hasn't smeared the edge of the disk.
+pub fn limb_sharpness(image:&ImageBuffer) -> error::Result<f64> {
+    check_size(image)?;
+    let disk = limb::find_disk(image)?;
+    let points = limb::find_limb_points(image, &disk, constants::DEFAULT_LIMB_RAYS);
+    if points.is_empty() {
+        return Err(Error::InsufficientLimbPoints(0));
+    }
+
+    let mut total = 0.0_f64;
+    for p in points.iter() {
+        let dx = p.x - disk.x;
+        let dy = p.y - disk.y;
+        let r = (dx * dx + dy * dy).sqrt().max(1.0);
+        let (cos, sin) = (dx / r, dy / r);
+        let inner = image.get_interpolated(p.x - cos, p.y - sin, Interpolation::Bilinear);
+        let outer = image.get_interpolated(p.x + cos, p.y + sin, Interpolation::Bilinear);
+        total += ((inner - outer) / 2.0) as f64;
+    }
+
+    let mm = image.get_min_max(-1.0)?;
+    let range = (mm.max - mm.min) as f64;
+    let mean = total / points.len() as f64;
+    Ok(if range > 0.0 { mean / range } else { 0.0 })
+}
+
+fn check_size(image:&ImageBuffer) -> error::Result<()> {
+    if image.is_empty() || image.width < 3 || image.height < 3 {
+        return Err(Error::EmptyImage);
+    }
+    Ok(())
+}
+
+pub fn score(image:&ImageBuffer, metric:QualityMetric) -> error::Result<f64> {
+    match metric {
+        QualityMetric::Laplacian => laplacian_variance(image),
+        QualityMetric::Gradient => gradient_energy(image),
+        QualityMetric::Limb => limb_sharpness(image),
+    }
+}
+
+// Sorts the scores best first. Ties keep their input order.
+pub fn rank(scores:&mut [FrameScore]) {
+    scores.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal).then(a.index.cmp(&b.index)));
+}
+
+// Ranks the scores and returns the ones kept by the selection, best first
+pub fn select(scores:&[FrameScore], selection:Selection) -> Vec<FrameScore> {
+    let mut ranked = scores.to_vec();
+    rank(&mut ranked);
+    let keep = selection.count_of(ranked.len());
+    vprintln!("Keeping {} of {} frames", keep, ranked.len());
+    ranked.truncate(keep);
+    ranked
+}
+
+// Turns scores into stacking weights relative to the best frame, so the sharpest
+// frame has a weight of 1
+pub fn weights_from_scores(scores:&[FrameScore]) -> FrameWeights {
+    let best = scores.iter().map(|s| s.score).fold(0.0_f64, f64::max);
+    let weights = scores.iter().map(|s| {
+        let w = if best > 0.0 { (s.score / best).max(0.0) } else { 1.0 };
+        (s.name.clone(), w as f32)
+    }).collect();
+    FrameWeights::from_pairs(weights).unwrap_or_default()
+}
+
+// Writes the ranked scores as CSV, marking which frames were kept
+pub fn write_report(scores:&[FrameScore], selection:Selection, to_file:&str) -> error::Result<()> {
+    let mut ranked = scores.to_vec();
+    rank(&mut ranked);
+    let keep = selection.count_of(ranked.len());
+
+    let mut csv = String::from("rank,file,score,selected\n");
+    for (i, s) in ranked.iter().enumerate() {
+        let name = if s.name.contains(',') || s.name.contains('"') {
+            format!("\"{}\"", s.name.replace('"', "\"\""))
+        } else {
+            s.name.clone()
+        };
+        csv.push_str(&format!("{},{},{},{}\n", i + 1, name, s.score, i < keep));
+    }
+    fs::write(to_file, csv).map_err(|e| Error::io(to_file, e))
+}
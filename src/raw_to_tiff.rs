@@ -1,8 +1,16 @@
 
 
-use crate::imagebuffer::ImageBuffer;
+use crate::imagebuffer::{ImageBuffer, Offset, OutputFormat};
 use crate::path;
+use crate::parallel;
 use crate::constants;
+use crate::error::{self, Error};
+use crate::limb;
+use crate::metadata::{self, RawMetadata};
+use crate::calibration::{CalibrationFrames, DarkScaling};
+use crate::cfa::CfaChannel;
+use crate::demosaic::DemosaicMethod;
+use crate::multiband::MultiBandImage;
 use crate::vprintln;
 
 extern crate image;
@@ -21,78 +29,286 @@ fn linear_to_srgb(lin:f32) -> f32 {
 }
 */
 
+// How the solar disk is located before it is shifted to the center of the frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Centering {
+    CenterOfMass(f32),
+    Limb,
+}
 
-pub fn calibrate_raw(raw_file:&str, flat:&ImageBuffer, dark:&ImageBuffer) -> Result<ImageBuffer, &'static str> {
-    let source = ImageBuffer::from_cr2(raw_file).unwrap();
+impl Centering {
+    pub fn from_name(name:&str) -> error::Result<Centering> {
+        match name.to_lowercase().as_str() {
+            constants::centering::CENTER_OF_MASS => Ok(Centering::CenterOfMass(constants::DEFAULT_CENTER_OF_MASS_THRESHOLD)),
+            constants::centering::LIMB => Ok(Centering::Limb),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_CENTERING_METHOD, name))
+        }
+    }
+}
 
-    let red = source.red().unwrap();
+// How the centered frame is cropped down to the output size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropMode {
+    // A centered crop of a fixed size
+    Size { width:usize, height:usize },
+    // An arbitrary rectangle, in the coordinates of the centered frame
+    Region { left:i64, top:i64, width:usize, height:usize },
+    // A centered square extending beyond the limb by a fraction of the detected disk radius
+    DiskMargin(f32),
+}
 
-    let mut corrected = red;
+fn parse_values<T:std::str::FromStr>(s:&str, separator:char, count:usize) -> error::Result<Vec<T>> {
+    let values:Vec<T> = s.split(separator)
+                         .map(|v| v.trim().parse::<T>())
+                         .collect::<Result<Vec<T>, _>>()
+                         .map_err(|_| Error::invalid_parameter(constants::status::INVALID_CROP_SPECIFICATION, s))?;
+    if values.len() != count {
+        return Err(Error::invalid_parameter(constants::status::INVALID_CROP_SPECIFICATION, s));
+    }
+    Ok(values)
+}
 
-    // Should support one or the other being left out
-    if !dark.is_empty() && !flat.is_empty() {
-        let darkflat = flat.subtract(&dark).unwrap();
+impl CropMode {
 
-        let mean_flat = darkflat.mean();
-        vprintln!("    Dark/Flat Mean Value: {}", mean_flat);
+    // Parses a size given as WIDTHxHEIGHT
+    pub fn from_size_str(s:&str) -> error::Result<CropMode> {
+        let values = parse_values::<usize>(&s.to_lowercase(), 'x', 2)?;
+        Ok(CropMode::Size{width:values[0], height:values[1]})
+    }
 
-        let red_minus_dark = corrected.subtract(&dark).unwrap();
+    // Parses a region given as LEFT,TOP,WIDTH,HEIGHT
+    pub fn from_region_str(s:&str) -> error::Result<CropMode> {
+        let values = parse_values::<i64>(s, ',', 4)?;
+        if values[2] <= 0 || values[3] <= 0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_CROP_SPECIFICATION, s));
+        }
+        Ok(CropMode::Region{left:values[0], top:values[1], width:values[2] as usize, height:values[3] as usize})
+    }
 
-        // Over-simplification:
-        corrected = red_minus_dark.scale(mean_flat).unwrap().divide(&flat).unwrap();
+    // Parses a margin around the disk as a fraction of its radius. Down to -1 the crop
+    // shrinks towards the disk's center; beyond that there would be nothing left.
+    pub fn from_margin_str(s:&str) -> error::Result<CropMode> {
+        match s.trim().parse::<f32>() {
+            Ok(margin) if margin.is_finite() && margin > -1.0 => Ok(CropMode::DiskMargin(margin)),
+            _ => Err(Error::invalid_parameter(constants::status::INVALID_CROP_SPECIFICATION, s))
+        }
     }
+}
 
-    let scaled = corrected.normalize(0.0, constants::_16_BIT_MAX).unwrap();
-    vprintln!("    Scaled Red-Only Buffer Width: {}", scaled.width);
-    vprintln!("    Scaled Red-Only Buffer Height: {}", scaled.height);
+// Options controlling how each light frame is calibrated and framed
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationOptions {
+    pub centering: Centering,
+    pub crop: CropMode,
+    pub channel: CfaChannel,
+    // When set, raws are converted to full resolution color instead of a single plane
+    pub demosaic: Option<DemosaicMethod>,
+    pub dark_scaling: DarkScaling,
+}
+
+impl Default for CalibrationOptions {
+    fn default() -> Self {
+        CalibrationOptions{
+            centering:Centering::CenterOfMass(constants::DEFAULT_CENTER_OF_MASS_THRESHOLD),
+            crop:CropMode::Size{width:constants::DEFAULT_CROP_WIDTH, height:constants::DEFAULT_CROP_HEIGHT},
+            channel:CfaChannel::Red,
+            demosaic:None,
+            dark_scaling:DarkScaling::None
+        }
+    }
+}
 
+// Determines the offset needed to bring the disk to the center of the image. When
+// the limb was fitted, the disk is returned as well.
+fn calc_centering_offset(image:&ImageBuffer, centering:&Centering) -> error::Result<(Offset, Option<limb::Disk>)> {
+    match *centering {
+        Centering::CenterOfMass(threshold) => {
+            let offset = image.calc_center_of_mass_offset(threshold)?;
+            vprintln!("    Horizonal center of Mass Offset: {}", offset.h);
+            vprintln!("    Vertical Center of Mass Offset: {}", offset.v);
+            Ok((offset, None))
+        },
+        Centering::Limb => {
+            let disk = limb::find_disk(image)?;
+            let offset = Offset{
+                h:(image.width as f32 / 2.0 - disk.x).round() as i32,
+                v:(image.height as f32 / 2.0 - disk.y).round() as i32
+            };
+            vprintln!("    Horizonal limb fit Offset: {}", offset.h);
+            vprintln!("    Vertical limb fit Offset: {}", offset.v);
+            Ok((offset, Some(disk)))
+        }
+    }
+}
 
-    let offset = scaled.calc_center_of_mass_offset(20000.0).unwrap();
-    vprintln!("    Horizonal center of Mass Offset: {}", offset.h);
-    vprintln!("    Vertical Center of Mass Offset: {}", offset.v);
+// Determines the region (left, top, width, height) to crop the centered frame to. The
+// disk is only located here if a margin relative to the radius was requested and
+// centering didn't already fit it.
+fn crop_bounds(image:&ImageBuffer, crop:&CropMode, disk:Option<limb::Disk>) -> error::Result<(i64, i64, usize, usize)> {
+    let centered = |width:usize, height:usize| {
+        ((image.width as i64 - width as i64) / 2, (image.height as i64 - height as i64) / 2, width, height)
+    };
 
-    let shifted = scaled.shift(offset.h, offset.v).unwrap();
-    let cropped = shifted.crop(1400, 1400).unwrap();
+    match *crop {
+        CropMode::Size{width, height} => {
+            vprintln!("    Cropping to {}x{}", width, height);
+            Ok(centered(width, height))
+        },
+        CropMode::Region{left, top, width, height} => {
+            vprintln!("    Cropping to region {},{} {}x{}", left, top, width, height);
+            Ok((left, top, width, height))
+        },
+        CropMode::DiskMargin(margin) => {
+            let disk = match disk {
+                Some(d) => d,
+                None => limb::find_disk(image)?
+            };
+            let size = (2.0 * disk.radius * (1.0 + margin)).ceil() as usize;
+            vprintln!("    Cropping to {}x{} for disk radius {} with margin {}", size, size, disk.radius, margin);
+            Ok(centered(size, size))
+        }
+    }
+}
 
-    let scaled2 = cropped.normalize(0.0, constants::_16_BIT_MAX).unwrap();
+// How a calibrated plane is framed: shifted by a whole number of pixels to center the
+// disk, then cropped to a region of the shifted plane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Framing {
+    pub h: i32,
+    pub v: i32,
+    pub left: i64,
+    pub top: i64,
+    pub width: usize,
+    pub height: usize,
+}
 
-    Ok(scaled2)
+// Finds the framing of a plane scaled to 16 bits. Centering moves the disk but doesn't
+// change its size or the plane's, so the crop is worked out on the plane as it is.
+pub fn find_framing(scaled:&ImageBuffer, options:&CalibrationOptions) -> error::Result<Framing> {
+    let (offset, disk) = calc_centering_offset(scaled, &options.centering)?;
+    let (left, top, width, height) = crop_bounds(scaled, &options.crop, disk)?;
+    Ok(Framing{h:offset.h, v:offset.v, left, top, width, height})
 }
 
-// Processes an input CR2 raw image file (Canon EOS)
-fn process_file(raw_file:&str, flat:&ImageBuffer, dark:&ImageBuffer) {
+// Extracts the requested plane from a raw and calibrates it, without rescaling or framing it
+pub fn calibrate_raw_plane(raw_file:&str, frames:&CalibrationFrames, options:&CalibrationOptions) -> error::Result<ImageBuffer> {
+    let source = ImageBuffer::from_cr2(raw_file)?;
 
-    let calibrated = calibrate_raw(raw_file, flat, dark).unwrap();
+    let plane = source.channel(options.channel)?;
 
-    let out_file = raw_file.replace("CR2", "tif").replace("cr2", "tif");
-    vprintln!("    Determined output file path to be {}", out_file);
-    calibrated.save(&out_file).expect(constants::status::OK);
+    frames.apply(&plane, options.dark_scaling)
 }
 
-pub fn run_convert(file_list:Vec<&str>, dark_file:&str, flat_file:&str) {
+pub fn calibrate_raw(raw_file:&str, frames:&CalibrationFrames, options:&CalibrationOptions) -> error::Result<ImageBuffer> {
+    let corrected = calibrate_raw_plane(raw_file, frames, options)?;
+
+    let scaled = corrected.normalize(0.0, constants::_16_BIT_MAX)?;
+    vprintln!("    Scaled {:?} Buffer Width: {}", options.channel, scaled.width);
+    vprintln!("    Scaled {:?} Buffer Height: {}", options.channel, scaled.height);
+
+    let framing = find_framing(&scaled, options)?;
 
-    vprintln!("Flat File: {}", flat_file);
-    vprintln!("Dark File: {}", dark_file);
+    let shifted = scaled.shift(framing.h, framing.v)?;
+    let cropped = shifted.crop_region(framing.left, framing.top, framing.width, framing.height)?;
 
-    if !flat_file.is_empty() && !path::file_exists(flat_file) {
-        eprintln!("Flat file not found: {}", flat_file);
-        return;
+    let scaled2 = cropped.normalize(0.0, constants::_16_BIT_MAX)?;
+
+    Ok(scaled2)
+}
+
+// Warns when the dark was taken with a different camera, ISO, exposure time or
+// temperature than the light. A scaled dark only needs the same camera and ISO.
+// Nothing is checked when either lacks metadata.
+pub fn check_dark(dark:&ImageBuffer, light_file:&str, scaling:DarkScaling) {
+    let dark_metadata = match dark.metadata() {
+        Some(m) => m,
+        None => return
+    };
+    let light_metadata = match metadata::read_file(light_file) {
+        Ok(m) => m,
+        Err(e) => {
+            vprintln!("    Unable to check dark against {}: {}", light_file, e);
+            return;
+        }
+    };
+
+    let mut mismatches = if scaling == DarkScaling::None {
+        light_metadata.mismatches(dark_metadata)
+    } else {
+        light_metadata.settings_mismatches(dark_metadata)
+    };
+    if let (DarkScaling::None, Some(light_temp), Some(dark_temp)) = (scaling, light_metadata.temperature, dark_metadata.temperature) {
+        if (light_temp - dark_temp).abs() > constants::DARK_TEMPERATURE_TOLERANCE {
+            mismatches.push(format!("temperature {}C vs {}C", light_temp, dark_temp));
+        }
     }
+    for mismatch in mismatches {
+        eprintln!("Warning: Dark does not match {}: {}", light_file, mismatch);
+    }
+}
+
+// Calibrates a raw as a whole mosaic, so the masters must be full mosaics too, then
+// demosaics it to full resolution color and centers and crops it the same way as a
+// single plane. The disk is located using the luminance. Each band keeps the raw's
+// shooting metadata.
+pub fn calibrate_raw_rgb(raw_file:&str, frames:&CalibrationFrames, options:&CalibrationOptions, method:DemosaicMethod) -> error::Result<MultiBandImage> {
+    let source = ImageBuffer::from_cr2(raw_file)?;
+
+    let corrected = frames.apply(&source, options.dark_scaling)?;
+
+    let metadata = source.metadata().map(|m| RawMetadata{cfa:None, ..m.clone()});
+    let color = corrected.demosaic(method)?.normalize(0.0, constants::_16_BIT_MAX)?.map(|band| {
+        let mut band = band.clone();
+        band.set_metadata(metadata.clone());
+        Ok(band)
+    })?;
+    vprintln!("    Demosaiced Buffer Width: {}", color.width);
+    vprintln!("    Demosaiced Buffer Height: {}", color.height);
+
+    let luminance = color.luminance()?;
+    let (offset, disk) = calc_centering_offset(&luminance, &options.centering)?;
+
+    let shifted = color.shift(offset.h, offset.v)?;
+    let (left, top, width, height) = crop_bounds(&luminance.shift(offset.h, offset.v)?, &options.crop, disk)?;
+    let cropped = shifted.crop_region(left, top, width, height)?;
 
-    if !dark_file.is_empty() && !path::file_exists(dark_file) {
-        eprintln!("Dark file not found: {}", dark_file);
-        return;
+    cropped.normalize(0.0, constants::_16_BIT_MAX)
+}
+
+// Processes an input CR2 raw image file (Canon EOS)
+fn process_file(raw_file:&str, frames:&CalibrationFrames, options:&CalibrationOptions, format:OutputFormat) -> error::Result<()> {
+
+    let out_file = raw_file.replace("CR2", "tif").replace("cr2", "tif");
+    vprintln!("    Determined output file path to be {}", out_file);
+
+    if let Some(method) = options.demosaic {
+        let color = calibrate_raw_rgb(raw_file, frames, options, method)?;
+        return color.save_as(&out_file, format);
     }
 
-    let flat = if flat_file.is_empty() { ImageBuffer::new_empty().unwrap() } else { ImageBuffer::from_file(flat_file).unwrap() };
-    let dark = if dark_file.is_empty() { ImageBuffer::new_empty().unwrap() } else { ImageBuffer::from_file(dark_file).unwrap() };
+    let calibrated = calibrate_raw(raw_file, frames, options)?;
+    calibrated.save_as(&out_file, format)
+}
+
+// Converts each of the raws. A file that fails to convert is reported and skipped
+// so one bad frame doesn't abort the rest of the batch.
+pub fn run_convert(file_list:Vec<&str>, frames:&CalibrationFrames, options:&CalibrationOptions, format:OutputFormat) -> error::Result<()> {
+
+    vprintln!("Calibration: {}", frames.corrections(options.dark_scaling).join(", "));
+    if let (Some(dark), Some(first)) = (frames.dark(), file_list.iter().find(|f| path::file_exists(f))) {
+        check_dark(dark, first, options.dark_scaling);
+    }
 
-    for in_file in file_list.iter() {
+    parallel::map_items(&file_list, |in_file| {
         if path::file_exists(in_file) {
             vprintln!("Processing File: {}", in_file);
-            process_file(&in_file, &flat, &dark);
+            if let Err(e) = process_file(in_file, frames, options, format) {
+                eprintln!("Error processing {}: {}", in_file, e);
+            }
         } else {
             eprintln!("File not found: {}", in_file);
         }
-    }
-}
\ No newline at end of file
+    });
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use crate::imagebuffer::{ImageBuffer, SubpixelOffset};
+use crate::interpolation::Interpolation;
+use crate::fft::{self, Complex};
+use crate::error::{self, Error};
+use crate::vprintln;
+
+use std::f64::consts::PI;
+
+// Hann window value for position i of n
+fn hann(i:usize, n:usize) -> f64 {
+    if n <= 1 {
+        return 1.0;
+    }
+    0.5 - 0.5 * (2.0 * PI * i as f64 / (n - 1) as f64).cos()
+}
+
+// Copies the image into a zero padded, power-of-two sized complex buffer after
+// removing the mean and applying a Hann window to suppress edge effects.
+fn to_windowed_complex(image:&ImageBuffer, fft_width:usize, fft_height:usize) -> error::Result<Vec<Complex>> {
+    let mut data:Vec<Complex> = vec![Complex::zero(); fft_width * fft_height];
+
+    let mut total = 0.0;
+    for y in 0..image.height {
+        for x in 0..image.width {
+            total += image.get(x, y)? as f64;
+        }
+    }
+    let mean = total / (image.width * image.height) as f64;
+
+    for y in 0..image.height {
+        let wy = hann(y, image.height);
+        for x in 0..image.width {
+            let wx = hann(x, image.width);
+            let value = (image.get(x, y)? as f64 - mean) * wx * wy;
+            data[y * fft_width + x] = Complex::new(value, 0.0);
+        }
+    }
+
+    Ok(data)
+}
+
+// Fits a parabola through three samples and returns the fractional peak position
+// relative to the center sample.
+fn parabolic_peak(left:f64, center:f64, right:f64) -> f64 {
+    let denom = left - 2.0 * center + right;
+    if denom.abs() < f64::EPSILON {
+        return 0.0;
+    }
+    let p = 0.5 * (left - right) / denom;
+    p.clamp(-0.5, 0.5)
+}
+
+// Determines the sub-pixel translation of image relative to reference using phase
+// correlation. The returned offset is the shift that, when applied to image,
+// aligns it with the reference.
+pub fn phase_correlate(reference:&ImageBuffer, image:&ImageBuffer) -> error::Result<SubpixelOffset> {
+    if reference.width != image.width || reference.height != image.height {
+        return Err(Error::DimensionMismatch{expected:(reference.width, reference.height), found:(image.width, image.height)});
+    }
+
+    if reference.width == 0 || reference.height == 0 {
+        return Err(Error::EmptyImage);
+    }
+
+    let fft_width = fft::next_pow2(reference.width);
+    let fft_height = fft::next_pow2(reference.height);
+
+    let mut ref_data = to_windowed_complex(reference, fft_width, fft_height)?;
+    let mut img_data = to_windowed_complex(image, fft_width, fft_height)?;
+
+    fft::fft2d(&mut ref_data, fft_width, fft_height, false);
+    fft::fft2d(&mut img_data, fft_width, fft_height, false);
+
+    // Normalized cross power spectrum
+    for (r, i) in ref_data.iter_mut().zip(img_data.iter()) {
+        let cross = r.multiply(&i.conj());
+        let mag = cross.norm();
+        *r = if mag > 0.0 { Complex::new(cross.re / mag, cross.im / mag) } else { Complex::zero() };
+    }
+
+    fft::fft2d(&mut ref_data, fft_width, fft_height, true);
+
+    let mut peak_idx = 0;
+    let mut peak_val = f64::MIN;
+    for (i, c) in ref_data.iter().enumerate() {
+        if c.re > peak_val {
+            peak_val = c.re;
+            peak_idx = i;
+        }
+    }
+
+    let px = peak_idx % fft_width;
+    let py = peak_idx / fft_width;
+
+    let at = |x:usize, y:usize| -> f64 { ref_data[(y % fft_height) * fft_width + (x % fft_width)].re };
+    let dx = parabolic_peak(at(px + fft_width - 1, py), peak_val, at(px + 1, py));
+    let dy = parabolic_peak(at(px, py + fft_height - 1), peak_val, at(px, py + 1));
+
+    // Peaks past the halfway point wrap around to negative shifts
+    let mut h = px as f64 + dx;
+    let mut v = py as f64 + dy;
+    if h > fft_width as f64 / 2.0 {
+        h -= fft_width as f64;
+    }
+    if v > fft_height as f64 / 2.0 {
+        v -= fft_height as f64;
+    }
+
+    vprintln!("    Phase correlation offset: {:.3}, {:.3} (peak {:.4})", h, v, peak_val);
+
+    Ok(SubpixelOffset{h:h as f32, v:v as f32, peak:peak_val as f32})
+}
+
+// Registers image against the reference and returns the resampled, aligned image.
+pub fn register(reference:&ImageBuffer, image:&ImageBuffer, method:Interpolation) -> error::Result<ImageBuffer> {
+    let offset = phase_correlate(reference, image)?;
+    image.shift_subpixel(offset.h, offset.v, method)
+}
@@ -0,0 +1,122 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::interpolation::Interpolation;
+use crate::datetime::{self, DateTime};
+use crate::fieldrotation::{self, Equatorial};
+use crate::constants;
+use crate::error;
+use crate::vprintln;
+
+// Sin and cos of an angle in degrees
+fn sin_d(degrees:f64) -> f64 {
+    degrees.to_radians().sin()
+}
+
+fn cos_d(degrees:f64) -> f64 {
+    degrees.to_radians().cos()
+}
+
+// The Sun's place in ecliptic coordinates (Meeus, Astronomical Algorithms, chapter 25,
+// accurate to about 0.01 degree). Angles are in degrees.
+struct EclipticPosition {
+    // Apparent longitude, corrected for aberration but not nutation
+    longitude: f64,
+    // Apparent longitude, corrected for both aberration and nutation
+    apparent_longitude: f64,
+    // True obliquity of the ecliptic
+    obliquity: f64,
+    // Distance in AU
+    distance: f64,
+}
+
+fn ecliptic_position(jd:f64) -> EclipticPosition {
+    let t = datetime::centuries_since_j2000(jd);
+    let mean_longitude = 280.466_46 + 36_000.769_83 * t + 0.000_303_2 * t * t;
+    let mean_anomaly = 357.529_11 + 35_999.050_29 * t - 0.000_153_7 * t * t;
+    let eccentricity = 0.016_708_634 - 0.000_042_037 * t - 0.000_000_126_7 * t * t;
+    let center = (1.914_602 - 0.004_817 * t - 0.000_014 * t * t) * sin_d(mean_anomaly)
+               + (0.019_993 - 0.000_101 * t) * sin_d(2.0 * mean_anomaly)
+               + 0.000_289 * sin_d(3.0 * mean_anomaly);
+    let true_longitude = mean_longitude + center;
+    let true_anomaly = mean_anomaly + center;
+    let distance = 1.000_001_018 * (1.0 - eccentricity * eccentricity) / (1.0 + eccentricity * cos_d(true_anomaly));
+
+    // Longitude of the Moon's ascending node, which drives the largest nutation terms
+    let omega = 125.04 - 1_934.136 * t;
+    let mean_obliquity = 23.439_291_111 - (46.815_0 * t + 0.000_59 * t * t - 0.001_813 * t * t * t) / 3600.0;
+    let longitude = (true_longitude - 0.005_69).rem_euclid(360.0);
+    EclipticPosition{
+        longitude,
+        apparent_longitude:(longitude - 0.004_78 * sin_d(omega)).rem_euclid(360.0),
+        obliquity:mean_obliquity + 0.002_56 * cos_d(omega),
+        distance
+    }
+}
+
+// Apparent right ascension and declination of the Sun at a Julian date in UT
+pub fn position(jd:f64) -> Equatorial {
+    let sun = ecliptic_position(jd);
+    let ra = (cos_d(sun.obliquity) * sin_d(sun.apparent_longitude)).atan2(cos_d(sun.apparent_longitude)).to_degrees();
+    let dec = (sin_d(sun.obliquity) * sin_d(sun.apparent_longitude)).asin().to_degrees();
+    Equatorial{ra:ra.rem_euclid(360.0), dec}
+}
+
+// Orientation and size of the solar disk as seen from Earth. Angles are in degrees.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SolarEphemeris {
+    // Position angle of the northern rotation axis, measured eastward from celestial north
+    pub p: f64,
+    // Heliographic latitude of the center of the disk
+    pub b0: f64,
+    // Carrington longitude of the center of the disk
+    pub l0: f64,
+    // Apparent radius of the disk in arcseconds
+    pub semi_diameter: f64,
+    // Distance to the Sun in AU
+    pub distance: f64,
+}
+
+impl SolarEphemeris {
+    // Ephemeris for physical observations of the Sun at a Julian date in UT (Meeus, chapter 29)
+    pub fn at_julian_date(jd:f64) -> SolarEphemeris {
+        let sun = ecliptic_position(jd);
+
+        // Carrington rotation angle, and the node and inclination of the solar equator
+        let theta = (jd - 2_398_220.0) * 360.0 / 25.38;
+        let inclination = 7.25_f64;
+        let node = 73.666_7 + 1.395_833_3 * (jd - 2_396_758.0) / 36_525.0;
+
+        let x = (-cos_d(sun.apparent_longitude) * sun.obliquity.to_radians().tan()).atan().to_degrees();
+        let y = (-cos_d(sun.longitude - node) * inclination.to_radians().tan()).atan().to_degrees();
+        let b0 = (sin_d(sun.longitude - node) * sin_d(inclination)).asin().to_degrees();
+        let eta = (-sin_d(sun.longitude - node) * cos_d(inclination)).atan2(-cos_d(sun.longitude - node)).to_degrees();
+
+        SolarEphemeris{
+            p:x + y,
+            b0,
+            l0:(eta - theta).rem_euclid(360.0),
+            semi_diameter:constants::SOLAR_SEMI_DIAMETER_AT_1AU / sun.distance,
+            distance:sun.distance
+        }
+    }
+
+    // Ephemeris at a UTC date and time
+    pub fn at(time:&DateTime) -> SolarEphemeris {
+        SolarEphemeris::at_julian_date(time.julian_date())
+    }
+
+    // Apparent radius of the disk in pixels for a plate scale in arcseconds per pixel
+    pub fn radius_pixels(&self, arcsec_per_pixel:f64) -> f64 {
+        self.semi_diameter / arcsec_per_pixel
+    }
+}
+
+// Rotates an image so solar north is up. celestial_north gives the direction of
+// celestial north in the image, in degrees clockwise from up: zero for a camera
+// aligned on an equatorial mount, or the parallactic angle for a level camera on an
+// alt-az mount. With celestial north up east is to the left, so solar north lies
+// P degrees counterclockwise from it.
+pub fn north_up(image:&ImageBuffer, ephemeris:&SolarEphemeris, celestial_north:f64, method:Interpolation) -> error::Result<ImageBuffer> {
+    let angle = fieldrotation::wrap_degrees(celestial_north - ephemeris.p);
+    vprintln!("    Rotating {:.3} degrees to put solar north up (P = {:.3})", angle, ephemeris.p);
+    image.rotate(angle as f32, method)
+}
@@ -0,0 +1,508 @@
+use crate::imagebuffer::ImageBuffer;
+use crate::multiband::MultiBandImage;
+use crate::metadata::RawMetadata;
+use crate::constants;
+use crate::path;
+use crate::error::{self, Error};
+use crate::vprintln;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Per-pixel combination method used when reducing a stack of frames
+// to a single master frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StackMethod {
+    Mean,
+    Median,
+    SigmaClip { kappa:f32, iterations:usize },
+    Winsorized { kappa:f32, iterations:usize },
+}
+
+impl StackMethod {
+
+    // Parses a method name as supplied on the command line. The kappa and
+    // iteration count only apply to the sigma clipped and winsorized methods.
+    pub fn from_name(method:&str, kappa:f32, iterations:usize) -> error::Result<StackMethod> {
+        match method.to_lowercase().as_str() {
+            constants::stacking::METHOD_MEAN => Ok(StackMethod::Mean),
+            constants::stacking::METHOD_MEDIAN => Ok(StackMethod::Median),
+            constants::stacking::METHOD_SIGMA_CLIP => Ok(StackMethod::SigmaClip{kappa, iterations}),
+            constants::stacking::METHOD_WINSORIZED => Ok(StackMethod::Winsorized{kappa, iterations}),
+            _ => Err(Error::invalid_parameter(constants::status::UNKNOWN_STACK_METHOD, method))
+        }
+    }
+}
+
+// A pixel value from one frame along with that frame's weight
+pub type Sample = (f32, f32);
+
+fn weight_of(samples:&[Sample]) -> f32 {
+    samples.iter().map(|s| s.1).sum()
+}
+
+fn mean_of(samples:&[Sample]) -> f32 {
+    let total_weight = weight_of(samples);
+    if total_weight <= 0.0 {
+        return 0.0;
+    }
+    samples.iter().map(|s| s.0 * s.1).sum::<f32>() / total_weight
+}
+
+// Computes the weighted median of the samples. The slice will be reordered. When
+// the cumulative weight lands exactly on the halfway point the two neighboring
+// values are averaged, so equal weights give the usual median.
+fn median_of(samples:&mut [Sample]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let half = weight_of(samples) / 2.0;
+    let mut cumulative = 0.0;
+    for i in 0..samples.len() {
+        cumulative += samples[i].1;
+        if (cumulative - half).abs() <= f32::EPSILON * half.max(1.0) && i + 1 < samples.len() {
+            return (samples[i].0 + samples[i + 1].0) / 2.0;
+        }
+        if cumulative > half {
+            return samples[i].0;
+        }
+    }
+    samples[samples.len() - 1].0
+}
+
+// Iteratively rejects values further than kappa standard deviations from the mean of
+// the other remaining values, then returns the mean of what's left. Measured against
+// the others, a lone outlier can't inflate the deviation enough to hide in a small
+// stack. The deviation is floored so values a count apart aren't rejected.
+fn sigma_clip_of(samples:&mut Vec<Sample>, kappa:f32, iterations:usize) -> f32 {
+    for _ in 0..iterations {
+        if samples.len() < 3 {
+            break;
+        }
+        let (mut w, mut s, mut q) = (0.0_f64, 0.0_f64, 0.0_f64);
+        for (v, weight) in samples.iter().map(|s| (s.0 as f64, s.1 as f64)) {
+            w += weight;
+            s += weight * v;
+            q += weight * v * v;
+        }
+        let rejected = |sample:&Sample| {
+            let (v, weight) = (sample.0 as f64, sample.1 as f64);
+            let others = w - weight;
+            if others <= 0.0 {
+                return false;
+            }
+            let mean = (s - weight * v) / others;
+            let sigma = ((q - weight * v * v) / others - mean * mean).max(0.0).sqrt().max(constants::MIN_CLIP_SIGMA as f64);
+            (v - mean).abs() > kappa as f64 * sigma
+        };
+        let kept:Vec<Sample> = samples.iter().filter(|s| !rejected(s)).cloned().collect();
+        if kept.len() == samples.len() {
+            break;
+        }
+        *samples = kept;
+    }
+    mean_of(samples)
+}
+
+// Robust estimate of the standard deviation from the median absolute deviation.
+fn mad_sigma_of(samples:&[Sample], center:f32) -> f32 {
+    let mut deviations:Vec<Sample> = samples.iter().map(|s| ((s.0 - center).abs(), s.1)).collect();
+    1.4826 * median_of(&mut deviations)
+}
+
+// Iteratively replaces values further than kappa (robust) standard deviations from
+// the median with the boundary value, then returns the mean of the winsorized set.
+fn winsorized_of(samples:&mut [Sample], kappa:f32, iterations:usize) -> f32 {
+    for _ in 0..iterations {
+        if samples.len() < 3 {
+            break;
+        }
+        // Floored, since with integer data most values often equal the median, leaving no
+        // deviation and clamping the set to the median
+        let center = median_of(samples);
+        let sigma = mad_sigma_of(samples, center).max(constants::MIN_CLIP_SIGMA);
+        let low = center - kappa * sigma;
+        let high = center + kappa * sigma;
+
+        let mut changed = false;
+        for s in samples.iter_mut() {
+            if s.0 < low {
+                s.0 = low;
+                changed = true;
+            } else if s.0 > high {
+                s.0 = high;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    mean_of(samples)
+}
+
+// Reduces the weighted values of a single pixel across the stack
+pub fn combine_samples(samples:&mut Vec<Sample>, method:&StackMethod) -> f32 {
+    match *method {
+        StackMethod::Mean => mean_of(samples),
+        StackMethod::Median => median_of(samples),
+        StackMethod::SigmaClip{kappa, iterations} => sigma_clip_of(samples, kappa, iterations),
+        StackMethod::Winsorized{kappa, iterations} => winsorized_of(samples, kappa, iterations),
+    }
+}
+
+// Reduces the values of a single pixel across the stack
+pub fn combine_values(values:&[f32], method:&StackMethod) -> f32 {
+    let mut samples:Vec<Sample> = values.iter().map(|v| (*v, 1.0)).collect();
+    combine_samples(&mut samples, method)
+}
+
+// Kish's effective sample size, (sum of w)^2 / sum of w^2. Equals the number of
+// frames when the weights are equal and falls as they become more uneven.
+pub fn effective_count(weights:&[f32]) -> f32 {
+    let sum:f32 = weights.iter().sum();
+    let sum_sq:f32 = weights.iter().map(|w| w * w).sum();
+    if sum_sq > 0.0 { sum * sum / sum_sq } else { 0.0 }
+}
+
+// Combines a list of equally sized frames into a single frame using the requested method
+pub fn combine(frames:&[ImageBuffer], method:&StackMethod) -> error::Result<ImageBuffer> {
+    combine_weighted(frames, &vec![1.0; frames.len()], method)
+}
+
+// Combines frames with each contributing in proportion to its weight
+pub fn combine_weighted(frames:&[ImageBuffer], weights:&[f32], method:&StackMethod) -> error::Result<ImageBuffer> {
+    if frames.is_empty() {
+        return Err(Error::NoFilesUsed);
+    }
+    if weights.len() != frames.len() {
+        return Err(Error::invalid_parameter(constants::status::WEIGHT_COUNT_MISMATCH, &format!("{} weights for {} frames", weights.len(), frames.len())));
+    }
+
+    let width = frames[0].width;
+    let height = frames[0].height;
+
+    for frame in frames.iter() {
+        if frame.width != width || frame.height != height {
+            return Err(Error::DimensionMismatch{expected:(width, height), found:(frame.width, frame.height)});
+        }
+    }
+
+    vprintln!("    Combining {} frames using {:?}", frames.len(), method);
+
+    let mut dest = ImageBuffer::new(width, height)?;
+    let mut samples:Vec<Sample> = Vec::with_capacity(frames.len());
+
+    for y in 0..height {
+        for x in 0..width {
+            samples.clear();
+            for (frame, weight) in frames.iter().zip(weights.iter()) {
+                samples.push((frame.get(x, y)?, *weight));
+            }
+            dest.put(x, y, combine_samples(&mut samples, method))?;
+        }
+    }
+
+    Ok(dest)
+}
+
+// Combines multi-band frames one band at a time
+pub fn combine_bands(frames:&[MultiBandImage], method:&StackMethod) -> error::Result<MultiBandImage> {
+    if frames.is_empty() {
+        return Err(Error::NoFilesUsed);
+    }
+
+    let mut bands:Vec<ImageBuffer> = Vec::with_capacity(frames[0].num_bands());
+    for band in 0..frames[0].num_bands() {
+        let planes:Vec<ImageBuffer> = frames.iter().map(|frame| frame.band(band).cloned()).collect::<error::Result<Vec<ImageBuffer>>>()?;
+        bands.push(combine(&planes, method)?);
+    }
+    MultiBandImage::merge(bands)
+}
+
+// Per-frame weights keyed by file path. A frame is looked up by its full path
+// first, then by file name alone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameWeights {
+    weights: Vec<(String, f32)>,
+}
+
+fn file_name_of(path:&str) -> &str {
+    std::path::Path::new(path).file_name().and_then(|n| n.to_str()).unwrap_or(path)
+}
+
+impl FrameWeights {
+    pub fn from_pairs(weights:Vec<(String, f32)>) -> error::Result<FrameWeights> {
+        if let Some((name, w)) = weights.iter().find(|(_, w)| !w.is_finite() || *w < 0.0) {
+            return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, &format!("{}: {}", name, w)));
+        }
+        Ok(FrameWeights{weights})
+    }
+
+    // Reads weights from a text file of FILE,WEIGHT lines. Blank lines, lines
+    // starting with # and a header line without a numeric weight are skipped.
+    pub fn from_file(file_path:&str) -> error::Result<FrameWeights> {
+        if !path::file_exists(file_path) {
+            return Err(Error::FileNotFound(String::from(file_path)));
+        }
+        let text = std::fs::read_to_string(file_path).map_err(|e| Error::io(file_path, e))?;
+
+        let mut weights:Vec<(String, f32)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, weight) = match line.rfind(',') {
+                Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+                None => return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, line))
+            };
+            match weight.parse::<f32>() {
+                Ok(w) => weights.push((name.trim_matches('"').to_string(), w)),
+                Err(_) if i == 0 => continue,
+                Err(_) => return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, line))
+            }
+        }
+        FrameWeights::from_pairs(weights)
+    }
+
+    pub fn weight_of(&self, name:&str) -> Option<f32> {
+        self.weights.iter().find(|(n, _)| n == name)
+            .or_else(|| self.weights.iter().find(|(n, _)| file_name_of(n) == file_name_of(name)))
+            .map(|(_, w)| *w)
+    }
+
+    // Weight of the frame, or 1 if it isn't listed
+    pub fn weight_or_default(&self, name:&str) -> f32 {
+        match self.weight_of(name) {
+            Some(w) => w,
+            None => {
+                eprintln!("No weight given for {}, using 1", name);
+                1.0
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+}
+
+// What went into a stack
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StackSummary {
+    // Name and weight of each frame used, in the order they were added
+    pub frames: Vec<(String, f32)>,
+    // Metadata of the first frame, recorded as the provenance of the stack
+    pub metadata: Option<RawMetadata>,
+}
+
+impl StackSummary {
+    // Records a frame, warning when its metadata doesn't match the first frame's
+    pub fn add(&mut self, name:&str, weight:f32, metadata:Option<&RawMetadata>) {
+        match (&self.metadata, metadata) {
+            (Some(first), Some(m)) => {
+                for mismatch in first.mismatches(m) {
+                    eprintln!("Warning: {} does not match the first frame: {}", name, mismatch);
+                }
+            },
+            (None, Some(m)) if self.frames.is_empty() => self.metadata = Some(m.clone()),
+            _ => {}
+        }
+        self.frames.push((String::from(name), weight));
+    }
+
+    pub fn count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn effective_count(&self) -> f32 {
+        effective_count(&self.frames.iter().map(|(_, w)| *w).collect::<Vec<f32>>())
+    }
+
+    pub fn is_weighted(&self) -> bool {
+        self.frames.iter().any(|(_, w)| *w != 1.0)
+    }
+
+    pub fn print_weights(&self) {
+        if self.is_weighted() {
+            for (name, w) in self.frames.iter() {
+                vprintln!("    Weight {}: {}", name, w);
+            }
+        }
+        vprintln!("    Effective frame count: {} of {}", self.effective_count(), self.count());
+    }
+}
+
+// Frames written to a scratch file one after another, each as its pixels in native byte
+// order, so they can be read back a band of rows at a time. The file is removed on drop.
+struct FrameSpill {
+    path: String,
+    file: File,
+    width: usize,
+    height: usize,
+    count: usize,
+}
+
+impl FrameSpill {
+    fn create(width:usize, height:usize) -> error::Result<FrameSpill> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("stack_{}_{}.tmp", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name).to_string_lossy().to_string();
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path).map_err(|e| Error::io(&path, e))?;
+        Ok(FrameSpill{path, file, width, height, count:0})
+    }
+
+    fn push(&mut self, frame:&ImageBuffer) -> error::Result<()> {
+        let bytes:Vec<u8> = frame.pixels().iter().flat_map(|v| v.to_ne_bytes()).collect();
+        self.file.seek(SeekFrom::End(0)).and_then(|_| self.file.write_all(&bytes)).map_err(|e| Error::io(&self.path, e))?;
+        self.count += 1;
+        Ok(())
+    }
+
+    // Values of the given rows in each frame, in the order the frames were pushed
+    fn read_rows(&mut self, top:usize, rows:usize) -> error::Result<Vec<Vec<f32>>> {
+        let frame_len = self.width * self.height;
+        let mut bytes = vec![0_u8; rows * self.width * 4];
+        let mut frames = Vec::with_capacity(self.count);
+        for i in 0..self.count {
+            let start = ((i * frame_len + top * self.width) * 4) as u64;
+            self.file.seek(SeekFrom::Start(start)).and_then(|_| self.file.read_exact(&mut bytes)).map_err(|e| Error::io(&self.path, e))?;
+            frames.push(bytes.chunks_exact(4).map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])).collect());
+        }
+        Ok(frames)
+    }
+
+    // Combines the spilled frames a band of rows at a time, reading only as many rows as
+    // fit the memory budget
+    fn combine_weighted(&mut self, weights:&[f32], method:&StackMethod) -> error::Result<ImageBuffer> {
+        let band_rows = (constants::STACK_READ_BUDGET_BYTES / (self.width * self.count * 4).max(1)).clamp(1, self.height.max(1));
+        vprintln!("    Combining {} frames using {:?}, {} rows at a time", self.count, method, band_rows);
+
+        let mut dest = ImageBuffer::new(self.width, self.height)?;
+        let mut samples:Vec<Sample> = Vec::with_capacity(self.count);
+        for top in (0..self.height).step_by(band_rows) {
+            let rows = band_rows.min(self.height - top);
+            let frames = self.read_rows(top, rows)?;
+            for i in 0..rows * self.width {
+                samples.clear();
+                for (frame, weight) in frames.iter().zip(weights.iter()) {
+                    samples.push((frame[i], *weight));
+                }
+                dest.put(i % self.width, top + i / self.width, combine_samples(&mut samples, method))?;
+            }
+        }
+        Ok(dest)
+    }
+}
+
+impl Drop for FrameSpill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Combines frames as they arrive. The mean is kept as a running weighted sum so
+// only a single frame's worth of memory is needed however many frames are added.
+// The other methods need every value of a pixel at once. They hold the first frames
+// in memory and move them to a scratch file once there are more than
+// constants::STACK_FRAMES_IN_MEMORY, which finish reads back a band of rows at a time.
+pub struct StackAccumulator {
+    method: StackMethod,
+    sum: Option<ImageBuffer>,
+    frames: Vec<ImageBuffer>,
+    spill: Option<FrameSpill>,
+    weights: Vec<f32>,
+}
+
+impl StackAccumulator {
+    pub fn new(method:&StackMethod) -> StackAccumulator {
+        StackAccumulator{method:*method, sum:None, frames:Vec::new(), spill:None, weights:Vec::new()}
+    }
+
+    // Whether frames are combined as they are added rather than held
+    pub fn is_streaming(&self) -> bool {
+        self.method == StackMethod::Mean
+    }
+
+    // Whether held frames have been moved out of memory to a scratch file
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    pub fn add(&mut self, frame:ImageBuffer) -> error::Result<()> {
+        self.add_weighted(frame, 1.0)
+    }
+
+    pub fn add_weighted(&mut self, frame:ImageBuffer, weight:f32) -> error::Result<()> {
+        if !weight.is_finite() || weight < 0.0 {
+            return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, &weight.to_string()));
+        }
+        if self.is_streaming() {
+            let weighted = if weight != 1.0 { frame.scale(weight)? } else { frame };
+            let sum = match &self.sum {
+                Some(sum) => sum.add(&weighted)?,
+                None => weighted
+            };
+            self.sum = Some(sum);
+        } else {
+            let size = match (&self.spill, self.frames.first()) {
+                (Some(spill), _) => Some((spill.width, spill.height)),
+                (None, Some(first)) => Some((first.width, first.height)),
+                (None, None) => None
+            };
+            if let Some((width, height)) = size {
+                if width != frame.width || height != frame.height {
+                    return Err(Error::DimensionMismatch{expected:(width, height), found:(frame.width, frame.height)});
+                }
+            }
+            match &mut self.spill {
+                Some(spill) => spill.push(&frame)?,
+                None => self.frames.push(frame)
+            }
+            if self.spill.is_none() && self.frames.len() > constants::STACK_FRAMES_IN_MEMORY {
+                let mut spill = FrameSpill::create(self.frames[0].width, self.frames[0].height)?;
+                vprintln!("    Moving {} held frames to {}", self.frames.len(), spill.path);
+                for held in self.frames.drain(..) {
+                    spill.push(&held)?;
+                }
+                self.spill = Some(spill);
+            }
+        }
+        self.weights.push(weight);
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn finish(mut self) -> error::Result<ImageBuffer> {
+        if self.weights.is_empty() {
+            return Err(Error::NoFilesUsed);
+        }
+        if let Some(spill) = &mut self.spill {
+            return spill.combine_weighted(&self.weights, &self.method);
+        }
+        match self.sum {
+            Some(sum) => {
+                let total:f32 = self.weights.iter().sum();
+                if total <= 0.0 {
+                    return Err(Error::invalid_parameter(constants::status::INVALID_WEIGHT, &total.to_string()));
+                }
+                sum.scale(1.0 / total)
+            },
+            None => combine_weighted(&self.frames, &self.weights, &self.method)
+        }
+    }
+}
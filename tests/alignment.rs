@@ -0,0 +1,120 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::alignment::{self, AlignmentOptions, LocalStacker};
+use cr2_to_tiff_halpha::batch::{self, BatchOptions};
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::registration;
+use cr2_to_tiff_halpha::stacking::{self, StackMethod};
+use cr2_to_tiff_halpha::error::Error;
+
+const SIZE : usize = 128;
+
+fn scene(x:f32, y:f32) -> f32 {
+    let r = ((x - 64.0).powi(2) + (y - 64.0).powi(2)).sqrt();
+    let disk = 0.5 - 0.5 * ((r - 44.0) / 1.5).tanh();
+    disk * (1000.0 + 200.0 * (x / 4.0).sin() * (y / 5.0).cos() + 100.0 * ((x + y) / 6.0).sin())
+}
+
+// Renders the scene with each pixel displaced by the given field
+fn render<F>(displacement:F) -> ImageBuffer
+    where F: Fn(f32, f32) -> (f32, f32) {
+    let mut image = ImageBuffer::new(SIZE, SIZE).unwrap();
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let (dx, dy) = displacement(x as f32, y as f32);
+            image.put(x, y, scene(x as f32 - dx, y as f32 - dy)).unwrap();
+        }
+    }
+    image
+}
+
+fn options() -> AlignmentOptions {
+    AlignmentOptions{interpolation:Interpolation::Bicubic, ..AlignmentOptions::with_box_size(24).unwrap()}
+}
+
+// Mean absolute error against the undistorted scene over the middle of the disk
+fn disk_error(image:&ImageBuffer) -> f32 {
+    let truth = render(|_, _| (0.0, 0.0));
+    let mut err = 0.0;
+    let mut n = 0;
+    for y in 40..88 {
+        for x in 40..88 {
+            err += (image.get(x, y).unwrap() - truth.get(x, y).unwrap()).abs();
+            n += 1;
+        }
+    }
+    err / n as f32
+}
+
+#[test]
+fn points_cover_the_disk() {
+    let reference = render(|_, _| (0.0, 0.0));
+    let points = alignment::place_points(&reference, &options()).unwrap();
+    assert!(points.len() > 10);
+    for p in points.iter() {
+        let r = ((p.x as f32 - 64.0).powi(2) + (p.y as f32 - 64.0).powi(2)).sqrt();
+        assert!(r <= 46.0, "{:?}", p);
+    }
+
+    assert!(AlignmentOptions::with_box_size(4).is_err());
+    assert!(matches!(alignment::place_points(&ImageBuffer::new_empty().unwrap(), &options()), Err(Error::EmptyImage)));
+}
+
+#[test]
+fn measures_local_offsets() {
+    let reference = render(|_, _| (0.0, 0.0));
+    let stacker = LocalStacker::new(reference, &options()).unwrap();
+    let shifted = render(|_, _| (1.5, -1.0));
+    let offsets = stacker.measure(&shifted);
+    assert_eq!(offsets.len(), stacker.points().len());
+    // Boxes with little structure in one direction can be off, but most should be close
+    let close = offsets.iter().filter(|o| {
+        let o = o.unwrap();
+        (o.h + 1.5).abs() < 0.25 && (o.v - 1.0).abs() < 0.25
+    }).count();
+    assert!(close as f32 >= 0.9 * offsets.len() as f32, "{} of {}", close, offsets.len());
+}
+
+#[test]
+fn local_alignment_corrects_warping() {
+    // Seeing distorts each frame differently across the disk
+    let warps:Vec<f32> = vec![0.0, 2.0, -2.0, 1.5, -1.5];
+    let frames:Vec<ImageBuffer> = warps.iter().map(|a| {
+        let a = *a;
+        render(move |x, y| (a * ((x - 64.0) / 30.0).tanh(), -a * ((y - 64.0) / 30.0).tanh()))
+    }).collect();
+
+    let mut stacker = LocalStacker::new(frames[0].clone(), &options()).unwrap();
+    for frame in frames.iter() {
+        stacker.add(frame).unwrap();
+    }
+    assert_eq!(stacker.count(), 5);
+    let local = stacker.finish().unwrap();
+
+    let registered:Vec<ImageBuffer> = frames.iter().map(|f| registration::register(&frames[0], f, Interpolation::Bicubic).unwrap()).collect();
+    let global = stacking::combine(&registered, &StackMethod::Mean).unwrap();
+
+    let local_err = disk_error(&local);
+    let global_err = disk_error(&global);
+    assert!(local_err < global_err * 0.6, "local {} vs global {}", local_err, global_err);
+}
+
+#[test]
+fn identical_frames_stack_unchanged() {
+    let frame = render(|_, _| (0.0, 0.0));
+    let items:Vec<usize> = (0..4).collect();
+    let batch_options = BatchOptions{batch_size:2, ..Default::default()};
+    let (stack, summary) = batch::stack_local_with(&items, |i| {
+        if *i == 1 { Err(Error::EmptyImage) } else { Ok(frame.clone()) }
+    }, None, &options(), &batch_options).unwrap();
+
+    assert_eq!(summary.count(), 3);
+    assert!(disk_error(&stack) < 0.5, "{}", disk_error(&stack));
+}
+
+#[test]
+fn mismatched_frames_rejected() {
+    let mut stacker = LocalStacker::new(render(|_, _| (0.0, 0.0)), &options()).unwrap();
+    assert!(matches!(stacker.add(&ImageBuffer::new(64, 64).unwrap()), Err(Error::DimensionMismatch{..})));
+    assert!(stacker.add_weighted(&render(|_, _| (0.0, 0.0)), -1.0).is_err());
+    assert!(matches!(stacker.finish(), Err(Error::NoFilesUsed)));
+}
@@ -0,0 +1,119 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::batch::{self, BatchOptions};
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::stacking::{self, StackAccumulator, StackMethod};
+use cr2_to_tiff_halpha::constants;
+use cr2_to_tiff_halpha::error::Error;
+
+mod common;
+use common::constant;
+
+#[test]
+fn accumulator_streams_the_mean() {
+    let mut accumulator = StackAccumulator::new(&StackMethod::Mean);
+    assert!(accumulator.is_streaming());
+    for v in [1.0, 2.0, 6.0].iter() {
+        accumulator.add(constant(2, 2, *v)).unwrap();
+    }
+    assert!(accumulator.add(ImageBuffer::new(3, 3).unwrap()).is_err());
+    assert_eq!(accumulator.count(), 3);
+    assert_eq!(accumulator.finish().unwrap().get(1, 1).unwrap(), 3.0);
+
+    assert!(matches!(StackAccumulator::new(&StackMethod::Mean).finish(), Err(Error::NoFilesUsed)));
+}
+
+#[test]
+fn accumulator_holds_frames_for_median() {
+    let mut accumulator = StackAccumulator::new(&StackMethod::Median);
+    assert!(!accumulator.is_streaming());
+    for v in [1.0, 2.0, 60.0].iter() {
+        accumulator.add(constant(2, 2, *v)).unwrap();
+    }
+    assert!(!accumulator.is_spilled());
+    assert!(accumulator.add(ImageBuffer::new(3, 3).unwrap()).is_err());
+    assert_eq!(accumulator.finish().unwrap().get(0, 0).unwrap(), 2.0);
+}
+
+#[test]
+fn accumulator_moves_many_frames_out_of_memory() {
+    // More frames than are held in memory, each pixel a different function of the frame
+    let frames:Vec<ImageBuffer> = (0..constants::STACK_FRAMES_IN_MEMORY + 9).map(|i| {
+        ImageBuffer::from_vec((0..12).map(|p| ((i * 7 + p * 13) % 31) as f32 + p as f32).collect(), 4, 3).unwrap()
+    }).collect();
+    let weights:Vec<f32> = (0..frames.len()).map(|i| 1.0 + (i % 3) as f32).collect();
+
+    for method in [StackMethod::Median, StackMethod::SigmaClip{kappa:2.0, iterations:3}].iter() {
+        let mut accumulator = StackAccumulator::new(method);
+        for (frame, weight) in frames.iter().zip(weights.iter()) {
+            accumulator.add_weighted(frame.clone(), *weight).unwrap();
+        }
+        assert!(accumulator.is_spilled());
+        assert!(accumulator.add(ImageBuffer::new(3, 3).unwrap()).is_err());
+
+        let expected = stacking::combine_weighted(&frames, &weights, method).unwrap();
+        let stack = accumulator.finish().unwrap();
+        for y in 0..3 {
+            for x in 0..4 {
+                assert_eq!(stack.get(x, y).unwrap(), expected.get(x, y).unwrap());
+            }
+        }
+    }
+}
+
+#[test]
+fn batches_are_delivered_in_order() {
+    let items:Vec<usize> = (0..10).collect();
+    let mut seen:Vec<f32> = Vec::new();
+    batch::for_each_batch(&items, 3, |i| if *i == 4 { None } else { Some(constant(2, 2, *i as f32)) }, |i, frame| {
+        assert_eq!(frame.get(0, 0).unwrap(), *i as f32);
+        seen.push(frame.get(0, 0).unwrap());
+        Ok(())
+    }).unwrap();
+    assert_eq!(seen, vec![0.0, 1.0, 2.0, 3.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+}
+
+#[test]
+fn stack_skips_frames_that_fail() {
+    let items:Vec<String> = vec!["a".to_string(), "bad".to_string(), "b".to_string()];
+    let options = BatchOptions{batch_size:2, ..Default::default()};
+    let (stack, summary) = batch::stack_with(&items, |name| {
+        match name.as_str() {
+            "a" => Ok(constant(2, 2, 2.0)),
+            "b" => Ok(constant(2, 2, 4.0)),
+            _ => Err(Error::FileNotFound(name.clone()))
+        }
+    }, &StackMethod::Mean, None, &options).unwrap();
+    assert_eq!(summary.count(), 2);
+    assert_eq!(stack.get(0, 0).unwrap(), 3.0);
+
+    let none:Vec<String> = vec!["bad".to_string()];
+    assert!(matches!(batch::stack_with(&none, |name:&String| Err(Error::FileNotFound(name.clone())), &StackMethod::Mean, None, &options),
+                     Err(Error::NoFilesUsed)));
+}
+
+#[test]
+fn stack_registers_against_first_good_frame() {
+    // A bright square moved a few pixels in each frame
+    let frame = |dx:usize, dy:usize| {
+        let mut image = ImageBuffer::new(32, 32).unwrap();
+        for y in 10..16 {
+            for x in 10..16 {
+                image.put(x + dx, y + dy, 1000.0).unwrap();
+            }
+        }
+        image
+    };
+    let items:Vec<usize> = (0..4).collect();
+    let options = BatchOptions{registration:Some(Interpolation::Bilinear), batch_size:2, ..Default::default()};
+    let (stack, summary) = batch::stack_with(&items, |i| {
+        match *i {
+            0 => Err(Error::EmptyImage),
+            i => Ok(frame(i, 3 - i))
+        }
+    }, &StackMethod::Median, None, &options).unwrap();
+    assert_eq!(summary.count(), 3);
+
+    // Aligned to frame 1 (offset 1, 2)
+    assert!((stack.get(13, 14).unwrap() - 1000.0).abs() < 1.0);
+    assert!(stack.get(9, 9).unwrap() < 1.0);
+}
@@ -0,0 +1,213 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::metadata::RawMetadata;
+use cr2_to_tiff_halpha::calibration::{self, CalibrationFrames, DarkScaling};
+use cr2_to_tiff_halpha::flatfield::FlatNormalization;
+
+mod common;
+use common::constant;
+
+fn frame(exposure:f32, temperature:Option<f32>) -> RawMetadata {
+    RawMetadata{exposure:Some(exposure), temperature, black_level:1000.0, ..Default::default()}
+}
+
+// Pseudo-random hot pixels, the same pattern for every call
+fn hot_pixels(width:usize, height:usize, level:f32) -> ImageBuffer {
+    let mut image = ImageBuffer::new(width, height).unwrap();
+    let mut state = 12345_u32;
+    for y in 0..height {
+        for x in 0..width {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let hot = (state >> 16).is_multiple_of(17);
+            image.put(x, y, if hot { level * (1.0 + ((state >> 8) % 5) as f32) } else { 0.0 }).unwrap();
+        }
+    }
+    image
+}
+
+#[test]
+fn parses_dark_scaling() {
+    assert_eq!(DarkScaling::from_name("none").unwrap(), DarkScaling::None);
+    assert_eq!(DarkScaling::from_name("Exposure").unwrap(), DarkScaling::Exposure);
+    assert_eq!(DarkScaling::from_name("optimize").unwrap(), DarkScaling::Optimize);
+    assert!(DarkScaling::from_name("auto").is_err());
+    assert_eq!(DarkScaling::default(), DarkScaling::None);
+}
+
+#[test]
+fn scales_by_exposure_and_temperature() {
+    assert_eq!(calibration::exposure_scale(&frame(2.0, None), &frame(0.5, None)), Some(4.0));
+    // Six degrees warmer doubles the dark current
+    let warmer = calibration::exposure_scale(&frame(1.0, Some(26.0)), &frame(1.0, Some(20.0))).unwrap();
+    assert!((warmer - 2.0).abs() < 1e-5, "{}", warmer);
+    // Temperature is ignored unless both frames record it
+    assert_eq!(calibration::exposure_scale(&frame(1.0, Some(26.0)), &frame(1.0, None)), Some(1.0));
+    assert_eq!(calibration::exposure_scale(&frame(1.0, None), &RawMetadata::default()), None);
+}
+
+#[test]
+fn subtracts_a_dark_scaled_by_exposure() {
+    let mut dark = hot_pixels(16, 16, 50.0).subtract_scalar(-1000.0).unwrap();
+    dark.set_metadata(Some(frame(0.5, None)));
+
+    // Lights at twice the exposure collect twice the dark current on top of a flat signal
+    let mut light = hot_pixels(16, 16, 100.0).subtract_scalar(-1300.0).unwrap();
+    light.set_metadata(Some(frame(1.0, None)));
+
+    let corrected = calibration::subtract_dark(&light, &dark, None, DarkScaling::Exposure).unwrap();
+    for y in 0..16 {
+        for x in 0..16 {
+            assert!((corrected.get(x, y).unwrap() - 300.0).abs() < 1e-3);
+        }
+    }
+    assert_eq!(corrected.metadata().unwrap().exposure, Some(1.0));
+
+    // Unscaled, the dark's hot pixels are under-corrected
+    let unscaled = calibration::subtract_dark(&light, &dark, None, DarkScaling::None).unwrap();
+    let (max, min) = (unscaled.get_min_max(-1.0).unwrap().max, unscaled.get_min_max(-1.0).unwrap().min);
+    assert!(max - min > 40.0);
+}
+
+#[test]
+fn optimizes_the_dark_scale() {
+    let thermal = hot_pixels(32, 32, 40.0);
+    // A smooth gradient plus the dark's pattern at 2.5 times its strength
+    let mut light = thermal.scale(2.5).unwrap();
+    for y in 0..32 {
+        for x in 0..32 {
+            light.put(x, y, light.get(x, y).unwrap() + 500.0 + 10.0 * x as f32 + 5.0 * y as f32).unwrap();
+        }
+    }
+    let k = calibration::optimize_scale(&light, &thermal).unwrap();
+    assert!((k - 2.5).abs() < 1e-3, "{}", k);
+
+    // A dark without structure leaves nothing to fit
+    assert_eq!(calibration::optimize_scale(&light, &ImageBuffer::new(32, 32).unwrap()).unwrap(), 1.0);
+    assert!(calibration::optimize_scale(&light, &ImageBuffer::new(16, 16).unwrap()).is_err());
+}
+
+#[test]
+fn dark_without_black_level_shares_the_lights() {
+    // A FITS master dark with no BLACKLVL card
+    let mut dark = hot_pixels(16, 16, 50.0).subtract_scalar(-1000.0).unwrap();
+    dark.set_metadata(Some(RawMetadata{exposure:Some(0.5), ..Default::default()}));
+    let mut light = hot_pixels(16, 16, 100.0).subtract_scalar(-1300.0).unwrap();
+    light.set_metadata(Some(frame(1.0, None)));
+
+    let corrected = calibration::subtract_dark(&light, &dark, None, DarkScaling::Optimize).unwrap();
+    let mm = corrected.get_min_max(-1.0).unwrap();
+    assert!((mm.min - 300.0).abs() < 1e-2 && (mm.max - 300.0).abs() < 1e-2, "{} {}", mm.min, mm.max);
+}
+
+// Vignetting falling off to the right: 1.0, 0.9, 0.8, 0.7 across each row
+fn vignetted(offset:f32, level:f32) -> ImageBuffer {
+    let mut image = ImageBuffer::new(4, 4).unwrap();
+    for y in 0..4 {
+        for x in 0..4 {
+            image.put(x, y, offset + level * (1.0 - 0.1 * x as f32)).unwrap();
+        }
+    }
+    image
+}
+
+#[test]
+fn applies_each_master_frame() {
+    // Lights carry a bias of 500, 100 of dark current and the vignetted signal
+    let light = vignetted(600.0, 1000.0);
+    let frames = CalibrationFrames::new(Some(constant(4, 4, 500.0)),
+                                        Some(constant(4, 4, 600.0)),
+                                        Some(constant(4, 4, 520.0)),
+                                        Some(vignetted(520.0, 20_000.0))).unwrap();
+    assert!(!frames.is_empty());
+    let corrected = frames.apply(&light, DarkScaling::None).unwrap();
+
+    // The flat-dark, not the lights' dark, comes off the flat, so the vignetting divides out
+    // evenly, leaving the signal scaled by the flat's mean response
+    let expected = corrected.get(0, 0).unwrap();
+    assert!((expected - 850.0).abs() < 0.1, "{}", expected);
+    for x in 0..4 {
+        assert!((corrected.get(x, 3).unwrap() - expected).abs() < 1e-2);
+    }
+}
+
+#[test]
+fn master_frames_are_each_optional() {
+    let light = vignetted(600.0, 1000.0);
+
+    // Only a bias: the dark current remains
+    let bias_only = CalibrationFrames::new(Some(constant(4, 4, 500.0)), None, None, None).unwrap();
+    assert_eq!(bias_only.apply(&light, DarkScaling::None).unwrap().get(0, 0).unwrap(), 1100.0);
+
+    // A flat without a flat-dark has the bias removed instead
+    let flat_only = CalibrationFrames::new(Some(constant(4, 4, 500.0)), None, None, Some(vignetted(500.0, 1000.0))).unwrap();
+    let corrected = flat_only.apply(&vignetted(500.0, 1000.0), DarkScaling::None).unwrap();
+    assert!((corrected.get(0, 0).unwrap() - corrected.get(3, 0).unwrap()).abs() < 1e-2);
+
+    // Nothing at all: the black level from the light's metadata comes off
+    let mut raw = constant(4, 4, 1500.0);
+    raw.set_metadata(Some(RawMetadata{black_level:1000.0, ..Default::default()}));
+    let none = CalibrationFrames::default();
+    assert!(none.is_empty());
+    assert_eq!(none.apply(&raw, DarkScaling::None).unwrap().get(2, 2).unwrap(), 500.0);
+
+    let none = CalibrationFrames::from_files("", "", "", "", FlatNormalization::Mean).unwrap();
+    assert!(none.is_empty() && none.dark().is_none());
+    assert!(CalibrationFrames::from_files("testing/no_bias.fits", "", "", "", FlatNormalization::Mean).is_err());
+}
+
+#[test]
+fn scales_dark_above_a_master_bias() {
+    let bias = constant(16, 16, 500.0);
+    let mut dark = hot_pixels(16, 16, 50.0).subtract_scalar(-500.0).unwrap();
+    dark.set_metadata(Some(RawMetadata{exposure:Some(1.0), ..Default::default()}));
+    let mut light = hot_pixels(16, 16, 150.0).subtract_scalar(-800.0).unwrap();
+    light.set_metadata(Some(RawMetadata{exposure:Some(3.0), ..Default::default()}));
+
+    let frames = CalibrationFrames::new(Some(bias), Some(dark), None, None).unwrap();
+    let corrected = frames.apply(&light, DarkScaling::Exposure).unwrap();
+    let mm = corrected.get_min_max(-1.0).unwrap();
+    assert!((mm.min - 300.0).abs() < 1e-2 && (mm.max - 300.0).abs() < 1e-2, "{} {}", mm.min, mm.max);
+}
+
+#[test]
+fn scales_dark_above_the_black_level_of_each_photosite() {
+    // Each photosite of the 2x2 cell has its own pedestal, which the dark shares
+    let cell_black = [[2048.0, 2050.0], [2049.0, 2047.0]];
+    let pedestal = |image:&ImageBuffer| {
+        let mut out = image.clone();
+        for y in 0..16 {
+            for x in 0..16 {
+                out.put(x, y, image.get(x, y).unwrap() + cell_black[y % 2][x % 2]).unwrap();
+            }
+        }
+        out
+    };
+    let mut light = pedestal(&hot_pixels(16, 16, 100.0).subtract_scalar(-300.0).unwrap());
+    light.set_metadata(Some(RawMetadata{exposure:Some(1.0), cell_black, ..Default::default()}));
+    // The dark records no black level of its own, so it takes the light's
+    let mut dark = pedestal(&hot_pixels(16, 16, 50.0));
+    dark.set_metadata(Some(RawMetadata{exposure:Some(0.5), ..Default::default()}));
+
+    let corrected = calibration::subtract_dark(&light, &dark, None, DarkScaling::Exposure).unwrap();
+    for y in 0..16 {
+        for x in 0..16 {
+            assert!((corrected.get(x, y).unwrap() - 300.0).abs() < 1e-3, "{} {} {}", x, y, corrected.get(x, y).unwrap());
+        }
+    }
+}
+
+#[test]
+fn reports_the_corrections_applied() {
+    assert_eq!(CalibrationFrames::default().corrections(DarkScaling::None), vec!["subtract black level"]);
+
+    let dark_only = CalibrationFrames::new(None, Some(constant(4, 4, 600.0)), None, None).unwrap();
+    assert_eq!(dark_only.corrections(DarkScaling::Exposure), vec!["subtract master dark scaled by exposure"]);
+    assert_eq!(dark_only.apply(&vignetted(600.0, 1000.0), DarkScaling::None).unwrap().get(0, 0).unwrap(), 1000.0);
+
+    let flat_only = CalibrationFrames::new(None, None, None, Some(vignetted(500.0, 1000.0))).unwrap();
+    assert_eq!(flat_only.corrections(DarkScaling::None),
+               vec!["subtract black level", "divide by master flat less its black level"]);
+
+    let full = CalibrationFrames::new(Some(constant(4, 4, 500.0)), Some(constant(4, 4, 600.0)), Some(constant(4, 4, 520.0)),
+                                      Some(vignetted(520.0, 20_000.0))).unwrap();
+    assert_eq!(full.corrections(DarkScaling::None), vec!["subtract master dark", "divide by master flat less its master flat-dark"]);
+}
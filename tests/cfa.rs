@@ -0,0 +1,84 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::cfa::{self, CfaChannel, CfaColor, CfaPattern};
+
+// 4x4 mosaic where each photosite's value encodes its position within the 2x2 cell:
+// (0,0) = 10, (1,0) = 20, (0,1) = 30, (1,1) = 40, plus the cell index
+fn mosaic() -> ImageBuffer {
+    let mut image = ImageBuffer::new(4, 4).unwrap();
+    for y in 0..4 {
+        for x in 0..4 {
+            let cell = ((y / 2) * 2 + x / 2) as f32;
+            let phase = (10 * (1 + (y % 2) * 2 + x % 2)) as f32;
+            image.put(x, y, phase + cell).unwrap();
+        }
+    }
+    image
+}
+
+#[test]
+fn parse_pattern_names() {
+    let bggr = CfaPattern::from_name("bggr").unwrap();
+    assert_eq!(bggr.name(), "BGGR");
+    assert_eq!(bggr.color_at(0, 0), CfaColor::Blue);
+    assert_eq!(bggr.color_at(3, 3), CfaColor::Red);
+    assert!(CfaPattern::from_name("RGBG").is_err());
+    assert!(CfaPattern::from_name("GGRB").is_err());
+    for name in ["RGGB", "BGGR", "GRBG", "GBRG"].iter() {
+        assert_eq!(CfaPattern::from_name(name).unwrap().name(), *name);
+    }
+    assert!(CfaPattern::from_name("RRGB").is_err());
+    assert!(CfaPattern::from_name("RGGBX").is_err());
+}
+
+#[test]
+fn channel_offsets_for_each_phase() {
+    let rggb = CfaPattern::from_name("RGGB").unwrap();
+    assert_eq!(rggb.offset_of(CfaChannel::Red), (0, 0));
+    assert_eq!(rggb.offset_of(CfaChannel::Green1), (1, 0));
+    assert_eq!(rggb.offset_of(CfaChannel::Green2), (0, 1));
+    assert_eq!(rggb.offset_of(CfaChannel::Blue), (1, 1));
+
+    let gbrg = CfaPattern::from_name("GBRG").unwrap();
+    assert_eq!(gbrg.offset_of(CfaChannel::Red), (0, 1));
+    assert_eq!(gbrg.offset_of(CfaChannel::Green1), (1, 1));
+    assert_eq!(gbrg.offset_of(CfaChannel::Green2), (0, 0));
+    assert_eq!(gbrg.offset_of(CfaChannel::Blue), (1, 0));
+}
+
+#[test]
+fn extract_planes_default_rggb() {
+    let image = mosaic();
+    assert!(image.cfa_pattern().is_none());
+
+    let red = image.red().unwrap();
+    assert_eq!(red.width, 2);
+    assert_eq!(red.height, 2);
+    assert_eq!(red.get(0, 0).unwrap(), 10.0);
+    assert_eq!(red.get(1, 1).unwrap(), 13.0);
+    assert_eq!(image.green1().unwrap().get(1, 0).unwrap(), 21.0);
+    assert_eq!(image.green2().unwrap().get(0, 1).unwrap(), 32.0);
+    assert_eq!(image.blue().unwrap().get(0, 0).unwrap(), 40.0);
+    assert_eq!(image.green().unwrap().get(0, 0).unwrap(), 25.0);
+}
+
+#[test]
+fn extract_planes_follow_pattern() {
+    let mut image = mosaic();
+    image.set_cfa_pattern(Some(CfaPattern::from_name("GRBG").unwrap()));
+
+    assert_eq!(image.red().unwrap().get(0, 0).unwrap(), 20.0);
+    assert_eq!(image.blue().unwrap().get(0, 0).unwrap(), 30.0);
+    assert_eq!(image.green1().unwrap().get(0, 0).unwrap(), 10.0);
+    assert_eq!(image.green2().unwrap().get(0, 0).unwrap(), 40.0);
+
+    let pattern = CfaPattern::from_name("GRBG").unwrap();
+    let red = cfa::extract_channel(&mosaic(), &pattern, CfaChannel::Red).unwrap();
+    assert_eq!(red.get(1, 0).unwrap(), 21.0);
+}
+
+#[test]
+fn channel_names() {
+    assert_eq!(CfaChannel::from_name("Green2").unwrap(), CfaChannel::Green2);
+    assert_eq!(CfaChannel::from_name("green").unwrap(), CfaChannel::Green);
+    assert!(CfaChannel::from_name("cyan").is_err());
+}
@@ -0,0 +1,61 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::colorize::{self, ColorizeMethod, ColorizeOptions, GradientStop, Palette};
+
+fn ramp() -> ImageBuffer {
+    ImageBuffer::from_vec(vec![100.0, 150.0, 200.0], 3, 1).unwrap()
+}
+
+#[test]
+fn gradient_interpolates_between_stops() {
+    let palette = Palette::from_gradient_str("1:ffffff, 0:000000, 0.5:#ff0000").unwrap();
+    assert_eq!(palette.stops()[1].position, 0.5);
+    assert_eq!(palette.color_at(0.25), [0.5, 0.0, 0.0]);
+    assert_eq!(palette.color_at(0.75), [1.0, 0.5, 0.5]);
+    assert_eq!(palette.color_at(-1.0), [0.0, 0.0, 0.0]);
+    assert_eq!(palette.color_at(2.0), [1.0, 1.0, 1.0]);
+}
+
+#[test]
+fn invalid_gradients_rejected() {
+    assert!(Palette::from_gradient_str("0:000000").is_err());
+    assert!(Palette::from_gradient_str("0:000000,1:fffff").is_err());
+    assert!(Palette::from_gradient_str("0:000000,1.5:ffffff").is_err());
+    assert!(Palette::from_gradient_str("x:000000,1:ffffff").is_err());
+    assert!(Palette::from_stops(vec![GradientStop{position:0.0, color:[0.0, 0.0, 0.0]}]).is_err());
+    assert!(Palette::from_name("green").is_err());
+}
+
+#[test]
+fn named_palettes_run_black_to_bright() {
+    for name in ["red", "orange", "yellow"].iter() {
+        let palette = Palette::from_name(name).unwrap();
+        assert_eq!(palette.color_at(0.0), [0.0, 0.0, 0.0]);
+        let mid = palette.color_at(0.5);
+        assert!(mid[0] > mid[1] && mid[1] >= mid[2], "{} {:?}", name, mid);
+    }
+}
+
+#[test]
+fn gamma_colorize() {
+    let options = ColorizeOptions{method:ColorizeMethod::from_gamma_str("2, 1, 0.5").unwrap(), invert:false};
+    let colored = colorize::colorize(&ramp(), &options).unwrap();
+    assert_eq!(colored.num_bands(), 3);
+    assert_eq!(colored.get_pixel(0, 0).unwrap(), vec![0.0, 0.0, 0.0]);
+    assert_eq!(colored.get_pixel(2, 0).unwrap(), vec![65535.0, 65535.0, 65535.0]);
+
+    let mid = colored.get_pixel(1, 0).unwrap();
+    assert!((mid[0] - 0.5_f32.sqrt() * 65535.0).abs() < 0.5);
+    assert!((mid[1] - 0.5 * 65535.0).abs() < 0.5);
+    assert!((mid[2] - 0.25 * 65535.0).abs() < 0.5);
+
+    assert!(ColorizeMethod::from_gamma_str("1,1").is_err());
+    assert!(ColorizeMethod::from_gamma_str("1,0,1").is_err());
+}
+
+#[test]
+fn invert_flips_intensities() {
+    let options = ColorizeOptions{invert:true, ..Default::default()};
+    let colored = colorize::colorize(&ramp(), &options).unwrap();
+    assert_eq!(colored.get_pixel(2, 0).unwrap(), vec![0.0, 0.0, 0.0]);
+    assert!(colored.get(0, 0, 0).unwrap() > 60000.0);
+}
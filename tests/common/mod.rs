@@ -0,0 +1,28 @@
+// Helpers shared by the integration tests. Each test crate uses only some of them.
+#![allow(dead_code)]
+
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+
+// Path of a scratch file in the system's temporary directory
+pub fn temp_path(name:&str) -> String {
+    std::env::temp_dir().join(name).to_str().unwrap().to_string()
+}
+
+// An image with every pixel set to the value
+pub fn constant(width:usize, height:usize, value:f32) -> ImageBuffer {
+    ImageBuffer::from_vec(vec![value; width * height], width, height).unwrap()
+}
+
+// Coordinates of the brightest pixel
+pub fn peak(image:&ImageBuffer) -> (usize, usize) {
+    let mut best = (0, 0, f32::MIN);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let v = image.get(x, y).unwrap();
+            if v > best.2 {
+                best = (x, y, v);
+            }
+        }
+    }
+    (best.0, best.1)
+}
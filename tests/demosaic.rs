@@ -0,0 +1,174 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::cfa::{CfaColor, CfaPattern};
+use cr2_to_tiff_halpha::demosaic::{self, DemosaicMethod};
+use cr2_to_tiff_halpha::multiband::MultiBandImage;
+use cr2_to_tiff_halpha::stacking::{self, StackMethod};
+use cr2_to_tiff_halpha::calibration::{CalibrationFrames, DarkScaling};
+use cr2_to_tiff_halpha::metadata::RawMetadata;
+use cr2_to_tiff_halpha::batch::{self, BatchOptions};
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::error::Error;
+
+// Samples a scene through the Bayer filter
+fn mosaic<F>(width:usize, height:usize, pattern:&CfaPattern, scene:F) -> ImageBuffer
+    where F: Fn(usize, usize) -> [f32; 3] {
+    let mut image = ImageBuffer::new(width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let rgb = scene(x, y);
+            let v = match pattern.color_at(x, y) {
+                CfaColor::Red => rgb[0],
+                CfaColor::Green => rgb[1],
+                CfaColor::Blue => rgb[2],
+            };
+            image.put(x, y, v).unwrap();
+        }
+    }
+    image
+}
+
+fn total_error<F>(image:&MultiBandImage, scene:F) -> f32
+    where F: Fn(usize, usize) -> [f32; 3] {
+    let mut err = 0.0;
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let got = image.get_pixel(x, y).unwrap();
+            let want = scene(x, y);
+            for c in 0..3 {
+                err += (got[c] - want[c]).abs();
+            }
+        }
+    }
+    err
+}
+
+#[test]
+fn flat_color_is_reconstructed_exactly() {
+    let scene = |_x:usize, _y:usize| [100.0, 50.0, 20.0];
+    for name in ["RGGB", "BGGR", "GRBG", "GBRG"].iter() {
+        let pattern = CfaPattern::from_name(name).unwrap();
+        let raw = mosaic(8, 6, &pattern, scene);
+        for method in [DemosaicMethod::Bilinear, DemosaicMethod::EdgeAware].iter() {
+            let color = demosaic::demosaic(&raw, &pattern, *method).unwrap();
+            assert_eq!(color.width, 8);
+            assert_eq!(color.height, 6);
+            assert!(total_error(&color, scene) < 1e-3, "{} {:?}", name, method);
+        }
+    }
+}
+
+#[test]
+fn edge_aware_reduces_fringing_on_edges() {
+    let scene = |x:usize, _y:usize| if x < 7 { [1000.0, 1000.0, 1000.0] } else { [100.0, 100.0, 100.0] };
+    let pattern = CfaPattern::rggb();
+    let raw = mosaic(16, 16, &pattern, scene);
+
+    let bilinear = total_error(&raw.demosaic(DemosaicMethod::Bilinear).unwrap(), scene);
+    let edge = total_error(&raw.demosaic(DemosaicMethod::EdgeAware).unwrap(), scene);
+    assert!(edge < bilinear / 2.0, "edge aware {} vs bilinear {}", edge, bilinear);
+}
+
+#[test]
+fn demosaic_method_names() {
+    assert_eq!(DemosaicMethod::from_name("bilinear").unwrap(), DemosaicMethod::Bilinear);
+    assert_eq!(DemosaicMethod::from_name("Edge").unwrap(), DemosaicMethod::EdgeAware);
+    assert!(DemosaicMethod::from_name("ahd").is_err());
+}
+
+#[test]
+fn normalize_keeps_color_balance() {
+    let image = MultiBandImage::from_rgb(ImageBuffer::from_vec(vec![10.0, 20.0], 2, 1).unwrap(),
+                                         ImageBuffer::from_vec(vec![5.0, 10.0], 2, 1).unwrap(),
+                                         ImageBuffer::from_vec(vec![0.0, 2.0], 2, 1).unwrap()).unwrap();
+    let normalized = image.normalize(0.0, 100.0).unwrap();
+    assert_eq!(normalized.get_pixel(1, 0).unwrap(), vec![100.0, 50.0, 10.0]);
+    assert_eq!(normalized.get_pixel(0, 0).unwrap(), vec![50.0, 25.0, 0.0]);
+}
+
+#[test]
+fn combine_bands_per_channel() {
+    let frame = |v:f32| MultiBandImage::from_rgb(ImageBuffer::from_vec(vec![v], 1, 1).unwrap(),
+                                                 ImageBuffer::from_vec(vec![v * 2.0], 1, 1).unwrap(),
+                                                 ImageBuffer::from_vec(vec![v * 3.0], 1, 1).unwrap()).unwrap();
+    let frames = vec![frame(1.0), frame(2.0), frame(30.0)];
+    let stack = stacking::combine_bands(&frames, &StackMethod::Median).unwrap();
+    assert_eq!(stack.get_pixel(0, 0).unwrap(), vec![2.0, 4.0, 6.0]);
+}
+
+#[test]
+fn calibrates_the_mosaic_before_demosaicing() {
+    let pattern = CfaPattern::from_name("GRBG").unwrap();
+    let scene = |_x:usize, _y:usize| [800.0, 400.0, 200.0];
+    let clean = mosaic(8, 8, &pattern, scene);
+
+    // The light has a hot pixel in the dark's pattern and red photosites vignetted to half
+    let mut dark = ImageBuffer::from_vec(vec![100.0; 64], 8, 8).unwrap();
+    dark.put(3, 4, 5000.0).unwrap();
+    let mut flat = ImageBuffer::from_vec(vec![1100.0; 64], 8, 8).unwrap();
+    flat.put(1, 0, 600.0).unwrap();
+    let mut light = clean.add(&dark).unwrap();
+    light.put(1, 0, 500.0).unwrap();
+    light.set_metadata(Some(RawMetadata{cfa:Some(pattern), ..Default::default()}));
+
+    let frames = CalibrationFrames::new(Some(ImageBuffer::from_vec(vec![100.0; 64], 8, 8).unwrap()), Some(dark), None, Some(flat)).unwrap();
+    let corrected = frames.apply(&light, DarkScaling::None).unwrap();
+    assert_eq!(corrected.cfa_pattern(), Some(pattern));
+
+    // The flat's mean response scales every band alike, leaving the scene's colors
+    let color = corrected.demosaic(DemosaicMethod::Bilinear).unwrap();
+    let expected = color.get_pixel(0, 0).unwrap();
+    for y in 0..8 {
+        for x in 0..8 {
+            let got = color.get_pixel(x, y).unwrap();
+            for band in 0..3 {
+                assert!((got[band] - expected[band]).abs() < 1e-2, "{} {} {:?} {:?}", x, y, got, expected);
+            }
+        }
+    }
+    assert!((expected[0] / expected[2] - 4.0).abs() < 1e-4);
+}
+
+#[test]
+fn stacks_color_frames_by_band() {
+    // A colored square moved a pixel right in each frame
+    let frame = |dx:usize| {
+        let band = |level:f32| {
+            let mut image = ImageBuffer::new(32, 32).unwrap();
+            for y in 10..16 {
+                for x in 10..16 {
+                    image.put(x + dx, y, level).unwrap();
+                }
+            }
+            image.set_metadata(Some(RawMetadata{exposure:Some(0.5), iso:Some(200.0), ..Default::default()}));
+            image
+        };
+        // Rescaled last, as calibrate_raw_rgb does, which must keep the metadata
+        MultiBandImage::from_rgb(band(900.0), band(600.0), band(300.0)).unwrap().normalize(0.0, 900.0).unwrap()
+    };
+    let items:Vec<String> = vec!["a".to_string(), "bad".to_string(), "b".to_string(), "c".to_string()];
+    let load = |name:&String| match name.as_str() {
+        "a" => Ok(frame(0)),
+        "b" => Ok(frame(1)),
+        "c" => Ok(frame(2)),
+        _ => Err(Error::FileNotFound(name.clone()))
+    };
+
+    let options = BatchOptions{registration:Some(Interpolation::Bilinear), batch_size:2, ..Default::default()};
+    let (stack, summary) = batch::stack_bands_with(&items, load, &StackMethod::Median, None, &options).unwrap();
+    assert_eq!(summary.count(), 3);
+    assert_eq!(summary.metadata.as_ref().and_then(|m| m.exposure), Some(0.5));
+    assert_eq!(summary.metadata.as_ref().and_then(|m| m.iso), Some(200.0));
+    assert_eq!(stack.num_bands(), 3);
+    for (x, y) in [(10, 10), (15, 15)].iter() {
+        let px = stack.get_pixel(*x, *y).unwrap();
+        assert!((px[0] - 900.0).abs() < 5.0 && (px[1] - 600.0).abs() < 5.0 && (px[2] - 300.0).abs() < 5.0, "{:?}", px);
+    }
+    assert!(stack.get(17, 12, 0).unwrap() < 1.0);
+
+    // Unregistered, the square smears to the right
+    let (smeared, _) = batch::stack_bands_with(&items, load, &StackMethod::Mean, None, &BatchOptions::default()).unwrap();
+    assert!((smeared.get(16, 12, 0).unwrap() - 600.0).abs() < 1e-3);
+
+    let none:Vec<String> = vec!["bad".to_string()];
+    assert!(matches!(batch::stack_bands_with(&none, load, &StackMethod::Mean, None, &options), Err(Error::NoFilesUsed)));
+}
@@ -0,0 +1,200 @@
+use cr2_to_tiff_halpha::imagebuffer::{ImageBuffer, OutputFormat, SubpixelOffset};
+use cr2_to_tiff_halpha::drizzle::{self, Drizzle, DrizzleOptions, FrameTransform};
+use cr2_to_tiff_halpha::batch::{self, BatchOptions};
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::error::Error;
+
+mod common;
+use common::{constant, peak, temp_path};
+
+const SIZE : usize = 48;
+
+// Detail close to the resolution limit of the input frames
+fn scene(x:f32, y:f32) -> f32 {
+    1000.0 + 300.0 * (x * 2.2).sin() * (y * 1.9).cos() + 200.0 * ((x - y) * 1.3).sin()
+}
+
+// Samples the scene at each input pixel, with the frame displaced by the given offset
+fn sample(h:f32, v:f32) -> ImageBuffer {
+    let mut image = ImageBuffer::new(SIZE, SIZE).unwrap();
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            image.put(x, y, scene(x as f32 + h, y as f32 + v)).unwrap();
+        }
+    }
+    image
+}
+
+fn offset(h:f32, v:f32) -> SubpixelOffset {
+    SubpixelOffset{h, v, peak:1.0}
+}
+
+#[test]
+fn validates_options() {
+    assert!(DrizzleOptions::new(2.0, 0.5).is_ok());
+    assert!(DrizzleOptions::new(0.0, 0.5).is_err());
+    assert!(DrizzleOptions::new(2.0, 0.0).is_err());
+    assert!(DrizzleOptions::new(2.0, 1.5).is_err());
+    assert!(DrizzleOptions::new(f32::NAN, 0.5).is_err());
+    assert_eq!(DrizzleOptions::new(1.5, 1.0).unwrap().output_size(100, 60), (150, 90));
+    assert!(matches!(Drizzle::new(ImageBuffer::new_empty().unwrap(), &DrizzleOptions::default()), Err(Error::EmptyImage)));
+}
+
+#[test]
+fn unit_scale_full_drops_reproduce_the_frame() {
+    let frame = sample(0.0, 0.0);
+    let mut d = Drizzle::new(frame.clone(), &DrizzleOptions::new(1.0, 1.0).unwrap()).unwrap();
+    d.add_with_offset(&frame, &offset(0.0, 0.0), 1.0).unwrap();
+    let (image, weights) = d.finish().unwrap();
+    assert_eq!((image.width, image.height), (SIZE, SIZE));
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            assert!((image.get(x, y).unwrap() - frame.get(x, y).unwrap()).abs() < 1e-2);
+            assert!((weights.get(x, y).unwrap() - 1.0).abs() < 1e-5);
+        }
+    }
+}
+
+#[test]
+fn weight_map_shows_coverage() {
+    let options = DrizzleOptions::new(2.0, 0.5).unwrap();
+    let mut d = Drizzle::new(constant(SIZE, SIZE, 500.0), &options).unwrap();
+    d.add_with_offset(&constant(SIZE, SIZE, 500.0), &offset(-0.25, -0.25), 2.0).unwrap();
+    let (image, weights) = d.finish().unwrap();
+    assert_eq!((weights.width, weights.height), (SIZE * 2, SIZE * 2));
+
+    // Offset by a quarter of an input pixel, each drop lands squarely on one output
+    // pixel, so a single frame fills one output pixel in four at the frame's weight
+    let covered = (0..weights.height).flat_map(|y| (0..weights.width).map(move |x| (x, y)))
+                                     .filter(|(x, y)| weights.get(*x, *y).unwrap() > 0.0)
+                                     .count();
+    assert_eq!(covered, SIZE * SIZE);
+    assert!((weights.get(0, 0).unwrap() - 2.0).abs() < 1e-5);
+    assert_eq!(weights.get(1, 1).unwrap(), 0.0);
+    assert!((image.get(0, 0).unwrap() - 500.0).abs() < 1e-3);
+    assert_eq!(image.get(1, 1).unwrap(), 0.0);
+
+    // Dithering by half an input pixel fills the gaps
+    let mut d = Drizzle::new(constant(SIZE, SIZE, 500.0), &options).unwrap();
+    for (h, v) in [(-0.25, -0.25), (0.25, -0.25), (-0.25, 0.25), (0.25, 0.25)].iter() {
+        d.add_with_offset(&constant(SIZE, SIZE, 500.0), &offset(*h, *v), 1.0).unwrap();
+    }
+    let (image, weights) = d.finish().unwrap();
+    for y in 2..weights.height - 2 {
+        for x in 2..weights.width - 2 {
+            assert!(weights.get(x, y).unwrap() > 0.0);
+            assert!((image.get(x, y).unwrap() - 500.0).abs() < 1e-3);
+        }
+    }
+}
+
+#[test]
+fn dithered_frames_recover_resolution() {
+    let options = DrizzleOptions::new(2.0, 0.5).unwrap();
+    let reference = sample(0.0, 0.0);
+    let mut d = Drizzle::new(reference.clone(), &options).unwrap();
+    for (h, v) in [(-0.25, -0.25), (0.25, -0.25), (-0.25, 0.25), (0.25, 0.25)].iter() {
+        // Each frame's pixels sample the scene at the position they are dropped at
+        d.add_with_offset(&sample(*h, *v), &offset(*h, *v), 1.0).unwrap();
+    }
+    let (image, _) = d.finish().unwrap();
+
+    // Output pixel centers in input coordinates
+    let at = |i:usize| (i as f32 + 0.5) / 2.0 - 0.5;
+    let mut drizzled = 0.0;
+    let mut upsampled = 0.0;
+    for y in 8..image.height - 8 {
+        for x in 8..image.width - 8 {
+            let truth = scene(at(x), at(y));
+            drizzled += (image.get(x, y).unwrap() - truth).abs();
+            upsampled += (reference.get_interpolated(at(x), at(y), Interpolation::Bicubic) - truth).abs();
+        }
+    }
+    assert!(drizzled < upsampled * 0.5, "drizzled {} upsampled {}", drizzled, upsampled);
+}
+
+#[test]
+fn transforms_turn_the_way_images_rotate() {
+    // Straight up from the center turns to the left, as with ImageBuffer::rotate
+    let quarter = FrameTransform::rotation(10.0, 10.0, 90.0);
+    let (x, y) = quarter.apply(10.0, 0.0);
+    assert!(x.abs() < 1e-4 && (y - 10.0).abs() < 1e-4, "{} {}", x, y);
+    let mut spot = ImageBuffer::new(21, 21).unwrap();
+    spot.put(10, 0, 100.0).unwrap();
+    assert_eq!(peak(&spot.rotate(90.0, Interpolation::Bilinear).unwrap()), (0, 10));
+
+    // Shifting then turning moves the point before it is turned
+    let (x, y) = FrameTransform::shift(0.0, 2.0).then(&quarter).apply(10.0, 0.0);
+    assert!((x - 2.0).abs() < 1e-4 && (y - 10.0).abs() < 1e-4, "{} {}", x, y);
+    assert_eq!(FrameTransform::default().apply(3.5, -1.0), (3.5, -1.0));
+}
+
+#[test]
+fn drops_turned_frames_without_resampling_them() {
+    let options = DrizzleOptions::new(2.0, 0.5).unwrap();
+    let c = (SIZE as f32 - 1.0) / 2.0;
+    let frames = [(-0.25, -0.25, 3.0), (0.25, -0.25, -2.0), (-0.25, 0.25, 1.5), (0.25, 0.25, -4.0)];
+
+    let mut direct = Drizzle::new(sample(0.0, 0.0), &options).unwrap();
+    let mut resampled = Drizzle::new(sample(0.0, 0.0), &options).unwrap();
+    for (h, v, degrees) in frames.iter() {
+        // Each frame's pixels sample the scene where the transform puts them
+        let transform = FrameTransform::rotation(c, c, *degrees).then(&FrameTransform::shift(*h, *v));
+        let mut frame = ImageBuffer::new(SIZE, SIZE).unwrap();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let (tx, ty) = transform.apply(x as f32, y as f32);
+                frame.put(x, y, scene(tx, ty)).unwrap();
+            }
+        }
+        direct.add_transformed(&frame, &transform, 1.0).unwrap();
+
+        // Resampling the frame onto the reference first blurs the detail drizzle recovers
+        let inverse = FrameTransform::shift(-h, -v).then(&FrameTransform::rotation(c, c, -degrees));
+        let mut aligned = ImageBuffer::new(SIZE, SIZE).unwrap();
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let (sx, sy) = inverse.apply(x as f32, y as f32);
+                aligned.put(x, y, frame.get_interpolated(sx, sy, Interpolation::Bilinear)).unwrap();
+            }
+        }
+        resampled.add_with_offset(&aligned, &offset(0.0, 0.0), 1.0).unwrap();
+    }
+
+    // Output pixel centers in input coordinates
+    let at = |i:usize| (i as f32 + 0.5) / 2.0 - 0.5;
+    let error = |d:Drizzle| {
+        let (image, _) = d.finish().unwrap();
+        let mut total = 0.0;
+        for y in 12..image.height - 12 {
+            for x in 12..image.width - 12 {
+                total += (image.get(x, y).unwrap() - scene(at(x), at(y))).abs();
+            }
+        }
+        total
+    };
+    let (direct, resampled) = (error(direct), error(resampled));
+    assert!(direct < resampled * 0.7, "direct {} resampled {}", direct, resampled);
+}
+
+#[test]
+fn drizzles_loaded_frames() {
+    let frames = [sample(0.0, 0.0), sample(-0.5, 0.0), ImageBuffer::new(8, 8).unwrap(), sample(0.0, -0.5)];
+    let names:Vec<String> = (0..frames.len()).map(|i| i.to_string()).collect();
+    let (image, weights, summary) = batch::drizzle_with(&names, |n| {
+        Ok(frames[n.parse::<usize>().unwrap()].clone())
+    }, None, &DrizzleOptions::default(), &BatchOptions::default()).unwrap();
+
+    // The mismatched frame is skipped
+    assert_eq!(summary.count(), 3);
+    assert_eq!((image.width, image.height), (SIZE * 2, SIZE * 2));
+    assert_eq!((weights.width, weights.height), (SIZE * 2, SIZE * 2));
+
+    assert!(matches!(batch::drizzle_with(&names, |_| Err(Error::EmptyImage), None, &DrizzleOptions::default(), &BatchOptions::default()), Err(Error::NoFilesUsed)));
+
+    let path = drizzle::weight_map_path(&temp_path("drizzle_stack.tif"));
+    assert!(path.ends_with("drizzle_stack_weights.tif"));
+    drizzle::save_weight_map(&weights, &path, OutputFormat::Float32).unwrap();
+    let loaded = ImageBuffer::from_file(&path).unwrap();
+    assert_eq!(loaded.get(10, 10).unwrap(), weights.get(10, 10).unwrap());
+}
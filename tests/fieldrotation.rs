@@ -0,0 +1,177 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::datetime::{self, DateTime};
+use cr2_to_tiff_halpha::fieldrotation::{self, Equatorial, FieldRotation, Observer};
+
+use cr2_to_tiff_halpha::solar::{self, SolarEphemeris};
+use cr2_to_tiff_halpha::constants;
+
+mod common;
+use common::{peak, temp_path};
+
+fn close(a:f64, b:f64, tolerance:f64) -> bool {
+    (a - b).abs() < tolerance
+}
+
+#[test]
+fn julian_dates() {
+    assert!(close(DateTime::new(2000, 1, 1, 12, 0, 0.0).unwrap().julian_date(), 2_451_545.0, 1e-6));
+    assert!(close(DateTime::new(1987, 1, 27, 0, 0, 0.0).unwrap().julian_date(), 2_446_822.5, 1e-6));
+    // Meeus example 7.a, 1957 October 4.81
+    assert!(close(DateTime::new(1957, 10, 4, 19, 26, 24.0).unwrap().julian_date(), 2_436_116.31, 1e-6));
+    assert!(DateTime::new(2021, 13, 1, 0, 0, 0.0).is_err());
+}
+
+#[test]
+fn parses_exif_times() {
+    let t = DateTime::from_exif("2021:03:16 17:45:02").unwrap();
+    assert_eq!(t, DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap());
+    assert_eq!(DateTime::from_exif("2021-03-16T17:45:02Z").unwrap(), t);
+    assert!(DateTime::from_exif("2021:03:16").is_err());
+    assert!(DateTime::from_exif("2021:03:16 25:00:00").is_err());
+    assert!(DateTime::from_exif("yesterday at noon").is_err());
+}
+
+// Builds a minimal TIFF with the given IFD0 entries and data appended after it
+fn tiff(little_endian:bool, entries:&[(u16, u16, u32, u32)], data:&[u8]) -> Vec<u8> {
+    let u16b = |v:u16| if little_endian { v.to_le_bytes().to_vec() } else { v.to_be_bytes().to_vec() };
+    let u32b = |v:u32| if little_endian { v.to_le_bytes().to_vec() } else { v.to_be_bytes().to_vec() };
+    let mut t:Vec<u8> = if little_endian { b"II".to_vec() } else { b"MM".to_vec() };
+    t.extend(u16b(42));
+    t.extend(u32b(8));
+    t.extend(u16b(entries.len() as u16));
+    for (tag, kind, count, value) in entries.iter() {
+        t.extend(u16b(*tag));
+        t.extend(u16b(*kind));
+        t.extend(u32b(*count));
+        t.extend(u32b(*value));
+    }
+    t.extend(u32b(0));
+    t.extend_from_slice(data);
+    t
+}
+
+#[test]
+fn reads_capture_time_from_raw_metadata() {
+    // One entry: data starts at 8 + 2 + 12 + 4
+    let raw = tiff(true, &[(0x0132, 2, 20, 26)], b"2021:03:16 17:45:02\0");
+    assert_eq!(datetime::read_capture_time(&raw), Some(DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap()));
+
+    // The original capture time in the EXIF directory wins over the modification time.
+    // Two entries: data starts at 8 + 2 + 24 + 4 = 38, with the EXIF directory after both strings.
+    let mut data = b"2021:03:16 18:00:00\0".to_vec();
+    data.extend_from_slice(b"2021:03:16 17:45:02\0");
+    let exif_ifd = 38 + 40;
+    data.extend_from_slice(&[0, 1, 0x90, 0x03, 0, 2, 0, 0, 0, 20, 0, 0, 0, 58, 0, 0, 0, 0]);
+    let raw = tiff(false, &[(0x0132, 2, 20, 38), (0x8769, 4, 1, exif_ifd)], &data);
+    assert_eq!(datetime::read_capture_time(&raw), Some(DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap()));
+
+    assert_eq!(datetime::read_capture_time(b"not a raw file"), None);
+    assert!(datetime::capture_time("testing/does_not_exist.CR2").is_err());
+
+    // Read from the header of a file, or from the whole file when the time lies beyond it
+    let near = temp_path("capture_time_near.CR2");
+    let mut raw = tiff(true, &[(0x0132, 2, 20, 26)], b"2021:03:16 17:45:02\0");
+    raw.resize(constants::exif::HEADER_BYTES * 2, 0);
+    std::fs::write(&near, &raw).unwrap();
+    assert_eq!(datetime::capture_time(&near).unwrap(), DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap());
+
+    let far = temp_path("capture_time_far.CR2");
+    let mut data = vec![0_u8; constants::exif::HEADER_BYTES];
+    data.extend_from_slice(b"2021:03:16 17:45:02\0");
+    std::fs::write(&far, tiff(true, &[(0x0132, 2, 20, 26 + constants::exif::HEADER_BYTES as u32)], &data)).unwrap();
+    assert_eq!(datetime::capture_time(&far).unwrap(), DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap());
+}
+
+#[test]
+fn sidereal_time_and_parallactic_angle() {
+    // Meeus examples 12.a and 12.b
+    assert!(close(fieldrotation::greenwich_sidereal_time(2_446_895.5), 197.693_195, 1e-5));
+    assert!(close(fieldrotation::greenwich_sidereal_time(2_446_896.306_25), 128.737_873, 1e-4));
+
+    let observer = Observer::from_spec("45,-75").unwrap();
+    let jd = 2_459_290.25;
+    let lst = fieldrotation::local_sidereal_time(jd, &observer);
+    let at_hour_angle = |h:f64| Equatorial{ra:(lst - h).rem_euclid(360.0), dec:0.0};
+    // Zero on the meridian, symmetric either side of it
+    assert!(close(fieldrotation::parallactic_angle(jd, &observer, &at_hour_angle(0.0)), 0.0, 1e-9));
+    assert!(close(fieldrotation::parallactic_angle(jd, &observer, &at_hour_angle(90.0)), 45.0, 1e-9));
+    assert!(close(fieldrotation::parallactic_angle(jd, &observer, &at_hour_angle(-90.0)), -45.0, 1e-9));
+
+    assert!(Observer::from_spec("95,0").is_err());
+    assert!(Equatorial::from_spec("10").is_err());
+    assert!(Equatorial::from_spec("400,0").is_err());
+}
+
+#[test]
+fn derotates_to_the_reference_orientation() {
+    let observer = Observer{latitude:34.0, longitude:-118.0};
+    let target = Equatorial{ra:355.0, dec:-2.0};
+    let start = DateTime::new(2021, 3, 16, 19, 0, 0.0).unwrap();
+    let later = DateTime::new(2021, 3, 16, 20, 0, 0.0).unwrap();
+    let rotation = FieldRotation::new(observer, target, 0.0, &start, Interpolation::Bilinear);
+    assert!(close(rotation.angle_at(&start), 0.0, 1e-9));
+
+    // An hour of tracking turns the field by a few degrees
+    let angle = rotation.angle_at(&later);
+    assert!(angle.abs() > 1.0 && angle.abs() < 45.0, "{}", angle);
+
+    // The same times on a camera clock two hours ahead of UT give the same rotation
+    let ahead = FieldRotation::new(observer, target, 2.0, &DateTime::new(2021, 3, 16, 21, 0, 0.0).unwrap(), Interpolation::Bilinear);
+    assert!(close(ahead.angle_at(&DateTime::new(2021, 3, 16, 22, 0, 0.0).unwrap()), angle, 1e-6));
+
+    // Rising towards the meridian, the parallactic angle grows, so the field turns clockwise
+    assert!(angle > 0.0, "{}", angle);
+
+    // A star straight up from the center in the reference frame, turned clockwise with the field
+    let (cx, cy) = (16.0_f32, 16.0_f32);
+    let (sx, sy) = (cx + 10.0 * (angle as f32).to_radians().sin(), cy - 10.0 * (angle as f32).to_radians().cos());
+    let mut frame = ImageBuffer::new(33, 33).unwrap();
+    for y in 0..33 {
+        for x in 0..33 {
+            let d2 = (x as f32 - sx).powi(2) + (y as f32 - sy).powi(2);
+            frame.put(x, y, 1000.0 * (-d2 / 4.0).exp()).unwrap();
+        }
+    }
+    assert_ne!(peak(&frame), (16, 6));
+
+    // Derotating puts it back straight up
+    let derotated = rotation.derotate(&frame, &later).unwrap();
+    assert_eq!(peak(&derotated), (16, 6));
+}
+
+#[test]
+fn puts_solar_north_up_for_a_later_reference_light() {
+    // The session starts at 18:00 UT, but the sharpest light, stacked first, is from 19:30
+    let observer = Observer{latitude:34.0, longitude:-118.0};
+    let start = DateTime::new(2021, 3, 16, 18, 0, 0.0).unwrap();
+    let sharpest = DateTime::new(2021, 3, 16, 19, 30, 0.0).unwrap();
+    let target = solar::position(start.julian_date());
+    let rotation = FieldRotation::new(observer, target, 0.0, &start, Interpolation::Bicubic);
+    let ephemeris = SolarEphemeris::at_julian_date(rotation.reference_julian_date());
+
+    // In the level camera, celestial north lies the parallactic angle clockwise from up,
+    // and solar north P counterclockwise from that
+    let north = fieldrotation::parallactic_angle(sharpest.julian_date(), &observer, &target);
+    let solar_north = (north - ephemeris.p) as f32;
+    assert!(rotation.angle_at(&sharpest).abs() > 5.0);
+
+    let (cx, cy) = (16.0_f32, 16.0_f32);
+    let (sx, sy) = (cx + 10.0 * solar_north.to_radians().sin(), cy - 10.0 * solar_north.to_radians().cos());
+    let mut frame = ImageBuffer::new(33, 33).unwrap();
+    for y in 0..33 {
+        for x in 0..33 {
+            let d2 = (x as f32 - sx).powi(2) + (y as f32 - sy).powi(2);
+            frame.put(x, y, 1000.0 * (-d2 / 4.0).exp()).unwrap();
+        }
+    }
+
+    // Derotated to the session start, then turned by P and celestial north at that same time
+    let derotated = rotation.derotate(&frame, &sharpest).unwrap();
+    let rotated = solar::north_up(&derotated, &ephemeris, rotation.reference_north(), Interpolation::Bicubic).unwrap();
+    assert_eq!(peak(&rotated), (16, 6));
+
+    // Celestial north from the sharpest light's time leaves it off by the field rotation between
+    let mismatched = solar::north_up(&derotated, &ephemeris, north, Interpolation::Bicubic).unwrap();
+    assert_ne!(peak(&mismatched), (16, 6));
+}
@@ -0,0 +1,100 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::fits::{self, BitDepth, FitsHeader};
+use cr2_to_tiff_halpha::limb::Disk;
+
+mod common;
+use common::temp_path;
+
+fn gradient(width:usize, height:usize) -> ImageBuffer {
+    let mut image = ImageBuffer::new(width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            image.put(x, y, x as f32 * 0.3333 + y as f32 * 1000.0 + 0.125).unwrap();
+        }
+    }
+    image
+}
+
+#[test]
+fn float32_round_trip_with_header() {
+    let image = gradient(37, 23);
+    let header = FitsHeader{
+        exposure:Some(0.0025),
+        iso:Some(160.0),
+        date_obs:Some(String::from("2021-03-16T17:42:03")),
+        stack_count:Some(42),
+        disk:Some(Disk{x:18.5, y:11.25, radius:9.75}),
+        cards:vec![(String::from("OBSERVER"), String::from("'Kevin'"))],
+        ..Default::default()
+    };
+    let path = temp_path("cr2_to_tiff_halpha_float32.fits");
+    fits::save(&image, &path, BitDepth::Float32, &header).unwrap();
+
+    let (loaded, loaded_header) = fits::load(&path).unwrap();
+    assert_eq!(loaded.width, 37);
+    assert_eq!(loaded.height, 23);
+    for y in 0..23 {
+        for x in 0..37 {
+            assert_eq!(loaded.get(x, y).unwrap(), image.get(x, y).unwrap());
+        }
+    }
+    assert_eq!(loaded_header.exposure, Some(0.0025));
+    assert_eq!(loaded_header.iso, Some(160.0));
+    assert_eq!(loaded_header.date_obs.as_deref(), Some("2021-03-16T17:42:03"));
+    assert_eq!(loaded_header.stack_count, Some(42));
+    assert_eq!(loaded_header.disk, Some(Disk{x:18.5, y:11.25, radius:9.75}));
+    assert_eq!(loaded_header.cards, vec![(String::from("OBSERVER"), String::from("'Kevin'"))]);
+}
+
+#[test]
+fn unknown_cards_survive_resaving() {
+    let header = FitsHeader{
+        cards:vec![(String::from("OBSERVER"), String::from("'O''Brien'")),
+                   (String::from("TELESCOP"), String::from("'Tamron 150-600/f10'")),
+                   (String::from("FOCALLEN"), String::from("250"))],
+        ..Default::default()
+    };
+    let path = temp_path("cr2_to_tiff_halpha_cards.fits");
+    fits::save(&gradient(4, 4), &path, BitDepth::Float32, &header).unwrap();
+
+    // Load and save again, then check the second generation is still valid
+    let (image, loaded_header) = fits::load(&path).unwrap();
+    let resaved = temp_path("cr2_to_tiff_halpha_cards_resaved.fits");
+    fits::save(&image, &resaved, BitDepth::Float32, &loaded_header).unwrap();
+    let (_, reloaded_header) = fits::load(&resaved).unwrap();
+    assert_eq!(reloaded_header.cards, header.cards);
+
+    let text = String::from_utf8_lossy(&std::fs::read(&resaved).unwrap()[..2880]).to_string();
+    assert!(text.contains("OBSERVER= 'O''Brien'"));
+    assert!(text.contains("TELESCOP= 'Tamron 150-600/f10'"));
+}
+
+#[test]
+fn int16_round_trip() {
+    let image = ImageBuffer::from_vec(vec![0.0, 1.0, 32767.0, 32768.0, 65535.0, 1234.4], 3, 2).unwrap();
+    let path = temp_path("cr2_to_tiff_halpha_int16.fits");
+    fits::save(&image, &path, BitDepth::Int16, &FitsHeader::default()).unwrap();
+
+    let loaded = ImageBuffer::from_file(&path).unwrap();
+    assert_eq!(loaded.get(0, 0).unwrap(), 0.0);
+    assert_eq!(loaded.get(2, 0).unwrap(), 32767.0);
+    assert_eq!(loaded.get(0, 1).unwrap(), 32768.0);
+    assert_eq!(loaded.get(1, 1).unwrap(), 65535.0);
+    assert_eq!(loaded.get(2, 1).unwrap(), 1234.0);
+}
+
+#[test]
+fn save_dispatches_on_extension() {
+    let image = gradient(8, 8);
+    let path = temp_path("cr2_to_tiff_halpha_master.fit");
+    image.save(&path).unwrap();
+    let loaded = ImageBuffer::from_file(&path).unwrap();
+    assert_eq!(loaded.get(7, 7).unwrap(), image.get(7, 7).unwrap());
+}
+
+#[test]
+fn load_rejects_non_fits() {
+    let path = temp_path("cr2_to_tiff_halpha_garbage.fits");
+    std::fs::write(&path, b"not a fits file").unwrap();
+    assert!(fits::load(&path).is_err());
+}
@@ -0,0 +1,86 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::calibration::{CalibrationFrames, DarkScaling};
+use cr2_to_tiff_halpha::flatfield::{self, FlatNormalization};
+use cr2_to_tiff_halpha::constants;
+
+mod common;
+use common::temp_path;
+
+// An 8x8 flat of 1000 with a bright center quarter of 1200, dark corners of 0 and a
+// dust shadow of 500
+fn dusty_flat() -> ImageBuffer {
+    let mut image = ImageBuffer::from_vec(vec![1000.0; 64], 8, 8).unwrap();
+    for y in 2..6 {
+        for x in 2..6 {
+            image.put(x, y, 1200.0).unwrap();
+        }
+    }
+    for (x, y) in [(0, 0), (7, 0), (0, 7), (7, 7)].iter() {
+        image.put(*x, *y, 0.0).unwrap();
+    }
+    image.put(1, 6, 500.0).unwrap();
+    image
+}
+
+#[test]
+fn parses_flat_normalization() {
+    assert_eq!(FlatNormalization::from_name("mean").unwrap(), FlatNormalization::Mean);
+    assert_eq!(FlatNormalization::from_name("Median").unwrap(), FlatNormalization::Median);
+    assert_eq!(FlatNormalization::from_name("center").unwrap(), FlatNormalization::Center);
+    assert!(FlatNormalization::from_name("max").is_err());
+    assert_eq!(FlatNormalization::default(), FlatNormalization::Mean);
+}
+
+#[test]
+fn reference_levels() {
+    let flat = dusty_flat();
+    // 43 pixels of 1000, 16 of 1200 and one of 500 over all 64, the zeros included
+    let mean = flatfield::reference_level(&flat, FlatNormalization::Mean).unwrap();
+    assert!((mean - 62_700.0 / 64.0).abs() < 1e-3, "{}", mean);
+    assert!(flat.mean() > mean);
+
+    assert_eq!(flatfield::reference_level(&flat, FlatNormalization::Median).unwrap(), 1000.0);
+    assert_eq!(flatfield::reference_level(&flat, FlatNormalization::Center).unwrap(), 1200.0);
+
+    assert!(flatfield::reference_level(&ImageBuffer::new(4, 4).unwrap(), FlatNormalization::Mean).is_err());
+}
+
+#[test]
+fn normalizes_to_unity() {
+    let normalized = flatfield::normalize(&dusty_flat(), FlatNormalization::Median).unwrap();
+    assert_eq!(normalized.get(0, 1).unwrap(), 1.0);
+    assert_eq!(normalized.get(3, 3).unwrap(), 1.2);
+    assert_eq!(normalized.get(1, 6).unwrap(), 0.5);
+
+    let normalized = flatfield::normalize(&dusty_flat(), FlatNormalization::Center).unwrap();
+    assert_eq!(normalized.get(4, 4).unwrap(), 1.0);
+}
+
+#[test]
+fn guards_vignetted_corners() {
+    let normalized = flatfield::normalize(&dusty_flat(), FlatNormalization::Median).unwrap();
+    assert_eq!(normalized.count_below(constants::MIN_FLAT_RESPONSE), 0);
+    assert_eq!(normalized.get(0, 0).unwrap(), constants::MIN_FLAT_RESPONSE);
+
+    // A light divided by the flat is boosted at most by the inverse of the floor
+    let frames = CalibrationFrames::normalized(None, None, None, Some(dusty_flat()), FlatNormalization::Median).unwrap();
+    let corrected = frames.apply(&ImageBuffer::from_vec(vec![100.0; 64], 8, 8).unwrap(), DarkScaling::None).unwrap();
+    assert_eq!(corrected.get(0, 1).unwrap(), 100.0);
+    assert!((corrected.get(7, 7).unwrap() - 100.0 / constants::MIN_FLAT_RESPONSE).abs() < 1e-3);
+    assert_eq!(corrected.get_min_max(-1.0).unwrap().max, corrected.get(7, 7).unwrap());
+}
+
+#[test]
+fn saves_the_normalized_flat() {
+    let file = temp_path("flatfield_normalized.fits");
+    let file = file.as_str();
+    let frames = CalibrationFrames::normalized(None, None, None, Some(dusty_flat()), FlatNormalization::Center).unwrap();
+    frames.save_flat_field(file).unwrap();
+
+    let loaded = ImageBuffer::from_file(file).unwrap();
+    assert_eq!(loaded.get(3, 3).unwrap(), 1.0);
+    assert_eq!(loaded.get(0, 0).unwrap(), constants::MIN_FLAT_RESPONSE);
+    assert_eq!(frames.flat_field().unwrap().get(1, 6).unwrap(), loaded.get(1, 6).unwrap());
+
+    assert!(CalibrationFrames::default().save_flat_field(file).is_err());
+}
@@ -1,54 +1,165 @@
-use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
-
-#[test]
-fn load_cr2() {
-    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
-    assert_eq!(image.width, 4770);
-    assert_eq!(image.height, 3176);
-}
-
-#[test]
-fn load_cr2_extract_red() {
-    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
-    let red = image.red().unwrap();
-    assert_eq!(red.width, 2385);
-    assert_eq!(red.height, 1588);
-}
-
-#[test]
-fn load_cr2_check_min_max_no_override() {
-    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
-    let red = image.red().unwrap();
-    let minmax = red.get_min_max(-1.0).unwrap();
-    assert_eq!(minmax.min, 935.0);
-    assert_eq!(minmax.max, 1223.0);
-}
-
-#[test]
-fn load_cr2_check_min_max_with_override() {
-    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
-    let red = image.red().unwrap();
-    let minmax = red.get_min_max(400.0).unwrap();
-    assert_eq!(minmax.min, 400.0);
-    assert_eq!(minmax.max, 1223.0);
-}
-
-#[test]
-fn load_cr2_scalar() {
-    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
-    let red = image.red().unwrap();
-    let scaled = red.scale(2.0).unwrap();
-    let minmax = scaled.get_min_max(-1.0).unwrap();
-    assert_eq!(minmax.min, 935.0 * 2.0);
-    assert_eq!(minmax.max, 1223.0 * 2.0);
-}
-
-#[test]
-fn load_cr2_divide_into() {
-    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
-    let red = image.red().unwrap();
-    let scaled = red.divide_into(2.0).unwrap();
-    let minmax = scaled.get_min_max(-1.0).unwrap();
-    assert_eq!(minmax.min, 0.0016353229);
-    assert_eq!(minmax.max, 0.0021390375);
-}
\ No newline at end of file
+use cr2_to_tiff_halpha::imagebuffer::{ImageBuffer, OutputFormat};
+use cr2_to_tiff_halpha::error::Error;
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+
+mod common;
+use common::temp_path;
+
+#[test]
+fn load_cr2() {
+    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
+    assert_eq!(image.width, 4770);
+    assert_eq!(image.height, 3176);
+}
+
+#[test]
+fn load_cr2_extract_red() {
+    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
+    let red = image.red().unwrap();
+    assert_eq!(red.width, 2385);
+    assert_eq!(red.height, 1588);
+}
+
+#[test]
+fn load_cr2_check_min_max_no_override() {
+    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
+    let red = image.red().unwrap();
+    let minmax = red.get_min_max(-1.0).unwrap();
+    assert_eq!(minmax.min, 935.0);
+    assert_eq!(minmax.max, 1223.0);
+}
+
+#[test]
+fn load_cr2_check_min_max_with_override() {
+    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
+    let red = image.red().unwrap();
+    let minmax = red.get_min_max(400.0).unwrap();
+    assert_eq!(minmax.min, 400.0);
+    assert_eq!(minmax.max, 1223.0);
+}
+
+#[test]
+fn load_cr2_scalar() {
+    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
+    let red = image.red().unwrap();
+    let scaled = red.scale(2.0).unwrap();
+    let minmax = scaled.get_min_max(-1.0).unwrap();
+    assert_eq!(minmax.min, 935.0 * 2.0);
+    assert_eq!(minmax.max, 1223.0 * 2.0);
+}
+
+#[test]
+fn load_cr2_divide_into() {
+    let image = ImageBuffer::from_cr2("testing/IMG_0107.CR2").unwrap();
+    let red = image.red().unwrap();
+    let scaled = red.divide_into(2.0).unwrap();
+    let minmax = scaled.get_min_max(-1.0).unwrap();
+    assert_eq!(minmax.min, 0.0016353229);
+    assert_eq!(minmax.max, 0.0021390375);
+}
+#[test]
+fn crop_pads_smaller_image() {
+    let image = ImageBuffer::from_vec(vec![1.0, 2.0, 3.0, 4.0], 2, 2).unwrap();
+    let cropped = image.crop(4, 4).unwrap();
+    assert_eq!(cropped.width, 4);
+    assert_eq!(cropped.height, 4);
+    assert_eq!(cropped.get(0, 0).unwrap(), 0.0);
+    assert_eq!(cropped.get(1, 1).unwrap(), 1.0);
+    assert_eq!(cropped.get(2, 2).unwrap(), 4.0);
+    assert_eq!(cropped.get(3, 3).unwrap(), 0.0);
+}
+
+#[test]
+fn crop_region_out_of_bounds() {
+    let image = ImageBuffer::from_vec(vec![1.0, 2.0, 3.0, 4.0], 2, 2).unwrap();
+    let cropped = image.crop_region(1, -1, 2, 2).unwrap();
+    assert_eq!(cropped.get(0, 0).unwrap(), 0.0);
+    assert_eq!(cropped.get(0, 1).unwrap(), 2.0);
+    assert_eq!(cropped.get(1, 1).unwrap(), 0.0);
+}
+
+#[test]
+fn missing_cr2_reports_path() {
+    match ImageBuffer::from_cr2("testing/does_not_exist.CR2") {
+        Err(Error::FileNotFound(path)) => assert_eq!(path, "testing/does_not_exist.CR2"),
+        _ => panic!("Expected FileNotFound")
+    }
+}
+
+#[test]
+fn invalid_coordinates_error() {
+    let image = ImageBuffer::new(4, 3).unwrap();
+    assert!(matches!(image.get(4, 0), Err(Error::InvalidCoordinates{x:4, y:0, width:4, height:3})));
+}
+
+#[test]
+fn dimension_mismatch_error() {
+    let a = ImageBuffer::new(4, 3).unwrap();
+    let b = ImageBuffer::new(3, 4).unwrap();
+    assert!(matches!(a.add(&b), Err(Error::DimensionMismatch{expected:(4, 3), found:(3, 4)})));
+}
+
+#[test]
+fn float32_tiff_round_trip() {
+    let image = ImageBuffer::from_vec(vec![0.25, 1.5, 65535.75, -3.0, 1234.5678, 0.0], 3, 2).unwrap();
+    let path = temp_path("cr2_to_tiff_halpha_float32.tif");
+    image.save_as(&path, OutputFormat::Float32).unwrap();
+
+    let loaded = ImageBuffer::from_file(&path).unwrap();
+    assert_eq!(loaded.width, 3);
+    assert_eq!(loaded.height, 2);
+    assert_eq!(loaded.get(2, 0).unwrap(), 65535.75);
+    assert_eq!(loaded.get(0, 1).unwrap(), -3.0);
+    assert_eq!(loaded.get(1, 1).unwrap(), 1234.5678);
+}
+
+#[test]
+fn gray16_tiff_round_trip() {
+    let image = ImageBuffer::from_vec(vec![0.0, 1.4, 32768.0, 65535.0], 2, 2).unwrap();
+    let path = temp_path("cr2_to_tiff_halpha_gray16.tif");
+    image.save_as(&path, OutputFormat::Gray16).unwrap();
+
+    let loaded = ImageBuffer::from_file(&path).unwrap();
+    assert_eq!(loaded.get(1, 0).unwrap(), 1.0);
+    assert_eq!(loaded.get(0, 1).unwrap(), 32768.0);
+    assert_eq!(loaded.get(1, 1).unwrap(), 65535.0);
+}
+
+#[test]
+fn float32_requires_tiff() {
+    let image = ImageBuffer::new(2, 2).unwrap();
+    let path = temp_path("cr2_to_tiff_halpha_float32.png");
+    assert!(matches!(image.save_as(&path, OutputFormat::Float32), Err(Error::Encode{..})));
+}
+
+#[test]
+fn rotate_quarter_turn() {
+    // 1 2 3
+    // 4 5 6
+    // 7 8 9
+    let image = ImageBuffer::from_vec((1..=9).map(|v| v as f32).collect(), 3, 3).unwrap();
+    let rotated = image.rotate(90.0, Interpolation::Bilinear).unwrap();
+    let expected = [3.0, 6.0, 9.0, 2.0, 5.0, 8.0, 1.0, 4.0, 7.0];
+    for (i, e) in expected.iter().enumerate() {
+        assert!((rotated.get(i % 3, i / 3).unwrap() - e).abs() < 1e-4, "{} at {}", rotated.get(i % 3, i / 3).unwrap(), i);
+    }
+
+    let back = rotated.rotate(-90.0, Interpolation::Bilinear).unwrap();
+    assert!((back.get(0, 0).unwrap() - 1.0).abs() < 1e-4);
+    let unchanged = image.rotate(360.0, Interpolation::Bicubic).unwrap();
+    assert!((unchanged.get(2, 1).unwrap() - 6.0).abs() < 1e-3);
+}
+
+#[test]
+fn rotate_about_point() {
+    let mut image = ImageBuffer::new(9, 9).unwrap();
+    image.put(6, 2, 100.0).unwrap();
+    // Half a turn about (4, 2) moves the point from two pixels right to two pixels left
+    let rotated = image.rotate_about(4.0, 2.0, 180.0, Interpolation::Bilinear).unwrap();
+    assert!((rotated.get(2, 2).unwrap() - 100.0).abs() < 1e-3);
+    assert!(rotated.get(6, 2).unwrap().abs() < 1e-3);
+    // Corners rotated in from outside the frame are zero
+    let rotated = ImageBuffer::from_vec(vec![1.0; 81], 9, 9).unwrap().rotate(45.0, Interpolation::Bilinear).unwrap();
+    assert_eq!(rotated.get(0, 0).unwrap(), 0.0);
+    assert!((rotated.get(4, 4).unwrap() - 1.0).abs() < 1e-5);
+}
@@ -0,0 +1,79 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::limb::{self, LimbPoint};
+
+// Draws an anti-aliased disk with an optional bright "prominence" bump on the limb
+fn synthetic_disk(width:usize, height:usize, cx:f32, cy:f32, radius:f32, prominence:bool) -> ImageBuffer {
+    let mut image = ImageBuffer::new(width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let dx = x as f32 - cx;
+            let dy = y as f32 - cy;
+            let d = (dx * dx + dy * dy).sqrt();
+            let mut val = 1000.0 + 30000.0 * (radius - d + 0.5).clamp(0.0, 1.0);
+            if prominence {
+                let px = x as f32 - (cx + radius + 4.0);
+                let py = y as f32 - cy;
+                if (px * px + py * py).sqrt() < 6.0 {
+                    val = 25000.0;
+                }
+            }
+            image.put(x, y, val).unwrap();
+        }
+    }
+    image
+}
+
+#[test]
+fn least_squares_exact_circle() {
+    let points:Vec<LimbPoint> = (0..36).map(|i| {
+        let t = i as f32 * std::f32::consts::PI / 18.0;
+        LimbPoint{x:50.0 + 20.0 * t.cos(), y:40.0 + 20.0 * t.sin()}
+    }).collect();
+    let disk = limb::fit_circle_least_squares(&points).unwrap();
+    assert!((disk.x - 50.0).abs() < 0.01);
+    assert!((disk.y - 40.0).abs() < 0.01);
+    assert!((disk.radius - 20.0).abs() < 0.01);
+}
+
+#[test]
+fn ransac_rejects_outliers() {
+    let mut points:Vec<LimbPoint> = (0..36).map(|i| {
+        let t = i as f32 * std::f32::consts::PI / 18.0;
+        LimbPoint{x:50.0 + 20.0 * t.cos(), y:40.0 + 20.0 * t.sin()}
+    }).collect();
+    points.push(LimbPoint{x:90.0, y:40.0});
+    points.push(LimbPoint{x:85.0, y:45.0});
+    let disk = limb::fit_circle_ransac(&points, 200, 1.0).unwrap();
+    assert!((disk.x - 50.0).abs() < 0.05);
+    assert!((disk.radius - 20.0).abs() < 0.05);
+}
+
+#[test]
+fn find_disk_with_prominence() {
+    let image = synthetic_disk(160, 140, 83.3, 66.7, 45.0, true);
+    let disk = limb::find_disk(&image).unwrap();
+    assert!((disk.x - 83.3).abs() < 0.5);
+    assert!((disk.y - 66.7).abs() < 0.5);
+    assert!((disk.radius - 45.0).abs() < 1.0);
+}
+
+#[test]
+fn find_disk_clipped_edge() {
+    let image = synthetic_disk(120, 120, 40.0, 60.0, 50.0, false);
+    let disk = limb::find_disk(&image).unwrap();
+    assert!((disk.x - 40.0).abs() < 0.5);
+    assert!((disk.y - 60.0).abs() < 0.5);
+}
+
+#[test]
+fn find_disk_blank_image() {
+    let image = ImageBuffer::new(32, 32).unwrap();
+    assert!(limb::find_disk(&image).is_err());
+}
+
+#[test]
+fn limb_points_of_empty_image() {
+    let image = ImageBuffer::new(0, 0).unwrap();
+    let estimate = limb::Disk{x:0.0, y:0.0, radius:10.0};
+    assert!(limb::find_limb_points(&image, &estimate, 36).is_empty());
+}
@@ -0,0 +1,164 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::cfa::{CfaChannel, CfaPattern};
+use cr2_to_tiff_halpha::datetime::DateTime;
+use cr2_to_tiff_halpha::metadata::{self, RawMetadata};
+use cr2_to_tiff_halpha::stacking::StackSummary;
+use cr2_to_tiff_halpha::fits::{self, BitDepth};
+use cr2_to_tiff_halpha::mean;
+
+mod common;
+use common::temp_path;
+
+fn eos_50d(exposure:f32, iso:f32) -> RawMetadata {
+    RawMetadata{
+        make:"Canon".to_string(),
+        model:"EOS 50D".to_string(),
+        exposure:Some(exposure),
+        iso:Some(iso),
+        timestamp:Some(DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap()),
+        black_level:1023.0,
+        white_level:15000.0,
+        cfa:Some(CfaPattern::rggb()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn reports_mismatched_darks() {
+    let light = eos_50d(0.0025, 160.0);
+    assert!(light.mismatches(&eos_50d(0.0025, 160.0)).is_empty());
+    assert_eq!(light.camera(), "Canon EOS 50D");
+
+    let found = light.mismatches(&eos_50d(0.005, 200.0));
+    assert_eq!(found.len(), 2, "{:?}", found);
+    assert!(found[0].contains("ISO"));
+    assert!(found[1].contains("exposure"));
+
+    // Rounding in the recorded exposure time is tolerated
+    assert!(light.mismatches(&eos_50d(0.002_51, 160.0)).is_empty());
+
+    let other_camera = RawMetadata{model:"EOS 5D Mark III".to_string(), ..eos_50d(0.0025, 160.0)};
+    assert_eq!(light.mismatches(&other_camera).len(), 1);
+
+    // Missing values aren't compared
+    assert!(light.mismatches(&RawMetadata::default()).is_empty());
+}
+
+#[test]
+fn black_level_per_cell() {
+    let m = RawMetadata{black_level:1000.0, cell_black:[[1.0, 2.0], [3.0, 4.0]], ..Default::default()};
+    assert_eq!(m.black_at(0, 0), 1001.0);
+    assert_eq!(m.black_at(1, 0), 1002.0);
+    assert_eq!(m.black_at(2, 1), 1003.0);
+    assert_eq!(m.black_at(5, 3), 1004.0);
+}
+
+#[test]
+fn metadata_is_carried_with_the_image() {
+    let mut image = ImageBuffer::new(8, 8).unwrap();
+    assert!(image.metadata().is_none());
+    image.set_metadata(Some(eos_50d(0.0025, 160.0)));
+
+    let processed = image.scale(2.0).unwrap().shift(1, 1).unwrap().crop(4, 4).unwrap();
+    assert_eq!(processed.metadata(), Some(&eos_50d(0.0025, 160.0)));
+
+    // Extracting a plane keeps the shooting metadata but drops the mosaic layout
+    let plane = image.channel(CfaChannel::Red).unwrap();
+    assert_eq!(plane.metadata().unwrap().iso, Some(160.0));
+    assert_eq!(plane.cfa_pattern(), None);
+
+    let mut bare = ImageBuffer::new(4, 4).unwrap();
+    bare.set_cfa_pattern(None);
+    assert!(bare.metadata().is_none());
+    bare.set_cfa_pattern(Some(CfaPattern::rggb()));
+    assert_eq!(bare.cfa_pattern(), Some(CfaPattern::rggb()));
+}
+
+#[test]
+fn stacks_record_their_provenance() {
+    let mut summary = StackSummary::default();
+    summary.add("IMG_0001.CR2", 1.0, Some(&eos_50d(0.0025, 160.0)));
+    summary.add("IMG_0002.CR2", 1.0, Some(&eos_50d(0.0025, 200.0)));
+    summary.add("IMG_0003.CR2", 1.0, None);
+    assert_eq!(summary.count(), 3);
+    assert_eq!(summary.metadata, Some(eos_50d(0.0025, 160.0)));
+
+    let file = temp_path("metadata_provenance.fits");
+    let file = file.as_str();
+    let header = mean::stack_header(&summary);
+    assert_eq!(header.instrument.as_deref(), Some("Canon EOS 50D"));
+    assert_eq!(header.date_obs.as_deref(), Some("2021-03-16T17:45:02.000"));
+    fits::save(&ImageBuffer::new(4, 4).unwrap(), file, BitDepth::Float32, &header).unwrap();
+
+    // A master loaded back can be checked against raw lights
+    let master = ImageBuffer::from_file(file).unwrap();
+    let loaded = master.metadata().unwrap();
+    assert_eq!(loaded.exposure, Some(0.0025));
+    assert_eq!(loaded.iso, Some(160.0));
+    assert_eq!(loaded.timestamp, Some(DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap()));
+    assert!(eos_50d(0.0025, 160.0).mismatches(loaded).is_empty());
+}
+
+#[test]
+fn unreadable_raws_have_no_metadata() {
+    assert_eq!(metadata::read_metadata(b"not a raw file"), None);
+    assert!(metadata::read_file("testing/does_not_exist.CR2").is_err());
+    assert!(fits::FitsHeader::default().metadata().is_none());
+}
+
+// A 12x6 readout with four masked columns on the left and two masked rows on top.
+// Each photosite of the 2x2 cell, counted from the first visible pixel, reads
+// its own black level in the border.
+fn readout(black:[[u16; 2]; 2]) -> Vec<u16> {
+    let (width, height, left, top) = (12, 6, 4, 2);
+    let mut data = vec![0_u16; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let level = black[(y + 2 - top) % 2][(x + 4 - left) % 2];
+            data[y * width + x] = if x < left || y < top { level } else { level + 500 };
+        }
+    }
+    // A hot pixel in the border doesn't move the median
+    data[2 * width] = 16000;
+    data
+}
+
+#[test]
+fn measures_black_level_from_the_masked_border() {
+    let data = readout([[2048, 2050], [2049, 2047]]);
+    assert_eq!(metadata::measure_masked_black(&data, 12, 6, 4, 2), Some([[2048.0, 2050.0], [2049.0, 2047.0]]));
+
+    // Without a left border, the rows above are used
+    let data = readout([[1024, 1024], [1023, 1025]]);
+    let top_only:Vec<u16> = data.chunks(12).flat_map(|row| row[4..].to_vec()).collect();
+    assert_eq!(metadata::measure_masked_black(&top_only, 8, 6, 0, 2), Some([[1024.0, 1024.0], [1023.0, 1025.0]]));
+
+    assert_eq!(metadata::measure_masked_black(&data, 12, 6, 0, 0), None);
+    assert_eq!(metadata::measure_masked_black(&data[..10], 12, 6, 4, 2), None);
+}
+
+#[test]
+fn subtracts_black_level_per_photosite() {
+    let mut image = ImageBuffer::from_vec(vec![1100.0, 1200.0, 1300.0, 1000.0, 1500.0, 1600.0, 1700.0, 1800.0], 4, 2).unwrap();
+    assert_eq!(image.subtract_black_level().unwrap().get(0, 0).unwrap(), 1100.0);
+
+    let m = RawMetadata{black_level:1000.0, cell_black:[[0.0, 50.0], [25.0, 75.0]], ..Default::default()};
+    assert!(m.has_black_level());
+    assert!(!RawMetadata::default().has_black_level());
+    image.set_metadata(Some(m));
+
+    let corrected = image.subtract_black_level().unwrap();
+    assert_eq!(corrected.get(0, 0).unwrap(), 100.0);
+    assert_eq!(corrected.get(1, 0).unwrap(), 150.0);
+    assert_eq!(corrected.get(2, 0).unwrap(), 300.0);
+    // Clamped rather than going negative
+    assert_eq!(corrected.get(3, 0).unwrap(), 0.0);
+    assert_eq!(corrected.get(0, 1).unwrap(), 475.0);
+    assert_eq!(corrected.get(3, 1).unwrap(), 725.0);
+
+    // A single plane has its photosites' black level throughout
+    let blue = image.channel(CfaChannel::Blue).unwrap();
+    assert_eq!(blue.metadata().unwrap().black_at(0, 0), 1075.0);
+    assert_eq!(blue.metadata().unwrap().black_at(1, 0), 1075.0);
+    assert_eq!(image.channel(CfaChannel::Green).unwrap().metadata().unwrap().black_at(0, 0), 1037.5);
+}
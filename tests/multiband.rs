@@ -0,0 +1,77 @@
+use cr2_to_tiff_halpha::imagebuffer::{ImageBuffer, OutputFormat};
+use cr2_to_tiff_halpha::multiband::MultiBandImage;
+use cr2_to_tiff_halpha::error::Error;
+
+mod common;
+use common::temp_path;
+
+fn rgb() -> MultiBandImage {
+    MultiBandImage::from_rgb(ImageBuffer::from_vec(vec![1.0, 2.0, 3.0, 4.0], 2, 2).unwrap(),
+                             ImageBuffer::from_vec(vec![10.0, 20.0, 30.0, 40.0], 2, 2).unwrap(),
+                             ImageBuffer::from_vec(vec![100.0, 200.0, 300.0, 400.0], 2, 2).unwrap()).unwrap()
+}
+
+#[test]
+fn split_and_merge() {
+    let image = rgb();
+    assert_eq!(image.num_bands(), 3);
+    assert_eq!(image.get(1, 1, 2).unwrap(), 400.0);
+
+    let mut bands = image.split();
+    bands.swap(0, 2);
+    let merged = MultiBandImage::merge(bands).unwrap();
+    assert_eq!(merged.get_pixel(0, 1).unwrap(), vec![300.0, 30.0, 3.0]);
+
+    let mismatched = vec![ImageBuffer::new(2, 2).unwrap(), ImageBuffer::new(3, 2).unwrap()];
+    assert!(matches!(MultiBandImage::merge(mismatched), Err(Error::DimensionMismatch{..})));
+}
+
+#[test]
+fn band_index_is_checked() {
+    let mut image = rgb();
+    assert!(matches!(image.band(3), Err(Error::InvalidBand{band:3, bands:3})));
+    assert!(image.put(0, 0, 5, 1.0).is_err());
+    assert!(image.set_band(1, ImageBuffer::new(1, 1).unwrap()).is_err());
+}
+
+#[test]
+fn per_band_arithmetic() {
+    let image = rgb();
+    let doubled = image.add(&image).unwrap();
+    assert_eq!(doubled.get_pixel(1, 0).unwrap(), vec![4.0, 40.0, 400.0]);
+
+    let balanced = image.scale_bands(&[2.0, 1.0, 0.5]).unwrap();
+    assert_eq!(balanced.get_pixel(0, 0).unwrap(), vec![2.0, 10.0, 50.0]);
+    assert!(image.scale_bands(&[1.0, 1.0]).is_err());
+
+    let zeroed = image.subtract(&image).unwrap();
+    assert_eq!(zeroed.get_min_max().unwrap().max, 0.0);
+
+    let normalized = image.normalize_bands(0.0, 1.0).unwrap();
+    assert_eq!(normalized.get_pixel(1, 1).unwrap(), vec![1.0, 1.0, 1.0]);
+}
+
+#[test]
+fn fits_cube_round_trip() {
+    let image = rgb();
+    let path = temp_path("cr2_to_tiff_halpha_cube.fits");
+    image.save(&path).unwrap();
+
+    let loaded = MultiBandImage::from_fits(&path).unwrap();
+    assert_eq!(loaded.num_bands(), 3);
+    assert_eq!(loaded.get_pixel(1, 0).unwrap(), vec![2.0, 20.0, 200.0]);
+
+    // Single band readers get the first plane
+    let first = ImageBuffer::from_file(&path).unwrap();
+    assert_eq!(first.get(1, 1).unwrap(), 4.0);
+}
+
+#[test]
+fn save_rgb_tiff_and_png() {
+    let image = rgb();
+    image.save_as(&temp_path("cr2_to_tiff_halpha_rgb.png"), OutputFormat::Rgb16).unwrap();
+    image.save_as(&temp_path("cr2_to_tiff_halpha_rgb32.tif"), OutputFormat::Float32).unwrap();
+
+    let two_band = MultiBandImage::new(2, 2, 2).unwrap();
+    assert!(matches!(two_band.save(&temp_path("cr2_to_tiff_halpha_two_band.png")), Err(Error::Encode{..})));
+}
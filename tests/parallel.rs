@@ -0,0 +1,61 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::parallel;
+
+#[test]
+fn map_and_zip_map_keep_order() {
+    let src:Vec<f32> = (0..1000).map(|v| v as f32).collect();
+    let doubled = parallel::map(&src, |v| v * 2.0);
+    assert_eq!(doubled[999], 1998.0);
+    assert_eq!(doubled[10], 20.0);
+
+    let summed = parallel::zip_map(&src, &doubled, |a, b| a + b);
+    assert_eq!(summed[500], 1500.0);
+
+    let generated = parallel::generate(5, |i| (i * i) as f32);
+    assert_eq!(generated, vec![0.0, 1.0, 4.0, 9.0, 16.0]);
+}
+
+#[test]
+fn reductions() {
+    let src = vec![3.0, -2.0, 0.0, 7.5, 1.0];
+    assert_eq!(parallel::min_max(&src), (-2.0, 7.5));
+
+    let (sum, count) = parallel::sum_where(&src, |v| v > 0.0);
+    assert_eq!(sum, 11.5);
+    assert_eq!(count, 3);
+}
+
+#[test]
+fn map_items_returns_results_in_input_order() {
+    let items:Vec<String> = (0..50).map(|i| format!("frame{}", i)).collect();
+    let lengths = parallel::map_items(&items, |s| s.len());
+    assert_eq!(lengths[0], 6);
+    assert_eq!(lengths[49], 7);
+    assert_eq!(lengths.len(), 50);
+}
+
+#[test]
+fn image_arithmetic_semantics() {
+    let a = ImageBuffer::from_vec(vec![1.0, 5.0, 0.0, 4.0], 2, 2).unwrap();
+    let b = ImageBuffer::from_vec(vec![2.0, 2.0, 2.0, 2.0], 2, 2).unwrap();
+
+    let diff = a.subtract(&b).unwrap();
+    assert_eq!(diff.get(0, 0).unwrap(), 0.0);
+    assert_eq!(diff.get(1, 0).unwrap(), 3.0);
+
+    // Zero pixels are excluded from the mean
+    assert_eq!(a.mean(), 10.0 / 3.0);
+
+    let mm = a.get_min_max(-1.0).unwrap();
+    assert_eq!(mm.min, 0.0);
+    assert_eq!(mm.max, 5.0);
+}
+
+#[test]
+fn shift_fills_with_zero() {
+    let image = ImageBuffer::from_vec(vec![1.0, 2.0, 3.0, 4.0], 2, 2).unwrap();
+    let shifted = image.shift(1, 0).unwrap();
+    assert_eq!(shifted.get(0, 0).unwrap(), 0.0);
+    assert_eq!(shifted.get(1, 0).unwrap(), 1.0);
+    assert_eq!(shifted.get(1, 1).unwrap(), 3.0);
+}
@@ -0,0 +1,113 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::quality::{self, FrameScore, QualityMetric, Selection};
+use cr2_to_tiff_halpha::batch;
+use cr2_to_tiff_halpha::error::Error;
+
+mod common;
+use common::temp_path;
+
+// Solar disk whose limb falls off over the given width in pixels
+fn disk(edge:f32) -> ImageBuffer {
+    let mut image = ImageBuffer::new(64, 64).unwrap();
+    for y in 0..64 {
+        for x in 0..64 {
+            let r = ((x as f32 - 32.0).powi(2) + (y as f32 - 32.0).powi(2)).sqrt();
+            let t = ((r - 20.0) / edge + 0.5).clamp(0.0, 1.0);
+            let granulation = if (x / 2 + y / 2) % 2 == 0 { 50.0 } else { 0.0 };
+            image.put(x, y, (1.0 - t) * (1000.0 + granulation) + 10.0).unwrap();
+        }
+    }
+    image
+}
+
+// Three pixel box blur
+fn blur(image:&ImageBuffer) -> ImageBuffer {
+    let mut dest = image.clone();
+    for y in 1..image.height - 1 {
+        for x in 1..image.width - 1 {
+            let mut sum = 0.0;
+            for dy in 0..3 {
+                for dx in 0..3 {
+                    sum += image.get(x + dx - 1, y + dy - 1).unwrap();
+                }
+            }
+            dest.put(x, y, sum / 9.0).unwrap();
+        }
+    }
+    dest
+}
+
+fn score(index:usize, score:f64) -> FrameScore {
+    FrameScore{index, name:format!("IMG_{}.CR2", index), score}
+}
+
+#[test]
+fn sharper_frames_score_higher() {
+    let sharp = disk(1.0);
+    let soft = blur(&blur(&sharp));
+    for metric in [QualityMetric::Laplacian, QualityMetric::Gradient].iter() {
+        let a = quality::score(&sharp, *metric).unwrap();
+        let b = quality::score(&soft, *metric).unwrap();
+        assert!(a > b, "{:?}: {} vs {}", metric, a, b);
+    }
+
+    // Brightness alone doesn't change the ranking
+    let dim = sharp.scale(0.5).unwrap();
+    let a = quality::laplacian_variance(&sharp).unwrap();
+    let b = quality::laplacian_variance(&dim).unwrap();
+    assert!((a - b).abs() / a < 1e-3);
+
+    assert!(matches!(quality::score(&ImageBuffer::new(2, 2).unwrap(), QualityMetric::Laplacian), Err(Error::EmptyImage)));
+}
+
+#[test]
+fn limb_sharpness_prefers_a_hard_edge() {
+    let hard = quality::limb_sharpness(&disk(1.0)).unwrap();
+    let soft = quality::limb_sharpness(&disk(8.0)).unwrap();
+    assert!(hard > soft * 2.0, "{} vs {}", hard, soft);
+}
+
+#[test]
+fn selection_specs() {
+    assert_eq!(Selection::from_spec("25").unwrap(), Selection::Best(25));
+    assert_eq!(Selection::from_spec(" 10% ").unwrap(), Selection::BestPercent(10.0));
+    assert!(Selection::from_spec("0").is_err());
+    assert!(Selection::from_spec("150%").is_err());
+    assert!(Selection::from_spec("best").is_err());
+
+    assert_eq!(Selection::Best(25).count_of(10), 10);
+    assert_eq!(Selection::BestPercent(10.0).count_of(15), 2);
+    assert_eq!(Selection::BestPercent(1.0).count_of(5), 1);
+    assert_eq!(Selection::BestPercent(50.0).count_of(0), 0);
+    assert_eq!(Selection::All.count_of(7), 7);
+}
+
+#[test]
+fn select_and_report_best_first() {
+    let scores = vec![score(0, 1.0), score(1, 5.0), score(2, 3.0), score(3, 5.0)];
+    let kept = quality::select(&scores, Selection::Best(3));
+    assert_eq!(kept.iter().map(|s| s.index).collect::<Vec<usize>>(), vec![1, 3, 2]);
+
+    let path = temp_path("cr2_to_tiff_halpha_quality.csv");
+    quality::write_report(&scores, Selection::BestPercent(50.0), &path).unwrap();
+    let csv = std::fs::read_to_string(&path).unwrap();
+    let lines:Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "rank,file,score,selected");
+    assert_eq!(lines[1], "1,IMG_1.CR2,5,true");
+    assert_eq!(lines[3], "3,IMG_2.CR2,3,false");
+    assert_eq!(lines.len(), 5);
+}
+
+#[test]
+fn scoring_skips_frames_that_fail() {
+    let items:Vec<usize> = (0..5).collect();
+    let scores = batch::score_with(&items, |i| {
+        if *i == 2 { Err(Error::EmptyImage) } else { Ok(disk(1.0 + *i as f32)) }
+    }, QualityMetric::Gradient, 2);
+    assert_eq!(scores.len(), 4);
+    assert_eq!(scores[2].index, 3);
+    assert_eq!(scores[2].name, "3");
+
+    let best = quality::select(&scores, Selection::Best(1));
+    assert_eq!(best[0].index, 0);
+}
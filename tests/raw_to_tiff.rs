@@ -0,0 +1,23 @@
+use cr2_to_tiff_halpha::raw_to_tiff::CropMode;
+
+#[test]
+fn parse_crop_size() {
+    assert_eq!(CropMode::from_size_str("1600x1200").unwrap(), CropMode::Size{width:1600, height:1200});
+    assert!(CropMode::from_size_str("1600").is_err());
+}
+
+#[test]
+fn parse_crop_region() {
+    assert_eq!(CropMode::from_region_str("-10,20,300,400").unwrap(), CropMode::Region{left:-10, top:20, width:300, height:400});
+    assert!(CropMode::from_region_str("10,20,0,400").is_err());
+    assert!(CropMode::from_region_str("10,20,300").is_err());
+}
+
+#[test]
+fn parse_disk_margin() {
+    assert_eq!(CropMode::from_margin_str("0.1").unwrap(), CropMode::DiskMargin(0.1));
+    assert_eq!(CropMode::from_margin_str("-0.5").unwrap(), CropMode::DiskMargin(-0.5));
+    assert!(CropMode::from_margin_str("-1").is_err());
+    assert!(CropMode::from_margin_str("-2").is_err());
+    assert!(CropMode::from_margin_str("wide").is_err());
+}
@@ -0,0 +1,59 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::registration;
+
+fn gaussian_disk(width:usize, height:usize, cx:f32, cy:f32) -> ImageBuffer {
+    let mut image = ImageBuffer::new(width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let dx = x as f32 - cx;
+            let dy = y as f32 - cy;
+            let val = 1000.0 * (-(dx * dx + dy * dy) / (2.0 * 6.0 * 6.0)).exp()
+                    + 300.0 * (-(dx * dx + (dy - 8.0) * (dy - 8.0)) / (2.0 * 2.0 * 2.0)).exp();
+            image.put(x, y, val).unwrap();
+        }
+    }
+    image
+}
+
+#[test]
+fn phase_correlate_integer_offset() {
+    let reference = gaussian_disk(64, 64, 32.0, 32.0);
+    let moved = reference.shift(5, -3).unwrap();
+    let offset = registration::phase_correlate(&reference, &moved).unwrap();
+    assert!((offset.h + 5.0).abs() < 0.1);
+    assert!((offset.v - 3.0).abs() < 0.1);
+}
+
+#[test]
+fn phase_correlate_subpixel_offset() {
+    let reference = gaussian_disk(64, 64, 32.0, 32.0);
+    let moved = gaussian_disk(64, 64, 34.4, 30.7);
+    let offset = registration::phase_correlate(&reference, &moved).unwrap();
+    assert!((offset.h + 2.4).abs() < 0.25);
+    assert!((offset.v - 1.3).abs() < 0.25);
+}
+
+#[test]
+fn register_aligns_to_reference() {
+    let reference = gaussian_disk(64, 64, 32.0, 32.0);
+    let moved = gaussian_disk(64, 64, 33.5, 31.0);
+    let aligned = registration::register(&reference, &moved, Interpolation::Bicubic).unwrap();
+    let diff = (aligned.get(32, 32).unwrap() - reference.get(32, 32).unwrap()).abs();
+    assert!(diff < 25.0);
+}
+
+#[test]
+fn shift_subpixel_bilinear() {
+    let image = ImageBuffer::from_vec(vec![0.0, 10.0, 20.0, 30.0], 4, 1).unwrap();
+    let shifted = image.shift_subpixel(0.5, 0.0, Interpolation::Bilinear).unwrap();
+    assert_eq!(shifted.get(1, 0).unwrap(), 5.0);
+    assert_eq!(shifted.get(2, 0).unwrap(), 15.0);
+}
+
+#[test]
+fn phase_correlate_size_mismatch() {
+    let a = ImageBuffer::new(16, 16).unwrap();
+    let b = ImageBuffer::new(16, 8).unwrap();
+    assert!(registration::phase_correlate(&a, &b).is_err());
+}
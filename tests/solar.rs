@@ -0,0 +1,94 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::interpolation::Interpolation;
+use cr2_to_tiff_halpha::datetime::DateTime;
+use cr2_to_tiff_halpha::solar::{self, SolarEphemeris};
+use cr2_to_tiff_halpha::fits::{self, BitDepth, FitsHeader};
+
+mod common;
+use common::{peak, temp_path};
+
+fn close(a:f64, b:f64, tolerance:f64) -> bool {
+    (a - b).abs() < tolerance
+}
+
+#[test]
+fn meeus_ephemeris_example() {
+    // Meeus example 29.a, 1992 October 13 at 0h
+    let e = SolarEphemeris::at(&DateTime::new(1992, 10, 13, 0, 0, 0.0).unwrap());
+    assert!(close(e.p, 26.27, 0.02), "P {}", e.p);
+    assert!(close(e.b0, 5.99, 0.02), "B0 {}", e.b0);
+    assert!(close(e.l0, 238.63, 0.02), "L0 {}", e.l0);
+    assert!(close(e.distance, 0.997_66, 0.000_1), "R {}", e.distance);
+
+    // Meeus example 25.a, the same date
+    let position = solar::position(2_448_908.5);
+    assert!(close(position.ra, 198.380_83, 0.01), "RA {}", position.ra);
+    assert!(close(position.dec, -7.785_07, 0.01), "Dec {}", position.dec);
+}
+
+#[test]
+fn apparent_radius_follows_distance() {
+    let perihelion = SolarEphemeris::at(&DateTime::new(2021, 1, 2, 14, 0, 0.0).unwrap());
+    let aphelion = SolarEphemeris::at(&DateTime::new(2021, 7, 5, 22, 0, 0.0).unwrap());
+    assert!(close(perihelion.semi_diameter, 975.5, 0.5), "{}", perihelion.semi_diameter);
+    assert!(close(aphelion.semi_diameter, 943.8, 0.5), "{}", aphelion.semi_diameter);
+    assert!(close(perihelion.radius_pixels(1.5), perihelion.semi_diameter / 1.5, 1e-9));
+
+    // B0 is zero in early June and December, P is zero in early January and July
+    assert!(SolarEphemeris::at(&DateTime::new(2021, 6, 7, 0, 0, 0.0).unwrap()).b0.abs() < 0.2);
+    assert!(SolarEphemeris::at(&DateTime::new(2021, 1, 5, 0, 0, 0.0).unwrap()).p.abs() < 1.0);
+}
+
+// Position of the brightest pixel
+#[test]
+fn rotates_solar_north_up() {
+    // A spot ten pixels from the center, 30 degrees counterclockwise from up
+    let (cx, cy) = (16.0_f32, 16.0_f32);
+    let (sx, sy) = (cx - 10.0 * 30.0_f32.to_radians().sin(), cy - 10.0 * 30.0_f32.to_radians().cos());
+    let mut image = ImageBuffer::new(33, 33).unwrap();
+    for y in 0..33 {
+        for x in 0..33 {
+            let d2 = (x as f32 - sx).powi(2) + (y as f32 - sy).powi(2);
+            image.put(x, y, 1000.0 * (-d2 / 4.0).exp()).unwrap();
+        }
+    }
+
+    let ephemeris = SolarEphemeris{p:30.0, b0:0.0, l0:0.0, semi_diameter:960.0, distance:1.0};
+    let rotated = solar::north_up(&image, &ephemeris, 0.0, Interpolation::Bicubic).unwrap();
+    assert_eq!(peak(&rotated), (16, 6));
+
+    // With celestial north itself 30 degrees clockwise from up, solar north is already up
+    let unchanged = solar::north_up(&image, &ephemeris, 30.0, Interpolation::Bicubic).unwrap();
+    assert_eq!(peak(&unchanged), peak(&image));
+}
+
+#[test]
+fn julian_date_round_trip() {
+    let t = DateTime::new(2021, 3, 16, 17, 45, 2.5).unwrap();
+    assert_eq!(DateTime::from_julian_date(t.julian_date()), t);
+    assert_eq!(t.to_iso(), "2021-03-16T17:45:02.500");
+
+    // Crossing midnight and the end of February
+    let t = DateTime::new(2020, 2, 29, 23, 59, 59.0).unwrap();
+    assert_eq!(DateTime::from_julian_date(t.julian_date() + 2.0 / 86_400.0), DateTime::new(2020, 3, 1, 0, 0, 1.0).unwrap());
+}
+
+#[test]
+fn ephemeris_round_trips_through_fits() {
+    let file = temp_path("solar_ephemeris.fits");
+    let file = file.as_str();
+    let ephemeris = SolarEphemeris::at(&DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap());
+    let header = FitsHeader{
+        date_obs:Some(DateTime::new(2021, 3, 16, 17, 45, 2.0).unwrap().to_iso()),
+        solar:Some(ephemeris),
+        ..Default::default()
+    };
+    fits::save(&ImageBuffer::new(4, 4).unwrap(), file, BitDepth::Float32, &header).unwrap();
+    let (_, loaded) = fits::load(file).unwrap();
+    assert_eq!(loaded.date_obs.as_deref(), Some("2021-03-16T17:45:02.000"));
+    let solar = loaded.solar.unwrap();
+    assert!(close(solar.p, ephemeris.p, 1e-9));
+    assert!(close(solar.b0, ephemeris.b0, 1e-9));
+    assert!(close(solar.l0, ephemeris.l0, 1e-9));
+    assert!(close(solar.semi_diameter, ephemeris.semi_diameter, 1e-9));
+}
@@ -0,0 +1,65 @@
+use cr2_to_tiff_halpha::imagebuffer::ImageBuffer;
+use cr2_to_tiff_halpha::stacking::{self, StackMethod};
+
+fn frames_with_outlier() -> Vec<ImageBuffer> {
+    let mut frames:Vec<ImageBuffer> = Vec::new();
+    for i in 0..9 {
+        let mut frame = ImageBuffer::from_vec(vec![100.0 + i as f32; 4], 2, 2).unwrap();
+        if i == 4 {
+            frame.put(1, 1, 60000.0).unwrap();
+        }
+        frames.push(frame);
+    }
+    frames
+}
+
+#[test]
+fn combine_mean() {
+    let stack = stacking::combine(&frames_with_outlier(), &StackMethod::Mean).unwrap();
+    assert_eq!(stack.get(0, 0).unwrap(), 104.0);
+    assert!(stack.get(1, 1).unwrap() > 6000.0);
+}
+
+#[test]
+fn combine_median_rejects_outlier() {
+    let stack = stacking::combine(&frames_with_outlier(), &StackMethod::Median).unwrap();
+    assert_eq!(stack.get(0, 0).unwrap(), 104.0);
+    assert_eq!(stack.get(1, 1).unwrap(), 105.0);
+}
+
+#[test]
+fn combine_sigma_clip_rejects_outlier() {
+    let method = StackMethod::SigmaClip{kappa:2.0, iterations:5};
+    let stack = stacking::combine(&frames_with_outlier(), &method).unwrap();
+    assert_eq!(stack.get(0, 0).unwrap(), 104.0);
+    assert_eq!(stack.get(1, 1).unwrap(), 104.0);
+}
+
+#[test]
+fn combine_winsorized_limits_outlier() {
+    let method = StackMethod::Winsorized{kappa:2.0, iterations:5};
+    let stack = stacking::combine(&frames_with_outlier(), &method).unwrap();
+    assert_eq!(stack.get(0, 0).unwrap(), 104.0);
+    assert!(stack.get(1, 1).unwrap() < 110.0);
+}
+
+#[test]
+fn rejects_a_lone_hot_frame_among_five() {
+    // Integer data whose good frames agree exactly, so their deviation is zero
+    let values = [100.0, 100.0, 5000.0, 100.0, 100.0];
+    let clip = StackMethod::SigmaClip{kappa:3.0, iterations:5};
+    assert_eq!(stacking::combine_values(&values, &clip), 100.0);
+    let winsorized = StackMethod::Winsorized{kappa:3.0, iterations:5};
+    assert!((stacking::combine_values(&values, &winsorized) - 100.0).abs() < 1.0);
+
+    // Values a count apart are kept rather than clamped to the median
+    let values = [100.0, 101.0, 100.0, 99.0, 100.0];
+    assert_eq!(stacking::combine_values(&values, &clip), 100.0);
+    assert_eq!(stacking::combine_values(&[100.0, 100.0, 101.0, 100.0, 100.0], &winsorized), 100.2);
+}
+
+#[test]
+fn combine_size_mismatch() {
+    let frames = vec![ImageBuffer::new(2, 2).unwrap(), ImageBuffer::new(3, 2).unwrap()];
+    assert!(stacking::combine(&frames, &StackMethod::Median).is_err());
+}
@@ -0,0 +1,83 @@
+use cr2_to_tiff_halpha::stacking::{self, FrameWeights, StackAccumulator, StackMethod, StackSummary};
+use cr2_to_tiff_halpha::quality::{self, FrameScore};
+use cr2_to_tiff_halpha::{fits, mean};
+
+mod common;
+use common::{constant, temp_path};
+
+#[test]
+fn weighted_combine() {
+    let frames = vec![constant(2, 2, 10.0), constant(2, 2, 20.0), constant(2, 2, 100.0)];
+    let weights = [3.0, 1.0, 0.0];
+
+    let mean = stacking::combine_weighted(&frames, &weights, &StackMethod::Mean).unwrap();
+    assert_eq!(mean.get(0, 0).unwrap(), 12.5);
+
+    let median = stacking::combine_weighted(&frames, &weights, &StackMethod::Median).unwrap();
+    assert_eq!(median.get(0, 0).unwrap(), 10.0);
+
+    // Equal weights match the unweighted median of an even count
+    let even = stacking::combine_weighted(&frames[..2], &[2.0, 2.0], &StackMethod::Median).unwrap();
+    assert_eq!(even.get(0, 0).unwrap(), 15.0);
+
+    assert!(stacking::combine_weighted(&frames, &[1.0], &StackMethod::Mean).is_err());
+}
+
+#[test]
+fn accumulator_weights_the_running_mean() {
+    let mut accumulator = StackAccumulator::new(&StackMethod::Mean);
+    accumulator.add_weighted(constant(2, 2, 10.0), 1.0).unwrap();
+    accumulator.add_weighted(constant(2, 2, 40.0), 0.5).unwrap();
+    assert!(accumulator.add_weighted(constant(2, 2, 1.0), -1.0).is_err());
+    assert_eq!(accumulator.weights(), &[1.0, 0.5]);
+    assert_eq!(accumulator.finish().unwrap().get(1, 1).unwrap(), 20.0);
+}
+
+#[test]
+fn effective_count() {
+    assert_eq!(stacking::effective_count(&[1.0, 1.0, 1.0, 1.0]), 4.0);
+    assert_eq!(stacking::effective_count(&[1.0, 0.0, 0.0]), 1.0);
+    assert_eq!(stacking::effective_count(&[]), 0.0);
+
+    let summary = StackSummary{frames:vec![("a".to_string(), 1.0), ("b".to_string(), 0.5)], ..Default::default()};
+    assert!(summary.is_weighted());
+    assert!((summary.effective_count() - 1.8).abs() < 1e-6);
+}
+
+#[test]
+fn weights_from_file_and_scores() {
+    let path = temp_path("cr2_to_tiff_halpha_weights.csv");
+    std::fs::write(&path, "file,weight\n# comment\n/data/light/IMG_0001.CR2, 0.5\n\nIMG_0002.CR2,2\n").unwrap();
+    let weights = FrameWeights::from_file(&path).unwrap();
+    assert_eq!(weights.len(), 2);
+    assert_eq!(weights.weight_of("/data/light/IMG_0001.CR2"), Some(0.5));
+    assert_eq!(weights.weight_of("/other/IMG_0002.CR2"), Some(2.0));
+    assert_eq!(weights.weight_of("IMG_0003.CR2"), None);
+    assert_eq!(weights.weight_or_default("IMG_0003.CR2"), 1.0);
+
+    std::fs::write(&path, "IMG_0001.CR2,-1\n").unwrap();
+    assert!(FrameWeights::from_file(&path).is_err());
+
+    let scores = vec![FrameScore{index:0, name:"a".to_string(), score:2.0},
+                      FrameScore{index:1, name:"b".to_string(), score:8.0}];
+    let weights = quality::weights_from_scores(&scores);
+    assert_eq!(weights.weight_of("a"), Some(0.25));
+    assert_eq!(weights.weight_of("b"), Some(1.0));
+}
+
+#[test]
+fn weights_recorded_in_fits_header() {
+    let summary = StackSummary{frames:vec![("IMG_0001.CR2".to_string(), 1.0), ("IMG_0002.CR2".to_string(), 0.25)], ..Default::default()};
+    let path = temp_path("cr2_to_tiff_halpha_weighted.fits");
+    fits::save(&constant(2, 2, 5.0), &path, fits::BitDepth::Float32, &mean::stack_header(&summary)).unwrap();
+
+    let (_, header) = fits::load(&path).unwrap();
+    assert_eq!(header.stack_count, Some(2));
+    assert!((header.effective_count.unwrap() - summary.effective_count()).abs() < 1e-4);
+    let text = String::from_utf8_lossy(&std::fs::read(&path).unwrap()).to_string();
+    assert!(text.contains("HISTORY Weight 0.25 IMG_0002.CR2"));
+
+    // Unweighted stacks don't record weights
+    let unweighted = StackSummary{frames:vec![("IMG_0001.CR2".to_string(), 1.0)], ..Default::default()};
+    assert_eq!(mean::stack_header(&unweighted).effective_count, None);
+}